@@ -2,7 +2,9 @@ use super::{Result, RuntimeError};
 use mysql::{from_value_opt, prelude::FromValue, FromValueError, Row, Value};
 use serde::{Deserialize, Serialize};
 use std::{
+    borrow::Cow,
     fmt::Display,
+    hash::{Hash, Hasher},
     ops::{Deref, DerefMut},
 };
 
@@ -27,6 +29,90 @@ impl ValueAdaptor {
     pub fn new(v: Value) -> Self {
         Self(v)
     }
+
+    pub fn value(&self) -> &Value {
+        &self.0
+    }
+}
+
+/// The key of a string in the case-insensitive default collation of mysql,
+/// the bytes which are not UTF-8 are kept as they are.
+pub fn collate(bytes: &[u8]) -> Cow<'_, [u8]> {
+    match std::str::from_utf8(bytes) {
+        Ok(s) if s.chars().any(|c| c.to_lowercase().ne([c])) => {
+            Cow::Owned(s.to_lowercase().into_bytes())
+        }
+        _ => Cow::Borrowed(bytes),
+    }
+}
+
+/// A hashable wrapper of [`Value`], used as the key of the hash based operators
+/// (e.g. hash join) in control layer.
+///
+/// Integers are compared by their numeric value regardless of signedness,
+/// floating numbers are compared by their bit pattern, and strings are compared by
+/// [`collate`] as the shards compare them.
+#[derive(Debug, Clone)]
+pub struct HashValue(pub Value);
+
+impl HashValue {
+    fn as_i128(&self) -> Option<i128> {
+        match self.0 {
+            Value::Int(v) => Some(v as i128),
+            Value::UInt(v) => Some(v as i128),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for HashValue {
+    fn eq(&self, other: &Self) -> bool {
+        if let (Some(a), Some(b)) = (self.as_i128(), other.as_i128()) {
+            return a == b;
+        }
+        match (&self.0, &other.0) {
+            (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(),
+            (Value::Double(a), Value::Double(b)) => a.to_bits() == b.to_bits(),
+            (Value::Bytes(a), Value::Bytes(b)) => collate(a) == collate(b),
+            (a, b) => a == b,
+        }
+    }
+}
+
+impl Eq for HashValue {}
+
+impl Hash for HashValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if let Some(v) = self.as_i128() {
+            0u8.hash(state);
+            v.hash(state);
+            return;
+        }
+        match &self.0 {
+            Value::NULL => 1u8.hash(state),
+            Value::Bytes(bytes) => {
+                2u8.hash(state);
+                collate(bytes).hash(state);
+            }
+            Value::Float(v) => {
+                3u8.hash(state);
+                v.to_bits().hash(state);
+            }
+            Value::Double(v) => {
+                4u8.hash(state);
+                v.to_bits().hash(state);
+            }
+            Value::Date(y, m, d, h, mi, s, us) => {
+                5u8.hash(state);
+                (y, m, d, h, mi, s, us).hash(state);
+            }
+            Value::Time(neg, d, h, m, s, us) => {
+                6u8.hash(state);
+                (neg, d, h, m, s, us).hash(state);
+            }
+            Value::Int(_) | Value::UInt(_) => unreachable!(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -57,6 +143,17 @@ impl From<Row> for MyRow {
     }
 }
 
+impl From<Vec<Value>> for MyRow {
+    fn from(values: Vec<Value>) -> Self {
+        Self(
+            values
+                .into_iter()
+                .map(|v| Some(ValueAdaptor::new(v)))
+                .collect(),
+        )
+    }
+}
+
 pub struct BeRead<'a> {
     pub aid: &'a str,
     read_num: u64,
@@ -10,10 +10,15 @@ mod shard_info;
 mod symbol_table;
 pub mod utils;
 
-pub use db_types::{BeRead, MyDate, MyRow, PopularArticle, ValueAdaptor, ValueDef};
+pub use db_types::{
+    collate, BeRead, HashValue, MyDate, MyRow, PopularArticle, ValueAdaptor, ValueDef,
+};
 pub use profiler::{Profile, Profiler};
 pub use result_set::{ExecuteResult, ResultSet};
-pub use shard_info::{get_join_condition, get_shards_info, join_shard_info, DataShard};
+pub use shard_info::{
+    init_sharding_rules, sharding_rules, Colocation, DataShard, Partition, PartitionKind, ShardId,
+    ShardRange, ShardingRules, TableRule,
+};
 pub use symbol_table::SymbolTable;
 
 // #[derive(Debug, PartialEq)]
@@ -76,7 +81,15 @@ pub type Result<T> = std::result::Result<T, RuntimeError>;
 
 impl From<RuntimeError> for Status {
     fn from(e: RuntimeError) -> Self {
-        Status::internal(e.to_string())
+        match e {
+            // errors of the statement from client
+            RuntimeError::SqlParserError(_)
+            | RuntimeError::UnsupportSql(_)
+            | RuntimeError::InvalidArg(_)
+            | RuntimeError::RpcInvalidArg(_) => Status::invalid_argument(e.to_string()),
+            RuntimeError::TonicStatus(status) => status,
+            e => Status::internal(e.to_string()),
+        }
     }
 }
 
@@ -7,6 +7,9 @@ use serde::{ser::SerializeMap, Serialize};
 pub struct ResultSet {
     /// The headers of the table.
     pub header: Vec<String>,
+    /// The type names in mysql of the columns, empty if unknown.
+    #[serde(skip)]
+    pub types: Vec<String>,
     /// The result of the table.
     #[serde(serialize_with = "serialize_value_table")]
     pub table: Vec<Vec<Value>>,
@@ -44,8 +47,23 @@ impl<'a> Serialize for ValueWrapper<'a> {
             Value::UInt(v) => serializer.serialize_u64(*v),
             Value::Float(v) => serializer.serialize_f32(*v),
             Value::Double(v) => serializer.serialize_f64(*v),
-            Value::Date(..) => unreachable!(),
-            Value::Time(..) => unreachable!(),
+            Value::Date(year, month, day, hour, minute, second, micro_second) => {
+                let mut s =
+                    format!("{year:04}-{month:02}-{day:02} {hour:02}:{minute:02}:{second:02}");
+                if *micro_second > 0 {
+                    s += &format!(".{micro_second:06}");
+                }
+                serializer.collect_str(&s)
+            }
+            Value::Time(negative, days, hours, minutes, seconds, micro_seconds) => {
+                let sign = if *negative { "-" } else { "" };
+                let hours = days * 24 + u32::from(*hours);
+                let mut s = format!("{sign}{hours:02}:{minutes:02}:{seconds:02}");
+                if *micro_seconds > 0 {
+                    s += &format!(".{micro_seconds:06}");
+                }
+                serializer.collect_str(&s)
+            }
         }
     }
 }
@@ -68,6 +86,8 @@ where
 pub struct ExecuteResult {
     /// The result set in table format.
     pub result_set: Option<ResultSet>,
+    /// The number of rows changed by INSERT, UPDATE or DELETE.
+    pub affected_rows: Option<u64>,
     /// The elapsed time during the execution, in seconds.
     pub profile: Profile,
 }
@@ -80,8 +100,34 @@ impl Serialize for ExecuteResult {
         let mut object = serializer.serialize_map(None)?;
 
         object.serialize_entry("resultSet", &self.result_set)?;
+        if let Some(affected_rows) = self.affected_rows {
+            object.serialize_entry("affectedRows", &affected_rows)?;
+        }
         object.serialize_entry("profile", &self.profile)?;
 
         object.end()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::ResultSet;
+    use mysql::Value;
+
+    #[test]
+    fn test_serialize_date_time() {
+        let result_set = ResultSet {
+            header: vec!["timestamp".to_owned(), "length".to_owned()],
+            types: vec![],
+            table: vec![vec![
+                Value::Date(2017, 9, 25, 12, 0, 1, 0),
+                Value::Time(true, 1, 2, 3, 4, 500),
+            ]],
+        };
+        let json = serde_json::to_value(&result_set).unwrap();
+        assert_eq!(
+            json["table"],
+            serde_json::json!([["2017-09-25 12:00:01", "-26:03:04.000500"]])
+        );
+    }
+}
@@ -1,124 +1,433 @@
 // Database shards info
 
-use sqlparser::ast::{BinaryOperator::Eq, Expr, Ident};
-use std::{collections::HashMap, vec};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::OnceLock;
 
-#[derive(Debug, Clone)]
+use serde::Deserialize;
+
+use crate::{Result, RuntimeError};
+
+/// The index of a shard, 0 is DBMS1 and 1 is DBMS2.
+pub type ShardId = i32;
+
+/// The rules of this dataset, used if no rules file is given at startup.
+static DEFAULT_SHARDING_RULES: &str = include_str!("../sharding_rules.toml");
+
+static SHARDING_RULES: OnceLock<ShardingRules> = OnceLock::new();
+
+/// Load the sharding rules from the TOML file at `path`, must be called before any query.
+pub fn init_sharding_rules(path: impl AsRef<Path>) -> Result<()> {
+    let rules = ShardingRules::new(std::fs::read_to_string(path)?.as_str())?;
+    SHARDING_RULES
+        .set(rules)
+        .map_err(|_| RuntimeError::Initialized)
+}
+
+/// Get the sharding rules loaded at startup, or the default rules.
+pub fn sharding_rules() -> &'static ShardingRules {
+    SHARDING_RULES.get_or_init(|| {
+        ShardingRules::new(DEFAULT_SHARDING_RULES).expect("default sharding rules are invalid")
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DataShard {
+    /// the rows are split over shards
     Shard,
-    OnlyTwo,
-    NotShard,
-}
-
-// Dbshare1: 0, Dbshard2: 0;
-pub fn get_shards_info() -> HashMap<i32, Vec<Expr>> {
-    let mut shards_info = HashMap::new();
-    let mut db1_info = vec![];
-    let mut db2_info = vec![];
-
-    // basic shard information
-    let region_expr = Box::new(Expr::Identifier(Ident {
-        value: "region".to_string(),
-        quote_style: None,
-    }));
-    // let category_expr = Box::new(Expr::Identifier(Ident {
-    //     value: "category".to_string(),
-    //     quote_style: None,
-    // }));
-    let beijing_expr = Box::new(Expr::Identifier(Ident {
-        value: "Beijing".to_string(),
-        quote_style: Some('"'),
-    }));
-    let hongkong_expr = Box::new(Expr::Identifier(Ident {
-        value: "Hong Kong".to_string(),
-        quote_style: Some('"'),
-    }));
-    // let science_expr = Box::new(Expr::Identifier(Ident {
-    //     value: "Hong Kong".to_string(),
-    //     quote_style: None,
-    // }));
-    // let _expr = Box::new(Expr::Identifier(Ident {
-    //     value: "Hong Kong".to_string(),
-    //     quote_style: None,
-    // }));
-
-    // DB1:
-    let region_beijing = Expr::BinaryOp {
-        left: region_expr.clone(),
-        op: Eq,
-        right: beijing_expr,
-    };
-    db1_info.push(region_beijing);
-    // let category_science = Expr::BinaryOp {
-    //     left: category_expr.clone(),
-    //     op: Eq,
-    //     right: ,
-    // };
-
-    // DB2:
-    let region_hongkong = Expr::BinaryOp {
-        left: region_expr,
-        op: Eq,
-        right: hongkong_expr,
-    };
-    db2_info.push(region_hongkong);
-
-    shards_info.insert(0, db1_info);
-    shards_info.insert(1, db2_info);
-    shards_info
-}
-
-pub fn get_join_condition() -> HashMap<String, Ident> {
-    let mut join_condition = HashMap::new();
-
-    let uid_expr = Ident {
-        value: "uid".to_string(),
-        quote_style: None,
-    };
-
-    let aid_expr = Ident {
-        value: "aid".to_string(),
-        quote_style: None,
-    };
-
-    join_condition.insert("user".to_string(), uid_expr.clone());
-    join_condition.insert("read".to_string(), uid_expr);
-    join_condition.insert("article".to_string(), aid_expr.clone());
-    join_condition.insert("be_read".to_string(), aid_expr);
-
-    join_condition
-}
-
-pub fn join_shard_info() -> HashMap<(String, String), DataShard> {
-    let mut data_shard = HashMap::new();
-
-    // user join user_read
-    data_shard.insert(
-        ("user".to_string(), "user_read".to_string()),
-        DataShard::NotShard,
-    );
-    data_shard.insert(
-        ("user_read".to_string(), "user".to_string()),
-        DataShard::NotShard,
-    );
-    // user join article: need query on both shard and compute in control layer
-    data_shard.insert(
-        ("user".to_string(), "article".to_string()),
-        DataShard::Shard,
-    );
-    data_shard.insert(
-        ("article".to_string(), "user".to_string()),
-        DataShard::Shard,
-    );
-    // be_read join article: need only in shard2
-    data_shard.insert(
-        ("article".to_string(), "be_read".to_string()),
-        DataShard::OnlyTwo,
-    );
-    data_shard.insert(
-        ("be_read".to_string(), "article".to_string()),
-        DataShard::OnlyTwo,
-    );
-
-    data_shard
+    /// all the rows are in one shard
+    Whole(ShardId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionKind {
+    Value,
+    Range,
+    Hash,
+}
+
+/// A literal in the rules file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum Literal {
+    Int(i64),
+    Float(f64),
+    Str(String),
+}
+
+impl Literal {
+    fn into_string(self) -> String {
+        match self {
+            Self::Int(i) => i.to_string(),
+            Self::Float(f) => f.to_string(),
+            Self::Str(s) => s,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TomlPartition {
+    column: String,
+    kind: PartitionKind,
+}
+
+#[derive(Deserialize)]
+struct TomlShard {
+    shard: ShardId,
+    /// values of the column (value partition)
+    values: Option<Vec<Literal>>,
+    /// inclusive lower bound (range partition)
+    lower: Option<Literal>,
+    /// exclusive upper bound (range partition)
+    upper: Option<Literal>,
+}
+
+#[derive(Deserialize)]
+struct TomlColocation {
+    table: String,
+    column: String,
+    parent_column: String,
+}
+
+#[derive(Deserialize)]
+struct TomlTable {
+    name: String,
+    partition: Option<TomlPartition>,
+    shards: Option<Vec<TomlShard>>,
+    colocate_with: Option<TomlColocation>,
+    replicas: Option<Vec<ShardId>>,
+}
+
+#[derive(Deserialize)]
+struct TomlRules {
+    table: Vec<TomlTable>,
+}
+
+/// The rows of a shard in a partitioned table.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShardRange {
+    /// the column is one of the values
+    Values(Vec<String>),
+    /// `lower <= column < upper`
+    Range {
+        lower: Option<String>,
+        upper: Option<String>,
+    },
+    /// the hash of the column modulo `count` is `index`
+    Hash { index: u64, count: u64 },
+}
+
+impl ShardRange {
+    /// Whether the rows with the column of `value` are in the shard.
+    pub fn contains(&self, value: &str) -> bool {
+        match self {
+            // same as the default collation of mysql
+            Self::Values(values) => values.iter().any(|v| v.eq_ignore_ascii_case(value)),
+            Self::Range { lower, upper } => {
+                lower
+                    .as_ref()
+                    .is_none_or(|lower| compare_literal(lower, value) != Ordering::Greater)
+                    && upper
+                        .as_ref()
+                        .is_none_or(|upper| compare_literal(value, upper) == Ordering::Less)
+            }
+            Self::Hash { index, count } => hash_literal(value) % count == *index,
+        }
+    }
+
+    /// All the values of the column in the shard, `None` if they are not enumerable.
+    pub fn values(&self) -> Option<&[String]> {
+        match self {
+            Self::Values(values) => Some(values),
+            _ => None,
+        }
+    }
+}
+
+/// Numbers are compared by value, other literals by bytes.
+fn compare_literal(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.total_cmp(&b),
+        _ => a.cmp(b),
+    }
+}
+
+/// FNV-1a of the lowercase literal, the same for all processes and platforms.
+fn hash_literal(value: &str) -> u64 {
+    value
+        .bytes()
+        .map(|b| b.to_ascii_lowercase())
+        .fold(0xcbf29ce484222325, |hash, b| {
+            (hash ^ b as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+/// A table partitioned by `column`.
+#[derive(Debug, Clone)]
+pub struct Partition {
+    pub column: String,
+    pub shards: Vec<(ShardId, ShardRange)>,
+}
+
+impl Partition {
+    /// The shards holding the rows whose column is `value`.
+    pub fn route(&self, value: &str) -> Vec<ShardId> {
+        self.shards
+            .iter()
+            .filter(|(_, range)| range.contains(value))
+            .map(|(shard, _)| *shard)
+            .collect()
+    }
+}
+
+/// The rows of a table are in the shard of the parent row with `column = parent_column`.
+#[derive(Debug, Clone)]
+pub struct Colocation {
+    pub parent: String,
+    pub column: String,
+    pub parent_column: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TableRule {
+    pub partition: Option<Partition>,
+    pub colocate_with: Option<Colocation>,
+    /// the shards holding all the rows
+    pub replicas: Vec<ShardId>,
+}
+
+/// How the rows of tables are placed in shards.
+#[derive(Debug, Clone, Default)]
+pub struct ShardingRules {
+    tables: HashMap<String, TableRule>,
+}
+
+impl ShardingRules {
+    /// Parse the rules from the TOML content.
+    pub fn new(content: &str) -> Result<Self> {
+        let rules: TomlRules = toml::from_str(content)?;
+        rules.verify()
+    }
+
+    pub fn get_table(&self, table: &str) -> Option<&TableRule> {
+        self.tables.get(table)
+    }
+
+    /// The partition deciding the shard of the rows of `table`,
+    /// which is the partition of its parent if it is co-located.
+    pub fn get_partition(&self, table: &str) -> Option<&Partition> {
+        let rule = self.tables.get(table)?;
+        match &rule.colocate_with {
+            Some(colocation) => self.get_partition(&colocation.parent),
+            None => rule.partition.as_ref(),
+        }
+    }
+
+    /// Where the rows of `table` are when it is queried alone.
+    ///
+    /// The tables out of the rules are regarded as split over shards.
+    pub fn table_location(&self, table: &str) -> DataShard {
+        match self.tables.get(table) {
+            Some(TableRule { replicas, .. }) if !replicas.is_empty() => {
+                DataShard::Whole(replicas[0])
+            }
+            _ => DataShard::Shard,
+        }
+    }
+
+    /// The tables co-located with `table` as their parent, sorted by name.
+    pub fn colocated_children(&self, table: &str) -> Vec<(&str, &Colocation)> {
+        let mut children = self
+            .tables
+            .iter()
+            .filter_map(|(name, rule)| {
+                let colocation = rule.colocate_with.as_ref()?;
+                (colocation.parent == table).then_some((name.as_str(), colocation))
+            })
+            .collect::<Vec<_>>();
+        children.sort_unstable_by_key(|(name, _)| *name);
+        children
+    }
+
+    /// The root of the co-location tree of `table`, whose partition places the rows.
+    fn colocation_root<'a>(&'a self, table: &'a str) -> &'a str {
+        match self
+            .tables
+            .get(table)
+            .and_then(|r| r.colocate_with.as_ref())
+        {
+            Some(colocation) => self.colocation_root(&colocation.parent),
+            None => table,
+        }
+    }
+
+    /// The columns `(column of a, column of b)` on which the rows of `a` and `b` are
+    /// in the same shard, e.g. `user.uid` and `user_read.uid`.
+    ///
+    /// Return `None` if the tables are not co-located.
+    pub fn join_key(&self, a: &str, b: &str) -> Option<(String, String)> {
+        if self.get_partition(a).is_none() || self.colocation_root(a) != self.colocation_root(b) {
+            return None;
+        }
+        // the key of each table to its parent, or to its children
+        let key = |table: &str| -> Option<String> {
+            let rule = self.tables.get(table)?;
+            if let Some(colocation) = &rule.colocate_with {
+                return Some(colocation.column.clone());
+            }
+            self.tables
+                .values()
+                .filter_map(|r| r.colocate_with.as_ref())
+                .find(|colocation| colocation.parent == table)
+                .map(|colocation| colocation.parent_column.clone())
+        };
+        Some((key(a)?, key(b)?))
+    }
+}
+
+impl TomlRules {
+    fn verify(self) -> Result<ShardingRules> {
+        let mut tables = HashMap::new();
+        for table in self.table {
+            let name = table.name;
+            let error =
+                |message: &str| RuntimeError::ConfigError(format!("table {name}: {message}"));
+            let partition = match (table.partition, table.shards) {
+                (Some(TomlPartition { column, kind }), Some(shards)) => {
+                    let count = shards.len() as u64;
+                    let mut ranges = vec![];
+                    for (index, shard) in shards.into_iter().enumerate() {
+                        let range = match kind {
+                            PartitionKind::Value => ShardRange::Values(
+                                shard
+                                    .values
+                                    .ok_or_else(|| error("value partition needs `values`"))?
+                                    .into_iter()
+                                    .map(Literal::into_string)
+                                    .collect(),
+                            ),
+                            PartitionKind::Range => ShardRange::Range {
+                                lower: shard.lower.map(Literal::into_string),
+                                upper: shard.upper.map(Literal::into_string),
+                            },
+                            PartitionKind::Hash => ShardRange::Hash {
+                                index: index as u64,
+                                count,
+                            },
+                        };
+                        ranges.push((shard.shard, range));
+                    }
+                    Some(Partition {
+                        column,
+                        shards: ranges,
+                    })
+                }
+                (None, None) => None,
+                _ => return Err(error("`partition` and `shards` must be given together")),
+            };
+            let colocate_with = table.colocate_with.map(|c| Colocation {
+                parent: c.table,
+                column: c.column,
+                parent_column: c.parent_column,
+            });
+            if partition.is_some() && colocate_with.is_some() {
+                return Err(error("a co-located table cannot be partitioned"));
+            }
+            let rule = TableRule {
+                partition,
+                colocate_with,
+                replicas: table.replicas.unwrap_or_default(),
+            };
+            if tables.insert(name.clone(), rule).is_some() {
+                return Err(error("duplicated"));
+            }
+        }
+        for (name, rule) in tables.iter() {
+            if let Some(colocation) = &rule.colocate_with {
+                let mut parent = Some(colocation.parent.as_str());
+                let mut depth = 0;
+                while let Some(table) = parent {
+                    if depth > tables.len() {
+                        return Err(RuntimeError::ConfigError(format!(
+                            "table {name}: co-location has a cycle"
+                        )));
+                    }
+                    let Some(rule) = tables.get(table) else {
+                        return Err(RuntimeError::ConfigError(format!(
+                            "table {name}: co-located with unknown table {table}"
+                        )));
+                    };
+                    parent = rule.colocate_with.as_ref().map(|c| c.parent.as_str());
+                    depth += 1;
+                }
+            }
+        }
+        Ok(ShardingRules { tables })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sharding_rules, DataShard, ShardRange, ShardingRules};
+
+    #[test]
+    fn test_default_rules() {
+        let rules = sharding_rules();
+        assert_eq!(rules.table_location("user"), DataShard::Shard);
+        assert_eq!(rules.table_location("be_read"), DataShard::Whole(1));
+        assert_eq!(rules.table_location("article"), DataShard::Whole(1));
+        assert_eq!(rules.get_partition("user_read").unwrap().column, "region");
+        assert_eq!(rules.get_partition("user").unwrap().route("beijing"), [0]);
+        assert_eq!(
+            rules.get_partition("article").unwrap().route("science"),
+            [0, 1]
+        );
+        assert_eq!(
+            rules.join_key("user_read", "user"),
+            Some(("uid".to_owned(), "uid".to_owned()))
+        );
+        assert_eq!(rules.join_key("user", "article"), None);
+        assert_eq!(rules.join_key("article", "be_read"), None);
+        assert_eq!(rules.colocated_children("user")[0].0, "user_read");
+        assert!(rules.colocated_children("user_read").is_empty());
+    }
+
+    #[test]
+    fn test_range_and_hash() {
+        let rules = ShardingRules::new(
+            r#"
+            [[table]]
+            name = "t"
+            partition = { column = "id", kind = "range" }
+            shards = [{ shard = 0, upper = 100 }, { shard = 1, lower = 100 }]
+
+            [[table]]
+            name = "h"
+            partition = { column = "name", kind = "hash" }
+            shards = [{ shard = 0 }, { shard = 1 }]
+            "#,
+        )
+        .unwrap();
+        let range = rules.get_partition("t").unwrap();
+        assert_eq!(range.route("99"), [0]);
+        assert_eq!(range.route("100"), [1]);
+        assert_eq!(range.route("1000"), [1]);
+        let hash = rules.get_partition("h").unwrap();
+        for name in ["a", "b", "Beijing", "Hong Kong"] {
+            assert_eq!(hash.route(name).len(), 1);
+            assert_eq!(hash.route(name), hash.route(&name.to_uppercase()));
+        }
+        assert!(ShardRange::Values(vec!["x".to_owned()]).values().is_some());
+
+        assert!(ShardingRules::new(
+            r#"
+            [[table]]
+            name = "t"
+            colocate_with = { table = "unknown", column = "id", parent_column = "id" }
+            "#
+        )
+        .is_err());
+    }
 }
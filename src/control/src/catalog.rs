@@ -0,0 +1,353 @@
+//! The schema of the tables in shards.
+//!
+//! The catalog is loaded from `INFORMATION_SCHEMA` of each dbserver after the cluster is
+//! initialized, and refreshed after DDL statements.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use common::{sharding_rules, MyRow, Result, RuntimeError, ShardId};
+use tracing::info;
+
+use crate::ControlService;
+
+/// Get the columns of all tables in the current database, in their defined order.
+const COLUMNS_SQL: &str = "SELECT TABLE_NAME, COLUMN_NAME, DATA_TYPE \
+    FROM INFORMATION_SCHEMA.COLUMNS \
+    WHERE TABLE_SCHEMA = DATABASE() \
+    ORDER BY TABLE_NAME, ORDINAL_POSITION";
+
+/// Get the estimated number of rows of all tables in the current database.
+const TABLE_ROWS_SQL: &str = "SELECT TABLE_NAME, CAST(IFNULL(TABLE_ROWS, 0) AS CHAR) \
+    FROM INFORMATION_SCHEMA.TABLES \
+    WHERE TABLE_SCHEMA = DATABASE()";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub name: String,
+    /// the type name in mysql, e.g. `char`, `int`
+    pub data_type: String,
+}
+
+/// The columns of each table.
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    tables: HashMap<String, Vec<Column>>,
+    /// the estimated number of rows of each table in all shards
+    table_rows: HashMap<String, u64>,
+}
+
+impl Catalog {
+    /// Build the catalog from the rows of [`COLUMNS_SQL`] of each shard.
+    ///
+    /// A table can be in several shards, its columns are taken from the first shard.
+    pub fn from_rows(rows_of_shards: Vec<Vec<MyRow>>) -> Result<Self> {
+        let mut tables = HashMap::new();
+        for rows in rows_of_shards {
+            let mut shard_tables: HashMap<String, Vec<Column>> = HashMap::new();
+            for row in rows {
+                let column = Column {
+                    name: row.get_row_str(1)?.to_owned(),
+                    data_type: row.get_row_str(2)?.to_owned(),
+                };
+                shard_tables
+                    .entry(row.get_row_str(0)?.to_owned())
+                    .or_default()
+                    .push(column);
+            }
+            for (table, columns) in shard_tables {
+                tables.entry(table).or_insert(columns);
+            }
+        }
+        Ok(Self {
+            tables,
+            ..Default::default()
+        })
+    }
+
+    /// Set the estimated rows of tables from the rows of [`TABLE_ROWS_SQL`] of each shard.
+    ///
+    /// The rows of a table are counted once by the sharding rules: a replicated table is
+    /// counted in its first replica, a partitioned table is summed over the shards of its
+    /// partition, and the other tables are summed over all shards.
+    pub fn set_table_rows(&mut self, rows_of_shards: Vec<(ShardId, Vec<MyRow>)>) -> Result<()> {
+        let rules = sharding_rules();
+        self.table_rows.clear();
+        for (shard, rows) in rows_of_shards {
+            for row in rows {
+                let table = row.get_row_str(0)?;
+                let counted = match rules.get_table(table) {
+                    Some(rule) if !rule.replicas.is_empty() => rule.replicas[0] == shard,
+                    _ => rules
+                        .get_partition(table)
+                        .is_none_or(|partition| partition.shards.iter().any(|(s, _)| *s == shard)),
+                };
+                if !counted {
+                    continue;
+                }
+                let rows = row.get_row_str(1)?.parse::<u64>().map_err(|e| {
+                    RuntimeError::DBTypeParseError(format!("invalid table rows: {e}"))
+                })?;
+                *self.table_rows.entry(table.to_owned()).or_default() += rows;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the estimated number of rows of `table`, `None` if it is unknown.
+    pub fn table_rows(&self, table: &str) -> Option<u64> {
+        self.table_rows.get(table).copied()
+    }
+
+    pub fn contains_table(&self, table: &str) -> bool {
+        self.tables.contains_key(table)
+    }
+
+    pub fn get_columns(&self, table: &str) -> Result<&[Column]> {
+        self.tables
+            .get(table)
+            .map(Vec::as_slice)
+            .ok_or_else(|| RuntimeError::UnsupportSql(format!("unknown table {table}")))
+    }
+
+    /// Get the column names of `table` in their defined order.
+    pub fn get_column_names(&self, table: &str) -> Result<Vec<String>> {
+        Ok(self
+            .get_columns(table)?
+            .iter()
+            .map(|column| column.name.clone())
+            .collect())
+    }
+
+    /// Get the catalog of the `columns` of each table, in their defined order.
+    pub fn project(&self, columns: &HashMap<String, HashSet<String>>) -> Self {
+        let tables = columns
+            .iter()
+            .filter_map(|(table, names)| {
+                let columns = self.tables.get(table)?;
+                let columns = columns
+                    .iter()
+                    .filter(|column| names.contains(&column.name))
+                    .cloned()
+                    .collect();
+                Some((table.clone(), columns))
+            })
+            .collect();
+        Self {
+            tables,
+            ..Default::default()
+        }
+    }
+
+    /// Get the position of `column` in `table`.
+    pub fn column_position(&self, table: &str, column: &str) -> Option<usize> {
+        self.tables
+            .get(table)?
+            .iter()
+            .position(|c| c.name == column)
+    }
+}
+
+impl ControlService {
+    /// Get the catalog used to plan the queries.
+    pub fn get_catalog(&self) -> Arc<Catalog> {
+        self.inner.catalog.read().unwrap().clone()
+    }
+
+    /// Reload the catalog from all shards.
+    pub async fn refresh_catalog(&self) -> Result<()> {
+        let shards = self.shard_map().iter().collect::<Vec<_>>();
+        let shard_sql = |sql: &str| {
+            shards
+                .iter()
+                .map(|(_, server_id)| (*server_id, sql.to_owned()))
+                .collect()
+        };
+        let mut catalog = Catalog::from_rows(self.fetch_rows(shard_sql(COLUMNS_SQL)).await?)?;
+        let table_rows = self.fetch_rows(shard_sql(TABLE_ROWS_SQL)).await?;
+        catalog.set_table_rows(
+            shards
+                .iter()
+                .map(|(shard, _)| *shard)
+                .zip(table_rows)
+                .collect(),
+        )?;
+        info!("refresh catalog: {} tables", catalog.tables.len());
+        *self.inner.catalog.write().unwrap() = Arc::new(catalog);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Catalog {
+    /// The tables of the application.
+    pub fn mock() -> Self {
+        let tables = [
+            (
+                "user",
+                vec![
+                    "timestamp",
+                    "id",
+                    "uid",
+                    "name",
+                    "gender",
+                    "email",
+                    "phone",
+                    "dept",
+                    "grade",
+                    "language",
+                    "region",
+                    "role",
+                    "preferTags",
+                    "obtainedCredits",
+                ],
+            ),
+            (
+                "article",
+                vec![
+                    "timestamp",
+                    "id",
+                    "aid",
+                    "title",
+                    "category",
+                    "abstract",
+                    "articleTags",
+                    "authors",
+                    "language",
+                    "text",
+                    "image",
+                    "video",
+                ],
+            ),
+            (
+                "user_read",
+                vec![
+                    "timestamp",
+                    "id",
+                    "uid",
+                    "aid",
+                    "readTimeLength",
+                    "agreeOrNot",
+                    "commentOrNot",
+                    "shareOrNot",
+                    "commentDetail",
+                ],
+            ),
+            (
+                "be_read",
+                vec![
+                    "id",
+                    "aid",
+                    "readNum",
+                    "readUidList",
+                    "commentNum",
+                    "commentUidList",
+                    "agreeNum",
+                    "agreeUidList",
+                    "shareNum",
+                    "shareUidList",
+                ],
+            ),
+            (
+                "popular_rank",
+                vec!["id", "popularDate", "temporalGranularity", "articleAidList"],
+            ),
+        ];
+        let rows = tables
+            .into_iter()
+            .flat_map(|(table, columns)| {
+                columns.into_iter().map(move |column| {
+                    let data_type = match column {
+                        "readNum" | "commentNum" | "agreeNum" | "shareNum" => "int",
+                        "id" if table == "be_read" || table == "popular_rank" => "int",
+                        _ => "char",
+                    };
+                    MyRow::from(vec![
+                        mysql::Value::from(table),
+                        mysql::Value::from(column),
+                        mysql::Value::from(data_type),
+                    ])
+                })
+            })
+            .collect();
+        let mut catalog = Self::from_rows(vec![rows]).unwrap();
+        // the sizes of the generated data set
+        for (table, rows) in [
+            ("user", 10000),
+            ("article", 10000),
+            ("user_read", 1000000),
+            ("be_read", 10000),
+            ("popular_rank", 100),
+        ] {
+            catalog.table_rows.insert(table.to_owned(), rows);
+        }
+        catalog
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Catalog, Column};
+    use common::MyRow;
+
+    fn mock_rows(rows: &[(&str, &str, &str)]) -> Vec<MyRow> {
+        rows.iter()
+            .map(|(table, column, data_type)| {
+                MyRow::from(vec![
+                    mysql::Value::from(*table),
+                    mysql::Value::from(*column),
+                    mysql::Value::from(*data_type),
+                ])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_catalog() {
+        let shard1 = mock_rows(&[("user", "uid", "char"), ("user", "region", "char")]);
+        let shard2 = mock_rows(&[
+            ("user", "uid", "char"),
+            ("user", "region", "char"),
+            ("be_read", "aid", "char"),
+            ("be_read", "readNum", "int"),
+        ]);
+        let catalog = Catalog::from_rows(vec![shard1, shard2]).unwrap();
+        assert_eq!(catalog.get_column_names("user").unwrap(), ["uid", "region"]);
+        assert_eq!(
+            catalog.get_columns("be_read").unwrap()[1],
+            Column {
+                name: "readNum".to_owned(),
+                data_type: "int".to_owned(),
+            }
+        );
+        assert_eq!(catalog.column_position("be_read", "aid"), Some(0));
+        assert!(catalog.get_columns("article").is_err());
+        assert!(Catalog::mock().contains_table("popular_rank"));
+
+        // the rows of a table are counted once by the sharding rules
+        let mut catalog = catalog;
+        let table_rows = |rows: &[(&str, &str)]| {
+            rows.iter()
+                .map(|(table, rows)| {
+                    MyRow::from(vec![mysql::Value::from(*table), mysql::Value::from(*rows)])
+                })
+                .collect::<Vec<_>>()
+        };
+        catalog
+            .set_table_rows(vec![
+                (0, table_rows(&[("user", "10"), ("article", "3")])),
+                (
+                    1,
+                    table_rows(&[("user", "20"), ("article", "7"), ("be_read", "5")]),
+                ),
+            ])
+            .unwrap();
+        assert_eq!(catalog.table_rows("user"), Some(30));
+        assert_eq!(catalog.table_rows("article"), Some(7));
+        assert_eq!(catalog.table_rows("be_read"), Some(5));
+        catalog
+            .set_table_rows(vec![(0, table_rows(&[("user", "10")]))])
+            .unwrap();
+        assert_eq!(catalog.table_rows("article"), None);
+    }
+}
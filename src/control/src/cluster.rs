@@ -1,17 +1,61 @@
 use crate::ControlService;
-use common::{Result, RuntimeError, ServerId};
+use common::{Result, RuntimeError, ServerId, ShardId};
 use futures::stream::{FuturesOrdered, TryStreamExt};
+use itertools::Itertools;
 use protos::{db_server_client::DbServerClient, ServerRegisterRequest};
 use protos::{
     AppTables, BulkLoadRequest, DbServerMeta, DbShard, DbStatus, InitServerRequest,
     ListServerStatusResponse,
 };
+use std::collections::BTreeMap;
 use std::sync::atomic::Ordering;
 use tonic::transport::{Channel, Uri};
 use tracing::info;
 
 type DbClient = DbServerClient<Channel>;
 
+/// The server holding each shard of the sharding rules.
+///
+/// The shard of a server is the `DbShard` it is initialized with, `DbShard::One` is
+/// shard 0 (DBMS1) and `DbShard::Two` is shard 1 (DBMS2), no matter what its server id is.
+#[derive(Debug, Clone, Default)]
+pub struct ShardMap {
+    servers: BTreeMap<ShardId, ServerId>,
+}
+
+impl ShardMap {
+    pub fn new(shards: impl IntoIterator<Item = (ServerId, DbShard)>) -> Self {
+        let servers = shards
+            .into_iter()
+            .map(|(server_id, shard)| (shard as ShardId, server_id))
+            .collect();
+        Self { servers }
+    }
+
+    /// The shard of the server, `None` if it holds no shard.
+    pub fn shard_of(&self, server_id: ServerId) -> Option<ShardId> {
+        self.servers
+            .iter()
+            .find(|(_, server)| **server == server_id)
+            .map(|(shard, _)| *shard)
+    }
+
+    /// The server holding the shard.
+    pub fn server_of(&self, shard: ShardId) -> Result<ServerId> {
+        self.servers
+            .get(&shard)
+            .copied()
+            .ok_or(RuntimeError::ServerNotAlive)
+    }
+
+    /// The `(shard, server)` of all shards, in the order of shards.
+    pub fn iter(&self) -> impl Iterator<Item = (ShardId, ServerId)> + '_ {
+        self.servers
+            .iter()
+            .map(|(shard, server_id)| (*shard, *server_id))
+    }
+}
+
 impl ControlService {
     pub fn register(&self, req: ServerRegisterRequest) -> Result<ServerId> {
         let ServerRegisterRequest { uri } = req;
@@ -28,6 +72,17 @@ impl ControlService {
         Ok(next_server_id)
     }
 
+    /// Get the servers of the shards from the meta of the alive servers.
+    pub fn shard_map(&self) -> ShardMap {
+        let metas = self.inner.db_server_meta.read().unwrap();
+        ShardMap::new(
+            metas
+                .iter()
+                .filter(|(_, meta)| meta.status() == DbStatus::Alive && meta.shard.is_some())
+                .map(|(server_id, meta)| (*server_id, meta.shard())),
+        )
+    }
+
     pub fn list_server_status(&self) -> Result<ListServerStatusResponse> {
         let server_map = self.inner.db_server_meta.read().unwrap().clone();
         Ok(ListServerStatusResponse { server_map })
@@ -45,9 +100,11 @@ impl ControlService {
         // check the server status
         let target_servers = {
             let metas = self.inner.db_server_meta.read().unwrap();
+            // the servers registered first hold the shards, in the order of their ids
             let alive_servers = metas
                 .iter()
                 .filter(|(_, meta)| meta.status() == DbStatus::Alive)
+                .sorted_by_key(|(sid, _)| **sid)
                 .take(2)
                 .map(|(sid, meta)| (*sid, meta.clone()))
                 .collect::<Vec<_>>();
@@ -84,12 +141,15 @@ impl ControlService {
 
         // update inner state
         self.inner.clients.write().unwrap().extend(clients);
-        let mut metas = self.inner.db_server_meta.write().unwrap();
-        for ((id, m), shard) in target_servers.iter().zip([DbShard::One, DbShard::Two]) {
-            metas.entry(*id).and_modify(|meta| meta.set_shard(shard));
-            log_str += &format!("server {} with shard {:?}, ", m.uri, shard);
+        {
+            let mut metas = self.inner.db_server_meta.write().unwrap();
+            for ((id, m), shard) in target_servers.iter().zip([DbShard::One, DbShard::Two]) {
+                metas.entry(*id).and_modify(|meta| meta.set_shard(shard));
+                log_str += &format!("server {} with shard {:?}, ", m.uri, shard);
+            }
         }
         info!("{log_str}");
-        Ok(())
+        self.resolve_in_doubt().await?;
+        self.refresh_catalog().await
     }
 }
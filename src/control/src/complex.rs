@@ -158,7 +158,7 @@ impl ControlService {
             start = Instant::now();
         }
         trace!("generate_be_read_table: DBMS1 finish create the be_read_table");
-        Ok(())
+        self.refresh_catalog().await
     }
 }
 
@@ -231,7 +231,7 @@ impl ControlService {
             .await?;
         }
 
-        Ok(())
+        self.refresh_catalog().await
     }
 
     pub async fn generate_popular_rank_temporary_table(
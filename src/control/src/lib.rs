@@ -1,10 +1,15 @@
 use protos::db_server_client::DbServerClient;
 use tonic::transport::Channel;
 
+mod catalog;
 mod cluster;
 mod complex;
 mod query;
 mod service;
+mod session;
+mod transaction;
 
+pub use catalog::{Catalog, Column};
 pub use service::ControlService;
+pub use transaction::DEFAULT_DECISION_LOG;
 pub type DbClient = DbServerClient<Channel>;
@@ -0,0 +1,920 @@
+//! Two-phase aggregation.
+//!
+//! The aggregate functions in the projection are rewritten into partial aggregates,
+//! which are computed by each shard for each group, then control layer regroups
+//! the partial results by the group keys, merges them and applies HAVING.
+//!
+//! `COUNT(DISTINCT x)` ships the distinct values of `x` in each group by grouping the shard
+//! query by `x` too. `APPROX_COUNT_DISTINCT(x)` ships a HyperLogLog sketch instead, whose
+//! registers are computed by grouping the shard query by the bucket of the hash of `x`.
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use common::{HashValue, Result, RuntimeError};
+use sqlparser::ast::{
+    Expr, Function, FunctionArg, FunctionArgExpr, Ident, ObjectName, Select, SelectItem, SetExpr,
+    Statement, Value,
+};
+use sqlparser::dialect::GenericDialect;
+
+use super::eval::{eval_expr, is_true, resolve_columns, visit_expr, Number};
+use super::{compare_value, parse_sql};
+
+/// The number of registers of HyperLogLog, with 10 bits of the hash as the bucket.
+const HLL_REGISTERS: u32 = 1024;
+/// The bits of the hash after the bucket, whose leading zeros are counted.
+const HLL_BITS: u32 = 22;
+
+/// The aggregate functions which can be computed in two phases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateFunc {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+    CountDistinct,
+    ApproxCountDistinct,
+}
+
+impl AggregateFunc {
+    fn from_function(function: &Function) -> Option<Self> {
+        if function.over.is_some() || function.name.0.len() != 1 {
+            return None;
+        }
+        match function.name.0[0].value.to_lowercase().as_str() {
+            "count" if function.distinct => Some(Self::CountDistinct),
+            "count" => Some(Self::Count),
+            "sum" => Some(Self::Sum),
+            "min" => Some(Self::Min),
+            "max" => Some(Self::Max),
+            "avg" => Some(Self::Avg),
+            "approx_count_distinct" => Some(Self::ApproxCountDistinct),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Count => "COUNT",
+            Self::Sum => "SUM",
+            Self::Min => "MIN",
+            Self::Max => "MAX",
+            Self::Avg => "AVG",
+            Self::CountDistinct => "COUNT",
+            Self::ApproxCountDistinct => "APPROX_COUNT_DISTINCT",
+        }
+    }
+
+    /// The partial aggregates computed by shards.
+    fn partial_funcs(&self) -> &'static [AggregateFunc] {
+        match self {
+            Self::Count => &[Self::Count],
+            Self::Sum => &[Self::Sum],
+            Self::Min => &[Self::Min],
+            Self::Max => &[Self::Max],
+            // AVG = SUM / COUNT
+            Self::Avg => &[Self::Sum, Self::Count],
+            // the distinct values, or the bucket and MAX of the register of HyperLogLog
+            Self::CountDistinct => &[],
+            Self::ApproxCountDistinct => &[Self::Max],
+        }
+    }
+}
+
+/// Whether `expr` contains any aggregate function.
+pub fn contains_aggregate(expr: &Expr) -> bool {
+    match expr {
+        Expr::Function(function) => {
+            AggregateFunc::from_function(function).is_some()
+                || function.args.iter().any(|arg| match arg {
+                    FunctionArg::Named {
+                        arg: FunctionArgExpr::Expr(expr),
+                        ..
+                    }
+                    | FunctionArg::Unnamed(FunctionArgExpr::Expr(expr)) => contains_aggregate(expr),
+                    _ => false,
+                })
+        }
+        Expr::BinaryOp { left, right, .. } => contains_aggregate(left) || contains_aggregate(right),
+        Expr::UnaryOp { expr, .. }
+        | Expr::Nested(expr)
+        | Expr::Cast { expr, .. }
+        | Expr::IsNull(expr)
+        | Expr::IsNotNull(expr) => contains_aggregate(expr),
+        _ => false,
+    }
+}
+
+/// An aggregate function call in the projection.
+#[derive(Debug, Clone)]
+pub struct AggregateCall {
+    pub func: AggregateFunc,
+    /// the original function call, e.g. `avg(a.readTimeLength)`
+    pub expr: Expr,
+    /// position of the first partial aggregate in the rows from shards
+    pub partial_index: usize,
+}
+
+impl AggregateCall {
+    /// Get the argument of the function, e.g. `x` of `COUNT(DISTINCT x)`.
+    fn arg(&self) -> Result<&Expr> {
+        let Expr::Function(function) = &self.expr else {
+            unreachable!()
+        };
+        match function.args.as_slice() {
+            [FunctionArg::Unnamed(FunctionArgExpr::Expr(arg))] => Ok(arg),
+            _ => Err(RuntimeError::UnsupportSql(format!(
+                "{} needs exactly one argument",
+                self.expr
+            ))),
+        }
+    }
+
+    /// Get the argument of the function, `None` for `COUNT(*)`.
+    fn arg_or_wildcard(&self) -> Result<Option<&Expr>> {
+        let Expr::Function(function) = &self.expr else {
+            unreachable!()
+        };
+        match function.args.as_slice() {
+            [FunctionArg::Unnamed(FunctionArgExpr::Wildcard)] => Ok(None),
+            _ => self.arg().map(Some),
+        }
+    }
+
+    /// Get the HyperLogLog `(bucket, register)` of the argument computed by shards.
+    ///
+    /// The register is the position of the first 1 bit in the hash after the bucket bits.
+    fn hll_exprs(&self) -> Result<(Expr, Expr)> {
+        let arg = self.arg()?;
+        let hash = format!("CRC32({arg})");
+        let rest = format!("FLOOR({hash} / {HLL_REGISTERS})");
+        let sql = format!(
+            "SELECT {hash} % {HLL_REGISTERS}, \
+            IF({rest} = 0, {}, {HLL_BITS} - FLOOR(LOG2({rest})))",
+            HLL_BITS + 1
+        );
+        let statements = parse_sql(&GenericDialect {}, &sql)?;
+        if let [Statement::Query(query)] = statements.as_slice() {
+            if let SetExpr::Select(select) = query.body.as_ref() {
+                if let [SelectItem::UnnamedExpr(bucket), SelectItem::UnnamedExpr(register)] =
+                    select.projection.as_slice()
+                {
+                    return Ok((bucket.clone(), register.clone()));
+                }
+            }
+        }
+        Err(RuntimeError::UnsupportSql(format!(
+            "cannot compute HyperLogLog of {arg}"
+        )))
+    }
+
+    /// Get the partial aggregates of this call
+    fn partial_exprs(&self) -> Result<Vec<Expr>> {
+        let Expr::Function(function) = &self.expr else {
+            unreachable!()
+        };
+        let partials = match self.func {
+            AggregateFunc::CountDistinct => vec![self.arg()?.clone()],
+            AggregateFunc::ApproxCountDistinct => {
+                let (bucket, register) = self.hll_exprs()?;
+                let max = Function {
+                    name: ObjectName(vec![Ident::new(AggregateFunc::Max.name())]),
+                    args: vec![FunctionArg::Unnamed(FunctionArgExpr::Expr(register))],
+                    over: None,
+                    distinct: false,
+                    special: false,
+                };
+                vec![bucket, Expr::Function(max)]
+            }
+            func => func
+                .partial_funcs()
+                .iter()
+                .map(|func| {
+                    let mut partial = function.clone();
+                    partial.name = ObjectName(vec![Ident::new(func.name())]);
+                    Expr::Function(partial)
+                })
+                .collect(),
+        };
+        Ok(partials)
+    }
+
+    /// The number of partial aggregates of this call.
+    fn partial_len(&self) -> usize {
+        match self.func {
+            AggregateFunc::CountDistinct => 1,
+            AggregateFunc::ApproxCountDistinct => 2,
+            func => func.partial_funcs().len(),
+        }
+    }
+
+    /// Get the extra group keys of the shard query, which are shipped instead of aggregated.
+    fn group_keys(&self) -> Result<Vec<Expr>> {
+        Ok(match self.func {
+            AggregateFunc::CountDistinct => vec![self.arg()?.clone()],
+            AggregateFunc::ApproxCountDistinct => vec![self.hll_exprs()?.0],
+            _ => vec![],
+        })
+    }
+}
+
+/// Whether two expressions refer to the same group key,
+/// a column matches its qualified form, e.g. `region` and `u.region`.
+fn same_key(a: &Expr, b: &Expr) -> bool {
+    let column = |expr: &Expr| match expr {
+        Expr::Identifier(ident) if ident.quote_style != Some('"') => Some(ident.value.clone()),
+        Expr::CompoundIdentifier(idents) => idents.last().map(|ident| ident.value.clone()),
+        _ => None,
+    };
+    match (column(a), column(b)) {
+        (Some(a), Some(b)) => a.eq_ignore_ascii_case(&b),
+        _ => a.to_string().eq_ignore_ascii_case(&b.to_string()),
+    }
+}
+
+fn find_alias(aliases: &[Option<String>], name: &str) -> Option<usize> {
+    aliases.iter().position(|alias| {
+        alias
+            .as_ref()
+            .is_some_and(|alias| alias.eq_ignore_ascii_case(name))
+    })
+}
+
+/// How to merge the partial aggregates from shards.
+///
+/// Each row from shards is the group keys followed by the partial aggregates.
+#[derive(Debug, Clone)]
+pub struct AggregatePlan {
+    /// the GROUP BY expressions, empty if the whole result is one group
+    pub group_by: Vec<Expr>,
+    pub calls: Vec<AggregateCall>,
+    /// the projection, computed from the group keys and the aggregates
+    pub output: Vec<Expr>,
+    /// the aliases of the projection, which can be referred by HAVING
+    pub aliases: Vec<Option<String>>,
+    pub having: Option<Expr>,
+}
+
+impl AggregatePlan {
+    /// Build the plan from `select`, and rewrite it into the query of the partial aggregates.
+    ///
+    /// Return `None` if the select has neither aggregate function nor GROUP BY.
+    pub fn build(select: &mut Select) -> Result<Option<Self>> {
+        let has_aggregate = select.projection.iter().any(|item| match item {
+            SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                contains_aggregate(expr)
+            }
+            _ => false,
+        });
+        if !has_aggregate && select.group_by.is_empty() && select.having.is_none() {
+            return Ok(None);
+        }
+        let mut output = vec![];
+        let mut aliases = vec![];
+        for item in select.projection.iter() {
+            match item {
+                SelectItem::UnnamedExpr(expr) => {
+                    output.push(expr.clone());
+                    aliases.push(None);
+                }
+                SelectItem::ExprWithAlias { expr, alias } => {
+                    output.push(expr.clone());
+                    aliases.push(Some(alias.value.clone()));
+                }
+                _ => {
+                    return Err(RuntimeError::UnsupportSql(format!(
+                        "{item} in aggregate query"
+                    )))
+                }
+            }
+        }
+
+        // GROUP BY can refer to the projection by position or alias
+        let mut group_by = vec![];
+        for expr in select.group_by.iter() {
+            let resolved = match expr {
+                Expr::Value(Value::Number(n, _)) => {
+                    let position = n
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|p| (1..=output.len()).contains(p));
+                    let Some(position) = position else {
+                        return Err(RuntimeError::UnsupportSql(format!(
+                            "unknown column {n} in GROUP BY"
+                        )));
+                    };
+                    output[position - 1].clone()
+                }
+                Expr::Identifier(ident) => find_alias(&aliases, &ident.value)
+                    .map_or_else(|| expr.clone(), |idx| output[idx].clone()),
+                _ => expr.clone(),
+            };
+            if contains_aggregate(&resolved) {
+                return Err(RuntimeError::UnsupportSql(format!(
+                    "aggregate function {resolved} in GROUP BY"
+                )));
+            }
+            group_by.push(resolved);
+        }
+
+        // collect the aggregate calls in the projection and HAVING
+        let mut calls: Vec<AggregateCall> = vec![];
+        let mut partial_index = group_by.len();
+        for expr in output.iter().chain(select.having.iter()) {
+            visit_expr(expr, &mut |expr| {
+                let Expr::Function(function) = expr else {
+                    return Ok(true);
+                };
+                let Some(func) = AggregateFunc::from_function(function) else {
+                    return Ok(true);
+                };
+                if (function.distinct && func != AggregateFunc::CountDistinct)
+                    || function.args.iter().any(|arg| match arg {
+                        FunctionArg::Unnamed(FunctionArgExpr::Expr(expr)) => {
+                            contains_aggregate(expr)
+                        }
+                        _ => false,
+                    })
+                {
+                    return Err(RuntimeError::UnsupportSql(format!(
+                        "{expr} in aggregate query"
+                    )));
+                }
+                if !calls.iter().any(|call| call.expr == *expr) {
+                    let call = AggregateCall {
+                        func,
+                        expr: expr.clone(),
+                        partial_index,
+                    };
+                    partial_index += call.partial_len();
+                    calls.push(call);
+                }
+                Ok(false)
+            })?;
+        }
+
+        let plan = Self {
+            group_by,
+            calls,
+            output,
+            aliases,
+            having: select.having.take(),
+        };
+        // the columns out of the aggregates must be group keys,
+        // and HAVING can also refer to the aliases of the projection
+        let outputs = plan.output.iter().map(|expr| (expr, false));
+        for (expr, allow_alias) in outputs.chain(plan.having.iter().map(|expr| (expr, true))) {
+            visit_expr(expr, &mut |expr| {
+                if plan.group_by.iter().any(|key| same_key(key, expr)) {
+                    return Ok(false);
+                }
+                match expr {
+                    Expr::Function(function)
+                        if AggregateFunc::from_function(function).is_some() =>
+                    {
+                        Ok(false)
+                    }
+                    Expr::Identifier(ident)
+                        if ident.quote_style == Some('"')
+                            || (allow_alias && plan.alias_index(&ident.value).is_some()) =>
+                    {
+                        Ok(false)
+                    }
+                    Expr::Identifier(_) | Expr::CompoundIdentifier(_) => Err(
+                        RuntimeError::UnsupportSql(format!("{expr} is not in GROUP BY")),
+                    ),
+                    _ => Ok(true),
+                }
+            })?;
+        }
+
+        let mut projection = plan.group_by.clone();
+        let mut group_by = plan.group_by.clone();
+        for call in plan.calls.iter() {
+            projection.extend(call.partial_exprs()?);
+            for key in call.group_keys()? {
+                if !group_by.contains(&key) {
+                    group_by.push(key);
+                }
+            }
+        }
+        select.projection = projection
+            .into_iter()
+            .map(SelectItem::UnnamedExpr)
+            .collect();
+        select.group_by = group_by;
+        Ok(Some(plan))
+    }
+
+    fn alias_index(&self, name: &str) -> Option<usize> {
+        find_alias(&self.aliases, name)
+    }
+
+    /// Compute the partial aggregates of each row joined in control layer, which are merged
+    /// as the rows from shards by [`AggregatePlan::merge`].
+    ///
+    /// `resolve` gets the position of a column in the joined rows.
+    pub fn partial_rows<F>(
+        &self,
+        rows: Vec<Vec<mysql::Value>>,
+        resolve: F,
+    ) -> Result<Vec<Vec<mysql::Value>>>
+    where
+        F: Fn(&Expr) -> Result<Option<usize>>,
+    {
+        let mut args = vec![];
+        for call in self.calls.iter() {
+            if call.func == AggregateFunc::ApproxCountDistinct {
+                return Err(RuntimeError::UnsupportSql(format!(
+                    "{} over join computed in control layer",
+                    call.expr
+                )));
+            }
+            args.push(call.arg_or_wildcard()?);
+        }
+        let mut positions = HashMap::new();
+        for expr in self.group_by.iter().chain(args.iter().flatten().copied()) {
+            positions.extend(resolve_columns(expr, &resolve)?);
+        }
+
+        let mut partials = Vec::with_capacity(rows.len());
+        for row in rows {
+            let lookup = |expr: &Expr| row.get(*positions.get(expr)?).cloned();
+            let mut partial = self
+                .group_by
+                .iter()
+                .map(|key| eval_expr(key, &lookup))
+                .collect::<Result<Vec<_>>>()?;
+            for (call, arg) in self.calls.iter().zip(args.iter()) {
+                let value = match arg {
+                    Some(arg) => eval_expr(arg, &lookup)?,
+                    None => mysql::Value::Int(1),
+                };
+                let count = mysql::Value::Int((value != mysql::Value::NULL).into());
+                match call.func {
+                    AggregateFunc::Count => partial.push(count),
+                    AggregateFunc::Avg => partial.extend([value, count]),
+                    _ => partial.push(value),
+                }
+            }
+            partials.push(partial);
+        }
+        Ok(partials)
+    }
+
+    /// Merge the partial aggregates from shards into the final rows.
+    pub fn merge(&self, rows: Vec<Vec<mysql::Value>>) -> Result<Vec<Vec<mysql::Value>>> {
+        let key_len = self.group_by.len();
+        // the groups in the order they are first seen
+        let mut group_index = HashMap::<Vec<HashValue>, usize>::new();
+        let mut groups = Vec::<(Vec<mysql::Value>, Vec<Accumulator>)>::new();
+        let new_accumulators = || {
+            self.calls
+                .iter()
+                .map(|call| Accumulator::new(call.func))
+                .collect::<Vec<_>>()
+        };
+        for row in rows.iter() {
+            let keys = row.get(..key_len).ok_or_else(|| {
+                RuntimeError::DBTypeParseError("cannot get group keys".to_owned())
+            })?;
+            let idx = *group_index
+                .entry(keys.iter().cloned().map(HashValue).collect())
+                .or_insert_with(|| {
+                    groups.push((keys.to_vec(), new_accumulators()));
+                    groups.len() - 1
+                });
+            let accumulators = &mut groups[idx].1;
+            for (call, accumulator) in self.calls.iter().zip(accumulators.iter_mut()) {
+                let partial_len = call.partial_len();
+                let partials = row
+                    .get(call.partial_index..call.partial_index + partial_len)
+                    .ok_or_else(|| {
+                        RuntimeError::DBTypeParseError(format!(
+                            "cannot get partial aggregate of {}",
+                            call.expr
+                        ))
+                    })?;
+                accumulator.update(partials)?;
+            }
+        }
+        // aggregate without GROUP BY always returns one row
+        if key_len == 0 && groups.is_empty() {
+            groups.push((vec![], new_accumulators()));
+        }
+
+        let mut results = vec![];
+        for (keys, accumulators) in groups {
+            let aggregates = accumulators
+                .into_iter()
+                .map(Accumulator::finish)
+                .collect::<Vec<_>>();
+            let lookup = |expr: &Expr| {
+                if let Some(idx) = self.calls.iter().position(|call| call.expr == *expr) {
+                    return Some(aggregates[idx].clone());
+                }
+                self.group_by
+                    .iter()
+                    .position(|key| same_key(key, expr))
+                    .map(|idx| keys[idx].clone())
+            };
+            let row = self
+                .output
+                .iter()
+                .map(|expr| eval_expr(expr, &lookup))
+                .collect::<Result<Vec<_>>>()?;
+            if let Some(having) = &self.having {
+                // HAVING can also refer to the aliases of the projection
+                let lookup = |expr: &Expr| {
+                    if let Expr::Identifier(ident) = expr {
+                        if let Some(idx) = self.alias_index(&ident.value) {
+                            return Some(row[idx].clone());
+                        }
+                    }
+                    lookup(expr)
+                };
+                if !is_true(&eval_expr(having, &lookup)?) {
+                    continue;
+                }
+            }
+            results.push(row);
+        }
+        Ok(results)
+    }
+}
+
+/// The accumulator merging the partial aggregates of one aggregate call.
+#[derive(Debug, Clone)]
+enum Accumulator {
+    Count(i128),
+    Sum(Option<Number>),
+    Min(Option<mysql::Value>),
+    Max(Option<mysql::Value>),
+    Avg(Option<Number>, i128),
+    CountDistinct(HashSet<HashValue>),
+    /// the registers of HyperLogLog
+    ApproxCountDistinct(Vec<u8>),
+}
+
+impl Accumulator {
+    fn new(func: AggregateFunc) -> Self {
+        match func {
+            AggregateFunc::Count => Self::Count(0),
+            AggregateFunc::Sum => Self::Sum(None),
+            AggregateFunc::Min => Self::Min(None),
+            AggregateFunc::Max => Self::Max(None),
+            AggregateFunc::Avg => Self::Avg(None, 0),
+            AggregateFunc::CountDistinct => Self::CountDistinct(HashSet::new()),
+            AggregateFunc::ApproxCountDistinct => {
+                Self::ApproxCountDistinct(vec![0; HLL_REGISTERS as usize])
+            }
+        }
+    }
+
+    fn count_of(value: &mysql::Value) -> Result<i128> {
+        match Number::from_value(value)? {
+            Some(Number::Int(v)) => Ok(v),
+            Some(Number::Float(v)) => Ok(v as i128),
+            None => Ok(0),
+        }
+    }
+
+    fn sum(acc: Option<Number>, value: &mysql::Value) -> Result<Option<Number>> {
+        Ok(match (acc, Number::from_value(value)?) {
+            (Some(a), Some(b)) => Some(a.add(b)),
+            (a, b) => a.or(b),
+        })
+    }
+
+    fn extreme(acc: &mut Option<mysql::Value>, value: &mysql::Value, keep: Ordering) {
+        if *value == mysql::Value::NULL {
+            return;
+        }
+        match acc {
+            Some(acc) if compare_value(value, acc) != keep => {}
+            _ => *acc = Some(value.clone()),
+        }
+    }
+
+    /// Update with the partial aggregates from one shard.
+    fn update(&mut self, partials: &[mysql::Value]) -> Result<()> {
+        match self {
+            Self::Count(count) => *count += Self::count_of(&partials[0])?,
+            Self::Sum(sum) => *sum = Self::sum(*sum, &partials[0])?,
+            Self::Min(min) => Self::extreme(min, &partials[0], Ordering::Less),
+            Self::Max(max) => Self::extreme(max, &partials[0], Ordering::Greater),
+            Self::Avg(sum, count) => {
+                *sum = Self::sum(*sum, &partials[0])?;
+                *count += Self::count_of(&partials[1])?;
+            }
+            Self::CountDistinct(values) => {
+                if partials[0] != mysql::Value::NULL {
+                    values.insert(HashValue(partials[0].clone()));
+                }
+            }
+            Self::ApproxCountDistinct(registers) => {
+                // the bucket of NULL is NULL
+                let bucket = Number::from_value(&partials[0])?;
+                let rank = Number::from_value(&partials[1])?;
+                if let (Some(bucket), Some(rank)) = (bucket, rank) {
+                    let register =
+                        &mut registers[bucket.as_f64() as usize % HLL_REGISTERS as usize];
+                    *register = (*register).max(rank.as_f64() as u8);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> mysql::Value {
+        match self {
+            Self::Count(count) => Number::Int(count).into_value(),
+            Self::Sum(sum) => sum.map_or(mysql::Value::NULL, Number::into_value),
+            Self::Min(value) | Self::Max(value) => value.unwrap_or(mysql::Value::NULL),
+            Self::Avg(Some(sum), count) if count > 0 => {
+                mysql::Value::Double(sum.as_f64() / count as f64)
+            }
+            Self::Avg(..) => mysql::Value::NULL,
+            Self::CountDistinct(values) => Number::Int(values.len() as i128).into_value(),
+            Self::ApproxCountDistinct(registers) => {
+                Number::Int(hll_estimate(&registers).round() as i128).into_value()
+            }
+        }
+    }
+}
+
+/// Estimate the number of distinct values by the registers of HyperLogLog.
+fn hll_estimate(registers: &[u8]) -> f64 {
+    let m = registers.len() as f64;
+    let alpha = 0.7213 / (1.0 + 1.079 / m);
+    let sum = registers
+        .iter()
+        .map(|r| 2f64.powi(-(*r as i32)))
+        .sum::<f64>();
+    let estimate = alpha * m * m / sum;
+    let zeros = registers.iter().filter(|r| **r == 0).count();
+    // small range correction by linear counting
+    if estimate <= 2.5 * m && zeros > 0 {
+        return m * (m / zeros as f64).ln();
+    }
+    // large range correction of the 32-bit hash
+    let hash_space = 2f64.powi(32);
+    if estimate > hash_space / 30.0 {
+        return -hash_space * (1.0 - estimate / hash_space).ln();
+    }
+    estimate
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::HashMap;
+    use std::hash::{Hash, Hasher};
+
+    use super::AggregatePlan;
+    use mysql::Value;
+    use sqlparser::ast::{Expr, Select, SetExpr, Statement};
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn mock_select(sql: &str) -> Select {
+        let ast = Parser::parse_sql(&GenericDialect {}, sql).unwrap();
+        let Statement::Query(query) = &ast[0] else {
+            unreachable!()
+        };
+        let SetExpr::Select(select) = query.body.as_ref() else {
+            unreachable!()
+        };
+        *select.clone()
+    }
+
+    #[test]
+    fn test_two_phase_aggregate() {
+        let sql = "SELECT count(*), sum(obtainedCredits), min(uid), max(uid), avg(obtainedCredits) FROM user";
+        let mut select = mock_select(sql);
+        let plan = AggregatePlan::build(&mut select).unwrap().unwrap();
+        assert_eq!(
+            select.to_string(),
+            "SELECT COUNT(*), SUM(obtainedCredits), MIN(uid), MAX(uid), SUM(obtainedCredits), COUNT(obtainedCredits) FROM user"
+        );
+
+        let bytes = |s: &str| Value::Bytes(s.as_bytes().to_vec());
+        let partials = vec![
+            vec![
+                Value::Int(2),
+                bytes("30"),
+                bytes("1"),
+                bytes("5"),
+                bytes("30"),
+                Value::Int(2),
+            ],
+            vec![
+                Value::Int(1),
+                bytes("15"),
+                bytes("0"),
+                bytes("3"),
+                bytes("15"),
+                Value::Int(1),
+            ],
+            vec![
+                Value::Int(0),
+                Value::NULL,
+                Value::NULL,
+                Value::NULL,
+                Value::NULL,
+                Value::Int(0),
+            ],
+        ];
+        let rows = plan.merge(partials).unwrap();
+        assert_eq!(
+            rows,
+            vec![vec![
+                Value::Int(3),
+                Value::Int(45),
+                bytes("0"),
+                bytes("5"),
+                Value::Double(15.0),
+            ]]
+        );
+    }
+
+    #[test]
+    fn test_group_by_and_having() {
+        let sql = "SELECT region, count(*) AS cnt, avg(obtainedCredits) FROM user \
+            GROUP BY region HAVING cnt > 1 AND max(uid) < 10";
+        let mut select = mock_select(sql);
+        let plan = AggregatePlan::build(&mut select).unwrap().unwrap();
+        assert_eq!(
+            select.to_string(),
+            "SELECT region, COUNT(*), SUM(obtainedCredits), COUNT(obtainedCredits), MAX(uid) FROM user GROUP BY region"
+        );
+
+        let bytes = |s: &str| Value::Bytes(s.as_bytes().to_vec());
+        // the groups of Beijing and Hong Kong are split across shards
+        let partials = vec![
+            vec![
+                bytes("Beijing"),
+                Value::Int(1),
+                bytes("10"),
+                Value::Int(1),
+                Value::Int(1),
+            ],
+            vec![
+                bytes("Hong Kong"),
+                Value::Int(1),
+                bytes("20"),
+                Value::Int(1),
+                Value::Int(2),
+            ],
+            vec![
+                bytes("Beijing"),
+                Value::Int(2),
+                bytes("20"),
+                Value::Int(2),
+                Value::Int(5),
+            ],
+            vec![
+                bytes("Hong Kong"),
+                Value::Int(1),
+                bytes("20"),
+                Value::Int(1),
+                Value::Int(12),
+            ],
+            vec![
+                Value::NULL,
+                Value::Int(3),
+                Value::Int(3),
+                Value::Int(3),
+                Value::Int(3),
+            ],
+        ];
+        let rows = plan.merge(partials).unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                vec![bytes("Beijing"), Value::Int(3), Value::Double(10.0)],
+                vec![Value::NULL, Value::Int(3), Value::Double(1.0)],
+            ]
+        );
+
+        // no group, no row
+        assert!(plan.merge(vec![]).unwrap().is_empty());
+
+        let mut select = mock_select("SELECT uid, count(*) FROM user GROUP BY region");
+        assert!(AggregatePlan::build(&mut select).is_err());
+    }
+
+    #[test]
+    fn test_aggregate_joined_rows() {
+        let sql = "SELECT u.region, count(*), avg(r.readTimeLength), count(DISTINCT r.aid) \
+            FROM user AS u JOIN user_read AS r ON u.uid = r.uid GROUP BY u.region";
+        let mut select = mock_select(sql);
+        let plan = AggregatePlan::build(&mut select).unwrap().unwrap();
+
+        // the joined rows of the columns u.region, r.readTimeLength and r.aid
+        let bytes = |s: &str| Value::Bytes(s.as_bytes().to_vec());
+        let columns = ["u.region", "r.readTimeLength", "r.aid"];
+        let resolve = |expr: &Expr| Ok(columns.iter().position(|c| *c == expr.to_string()));
+        let rows = vec![
+            vec![bytes("Beijing"), Value::Int(10), bytes("1")],
+            vec![bytes("Hong Kong"), Value::NULL, bytes("1")],
+            vec![bytes("Beijing"), Value::Int(20), bytes("2")],
+            vec![bytes("Beijing"), Value::Int(30), bytes("1")],
+        ];
+        let partials = plan.partial_rows(rows, resolve).unwrap();
+        assert_eq!(
+            plan.merge(partials).unwrap(),
+            vec![
+                vec![
+                    bytes("Beijing"),
+                    Value::Int(3),
+                    Value::Double(20.0),
+                    Value::Int(2)
+                ],
+                vec![
+                    bytes("Hong Kong"),
+                    Value::Int(1),
+                    Value::NULL,
+                    Value::Int(1)
+                ],
+            ]
+        );
+
+        let sql = "SELECT approx_count_distinct(r.aid) FROM user AS u JOIN user_read AS r \
+            ON u.uid = r.uid";
+        let plan = AggregatePlan::build(&mut mock_select(sql))
+            .unwrap()
+            .unwrap();
+        assert!(plan.partial_rows(vec![], resolve).is_err());
+    }
+
+    #[test]
+    fn test_count_distinct() {
+        let sql = "SELECT region, count(DISTINCT aid), count(*) FROM user_read GROUP BY region";
+        let mut select = mock_select(sql);
+        let plan = AggregatePlan::build(&mut select).unwrap().unwrap();
+        // the distinct values of aid are shipped with the partial aggregates of each value
+        assert_eq!(
+            select.to_string(),
+            "SELECT region, aid, COUNT(*) FROM user_read GROUP BY region, aid"
+        );
+
+        let bytes = |s: &str| Value::Bytes(s.as_bytes().to_vec());
+        let partials = vec![
+            vec![bytes("Beijing"), bytes("1"), Value::Int(2)],
+            vec![bytes("Beijing"), bytes("2"), Value::Int(1)],
+            vec![bytes("Beijing"), Value::NULL, Value::Int(1)],
+            vec![bytes("Beijing"), bytes("1"), Value::Int(3)],
+        ];
+        let rows = plan.merge(partials).unwrap();
+        assert_eq!(
+            rows,
+            vec![vec![bytes("Beijing"), Value::Int(2), Value::Int(7)]]
+        );
+
+        let mut select = mock_select("SELECT sum(DISTINCT readTimeLength) FROM user_read");
+        assert!(AggregatePlan::build(&mut select).is_err());
+    }
+
+    #[test]
+    fn test_approx_count_distinct() {
+        let mut select = mock_select("SELECT approx_count_distinct(aid) FROM user_read");
+        let plan = AggregatePlan::build(&mut select).unwrap().unwrap();
+        assert_eq!(
+            select.to_string(),
+            "SELECT CRC32(aid) % 1024, MAX(IF(FLOOR(CRC32(aid) / 1024) = 0, 23, \
+            22 - FLOOR(LOG2(FLOOR(CRC32(aid) / 1024))))) FROM user_read GROUP BY CRC32(aid) % 1024"
+        );
+
+        // the sketches of two shards, each of 6000 values, 2000 of which are in both
+        let sketch = |values: std::ops::Range<u64>| {
+            let mut registers = HashMap::<i64, i64>::new();
+            for value in values {
+                let mut hasher = DefaultHasher::new();
+                value.hash(&mut hasher);
+                let hash = hasher.finish() as u32;
+                let rest = hash / 1024;
+                let rank = if rest == 0 {
+                    23
+                } else {
+                    22 - rest.ilog2() as i64
+                };
+                let register = registers.entry((hash % 1024) as i64).or_default();
+                *register = (*register).max(rank);
+            }
+            registers
+                .into_iter()
+                .map(|(bucket, rank)| vec![Value::Int(bucket), Value::Int(rank)])
+                .collect::<Vec<_>>()
+        };
+        let partials = [sketch(0..6000), sketch(4000..10000)].concat();
+        let rows = plan.merge(partials).unwrap();
+        let Value::Int(estimate) = rows[0][0] else {
+            unreachable!()
+        };
+        // the standard error is 1.04 / sqrt(1024)
+        assert!((estimate - 10000).abs() < 1000, "estimate {estimate}");
+    }
+}
@@ -0,0 +1,472 @@
+//! UPDATE and DELETE in the shards holding the rows.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use common::{sharding_rules, MyRow, Result, RuntimeError, ServerId, ShardId};
+use sqlparser::ast::{Expr, Statement, TableFactor, TableWithJoins};
+use tracing::warn;
+
+use super::literal;
+use crate::cluster::ShardMap;
+use crate::session::{Session, Transaction};
+use crate::ControlService;
+
+/// The updated rows are moved to the shards of the new value of the partition column.
+#[derive(Debug, Clone)]
+struct ShardMove {
+    column: String,
+    value: String,
+    /// the shards of the new value in the partition
+    shards: Vec<ShardId>,
+}
+
+/// The plan of UPDATE and DELETE of a single table.
+#[derive(Debug, Clone)]
+pub struct DmlPlan {
+    statement: Statement,
+    table: String,
+    relation: TableFactor,
+    selection: Option<Expr>,
+    /// the shards holding all the rows of the table
+    replicas: Vec<ShardId>,
+    shard_move: Option<ShardMove>,
+}
+
+impl DmlPlan {
+    /// Build the plan of `statement`, return `None` if it is not UPDATE or DELETE of
+    /// a single table.
+    pub fn build(statement: &Statement) -> Result<Option<Self>> {
+        let (relation, selection, assignments) = match statement {
+            Statement::Update {
+                table,
+                assignments,
+                from: None,
+                selection,
+                ..
+            } if table.joins.is_empty() => (&table.relation, selection, assignments.as_slice()),
+            Statement::Delete {
+                table_name,
+                using: None,
+                selection,
+                ..
+            } => (table_name, selection, [].as_slice()),
+            _ => return Ok(None),
+        };
+        let TableFactor::Table { name, .. } = relation else {
+            return Ok(None);
+        };
+        let table = name.0.last().unwrap().value.clone();
+
+        let rules = sharding_rules();
+        let rule = rules.get_table(&table);
+        let replicas = rule.map(|rule| rule.replicas.clone()).unwrap_or_default();
+        // the columns placing the rows of the table and its co-located children
+        let mut keys = rules
+            .colocated_children(&table)
+            .into_iter()
+            .map(|(_, colocation)| colocation.parent_column.as_str())
+            .collect::<Vec<_>>();
+        if let Some(colocation) = rule.and_then(|rule| rule.colocate_with.as_ref()) {
+            keys.push(&colocation.column);
+        }
+        let partition = rule.and_then(|rule| rule.partition.as_ref());
+
+        let mut shard_move = None;
+        for assignment in assignments {
+            let column = &assignment.id.last().unwrap().value;
+            if keys.iter().any(|key| key.eq_ignore_ascii_case(column)) {
+                return Err(RuntimeError::UnsupportSql(format!(
+                    "update {table}.{column}, which co-locates the rows"
+                )));
+            }
+            let Some(partition) = partition.filter(|p| p.column.eq_ignore_ascii_case(column))
+            else {
+                continue;
+            };
+            let value = literal(&assignment.value).flatten().ok_or_else(|| {
+                RuntimeError::UnsupportSql(format!(
+                    "{column} of {table} must be set to a non-NULL literal, found {}",
+                    assignment.value
+                ))
+            })?;
+            let shards = partition.route(&value);
+            if shards.is_empty() {
+                return Err(RuntimeError::InvalidArg(format!(
+                    "no shard for {column} = {value}"
+                )));
+            }
+            shard_move = Some(ShardMove {
+                column: column.clone(),
+                value,
+                shards,
+            });
+        }
+
+        Ok(Some(Self {
+            statement: statement.clone(),
+            table,
+            relation: relation.clone(),
+            selection: selection.clone(),
+            replicas,
+            shard_move,
+        }))
+    }
+
+    /// The table to prune the shards by the selection.
+    pub fn table_with_joins(&self) -> TableWithJoins {
+        TableWithJoins {
+            relation: self.relation.clone(),
+            joins: vec![],
+        }
+    }
+
+    pub fn selection(&self) -> Option<&Expr> {
+        self.selection.as_ref()
+    }
+
+    /// Get the sql executed in a shard with the `selection` of the shard.
+    ///
+    /// The rows to move to other shards are read at first.
+    pub fn shard_sql(&self, selection: Option<Expr>) -> String {
+        if self.shard_move.is_some() {
+            return match selection {
+                Some(selection) => format!("SELECT * FROM {} WHERE {selection}", self.relation),
+                None => format!("SELECT * FROM {}", self.relation),
+            };
+        }
+        let mut statement = self.statement.clone();
+        match &mut statement {
+            Statement::Update { selection: s, .. } | Statement::Delete { selection: s, .. } => {
+                *s = selection
+            }
+            _ => unreachable!(),
+        }
+        statement.to_string()
+    }
+
+    /// Describe how the updated rows are moved.
+    pub fn describe_move(&self) -> Option<String> {
+        let ShardMove { column, value, .. } = self.shard_move.as_ref()?;
+        let children = sharding_rules()
+            .colocated_children(&self.table)
+            .into_iter()
+            .map(|(child, _)| format!(" with the rows of {child}"))
+            .collect::<String>();
+        Some(format!(
+            "move the rows of {}{children} to shards {:?} of {column} = {value}",
+            self.table,
+            self.target()
+        ))
+    }
+
+    /// The shards of the updated rows.
+    fn target(&self) -> Vec<ShardId> {
+        let mut target = self
+            .shard_move
+            .as_ref()
+            .map(|shard_move| shard_move.shards.clone())
+            .unwrap_or_default();
+        target.extend(&self.replicas);
+        target.sort_unstable();
+        target.dedup();
+        target
+    }
+
+    fn count(&self, affected: impl Iterator<Item = (ShardId, u64)>) -> u64 {
+        count_affected(&self.replicas, affected)
+    }
+}
+
+/// The total affected rows of a table from the affected rows of each shard.
+///
+/// A replica holds all the rows, so its affected rows are the total.
+pub(crate) fn count_affected(
+    replicas: &[ShardId],
+    affected: impl Iterator<Item = (ShardId, u64)>,
+) -> u64 {
+    match replicas.first() {
+        Some(replica) => affected
+            .filter(|(shard, _)| shard == replica)
+            .map(|(_, count)| count)
+            .sum(),
+        None => affected.map(|(_, count)| count).sum(),
+    }
+}
+
+/// The rows in the literals of sql.
+fn to_sql_rows(rows: Vec<MyRow>) -> Result<Vec<Vec<String>>> {
+    rows.into_iter()
+        .map(|row| {
+            Ok(row
+                .get_raw_value()?
+                .iter()
+                .map(|value| value.as_sql(false))
+                .collect())
+        })
+        .collect()
+}
+
+fn insert_sql(table: &str, rows: &[&Vec<String>]) -> String {
+    let values = rows
+        .iter()
+        .map(|row| format!("({})", row.join(", ")))
+        .collect::<Vec<_>>();
+    format!("INSERT INTO {table} VALUES {}", values.join(", "))
+}
+
+impl ControlService {
+    /// Execute UPDATE or DELETE, return the number of affected rows.
+    ///
+    /// `shard_sql` are the sqls of [`DmlPlan::shard_sql`] in the shards not pruned.
+    /// The writes in all shards are in a distributed transaction, or in `txn` of the session.
+    pub(crate) async fn exec_dml(
+        &self,
+        txn: Option<&mut Transaction>,
+        dml: &DmlPlan,
+        shard_sql: Vec<(ServerId, String)>,
+    ) -> Result<u64> {
+        let shard_map = self.shard_map();
+        let shards = shard_sql
+            .iter()
+            .map(|(server_id, _)| {
+                shard_map
+                    .shard_of(*server_id)
+                    .ok_or(RuntimeError::ServerNotAlive)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if dml.shard_move.is_none() {
+            let affected = self
+                .exec_write_in(
+                    txn,
+                    shard_sql
+                        .into_iter()
+                        .map(|(server_id, sql)| (server_id, vec![sql]))
+                        .collect(),
+                )
+                .await?;
+            let affected = affected.into_iter().map(|affected| affected[0]);
+            return Ok(dml.count(shards.into_iter().zip(affected)));
+        }
+        if let Some(txn) = txn {
+            return self
+                .move_rows(txn, dml, &shard_map, shards, shard_sql)
+                .await;
+        }
+
+        // the rows are read in the same transaction as they are moved
+        let mut session = Session::default();
+        self.begin(&mut session)?;
+        let txn = session.transaction().unwrap();
+        match self
+            .move_rows(txn, dml, &shard_map, shards, shard_sql)
+            .await
+        {
+            Ok(affected) => {
+                self.commit(&mut session).await?;
+                Ok(affected)
+            }
+            Err(e) => {
+                if let Err(e) = self.rollback(&mut session).await {
+                    warn!("roll back the moved rows failed: {e}");
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Move the updated rows of `shards` to the target shards of [`ShardMove`] in `txn`.
+    async fn move_rows(
+        &self,
+        txn: &mut Transaction,
+        dml: &DmlPlan,
+        shard_map: &ShardMap,
+        shards: Vec<ShardId>,
+        shard_sql: Vec<(ServerId, String)>,
+    ) -> Result<u64> {
+        // the rows are locked until they are moved
+        let shard_sql = shard_sql
+            .into_iter()
+            .map(|(server_id, sql)| (server_id, format!("{sql} FOR UPDATE")))
+            .collect();
+        // 1. read the rows to update
+        let target = dml.target();
+        let rows = self.fetch_rows_in(Some(&mut *txn), shard_sql).await?;
+        let mut shard_rows = HashMap::new();
+        for (shard, rows) in shards.into_iter().zip(rows) {
+            shard_rows.insert(shard, to_sql_rows(rows)?);
+        }
+        // the shards out of the target whose rows are moved
+        let mut sources = shard_rows
+            .iter()
+            .filter(|(shard, rows)| !target.contains(shard) && !rows.is_empty())
+            .map(|(shard, _)| *shard)
+            .collect::<Vec<_>>();
+        sources.sort_unstable();
+        let mut moved = vec![];
+        let mut seen = HashSet::new();
+        for row in sources.iter().flat_map(|shard| &shard_rows[shard]) {
+            if seen.insert(row) {
+                moved.push(row);
+            }
+        }
+
+        // the statements of each shard, executed in order
+        let mut writes: BTreeMap<ShardId, Vec<String>> = BTreeMap::new();
+        if !moved.is_empty() {
+            // 2. copy the rows to the target shards which do not have them
+            for shard in target.iter() {
+                let existing = shard_rows
+                    .get(shard)
+                    .map(|rows| rows.iter().collect::<HashSet<_>>())
+                    .unwrap_or_default();
+                let rows = moved
+                    .iter()
+                    .filter(|row| !existing.contains(*row))
+                    .copied()
+                    .collect::<Vec<_>>();
+                if !rows.is_empty() {
+                    writes
+                        .entry(*shard)
+                        .or_default()
+                        .push(insert_sql(&dml.table, &rows));
+                }
+            }
+
+            // 3. move the rows of the co-located tables with the moved rows
+            let catalog = self.get_catalog();
+            for (child, colocation) in sharding_rules().colocated_children(&dml.table) {
+                let position = catalog
+                    .column_position(&dml.table, &colocation.parent_column)
+                    .ok_or_else(|| {
+                        RuntimeError::UnsupportSql(format!(
+                            "unknown column {}.{}",
+                            dml.table, colocation.parent_column
+                        ))
+                    })?;
+                let mut keys = moved
+                    .iter()
+                    .map(|row| row[position].as_str())
+                    .collect::<Vec<_>>();
+                keys.sort_unstable();
+                keys.dedup();
+                let selection = format!("{} IN ({})", colocation.column, keys.join(", "));
+                let child_sources = sources
+                    .iter()
+                    .filter(|shard| {
+                        sharding_rules()
+                            .get_table(child)
+                            .is_none_or(|rule| !rule.replicas.contains(shard))
+                    })
+                    .copied()
+                    .collect::<Vec<_>>();
+                let select_sql = format!("SELECT * FROM {child} WHERE {selection} FOR UPDATE");
+                let child_sql = child_sources
+                    .iter()
+                    .map(|shard| Ok((shard_map.server_of(*shard)?, select_sql.clone())))
+                    .collect::<Result<Vec<_>>>()?;
+                let child_rows = self.fetch_rows_in(Some(&mut *txn), child_sql).await?;
+                let child_rows = to_sql_rows(child_rows.into_iter().flatten().collect())?;
+                if child_rows.is_empty() {
+                    continue;
+                }
+                let child_rows = child_rows.iter().collect::<Vec<_>>();
+                for shard in dml.shard_move.as_ref().unwrap().shards.iter() {
+                    writes
+                        .entry(*shard)
+                        .or_default()
+                        .push(insert_sql(child, &child_rows));
+                }
+                for shard in child_sources {
+                    writes
+                        .entry(shard)
+                        .or_default()
+                        .push(format!("DELETE FROM {child} WHERE {selection}"));
+                }
+            }
+        }
+
+        // 4. update the rows in the target shards
+        for shard in target.iter() {
+            writes
+                .entry(*shard)
+                .or_default()
+                .push(dml.statement.to_string());
+        }
+
+        // 5. delete the moved rows from the shards out of the target
+        let delete_sql = match &dml.selection {
+            Some(selection) => format!("DELETE FROM {} WHERE {selection}", dml.relation),
+            None => format!("DELETE FROM {}", dml.relation),
+        };
+        for shard in sources {
+            writes.entry(shard).or_default().push(delete_sql.clone());
+        }
+
+        let shards = writes.keys().copied().collect::<Vec<_>>();
+        let server_statements = writes
+            .into_iter()
+            .map(|(shard, statements)| Ok((shard_map.server_of(shard)?, statements)))
+            .collect::<Result<Vec<_>>>()?;
+        let affected = self.exec_write_in(Some(txn), server_statements).await?;
+        // the affected rows of UPDATE, which is the last statement in the target shards
+        let affected = shards
+            .into_iter()
+            .zip(affected)
+            .filter(|(shard, _)| target.contains(shard))
+            .map(|(shard, affected)| (shard, *affected.last().unwrap()));
+        Ok(dml.count(affected))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use sqlparser::{dialect::GenericDialect, parser::Parser};
+
+    use super::DmlPlan;
+
+    fn plan(sql: &str) -> common::Result<Option<DmlPlan>> {
+        let statement = Parser::parse_sql(&GenericDialect {}, sql)
+            .unwrap()
+            .remove(0);
+        DmlPlan::build(&statement)
+    }
+
+    #[test]
+    fn test_dml_plan() {
+        let update = plan("UPDATE user SET name = 'u1' WHERE region = 'Beijing'")
+            .unwrap()
+            .unwrap();
+        assert!(update.describe_move().is_none());
+        assert_eq!(update.shard_sql(None), "UPDATE user SET name = 'u1'");
+
+        let update = plan("UPDATE user SET region = 'Hong Kong' WHERE uid = '1'")
+            .unwrap()
+            .unwrap();
+        assert_eq!(update.target(), [1]);
+        assert_eq!(
+            update.shard_sql(update.selection().cloned()),
+            "SELECT * FROM user WHERE uid = '1'"
+        );
+        assert_eq!(
+            update.describe_move().unwrap(),
+            "move the rows of user with the rows of user_read to shards [1] of region = Hong Kong"
+        );
+        assert!(plan("UPDATE user SET region = 'Shanghai'").is_err());
+        assert!(plan("UPDATE user SET region = lower(region)").is_err());
+        assert!(plan("UPDATE user SET uid = '2' WHERE uid = '1'").is_err());
+        assert!(plan("UPDATE user_read SET uid = '2' WHERE uid = '1'").is_err());
+
+        // the science articles are in both shards, and all articles are in the replica
+        let update = plan("UPDATE article SET category = 'science' WHERE aid = '1'")
+            .unwrap()
+            .unwrap();
+        assert_eq!(update.target(), [0, 1]);
+        assert_eq!(update.count([(0, 1), (1, 1)].into_iter()), 1);
+
+        let delete = plan("DELETE FROM user_read WHERE aid = '1'")
+            .unwrap()
+            .unwrap();
+        assert_eq!(delete.count([(0, 2), (1, 3)].into_iter()), 5);
+        assert!(plan("SELECT * FROM user").unwrap().is_none());
+    }
+}
@@ -0,0 +1,416 @@
+//! Evaluate expressions over the rows in control layer.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use common::{Result, RuntimeError};
+use sqlparser::ast::{BinaryOperator, Expr, FunctionArg, FunctionArgExpr, UnaryOperator, Value};
+
+use super::compare_value;
+
+/// A number in control layer computation.
+#[derive(Debug, Clone, Copy)]
+pub enum Number {
+    Int(i128),
+    Float(f64),
+}
+
+impl Number {
+    pub fn from_value(value: &mysql::Value) -> Result<Option<Self>> {
+        use mysql::Value::*;
+        let number = match value {
+            NULL => return Ok(None),
+            Int(v) => Self::Int(*v as i128),
+            UInt(v) => Self::Int(*v as i128),
+            Float(v) => Self::Float(*v as f64),
+            Double(v) => Self::Float(*v),
+            // DECIMAL and CHAR are returned in bytes
+            Bytes(bytes) => {
+                let s = std::str::from_utf8(bytes)?.trim();
+                match s.parse::<i128>() {
+                    Ok(v) => Self::Int(v),
+                    Err(_) => Self::Float(s.parse::<f64>().map_err(|_| {
+                        RuntimeError::DBTypeParseError(format!("{s} is not a number"))
+                    })?),
+                }
+            }
+            _ => {
+                return Err(RuntimeError::DBTypeParseError(format!(
+                    "{value:?} is not a number"
+                )))
+            }
+        };
+        Ok(Some(number))
+    }
+
+    pub fn add(self, other: Self) -> Self {
+        match (self, other) {
+            (Self::Int(a), Self::Int(b)) => Self::Int(a + b),
+            (a, b) => Self::Float(a.as_f64() + b.as_f64()),
+        }
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            Self::Int(v) => *v as f64,
+            Self::Float(v) => *v,
+        }
+    }
+
+    pub fn into_value(self) -> mysql::Value {
+        match self {
+            Self::Int(v) => i64::try_from(v)
+                .map(mysql::Value::Int)
+                .unwrap_or(mysql::Value::Double(v as f64)),
+            Self::Float(v) => mysql::Value::Double(v),
+        }
+    }
+}
+
+/// Whether the value is considered as true in a condition.
+pub fn is_true(value: &mysql::Value) -> bool {
+    match Number::from_value(value) {
+        Ok(Some(number)) => number.as_f64() != 0.0,
+        Ok(None) => false,
+        // a string which is not a number
+        Err(_) => false,
+    }
+}
+
+fn bool_value(b: bool) -> mysql::Value {
+    mysql::Value::Int(b as i64)
+}
+
+fn literal(value: &Value) -> Result<mysql::Value> {
+    Ok(match value {
+        Value::Number(number, _) => match number.parse::<i64>() {
+            Ok(v) => mysql::Value::Int(v),
+            Err(_) => mysql::Value::Double(
+                number
+                    .parse()
+                    .map_err(|_| RuntimeError::UnsupportSql(format!("invalid number {number}")))?,
+            ),
+        },
+        Value::SingleQuotedString(s) | Value::DoubleQuotedString(s) => {
+            mysql::Value::Bytes(s.as_bytes().to_vec())
+        }
+        Value::Boolean(b) => bool_value(*b),
+        Value::Null => mysql::Value::NULL,
+        _ => return Err(RuntimeError::UnsupportSql(format!("literal {value}"))),
+    })
+}
+
+fn compare(op: &BinaryOperator, a: &mysql::Value, b: &mysql::Value) -> mysql::Value {
+    if *a == mysql::Value::NULL || *b == mysql::Value::NULL {
+        return mysql::Value::NULL;
+    }
+    let ord = compare_value(a, b);
+    bool_value(match op {
+        BinaryOperator::Eq => ord == Ordering::Equal,
+        BinaryOperator::NotEq => ord != Ordering::Equal,
+        BinaryOperator::Lt => ord == Ordering::Less,
+        BinaryOperator::LtEq => ord != Ordering::Greater,
+        BinaryOperator::Gt => ord == Ordering::Greater,
+        BinaryOperator::GtEq => ord != Ordering::Less,
+        _ => unreachable!(),
+    })
+}
+
+fn arithmetic(op: &BinaryOperator, a: &mysql::Value, b: &mysql::Value) -> Result<mysql::Value> {
+    let (Some(a), Some(b)) = (Number::from_value(a)?, Number::from_value(b)?) else {
+        return Ok(mysql::Value::NULL);
+    };
+    let value = match (op, a, b) {
+        (BinaryOperator::Plus, a, b) => a.add(b),
+        (BinaryOperator::Minus, Number::Int(a), Number::Int(b)) => Number::Int(a - b),
+        (BinaryOperator::Minus, a, b) => Number::Float(a.as_f64() - b.as_f64()),
+        (BinaryOperator::Multiply, Number::Int(a), Number::Int(b)) => Number::Int(a * b),
+        (BinaryOperator::Multiply, a, b) => Number::Float(a.as_f64() * b.as_f64()),
+        // division by zero is NULL in mysql
+        (BinaryOperator::Divide | BinaryOperator::Modulo, _, b) if b.as_f64() == 0.0 => {
+            return Ok(mysql::Value::NULL)
+        }
+        (BinaryOperator::Divide, a, b) => Number::Float(a.as_f64() / b.as_f64()),
+        (BinaryOperator::Modulo, Number::Int(a), Number::Int(b)) => Number::Int(a % b),
+        (BinaryOperator::Modulo, a, b) => Number::Float(a.as_f64() % b.as_f64()),
+        _ => unreachable!(),
+    };
+    Ok(value.into_value())
+}
+
+/// Visit `expr` and its sub-expressions, `f` returns whether to visit the children.
+pub fn visit_expr<F>(expr: &Expr, f: &mut F) -> Result<()>
+where
+    F: FnMut(&Expr) -> Result<bool>,
+{
+    if !f(expr)? {
+        return Ok(());
+    }
+    match expr {
+        Expr::Function(function) => {
+            for arg in function.args.iter() {
+                if let FunctionArg::Named {
+                    arg: FunctionArgExpr::Expr(expr),
+                    ..
+                }
+                | FunctionArg::Unnamed(FunctionArgExpr::Expr(expr)) = arg
+                {
+                    visit_expr(expr, f)?;
+                }
+            }
+        }
+        Expr::BinaryOp { left, right, .. } => {
+            visit_expr(left, f)?;
+            visit_expr(right, f)?;
+        }
+        Expr::UnaryOp { expr, .. }
+        | Expr::Nested(expr)
+        | Expr::Cast { expr, .. }
+        | Expr::IsNull(expr)
+        | Expr::IsNotNull(expr) => visit_expr(expr, f)?,
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            visit_expr(expr, f)?;
+            visit_expr(low, f)?;
+            visit_expr(high, f)?;
+        }
+        Expr::InList { expr, list, .. } => {
+            visit_expr(expr, f)?;
+            for item in list {
+                visit_expr(item, f)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Resolve the positions of the columns referred by `expr` in the rows it is evaluated over.
+///
+/// `resolve` returns `None` if the expression is not a column of the rows, its errors
+/// (e.g. an ambiguous column) are returned before any row is evaluated.
+pub fn resolve_columns<F>(expr: &Expr, resolve: F) -> Result<HashMap<Expr, usize>>
+where
+    F: Fn(&Expr) -> Result<Option<usize>>,
+{
+    let mut columns = HashMap::new();
+    visit_expr(expr, &mut |expr| match resolve(expr)? {
+        Some(idx) => {
+            columns.insert(expr.clone(), idx);
+            Ok(false)
+        }
+        None => Ok(true),
+    })?;
+    Ok(columns)
+}
+
+/// Visit `expr` and its sub-expressions mutably, in the same order as [`visit_expr`].
+pub fn visit_expr_mut<F>(expr: &mut Expr, f: &mut F) -> Result<()>
+where
+    F: FnMut(&mut Expr) -> Result<bool>,
+{
+    if !f(expr)? {
+        return Ok(());
+    }
+    match expr {
+        Expr::Function(function) => {
+            for arg in function.args.iter_mut() {
+                if let FunctionArg::Named {
+                    arg: FunctionArgExpr::Expr(expr),
+                    ..
+                }
+                | FunctionArg::Unnamed(FunctionArgExpr::Expr(expr)) = arg
+                {
+                    visit_expr_mut(expr, f)?;
+                }
+            }
+        }
+        Expr::BinaryOp { left, right, .. } => {
+            visit_expr_mut(left, f)?;
+            visit_expr_mut(right, f)?;
+        }
+        Expr::UnaryOp { expr, .. }
+        | Expr::Nested(expr)
+        | Expr::Cast { expr, .. }
+        | Expr::IsNull(expr)
+        | Expr::IsNotNull(expr) => visit_expr_mut(expr, f)?,
+        Expr::Between {
+            expr, low, high, ..
+        } => {
+            visit_expr_mut(expr, f)?;
+            visit_expr_mut(low, f)?;
+            visit_expr_mut(high, f)?;
+        }
+        Expr::InList { expr, list, .. } => {
+            visit_expr_mut(expr, f)?;
+            for item in list {
+                visit_expr_mut(item, f)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Evaluate `expr` in control layer.
+///
+/// `lookup` resolves the sub-expressions which are computed already,
+/// e.g. columns of the row, or the aggregate functions of a group.
+/// It is tried before evaluating every sub-expression.
+pub fn eval_expr<F>(expr: &Expr, lookup: &F) -> Result<mysql::Value>
+where
+    F: Fn(&Expr) -> Option<mysql::Value>,
+{
+    if let Some(value) = lookup(expr) {
+        return Ok(value);
+    }
+    let value = match expr {
+        Expr::Value(value) => literal(value)?,
+        // mysql treats the double quoted string as string instead of identifier
+        Expr::Identifier(ident) if ident.quote_style == Some('"') => {
+            mysql::Value::Bytes(ident.value.as_bytes().to_vec())
+        }
+        Expr::Nested(expr) => eval_expr(expr, lookup)?,
+        Expr::BinaryOp { left, op, right } => {
+            let a = eval_expr(left, lookup)?;
+            let b = eval_expr(right, lookup)?;
+            match op {
+                BinaryOperator::And => {
+                    if (a != mysql::Value::NULL && !is_true(&a))
+                        || (b != mysql::Value::NULL && !is_true(&b))
+                    {
+                        bool_value(false)
+                    } else if a == mysql::Value::NULL || b == mysql::Value::NULL {
+                        mysql::Value::NULL
+                    } else {
+                        bool_value(true)
+                    }
+                }
+                BinaryOperator::Or => {
+                    if is_true(&a) || is_true(&b) {
+                        bool_value(true)
+                    } else if a == mysql::Value::NULL || b == mysql::Value::NULL {
+                        mysql::Value::NULL
+                    } else {
+                        bool_value(false)
+                    }
+                }
+                BinaryOperator::Eq
+                | BinaryOperator::NotEq
+                | BinaryOperator::Lt
+                | BinaryOperator::LtEq
+                | BinaryOperator::Gt
+                | BinaryOperator::GtEq => compare(op, &a, &b),
+                BinaryOperator::Plus
+                | BinaryOperator::Minus
+                | BinaryOperator::Multiply
+                | BinaryOperator::Divide
+                | BinaryOperator::Modulo => arithmetic(op, &a, &b)?,
+                _ => return Err(RuntimeError::UnsupportSql(format!("operator {op}"))),
+            }
+        }
+        Expr::UnaryOp { op, expr } => {
+            let value = eval_expr(expr, lookup)?;
+            match (op, value) {
+                (_, mysql::Value::NULL) => mysql::Value::NULL,
+                (UnaryOperator::Not, value) => bool_value(!is_true(&value)),
+                (UnaryOperator::Plus, value) => value,
+                (UnaryOperator::Minus, value) => {
+                    arithmetic(&BinaryOperator::Minus, &mysql::Value::Int(0), &value)?
+                }
+                (op, _) => return Err(RuntimeError::UnsupportSql(format!("operator {op}"))),
+            }
+        }
+        Expr::IsNull(expr) => bool_value(eval_expr(expr, lookup)? == mysql::Value::NULL),
+        Expr::IsNotNull(expr) => bool_value(eval_expr(expr, lookup)? != mysql::Value::NULL),
+        Expr::Between {
+            expr,
+            negated,
+            low,
+            high,
+        } => {
+            let value = eval_expr(expr, lookup)?;
+            let low = compare(&BinaryOperator::GtEq, &value, &eval_expr(low, lookup)?);
+            let high = compare(&BinaryOperator::LtEq, &value, &eval_expr(high, lookup)?);
+            match (low, high) {
+                (mysql::Value::NULL, _) | (_, mysql::Value::NULL) => mysql::Value::NULL,
+                (low, high) => bool_value((is_true(&low) && is_true(&high)) != *negated),
+            }
+        }
+        Expr::InList {
+            expr,
+            list,
+            negated,
+        } => {
+            let value = eval_expr(expr, lookup)?;
+            if value == mysql::Value::NULL {
+                return Ok(mysql::Value::NULL);
+            }
+            let mut has_null = false;
+            let mut found = false;
+            for item in list {
+                let item = eval_expr(item, lookup)?;
+                if item == mysql::Value::NULL {
+                    has_null = true;
+                } else if compare_value(&value, &item) == Ordering::Equal {
+                    found = true;
+                    break;
+                }
+            }
+            if !found && has_null {
+                mysql::Value::NULL
+            } else {
+                bool_value(found != *negated)
+            }
+        }
+        _ => {
+            return Err(RuntimeError::UnsupportSql(format!(
+                "cannot evaluate {expr} in control layer"
+            )))
+        }
+    };
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{eval_expr, is_true};
+    use mysql::Value;
+    use sqlparser::ast::{Expr, SelectItem, SetExpr, Statement};
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn parse_expr(sql: &str) -> Expr {
+        let ast = Parser::parse_sql(&GenericDialect {}, &format!("SELECT {sql}")).unwrap();
+        let Statement::Query(query) = &ast[0] else {
+            unreachable!()
+        };
+        let SetExpr::Select(select) = query.body.as_ref() else {
+            unreachable!()
+        };
+        let SelectItem::UnnamedExpr(expr) = &select.projection[0] else {
+            unreachable!()
+        };
+        expr.clone()
+    }
+
+    #[test]
+    fn test_eval_expr() {
+        let lookup = |expr: &Expr| match expr.to_string().as_str() {
+            "count(*)" => Some(Value::Int(12)),
+            "region" => Some(Value::Bytes(b"Beijing".to_vec())),
+            "missing" => Some(Value::NULL),
+            _ => None,
+        };
+        let eval = |sql: &str| eval_expr(&parse_expr(sql), &lookup).unwrap();
+
+        assert!(is_true(&eval("count(*) > 10 AND region = 'Beijing'")));
+        assert!(is_true(&eval("region IN ('Hong Kong', \"Beijing\")")));
+        assert!(!is_true(&eval("count(*) BETWEEN 1 AND 10")));
+        assert_eq!(eval("count(*) * 2 - 4"), Value::Int(20));
+        assert_eq!(eval("count(*) / 0"), Value::NULL);
+        assert_eq!(eval("missing > 1 OR count(*) < 1"), Value::NULL);
+        assert_eq!(eval("NOT (missing IS NULL)"), Value::Int(0));
+    }
+}
@@ -0,0 +1,388 @@
+//! EXPLAIN the distributed plan of a statement.
+
+use itertools::Itertools;
+use mysql::Value;
+use sqlparser::ast::{JoinConstraint, JoinOperator};
+
+use super::plan::PlanNode;
+use super::{AggregatePlan, DmlPlan, JoinInfo, RewriteResult};
+
+fn aliases(tables: &[(String, String)]) -> String {
+    tables.iter().map(|(_, alias)| alias).join(", ")
+}
+
+fn explain_join(
+    JoinInfo {
+        left,
+        right,
+        operator,
+    }: &JoinInfo,
+) -> String {
+    let (left, right) = (aliases(left), aliases(right));
+    let (kind, constraint) = match operator {
+        JoinOperator::Inner(constraint) => ("", constraint),
+        JoinOperator::LeftOuter(constraint) => ("left outer ", constraint),
+        JoinOperator::RightOuter(constraint) => ("right outer ", constraint),
+        JoinOperator::FullOuter(constraint) => ("full outer ", constraint),
+        JoinOperator::CrossJoin => return format!("cross join ({left}) and ({right})"),
+        operator => return format!("{operator:?} ({left}) and ({right})"),
+    };
+    match constraint {
+        JoinConstraint::On(on) => format!("{kind}hash join ({left}) and ({right}) ON {on}"),
+        JoinConstraint::Using(columns) => format!(
+            "{kind}hash join ({left}) and ({right}) USING ({})",
+            columns.iter().join(", ")
+        ),
+        JoinConstraint::Natural => format!("natural {kind}hash join ({left}) and ({right})"),
+        JoinConstraint::None => format!("cross join ({left}) and ({right})"),
+    }
+}
+
+fn explain_aggregate(aggregate: &AggregatePlan, joined: bool) -> String {
+    let calls = aggregate.calls.iter().map(|call| &call.expr).join(", ");
+    let mut detail = if joined {
+        format!("aggregate the joined rows {calls}")
+    } else {
+        format!("merge partial aggregates {calls}")
+    };
+    if !aggregate.group_by.is_empty() {
+        detail += &format!(" GROUP BY {}", aggregate.group_by.iter().join(", "));
+    }
+    detail
+}
+
+/// Describe the sqls of the shards scanned by `node`, labelled by the fragment of the join.
+fn explain_scans(node: &PlanNode, fragment: Option<usize>, stages: &mut Vec<(&str, String)>) {
+    let fragment = fragment.map_or(String::new(), |idx| format!("fragment {idx} "));
+    node.visit(&mut |node| {
+        if let PlanNode::Scan { server_id, sql } = node {
+            match sql {
+                Some(sql) => {
+                    stages.push(("shard", format!("{fragment}on server {server_id}: {sql}")))
+                }
+                None => stages.push(("shard", format!("{fragment}on server {server_id}: pruned"))),
+            }
+        }
+    });
+}
+
+/// Describe the computation of `node` after its inputs.
+fn explain_node(node: &PlanNode, stages: &mut Vec<(&str, String)>) {
+    match node {
+        // 1. sqls executed in shards
+        node if node.is_gather() => {
+            explain_scans(node, None, stages);
+            stages.push(("merge", "concatenate the rows of shards".to_owned()));
+        }
+        PlanNode::Join { inputs, join_plan } => {
+            for (idx, input) in inputs.iter().enumerate() {
+                explain_scans(input, Some(idx), stages);
+            }
+            if let Some(semi_join) = &join_plan.semi_join {
+                stages.push(("semi-join", semi_join.describe()));
+            }
+            for step in join_plan.steps.iter() {
+                stages.push(("join", explain_join(step)));
+            }
+        }
+        PlanNode::Aggregate {
+            input,
+            plan,
+            joined,
+        } => {
+            if input.is_gather() {
+                explain_scans(input, None, stages);
+            } else {
+                explain_node(input, stages);
+            }
+            stages.push(("aggregate", explain_aggregate(plan, joined.is_some())));
+            if let Some(having) = &plan.having {
+                stages.push(("having", having.to_string()));
+            }
+        }
+        // 2. computation in control layer
+        PlanNode::Filter {
+            input, predicate, ..
+        } => {
+            explain_node(input, stages);
+            stages.push(("filter", predicate.to_string()));
+        }
+        PlanNode::Project { input, join_plan } => {
+            explain_node(input, stages);
+            stages.push(("project", join_plan.projection.iter().join(", ")));
+        }
+        PlanNode::Distinct { input } => {
+            explain_node(input, stages);
+            stages.push(("distinct", "deduplicate the rows by hashing".to_owned()));
+        }
+        PlanNode::Sort {
+            input,
+            order_by,
+            presorted,
+            ..
+        } => {
+            explain_node(input, stages);
+            let order_by = order_by.iter().join(", ");
+            if *presorted {
+                stages.push(("sort", format!("merge sorted rows of shards by {order_by}")));
+            } else {
+                stages.push(("sort", format!("sort by {order_by}")));
+            }
+        }
+        PlanNode::Limit {
+            input,
+            limit,
+            offset,
+        } => {
+            explain_node(input, stages);
+            match (limit, offset) {
+                (Some(limit), 0) => stages.push(("limit", limit.to_string())),
+                (Some(limit), offset) => stages.push(("limit", format!("{limit} offset {offset}"))),
+                (None, offset) => stages.push(("limit", format!("offset {offset}"))),
+            }
+        }
+        PlanNode::Truncate { input, width } => {
+            explain_node(input, stages);
+            stages.push((
+                "project",
+                format!("drop the columns fetched for ORDER BY after the first {width}"),
+            ));
+        }
+        PlanNode::Union { inputs, op, all } => {
+            for input in inputs {
+                explain_node(input, stages);
+            }
+            let detail = if *all {
+                "keep the duplicate rows"
+            } else {
+                "deduplicate the rows by hashing"
+            };
+            stages.push(("set operation", format!("{op}: {detail}")));
+        }
+        PlanNode::Scan { .. } => unreachable!("a scan is a gather of one shard"),
+    }
+}
+
+impl RewriteResult {
+    /// Describe the plan in rows of `(stage, detail)`.
+    pub(super) fn explain(&self) -> Vec<Vec<Value>> {
+        let mut stages = vec![];
+        if self.insert.is_some() || self.dml.is_some() {
+            explain_scans(&self.plan, None, &mut stages);
+        } else {
+            explain_node(&self.plan, &mut stages);
+        }
+        if let Some(shard_move) = self.dml.as_ref().and_then(DmlPlan::describe_move) {
+            stages.push(("move", shard_move));
+        }
+        if let Some(insert) = &self.insert {
+            stages.push(("insert", format!("split {}", insert.describe())));
+        }
+        stages
+            .into_iter()
+            .map(|(stage, detail)| {
+                vec![
+                    Value::Bytes(stage.as_bytes().to_vec()),
+                    Value::Bytes(detail.into_bytes()),
+                ]
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use common::{Profiler, ServerId};
+    use mysql::Value;
+    use protos::{DbServerMeta, DbShard, DbStatus};
+
+    use super::super::rewrite_sql;
+    use crate::Catalog;
+
+    fn explain(sql: &str) -> Vec<(String, String)> {
+        explain_in(&[(0, DbShard::One), (1, DbShard::Two)], sql)
+    }
+
+    /// Explain `sql` in the servers holding the shards.
+    fn explain_in(servers: &[(ServerId, DbShard)], sql: &str) -> Vec<(String, String)> {
+        let mut shards = HashMap::new();
+        for &(server_id, shard) in servers {
+            let mut meta = DbServerMeta::default();
+            meta.set_shard(shard);
+            meta.set_status(DbStatus::Alive);
+            shards.insert(server_id, meta);
+        }
+        let plan = rewrite_sql(
+            sql.to_owned(),
+            shards,
+            Arc::new(Catalog::mock()),
+            &mut Profiler::default(),
+        )
+        .unwrap();
+        assert!(plan.explain);
+        plan.explain()
+            .into_iter()
+            .map(|row| {
+                let text = |value: &Value| match value {
+                    Value::Bytes(bytes) => String::from_utf8(bytes.clone()).unwrap(),
+                    _ => unreachable!(),
+                };
+                (text(&row[0]), text(&row[1]))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_explain() {
+        let stages = explain(
+            "EXPLAIN SELECT name FROM user WHERE region = 'Beijing' ORDER BY uid DESC LIMIT 5",
+        );
+        assert_eq!(
+            stages,
+            [
+                (
+                    "shard".to_owned(),
+                    "on server 0: SELECT name, uid FROM user ORDER BY uid DESC LIMIT 5".to_owned()
+                ),
+                ("shard".to_owned(), "on server 1: pruned".to_owned()),
+                (
+                    "merge".to_owned(),
+                    "concatenate the rows of shards".to_owned()
+                ),
+                (
+                    "sort".to_owned(),
+                    "merge sorted rows of shards by uid DESC".to_owned()
+                ),
+                ("limit".to_owned(), "5".to_owned()),
+                // uid is not in the select list, it is fetched for sorting then dropped
+                (
+                    "project".to_owned(),
+                    "drop the columns fetched for ORDER BY after the first 1".to_owned()
+                ),
+            ]
+        );
+
+        // the replicated table is only scanned in its first replica
+        let stages = explain("EXPLAIN SELECT count(*) FROM article");
+        assert_eq!(
+            stages[..2],
+            [
+                ("shard".to_owned(), "on server 0: pruned".to_owned()),
+                (
+                    "shard".to_owned(),
+                    "on server 1: SELECT COUNT(*) FROM article".to_owned()
+                ),
+            ]
+        );
+
+        // the shards are placed by the shard of the servers, not their ids
+        let stages = explain_in(
+            &[(3, DbShard::Two), (7, DbShard::One)],
+            "EXPLAIN SELECT name FROM user WHERE region = 'Beijing'",
+        );
+        assert_eq!(
+            stages[..2],
+            [
+                ("shard".to_owned(), "on server 3: pruned".to_owned()),
+                (
+                    "shard".to_owned(),
+                    "on server 7: SELECT name FROM user".to_owned()
+                ),
+            ]
+        );
+
+        // shards return LIMIT + OFFSET rows, OFFSET is skipped after merging
+        let stages = explain("EXPLAIN SELECT uid, name FROM user ORDER BY uid LIMIT 5 OFFSET 10");
+        assert_eq!(
+            stages[0].1,
+            "on server 0: SELECT uid, name FROM user ORDER BY uid LIMIT 15"
+        );
+        assert_eq!(stages.last().unwrap().1, "5 offset 10");
+
+        let stages = explain(
+            "EXPLAIN SELECT * FROM user AS u JOIN article AS a ON u.uid = a.aid WHERE u.uid < 10",
+        );
+        let stages = stages
+            .iter()
+            .map(|(stage, _)| stage.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stages,
+            [
+                "shard",
+                "shard",
+                "shard",
+                "shard",
+                "semi-join",
+                "join",
+                "project"
+            ]
+        );
+
+        // only the columns of the projection, join keys and ORDER BY are fetched
+        let stages = explain(
+            "EXPLAIN SELECT u.name, a.title FROM user AS u JOIN article AS a ON u.uid = a.aid \
+            ORDER BY a.timestamp LIMIT 5",
+        );
+        assert_eq!(
+            stages[0].1,
+            "fragment 0 on server 0: SELECT u.uid, u.name FROM user AS u"
+        );
+        assert_eq!(
+            stages[3].1,
+            "fragment 1 on server 1: SELECT a.timestamp, a.aid, a.title FROM article AS a"
+        );
+        assert_eq!(stages.last().unwrap().1, "u.name, a.title");
+
+        // the unmatched rows of outer joins are padded with NULL in control layer
+        let stages = explain(
+            "EXPLAIN SELECT u.uid FROM user AS u LEFT JOIN article AS a ON u.uid = a.aid \
+            WHERE a.aid IS NULL",
+        );
+        assert!(stages.contains(&(
+            "join".to_owned(),
+            "left outer hash join (u) and (a) ON u.uid = a.aid".to_owned()
+        )));
+        assert!(stages.contains(&("filter".to_owned(), "a.aid IS NULL".to_owned())));
+
+        // co-located outer joins are pushed down, and the shards are not pruned by
+        // the partition column which may be NULL padded
+        let stages = explain(
+            "EXPLAIN SELECT u.uid FROM user AS u LEFT JOIN user_read AS r ON u.uid = r.uid \
+            WHERE r.region IS NULL",
+        );
+        assert_eq!(
+            stages[..2],
+            [
+                (
+                    "shard".to_owned(),
+                    "on server 0: SELECT u.uid FROM user AS u LEFT JOIN user_read AS r \
+                    ON u.uid = r.uid WHERE r.region IS NULL"
+                        .to_owned()
+                ),
+                (
+                    "shard".to_owned(),
+                    "on server 1: SELECT u.uid FROM user AS u LEFT JOIN user_read AS r \
+                    ON u.uid = r.uid WHERE r.region IS NULL"
+                        .to_owned()
+                ),
+            ]
+        );
+
+        // the distinct rows of shards are deduplicated again
+        let stages = explain("EXPLAIN SELECT DISTINCT aid FROM user_read");
+        assert!(stages.contains(&(
+            "distinct".to_owned(),
+            "deduplicate the rows by hashing".to_owned()
+        )));
+
+        let stages = explain("EXPLAIN SELECT region, count(*) FROM user GROUP BY region");
+        assert_eq!(
+            stages.last().unwrap().1,
+            "merge partial aggregates count(*) GROUP BY region"
+        );
+    }
+}
@@ -0,0 +1,412 @@
+use std::collections::{BTreeMap, HashMap};
+
+use common::{sharding_rules, MyRow, Partition, Result, RuntimeError, ServerId, ShardId};
+use sqlparser::ast::{Expr, SetExpr, Statement, Values};
+
+use super::semi_join::to_literal;
+use super::{count_affected, literal};
+use crate::session::Transaction;
+use crate::{Catalog, ControlService};
+
+/// How the shards of the rows are decided.
+#[derive(Debug, Clone)]
+enum Routing {
+    /// every row is copied into all shards, e.g. the table is not in the sharding rules
+    Broadcast,
+    /// all the rows are in the replicas
+    Whole,
+    /// by the partition column at `position` of each row
+    Partition {
+        position: usize,
+        partition: Partition,
+    },
+    /// with the parent row which has the same key, the key is at `position` of each row
+    Parent {
+        position: usize,
+        parent: String,
+        parent_column: String,
+        partition: Partition,
+    },
+}
+
+/// The plan to split the rows of `INSERT ... VALUES` into shards.
+///
+/// The rows of a co-located table follow their parent rows, so the partition of the parent
+/// rows is looked up by [`InsertPlan::lookup_sql`] before the rows are split.
+#[derive(Debug, Clone)]
+pub struct InsertPlan {
+    /// the insert statement, the rows of each shard are filled in its `VALUES`
+    statement: Statement,
+    table: String,
+    rows: Vec<Vec<Expr>>,
+    routing: Routing,
+    /// the shards holding all the rows of the table
+    replicas: Vec<ShardId>,
+}
+
+impl InsertPlan {
+    /// Build the plan of `statement`, return `None` if it is not `INSERT ... VALUES`.
+    pub fn build(statement: &Statement, catalog: &Catalog) -> Result<Option<Self>> {
+        let Statement::Insert {
+            table_name,
+            columns,
+            source,
+            ..
+        } = statement
+        else {
+            return Ok(None);
+        };
+        let SetExpr::Values(Values(rows)) = source.body.as_ref() else {
+            return Ok(None);
+        };
+        let table = table_name.0.last().unwrap().value.clone();
+        let position = |column: &str| {
+            let position = if columns.is_empty() {
+                catalog
+                    .get_columns(&table)?
+                    .iter()
+                    .position(|c| c.name.eq_ignore_ascii_case(column))
+            } else {
+                columns
+                    .iter()
+                    .position(|c| c.value.eq_ignore_ascii_case(column))
+            };
+            position.ok_or_else(|| {
+                RuntimeError::InvalidArg(format!("missing column {column} to shard {table}"))
+            })
+        };
+
+        let rules = sharding_rules();
+        let (routing, replicas) = match rules.get_table(&table) {
+            None => (Routing::Broadcast, vec![]),
+            Some(rule) => {
+                let routing = if let Some(partition) = &rule.partition {
+                    Routing::Partition {
+                        position: position(&partition.column)?,
+                        partition: partition.clone(),
+                    }
+                } else if let Some(colocation) = &rule.colocate_with {
+                    let partition = rules
+                        .get_table(&colocation.parent)
+                        .and_then(|parent| parent.partition.clone())
+                        .ok_or_else(|| {
+                            RuntimeError::UnsupportSql(format!(
+                                "insert into {table} co-located with {}, which is not partitioned",
+                                colocation.parent
+                            ))
+                        })?;
+                    Routing::Parent {
+                        position: position(&colocation.column)?,
+                        parent: colocation.parent.clone(),
+                        parent_column: colocation.parent_column.clone(),
+                        partition,
+                    }
+                } else if !rule.replicas.is_empty() {
+                    Routing::Whole
+                } else {
+                    Routing::Broadcast
+                };
+                (routing, rule.replicas.clone())
+            }
+        };
+        Ok(Some(Self {
+            statement: statement.clone(),
+            table,
+            rows: rows.clone(),
+            routing,
+            replicas,
+        }))
+    }
+
+    /// The sql to get the partition column of the parent rows, keyed by the parent column.
+    ///
+    /// Return `None` if the rows can be split without the parent rows.
+    pub fn lookup_sql(&self) -> Option<String> {
+        let Routing::Parent {
+            position,
+            parent,
+            parent_column,
+            partition,
+        } = &self.routing
+        else {
+            return None;
+        };
+        // the keys which are not literals fail to split the rows, they are not looked up
+        let mut keys = self
+            .rows
+            .iter()
+            .filter_map(|row| literal(row.get(*position)?).flatten())
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        let keys = keys
+            .iter()
+            .map(|key| to_literal(&mysql::Value::from(key)).to_string())
+            .collect::<Vec<_>>();
+        Some(format!(
+            "SELECT {parent_column}, {} FROM {parent} WHERE {parent_column} IN ({})",
+            partition.column,
+            keys.join(", ")
+        ))
+    }
+
+    /// The total affected rows from the affected rows of each shard.
+    pub fn count(&self, affected: impl Iterator<Item = (ShardId, u64)>) -> u64 {
+        count_affected(&self.replicas, affected)
+    }
+
+    /// Describe how the rows are split.
+    pub fn describe(&self) -> String {
+        let rows = self.rows.len();
+        match &self.routing {
+            Routing::Broadcast => format!("{rows} rows into all shards"),
+            Routing::Whole => format!("{rows} rows into shards {:?}", self.replicas),
+            Routing::Partition { partition, .. } => {
+                format!("{rows} rows by {}.{}", self.table, partition.column)
+            }
+            Routing::Parent {
+                parent, partition, ..
+            } => format!(
+                "{rows} rows by {parent}.{} of the parent rows",
+                partition.column
+            ),
+        }
+    }
+
+    /// Split the rows into the sqls of their shards.
+    ///
+    /// `parent_rows` are the results of [`InsertPlan::lookup_sql`], the shards of all
+    /// servers are returned for the broadcast table.
+    pub fn split(
+        &self,
+        all_shards: &[ShardId],
+        parent_rows: &[MyRow],
+    ) -> Result<BTreeMap<ShardId, String>> {
+        let parents = parent_rows
+            .iter()
+            .map(|row| {
+                Ok((
+                    row.get_row_str(0)?.to_owned(),
+                    row.get_row_str(1)?.to_owned(),
+                ))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        let literal_at = |row: &[Expr], position: usize, column: &str| {
+            let expr = row.get(position).ok_or_else(|| {
+                RuntimeError::InvalidArg(format!("missing column {column} to shard {}", self.table))
+            })?;
+            literal(expr).flatten().ok_or_else(|| {
+                RuntimeError::UnsupportSql(format!(
+                    "{column} of {} must be a non-NULL literal, found {expr}",
+                    self.table
+                ))
+            })
+        };
+
+        let mut shard_rows: BTreeMap<ShardId, Vec<Vec<Expr>>> = BTreeMap::new();
+        for row in &self.rows {
+            let mut shards = match &self.routing {
+                Routing::Broadcast => all_shards.to_vec(),
+                Routing::Whole => vec![],
+                Routing::Partition {
+                    position,
+                    partition,
+                } => route(partition, &literal_at(row, *position, &partition.column)?)?,
+                Routing::Parent {
+                    position,
+                    parent,
+                    parent_column,
+                    partition,
+                } => {
+                    let key = literal_at(row, *position, parent_column)?;
+                    let value = parents.get(&key).ok_or_else(|| {
+                        RuntimeError::InvalidArg(format!(
+                            "no row in {parent} with {parent_column} = {key}"
+                        ))
+                    })?;
+                    route(partition, value)?
+                }
+            };
+            shards.extend(&self.replicas);
+            shards.sort_unstable();
+            shards.dedup();
+            for shard in shards {
+                shard_rows.entry(shard).or_default().push(row.clone());
+            }
+        }
+
+        let mut shard_sqls = BTreeMap::new();
+        for (shard, rows) in shard_rows {
+            if !all_shards.contains(&shard) {
+                return Err(RuntimeError::ServerNotAlive);
+            }
+            let mut statement = self.statement.clone();
+            if let Statement::Insert { source, .. } = &mut statement {
+                *source.body = SetExpr::Values(Values(rows));
+            }
+            shard_sqls.insert(shard, statement.to_string());
+        }
+        Ok(shard_sqls)
+    }
+}
+
+impl ControlService {
+    /// Insert the rows into their shards, return the number of affected rows.
+    ///
+    /// `shard_sql` are the sqls of the split rows, or [`InsertPlan::lookup_sql`] in all
+    /// shards to get the parent rows. The rows in all shards are in a distributed transaction,
+    /// or in `txn` of the session.
+    pub(crate) async fn exec_insert(
+        &self,
+        mut txn: Option<&mut Transaction>,
+        insert: &InsertPlan,
+        shard_sql: Vec<(ServerId, String)>,
+    ) -> Result<u64> {
+        let shard_map = self.shard_map();
+        let shard_sql = if insert.lookup_sql().is_some() {
+            let all_shards = shard_sql
+                .iter()
+                .filter_map(|(server_id, _)| shard_map.shard_of(*server_id))
+                .collect::<Vec<_>>();
+            let parent_rows = self.fetch_rows_in(txn.as_deref_mut(), shard_sql).await?;
+            let parent_rows = parent_rows.into_iter().flatten().collect::<Vec<_>>();
+            insert
+                .split(&all_shards, &parent_rows)?
+                .into_iter()
+                .map(|(shard, sql)| Ok((shard_map.server_of(shard)?, sql)))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            shard_sql
+        };
+        let shards = shard_sql
+            .iter()
+            .map(|(server_id, _)| {
+                shard_map
+                    .shard_of(*server_id)
+                    .ok_or(RuntimeError::ServerNotAlive)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let affected = self
+            .exec_write_in(
+                txn,
+                shard_sql
+                    .into_iter()
+                    .map(|(server_id, sql)| (server_id, vec![sql]))
+                    .collect(),
+            )
+            .await?;
+        let affected = affected.into_iter().map(|affected| affected[0]);
+        Ok(insert.count(shards.into_iter().zip(affected)))
+    }
+}
+
+fn route(partition: &Partition, value: &str) -> Result<Vec<ShardId>> {
+    let shards = partition.route(value);
+    if shards.is_empty() {
+        return Err(RuntimeError::InvalidArg(format!(
+            "no shard for {} = {value}",
+            partition.column
+        )));
+    }
+    Ok(shards)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use common::MyRow;
+    use sqlparser::{dialect::GenericDialect, parser::Parser};
+
+    use super::InsertPlan;
+    use crate::Catalog;
+
+    fn plan(sql: &str) -> InsertPlan {
+        let statement = Parser::parse_sql(&GenericDialect {}, sql)
+            .unwrap()
+            .remove(0);
+        InsertPlan::build(&statement, &Catalog::mock())
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_insert_partition() {
+        let insert = plan(
+            "INSERT INTO user (uid, region) VALUES ('1', 'Beijing'), ('2', 'Hong Kong'), ('3', 'Beijing')",
+        );
+        assert!(insert.lookup_sql().is_none());
+        let sqls = insert.split(&[0, 1], &[]).unwrap();
+        assert_eq!(
+            sqls,
+            BTreeMap::from([
+                (
+                    0,
+                    "INSERT INTO user (uid, region) VALUES ('1', 'Beijing'), ('3', 'Beijing')"
+                        .to_owned()
+                ),
+                (
+                    1,
+                    "INSERT INTO user (uid, region) VALUES ('2', 'Hong Kong')".to_owned()
+                ),
+            ])
+        );
+        let insert = plan("INSERT INTO user (uid, region) VALUES ('1', 'Shanghai')");
+        assert!(insert.split(&[0, 1], &[]).is_err());
+        assert!(insert.split(&[0], &[]).is_err());
+
+        // the position of the partition column is in the catalog
+        let insert = plan(
+            "INSERT INTO article VALUES ('1', 'a0', '0', 'title0', 'science', 'abstract', 'tags', 'authors', 'en', 'text', '', ''),
+             ('2', 'a1', '1', 'title1', 'technology', 'abstract', 'tags', 'authors', 'en', 'text', '', '')",
+        );
+        let sqls = insert.split(&[0, 1], &[]).unwrap();
+        assert!(sqls[&0].contains("'science'") && !sqls[&0].contains("'technology'"));
+        assert!(sqls[&1].contains("'science'") && sqls[&1].contains("'technology'"));
+    }
+
+    #[test]
+    fn test_insert_colocated() {
+        let insert =
+            plan("INSERT INTO user_read (uid, aid) VALUES ('1', '10'), ('2', '10'), ('1', '11')");
+        assert_eq!(
+            insert.lookup_sql().unwrap(),
+            "SELECT uid, region FROM user WHERE uid IN ('1', '2')"
+        );
+        // the keys are quoted as literals
+        let insert =
+            plan(r#"INSERT INTO user_read (uid, aid) VALUES ('1'' OR ''1', '10'), (2, '11')"#);
+        assert_eq!(
+            insert.lookup_sql().unwrap(),
+            "SELECT uid, region FROM user WHERE uid IN ('1'' OR ''1', '2')"
+        );
+        let insert =
+            plan("INSERT INTO user_read (uid, aid) VALUES ('1', '10'), ('2', '10'), ('1', '11')");
+        let parents = [("1", "Hong Kong"), ("2", "Beijing")]
+            .into_iter()
+            .map(|(uid, region)| {
+                MyRow::from(vec![mysql::Value::from(uid), mysql::Value::from(region)])
+            })
+            .collect::<Vec<_>>();
+        let sqls = insert.split(&[0, 1], &parents).unwrap();
+        assert_eq!(
+            sqls[&0],
+            "INSERT INTO user_read (uid, aid) VALUES ('2', '10')"
+        );
+        assert_eq!(
+            sqls[&1],
+            "INSERT INTO user_read (uid, aid) VALUES ('1', '10'), ('1', '11')"
+        );
+        assert!(insert.split(&[0, 1], &parents[..1]).is_err());
+
+        let insert = plan("INSERT INTO be_read (aid, readNum) VALUES ('1', 0)");
+        assert_eq!(
+            insert
+                .split(&[0, 1], &[])
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            [&1]
+        );
+    }
+}
@@ -1,34 +1,79 @@
+mod aggregate;
+mod dml;
+mod eval;
+mod explain;
+mod insert;
 mod optimizer;
+mod plan;
+mod prune;
 mod query_context;
+mod response;
+mod semi_join;
+mod set_operation;
+mod stream;
+mod subquery;
 mod util;
 use std::collections::HashMap;
+use std::sync::Arc;
+
+pub use aggregate::*;
+pub use dml::*;
+pub use insert::*;
+pub use prune::*;
+pub use semi_join::plan_semi_join;
+pub use stream::ExecStream;
 
 use flexbuffers::Reader;
+use futures::future::BoxFuture;
+use futures::{Stream, StreamExt};
 pub use query_context::QueryContext;
 use tracing::debug;
 pub use util::*;
 
-use crate::ControlService;
-use common::{ExecuteResult, MyRow, Profiler, Result, ResultSet, ServerId, StatusResult};
+use crate::session::{is_transaction_control, Session, Transaction};
+use crate::{Catalog, ControlService};
+use common::{
+    ExecuteResult, MyRow, Profiler, Result, ResultSet, RuntimeError, ServerId, StatusResult,
+};
 use mysql::Value;
 use optimizer::Optimizer;
-use protos::{DbServerMeta, DbStatus, ExecRequest};
+use plan::PlanNode;
+use protos::{
+    DbServerMeta, DbStatus, ExecRequest, ExecResponse, ExecSqlBatchRequest, ResultFormat,
+};
+use response::to_exec_response;
 use serde::Deserialize;
-use sqlparser::ast::{Expr, JoinOperator, OrderByExpr};
+use set_operation::SetOperationPlan;
+use sqlparser::ast::Statement;
+use sqlparser::dialect::GenericDialect;
+use stream::STREAM_BATCH_SIZE;
 
 type RewriteSqls = Vec<Vec<(ServerId, String)>>;
-type OrderByAndLimit = Option<(Vec<OrderByExpr>, Option<Expr>)>;
+
+/// The distributed plan of a statement.
+#[derive(Debug)]
+struct RewriteResult {
+    /// the tree of the computation in shards and control
+    plan: PlanNode,
+    header: Vec<String>,
+    /// the types of the columns in mysql, empty if unknown
+    types: Vec<String>,
+    /// INSERT, whose rows may be split after looking up the shards of their parent rows
+    insert: Option<InsertPlan>,
+    /// UPDATE or DELETE, whose affected rows are returned
+    dml: Option<DmlPlan>,
+    /// only explain the plan instead of executing it
+    explain: bool,
+    /// the statement changes the schema, the catalog needs to be refreshed
+    ddl: bool,
+}
 
 fn rewrite_sql(
     statement: String,
     shards_info: HashMap<ServerId, DbServerMeta>,
+    catalog: Arc<Catalog>,
     profiler: &mut Profiler,
-) -> (
-    RewriteSqls,
-    Vec<String>,
-    Option<JoinOperator>,
-    OrderByAndLimit,
-) {
+) -> Result<RewriteResult> {
     let shards = shards_info
         .into_iter()
         .filter_map(|(server_id, server_meta)| {
@@ -41,175 +86,383 @@ fn rewrite_sql(
     debug!("debug: shards: {shards:#?}");
 
     profiler.reset_last();
-    let mut optimizer = Optimizer::new(statement, shards);
+    let mut optimizer = Optimizer::new(statement, shards, catalog);
     profiler.parse_finished();
 
     // 1. parser sql query and fill context
-    optimizer.parse();
+    optimizer.parse()?;
 
-    // 2. get the order by and limit information
-    let order_by_and_limit = optimizer.extract_order_by_and_limit();
-    let header = optimizer.extract_header();
+    // 2. get the columns of the result
+    let (header, types) = optimizer
+        .extract_schema()?
+        .into_iter()
+        .map(|column| (column.name, column.data_type))
+        .unzip();
 
-    let (rewrite_sql, join_operator) = optimizer.rewrite();
+    // 3. plan the computation in shards and control
+    let plan = optimizer.rewrite()?;
     profiler.rewrite_finished();
-    debug!("debug: rewrite sql{rewrite_sql:#?}");
-
-    let mut final_sql = Vec::new();
-    for single_rewrite_sql in rewrite_sql {
-        final_sql.push(
-            single_rewrite_sql
-                .into_iter()
-                .filter_map(|(server_id, server_sql)| {
-                    server_sql.map(|server_sql| (server_id, server_sql))
-                })
-                .collect::<Vec<_>>(),
-        );
-    }
+    debug!("debug: rewrite plan{plan:#?}");
 
-    (final_sql, header, join_operator, order_by_and_limit)
+    Ok(RewriteResult {
+        plan,
+        header,
+        types,
+        insert: optimizer.extract_insert(),
+        dml: optimizer.extract_dml(),
+        explain: optimizer.is_explain(),
+        ddl: optimizer.is_ddl(),
+    })
 }
 
 fn parse_row(row: &MyRow, _header: &[String]) -> Vec<Value> {
     row.get_raw_value().unwrap()
 }
 
-impl ControlService {
-    // query from client
-    pub async fn exec(&self, req: ExecRequest) -> Result<String> {
-        // Step1. get the sql query string and get the shards information.
-        let ExecRequest { statement } = req;
-        let mut result_set = ResultSet::new();
-        let mut exec_profile = Profiler::default();
+/// Deserialize the rows serialized by flexbuffers in dbserver.
+fn deserialize_rows(buffer: &[u8]) -> Result<Vec<MyRow>> {
+    let s = Reader::get_root(buffer).map_err(|e| RuntimeError::DBTypeParseError(e.to_string()))?;
+    Ok(Vec::<MyRow>::deserialize(s)?)
+}
 
-        let shards = self.inner.db_server_meta.read().unwrap().clone();
-        // Step2. Refactoring queries and getting distributed query sql.
-        let (rewrite_sqls, header, join_operator, order_by_and_limit) =
-            rewrite_sql(statement, shards, &mut exec_profile);
-        debug!("Step1: rewrite sqls: {rewrite_sqls:#?}");
-        debug!("Step1: get query header: {header:#?}");
-        result_set.set_header(header);
-        // Step3. Execute rewrite sqls.
-        let final_result = if rewrite_sqls.len() == 1 {
-            let shard_sql = rewrite_sqls.get(0).unwrap().clone();
-            exec_profile.reset_last();
-            let futs = shard_sql
-                .into_iter()
-                .map(|(server_id, sql)| {
-                    let mut dbms_client = {
-                        let db_clients = self.inner.clients.read().unwrap();
-                        db_clients.get(&server_id).unwrap().clone()
-                    };
-                    async move {
-                        let resp = dbms_client.exec_sql(sql).await?;
-                        StatusResult::<_>::Ok(resp.into_inner())
-                    }
+/// Collect the batches of `branches` branches into the rows of each branch,
+/// in the order they arrive.
+async fn collect_batches<S>(mut batches: S, branches: usize) -> Result<Vec<Vec<MyRow>>>
+where
+    S: Stream<Item = (usize, StatusResult<Vec<u8>>)> + Unpin,
+{
+    let mut rows = vec![vec![]; branches];
+    while let Some((idx, batch)) = batches.next().await {
+        rows[idx].extend(deserialize_rows(&batch?)?);
+    }
+    Ok(rows)
+}
+
+impl ControlService {
+    /// Execute the sqls in shards, return the rows of each shard.
+    pub(crate) async fn fetch_rows(
+        &self,
+        shard_sql: Vec<(ServerId, String)>,
+    ) -> Result<Vec<Vec<MyRow>>> {
+        let futs = shard_sql
+            .into_iter()
+            .map(|(server_id, sql)| {
+                let mut dbms_client = {
+                    let db_clients = self.inner.clients.read().unwrap();
+                    db_clients
+                        .get(&server_id)
+                        .ok_or(RuntimeError::ServerNotAlive)?
+                        .clone()
+                };
+                Ok(async move {
+                    let resp = dbms_client.exec_sql(sql).await?;
+                    StatusResult::<_>::Ok(resp.into_inner())
                 })
-                .collect::<Vec<_>>();
-            let results = futures::future::join_all(futs).await;
-            exec_profile.exec_finished();
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let results = futures::future::join_all(futs).await;
+
+        let mut rows_of_shards = vec![];
+        for (server_id, result) in results.into_iter().enumerate() {
+            debug!("debug: in {server_id:#?}, get result: {result:?}");
+            let final_result = result?;
+            let s = Reader::get_root(final_result.as_slice())
+                .map_err(|e| RuntimeError::DBTypeParseError(e.to_string()))?;
+            rows_of_shards.push(Vec::<MyRow>::deserialize(s)?);
+        }
+        Ok(rows_of_shards)
+    }
 
-            // let mut final_results = Vec::<u8>::new();
-            let mut final_results = Vec::<MyRow>::new();
-            for (server_id, result) in results.into_iter().enumerate() {
-                debug!("debug: in {server_id:#?}, get result: {result:?}");
-                // final_results.append(&mut result?);
-                let final_result = result?;
-                let s = Reader::get_root(final_result.as_slice()).unwrap();
-                let mut rows = Vec::<MyRow>::deserialize(s)?;
-                final_results.append(&mut rows);
+    /// Execute the sqls of all branches in shards together, return the rows of each branch.
+    ///
+    /// The rows are read in batches by `exec_sql_batch` from all shards at the same time,
+    /// and collected into their branches as they arrive.
+    pub(crate) async fn fetch_branches(
+        &self,
+        branches: Vec<Vec<(ServerId, String)>>,
+    ) -> Result<Vec<Vec<MyRow>>> {
+        let mut futs = vec![];
+        for (idx, shard_sql) in branches.iter().enumerate() {
+            for (server_id, sql) in shard_sql {
+                let client = self.get_client(*server_id);
+                let req = ExecSqlBatchRequest {
+                    sql: sql.clone(),
+                    batch_size: STREAM_BATCH_SIZE as u64,
+                };
+                futs.push(async move {
+                    let batches = client?.exec_sql_batch(req).await?.into_inner();
+                    Result::Ok(batches.map(move |batch| (idx, batch)))
+                });
             }
+        }
+        let streams = futures::future::try_join_all(futs).await?;
+        collect_batches(futures::stream::select_all(streams), branches.len()).await
+    }
 
-            final_results
-        } else if rewrite_sqls.len() == 2 {
-            // Query to get the data of the left branch of join
-            let left_shard_sql = rewrite_sqls.get(0).unwrap().clone();
-            exec_profile.reset_last();
-            let left_futs = left_shard_sql
-                .into_iter()
-                .map(|(server_id, sql)| {
-                    let mut dbms_client = {
-                        let db_clients = self.inner.clients.read().unwrap();
-                        db_clients.get(&server_id).unwrap().clone()
-                    };
-                    async move {
-                        let resp = dbms_client.exec_sql(sql).await?;
-                        StatusResult::<_>::Ok(resp.into_inner())
-                    }
+    /// Execute the sqls returning no rows in shards, return the affected rows of each shard.
+    pub(crate) async fn exec_drop(&self, shard_sql: Vec<(ServerId, String)>) -> Result<Vec<u64>> {
+        let futs = shard_sql
+            .into_iter()
+            .map(|(server_id, sql)| {
+                let mut dbms_client = {
+                    let db_clients = self.inner.clients.read().unwrap();
+                    db_clients
+                        .get(&server_id)
+                        .ok_or(RuntimeError::ServerNotAlive)?
+                        .clone()
+                };
+                Ok(async move {
+                    let resp = dbms_client.exec_sql_drop(sql).await?;
+                    StatusResult::<_>::Ok(resp.into_inner())
                 })
-                .collect::<Vec<_>>();
-            let left_results = futures::future::join_all(left_futs).await;
-
-            let mut final_left_results = Vec::<MyRow>::new();
-            for left_result in left_results {
-                let final_result = left_result?;
-                let s = Reader::get_root(final_result.as_slice()).unwrap();
-                let mut rows = Vec::<MyRow>::deserialize(s)?;
-                final_left_results.append(&mut rows);
-            }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let results = futures::future::join_all(futs).await;
+        Ok(results.into_iter().collect::<StatusResult<Vec<_>>>()?)
+    }
 
-            // Query to get the data of the right branch of join
-            let right_shard_sql = rewrite_sqls.get(1).unwrap().clone();
-            let right_futs = right_shard_sql
-                .into_iter()
-                .map(|(server_id, sql)| {
-                    let mut dbms_client = {
-                        let db_clients = self.inner.clients.read().unwrap();
-                        db_clients.get(&server_id).unwrap().clone()
-                    };
-                    async move {
-                        let resp = dbms_client.exec_sql(sql).await?;
-                        StatusResult::<_>::Ok(resp.into_inner())
+    // query from client
+    pub async fn exec(&self, req: ExecRequest) -> Result<ExecResponse> {
+        let format = req.format();
+        let ExecRequest {
+            statement,
+            session_id,
+            ..
+        } = req;
+        let mut session = self.lock_session(session_id).await?;
+        let statements = parse_sql(&GenericDialect {}, &statement)?;
+        let result = self
+            .exec_statements(session.as_deref_mut(), statement, statements)
+            .await?;
+        if format == ResultFormat::Json {
+            let result = serde_json::json!(result).to_string();
+            debug!("debug: final result{result:#?}");
+            return Ok(ExecResponse {
+                result,
+                ..Default::default()
+            });
+        }
+        Ok(to_exec_response(result))
+    }
+
+    /// Execute the parsed statements of `statement` in the session if any,
+    /// return the result of the last one.
+    pub(crate) async fn exec_statements(
+        &self,
+        mut session: Option<&mut Session>,
+        statement: String,
+        statements: Vec<Statement>,
+    ) -> Result<ExecuteResult> {
+        let single = statements.len() == 1;
+        let mut result = None;
+        for ast in statements {
+            // the statements are executed one by one, the result is of the last one
+            let output = match ast {
+                ast if is_transaction_control(&ast) => {
+                    let session = session.as_deref_mut().ok_or_else(|| {
+                        RuntimeError::InvalidArg(
+                            "BEGIN, COMMIT and ROLLBACK need a session from OpenSession".to_owned(),
+                        )
+                    })?;
+                    match ast {
+                        Statement::StartTransaction { .. } => self.begin(session)?,
+                        Statement::Commit { .. } => self.commit(session).await?,
+                        _ => self.rollback(session).await?,
                     }
-                })
-                .collect::<Vec<_>>();
-            let right_results = futures::future::join_all(right_futs).await;
-            exec_profile.exec_finished();
+                    ExecuteResult::default()
+                }
+                _ => {
+                    let sql = if single {
+                        statement.clone()
+                    } else {
+                        ast.to_string()
+                    };
+                    let txn = session.as_deref_mut().and_then(Session::transaction);
+                    self.exec_query(txn, sql).await?
+                }
+            };
+            result = Some(output);
+        }
+        result.ok_or_else(|| RuntimeError::InvalidArg("no statement to execute".to_owned()))
+    }
 
-            let mut final_right_results = Vec::<MyRow>::new();
-            for right_result in right_results {
-                // final_right_results.append(&mut right_result?);
-                let final_result = right_result?;
-                let s = Reader::get_root(final_result.as_slice()).unwrap();
-                let mut rows = Vec::<MyRow>::deserialize(s)?;
-                final_right_results.append(&mut rows);
-            }
+    /// Execute a statement other than transaction control, in the transaction if any.
+    async fn exec_query(
+        &self,
+        mut txn: Option<&mut Transaction>,
+        sql: String,
+    ) -> Result<ExecuteResult> {
+        let mut profiler = Profiler::default();
+        let plan = self
+            .plan_query(txn.as_deref_mut(), sql, &mut profiler)
+            .await?;
+        self.exec_plan(txn, plan, profiler).await
+    }
 
-            // Actual execution of join operation
-            do_join(final_left_results, final_right_results, join_operator)?
-        } else {
-            unreachable!()
-        };
+    /// Plan a statement other than transaction control.
+    ///
+    /// The subqueries are executed and inlined first, and the operands of set operations
+    /// are planned separately as the inputs of their combination.
+    fn plan_query<'a>(
+        &'a self,
+        mut txn: Option<&'a mut Transaction>,
+        sql: String,
+        profiler: &'a mut Profiler,
+    ) -> BoxFuture<'a, Result<RewriteResult>> {
+        Box::pin(async move {
+            let sql = self.inline_subqueries(txn.as_deref_mut(), sql).await?;
+            let Some(set_operation) = SetOperationPlan::new(&sql)? else {
+                return self.plan_statement(sql, profiler);
+            };
+            let mut operands = vec![];
+            for operand in set_operation.operands.iter() {
+                let operand = self
+                    .plan_query(txn.as_deref_mut(), operand.clone(), &mut *profiler)
+                    .await?;
+                operands.push(operand);
+            }
+            // the columns are named by the first operand
+            let (header, types) = operands
+                .first()
+                .map(|operand| (operand.header.clone(), operand.types.clone()))
+                .unwrap_or_default();
+            let operands = operands.into_iter().map(|operand| operand.plan).collect();
+            Ok(RewriteResult {
+                plan: set_operation.build(operands, header.clone()).optimize(),
+                header,
+                types,
+                insert: None,
+                dml: None,
+                explain: false,
+                ddl: false,
+            })
+        })
+    }
 
-        // Step 4. Filter the result by the order by and limit information.
-        debug!("debug: tmp\n {final_result:?}");
-        if final_result.is_empty() {
-            debug!("no answer return");
-            // result_set.table = vec![];
-        } else {
-            let header = &result_set.header;
-            let vec_value = final_result
-                .iter()
-                .map(|row| parse_row(row, header))
-                .collect::<Vec<_>>();
+    /// Plan the distributed execution of a statement.
+    fn plan_statement(&self, statement: String, profiler: &mut Profiler) -> Result<RewriteResult> {
+        // Step1. get the shards information.
+        let shards = self.inner.db_server_meta.read().unwrap().clone();
+        // Step2. Refactoring queries and getting distributed query sql.
+        rewrite_sql(statement, shards, self.get_catalog(), profiler)
+    }
 
-            debug!("debug: before order_by and limit \n {vec_value:?}");
-            let final_result = do_order_by_and_limit(vec_value, order_by_and_limit);
-            debug!("debug: result_set \n {final_result:?}");
-            result_set.table = final_result;
-            debug!("debug: after order_by and limit: result_set \n {result_set:?}");
+    /// Execute the plan of a statement, in the transaction of the session if any.
+    async fn exec_plan(
+        &self,
+        mut txn: Option<&mut Transaction>,
+        plan: RewriteResult,
+        mut exec_profile: Profiler,
+    ) -> Result<ExecuteResult> {
+        let mut result_set = ResultSet::new();
+        if plan.explain {
+            result_set.set_header(["stage".to_owned(), "detail".to_owned()]);
+            result_set.table = plan.explain();
+            return Ok(ExecuteResult {
+                result_set: Some(result_set),
+                affected_rows: None,
+                profile: exec_profile.profile,
+            });
+        }
+        let RewriteResult {
+            plan,
+            header,
+            types,
+            insert,
+            dml,
+            ddl,
+            ..
+        } = plan;
+        debug!("Step1: rewrite plan: {plan:#?}");
+        debug!("Step1: get query header: {header:#?}");
+        result_set.set_header(header);
+        result_set.types = types;
+        // Step3. Execute rewrite sqls.
+        exec_profile.reset_last();
+        let affected_rows = match (insert, dml) {
+            (Some(insert), _) => Some(
+                self.exec_insert(txn.as_deref_mut(), &insert, plan.shard_sql())
+                    .await?,
+            ),
+            (_, Some(dml)) => Some(
+                self.exec_dml(txn.as_deref_mut(), &dml, plan.shard_sql())
+                    .await?,
+            ),
+            _ => None,
+        };
+        if let Some(affected_rows) = affected_rows {
+            exec_profile.exec_finished();
+            return Ok(ExecuteResult {
+                result_set: None,
+                affected_rows: Some(affected_rows),
+                profile: exec_profile.profile,
+            });
         }
+        // Step 4. Compute the rows of the plan from the rows of shards.
+        let runs = self.exec_node(txn, &plan).await?;
+        exec_profile.exec_finished();
+        if ddl {
+            self.refresh_catalog().await?;
+        }
+        result_set.table = runs.concat();
+        debug!("debug: result_set \n {result_set:?}");
 
-        // collect the result of the two query to get the final result.
-        // execute join operate
-        let final_result = serde_json::json!(ExecuteResult {
+        Ok(ExecuteResult {
             result_set: Some(result_set),
+            affected_rows: None,
             profile: exec_profile.profile,
         })
-        .to_string();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use common::MyRow;
+    use futures::stream;
+    use mysql::Value;
+
+    use super::collect_batches;
 
-        debug!("debug: final result{final_result:#?}");
+    #[tokio::test]
+    async fn test_collect_batches() {
+        let batch = |uids: &[i64]| {
+            let rows = uids
+                .iter()
+                .map(|uid| MyRow::from(vec![Value::Int(*uid)]))
+                .collect::<Vec<_>>();
+            Ok(flexbuffers::to_vec(rows).unwrap())
+        };
+        // the batches of the branches arrive interleaved from their shards
+        let batches = stream::iter(vec![
+            (1, batch(&[10])),
+            (0, batch(&[1, 2])),
+            (1, batch(&[])),
+            (0, batch(&[3])),
+            (1, batch(&[11, 12])),
+        ]);
+        let rows = collect_batches(batches, 3).await.unwrap();
+        let uids = rows
+            .into_iter()
+            .map(|rows| {
+                rows.into_iter()
+                    .map(|row| row.get_raw_value().unwrap()[0].clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            uids,
+            [
+                vec![Value::Int(1), Value::Int(2), Value::Int(3)],
+                vec![Value::Int(10), Value::Int(11), Value::Int(12)],
+                vec![],
+            ]
+        );
 
-        Ok(final_result)
+        // the error of any batch fails the fetch
+        let batches = stream::iter(vec![
+            (0, batch(&[1])),
+            (1, Err(tonic::Status::unavailable("shard is down"))),
+        ]);
+        assert!(collect_batches(batches, 2).await.is_err());
     }
 }
@@ -1,113 +1,169 @@
-use std::collections::HashMap;
 use std::sync::Arc;
 use std::vec;
 
-use common::{Profiler, ServerId};
+use common::{Profiler, Result, ServerId};
 use protos::DbShard;
 
-use sqlparser::ast::{Expr, JoinOperator, OrderByExpr};
+use sqlparser::ast::Statement;
 
-use sqlparser::parser::Parser;
-
-use super::QueryContext;
+use super::plan::PlanNode;
+use super::{parse_sql, DmlPlan, InsertPlan, QueryContext};
+use crate::cluster::ShardMap;
+use crate::{Catalog, Column};
 
 #[derive(Default)]
 pub struct Optimizer {
     query: String,
     ctx: Arc<QueryContext>,
     profiler: Profiler,
-    shards: Vec<(ServerId, DbShard)>,
+    shards: ShardMap,
+    catalog: Arc<Catalog>,
+    /// the statement is wrapped in EXPLAIN
+    explain: bool,
+    insert: Option<InsertPlan>,
+    dml: Option<DmlPlan>,
 }
 
 impl Optimizer {
-    pub fn new(query: String, shards: impl Iterator<Item = (ServerId, DbShard)>) -> Self {
+    pub fn new(
+        query: String,
+        shards: impl Iterator<Item = (ServerId, DbShard)>,
+        catalog: Arc<Catalog>,
+    ) -> Self {
         Optimizer {
             query,
             ctx: Arc::new(QueryContext::default()),
             profiler: Profiler::default(),
-            shards: shards.collect::<Vec<_>>(),
+            shards: ShardMap::new(shards),
+            catalog,
+            explain: false,
+            insert: None,
+            dml: None,
         }
     }
 
-    pub fn parse(&mut self) {
+    pub fn parse(&mut self) -> Result<()> {
         let mut query_context = QueryContext::new();
         let dialect = query_context.get_dialect_ref();
-        let ast = Parser::parse_sql(dialect, &self.query).unwrap();
+        let mut ast = parse_sql(dialect, &self.query)?;
         self.profiler.parse_finished();
+        // EXPLAIN plans the statement without executing it,
+        // EXPLAIN ANALYZE is forwarded to shards
+        if let [Statement::Explain {
+            statement,
+            analyze: false,
+            ..
+        }] = ast.as_slice()
+        {
+            self.query = statement.to_string();
+            ast = vec![*statement.clone()];
+            self.explain = true;
+        }
         query_context.set_ast(ast.into_iter());
-        query_context.set_server_list(self.shards.iter().map(|(x, _)| *x));
+        query_context.set_shards(self.shards.clone());
+        query_context.set_catalog(self.catalog.clone());
+        self.insert = query_context.is_insert()?;
+        self.dml = query_context.is_dml()?;
         self.ctx = Arc::new(query_context);
+        Ok(())
     }
 
-    // only rewrite sql::ast::query
-    pub fn rewrite(&mut self) -> (Vec<HashMap<ServerId, Option<String>>>, Option<JoinOperator>) {
-        let mut rewrite_sql = vec![];
-        let join_operator = if let Some(query) = self.ctx.is_query() {
-            // 1.
-            let (vec_shard_req, join_operator) = self.ctx.extract_join(*query.clone().body);
-            for shard_select in vec_shard_req {
-                let mut shard_sql = HashMap::new();
-                for (server_id, server_select) in shard_select {
-                    if let Some(server_select) = server_select {
-                        let mut new_query = query.clone();
-                        new_query.body = Box::new(server_select);
-                        let new_sql_string = new_query.to_string();
-                        shard_sql.insert(server_id, Some(new_sql_string));
-                    } else {
-                        shard_sql.insert(server_id, None);
-                    }
-                }
-                rewrite_sql.push(shard_sql);
-            }
-
-            join_operator
-        } else if let Some(server_id) = self.ctx.is_insert() {
-            let mut shard_sql = HashMap::new();
-            // not query, directly forward to all shards.
-            for (server_iter, _) in self.shards.iter() {
-                if *server_iter == server_id {
-                    shard_sql.insert(server_id, Some(self.query.clone()));
-                } else {
-                    shard_sql.insert(*server_iter, None);
+    /// Plan the distributed execution of the statement.
+    ///
+    /// Queries are planned by [`PlanNode::build`], other statements gather the rows
+    /// (if any) of the sqls executed in shards.
+    pub fn rewrite(&mut self) -> Result<PlanNode> {
+        if let Some(query) = self.ctx.is_query() {
+            let header = self
+                .extract_schema()?
+                .into_iter()
+                .map(|column| column.name)
+                .collect();
+            let plan = PlanNode::build(&self.ctx, query, header)?.optimize();
+            self.profiler.rewrite_finished();
+            return Ok(plan);
+        }
+        let shard_sql = if let Some(insert) = &self.insert {
+            match insert.lookup_sql() {
+                // look up the parent rows in all shards, the rows are split after that
+                Some(lookup_sql) => self
+                    .shards
+                    .iter()
+                    .map(|(_, server_id)| (server_id, Some(lookup_sql.clone())))
+                    .collect(),
+                None => {
+                    let all_shards = self
+                        .shards
+                        .iter()
+                        .map(|(shard, _)| shard)
+                        .collect::<Vec<_>>();
+                    let mut shard_sqls = insert.split(&all_shards, &[])?;
+                    self.shards
+                        .iter()
+                        .map(|(shard, server_id)| (server_id, shard_sqls.remove(&shard)))
+                        .collect()
                 }
             }
-            rewrite_sql.push(shard_sql);
-            None
+        } else if let Some(dml) = &self.dml {
+            // prune the shards by the selection as SELECT
+            let selections = match dml.selection() {
+                Some(selection) => self
+                    .ctx
+                    .reslove_selection(&[dml.table_with_joins()], selection.clone()),
+                None => self.shards.iter().map(|(shard, _)| (shard, None)).collect(),
+            };
+            self.shards
+                .iter()
+                .map(|(shard, server_id)| {
+                    let selection = selections.get(&shard);
+                    (server_id, selection.map(|s| dml.shard_sql(s.clone())))
+                })
+                .collect()
         } else {
-            let mut shard_sql = HashMap::new();
             // not query, directly forward to all shards.
-            for (server_id, _) in self.shards.iter() {
-                shard_sql.insert(*server_id, Some(self.query.clone()));
-            }
-            rewrite_sql.push(shard_sql);
-            None
+            self.shards
+                .iter()
+                .map(|(_, server_id)| (server_id, Some(self.query.clone())))
+                .collect()
         };
         self.profiler.rewrite_finished();
-        (rewrite_sql, join_operator)
+        Ok(PlanNode::gather(shard_sql))
+    }
+
+    pub fn is_explain(&self) -> bool {
+        self.explain
+    }
+
+    pub fn extract_insert(&self) -> Option<InsertPlan> {
+        self.insert.clone()
+    }
+
+    pub fn extract_dml(&self) -> Option<DmlPlan> {
+        self.dml.clone()
     }
 
-    pub fn extract_order_by_and_limit(&self) -> Option<(Vec<OrderByExpr>, Option<Expr>)> {
-        self.ctx.is_query().map(|query| {
-            (
-                self.ctx.extract_order_by(&query),
-                self.ctx.extract_limit(&query),
-            )
-        })
+    pub fn is_ddl(&self) -> bool {
+        self.ctx.is_ddl()
     }
 
-    pub fn extract_header(&self) -> Vec<String> {
+    /// Get the columns of the result, with their types in mysql if known.
+    pub fn extract_schema(&self) -> Result<Vec<Column>> {
         if let Some(query) = self.ctx.is_query() {
-            self.ctx.get_header(*query.body)
+            self.ctx.get_schema(*query.body)
         } else {
-            vec![]
+            Ok(vec![])
         }
     }
 }
 
 #[cfg(test)]
 mod test_optimize {
+    use std::sync::Arc;
+
     use super::DbShard;
-    use super::{Optimizer, QueryContext};
+    use super::{Optimizer, QueryContext, ShardMap};
+    use crate::query::TopN;
+    use crate::Catalog;
     use sqlparser::parser::Parser;
 
     #[test]
@@ -117,16 +173,14 @@ mod test_optimize {
         let dialect = query_context.get_dialect_ref();
         let ast = Parser::parse_sql(dialect, query).unwrap();
         query_context.set_ast(ast.into_iter());
-        query_context.set_server_list([1, 2].into_iter());
+        query_context.set_shards(ShardMap::new([(1, DbShard::One), (2, DbShard::Two)]));
+        query_context.set_catalog(Arc::new(Catalog::mock()));
 
         let query = query_context.is_query().unwrap();
         println!("First, get query context: \n{query:#?}\n");
 
-        let order_by = query_context.extract_order_by(&query);
-        println!("Second, get order by context: \n{order_by:#?}\n");
-
-        let limit = query_context.extract_limit(&query);
-        println!("Third, get limit context: \n{limit:#?}\n");
+        let top_n = TopN::from_query(&query).unwrap();
+        println!("Second, get order by and limit context: \n{top_n:#?}\n");
 
         let shard_select = query_context.rewrite_selection(*query.body);
         for (server_id, server_select) in shard_select {
@@ -155,18 +209,18 @@ mod test_optimize {
         let dialect = query_context.get_dialect_ref();
         let ast = Parser::parse_sql(dialect, query).unwrap();
         query_context.set_ast(ast.into_iter());
-        query_context.set_server_list([1, 2].into_iter());
+        query_context.set_shards(ShardMap::new([(1, DbShard::One), (2, DbShard::Two)]));
+        query_context.set_catalog(Arc::new(Catalog::mock()));
 
         let query = query_context.is_query().unwrap();
         println!("First, get query context: \n{query:#?}\n");
 
-        let order_by = query_context.extract_order_by(&query);
-        println!("Second, get order by context: \n{order_by:#?}\n");
-
-        let limit = query_context.extract_limit(&query);
-        println!("Third, get limit context: \n{limit:#?}\n");
+        let top_n = TopN::from_query(&query).unwrap();
+        println!("Second, get order by and limit context: \n{top_n:#?}\n");
 
-        let (shard_select, join_operator) = query_context.extract_join(*query.body);
+        let (shard_select, join_plan) = query_context
+            .extract_join(*query.body, &query.order_by)
+            .unwrap();
         for iter in shard_select {
             for (server_id, server_select) in iter {
                 println!(
@@ -174,7 +228,7 @@ mod test_optimize {
                 );
             }
         }
-        println!("get join operator: \n{join_operator:#?}\n");
+        println!("get join plan: \n{join_plan:#?}\n");
         // println!("get symbol table: \n{symbol_table:#?}\n");
     }
 
@@ -185,7 +239,7 @@ mod test_optimize {
         // "SELECT name, gender FROM user WHERE region = \"Hong Kong\" AND region = \"Hong Kong\"".to_string();
         // "SELECT name, gender FROM user WHERE region = \"Hong Kong\" AND region = \"Beijing\"".to_string();
         let shards = vec![(0, DbShard::One), (1, DbShard::Two)];
-        Optimizer::new(query, shards.into_iter())
+        Optimizer::new(query, shards.into_iter(), Arc::new(Catalog::mock()))
     }
 
     #[test]
@@ -199,6 +253,10 @@ mod test_optimize {
             "SELECT * FROM user AS a INNER JOIN user_read AS b ON a.uid = b.uid
                 where a.region = \"Beijing\"
                 LIMIT 5",
+            "SELECT * FROM user AS a JOIN user_read AS b ON a.uid = b.uid
+                JOIN article AS c ON b.aid = c.aid
+                where a.region = \"Beijing\"
+                LIMIT 5",
             // "SELECT a.title, b.readNum FROM user AS a INNER JOIN article AS b ON a.uid = b.aid
             //     where a.uid = 100
             //     ORDER BY b.timestamp DESC
@@ -217,21 +275,18 @@ mod test_optimize {
         for test_sql in test_sqls {
             println!("Origin sql: \n{test_sql:#}\n");
             let mut optimizer = construct_optimzier_mock(test_sql);
-            optimizer.parse();
-            let result = optimizer.rewrite();
-            // println!("Result: get rewrite join operatpr \n: {:#?} \n", &result.1);
-            for (number, iter) in result.0.into_iter().enumerate() {
-                for (shard_id, shard_sql) in iter {
-                    println!(
-                                "Result: Iter: iter number {number}, get rewrite select context, server_id: {shard_id:#?} shard_sql:\n{shard_sql:#?}\n"
-                            );
-                }
+            optimizer.parse().unwrap();
+            let plan = optimizer.rewrite().unwrap();
+            for (shard_id, shard_sql) in plan.shard_sql() {
+                println!(
+                    "Result: get rewrite select context, server_id: {shard_id:#?} shard_sql:\n{shard_sql:#?}\n"
+                );
             }
         }
     }
 
     #[test]
-    fn test_get_header() {
+    fn test_get_schema() {
         let test_sqls = [
             "SELECT * FROM user AS a INNER JOIN article AS b ON a.uid = b.aid
                 where a.uid = 100
@@ -256,10 +311,21 @@ mod test_optimize {
         for test_sql in test_sqls {
             println!("Origin sql: \n{test_sql:#}\n");
             let mut optimizer = construct_optimzier_mock(test_sql);
-            optimizer.parse();
-            let header = optimizer.extract_header();
-            println!("Result header: {header:#?}\n");
+            optimizer.parse().unwrap();
+            let schema = optimizer.extract_schema().unwrap();
+            println!("Result schema: {schema:#?}\n");
         }
+
+        let mut optimizer = construct_optimzier_mock(
+            "SELECT name, b.readNum AS n, count(*) FROM user, be_read AS b",
+        );
+        optimizer.parse().unwrap();
+        let schema = optimizer.extract_schema().unwrap();
+        let schema = schema
+            .iter()
+            .map(|c| (c.name.as_str(), c.data_type.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(schema, [("name", "char"), ("n", "int"), ("count(*)", "")]);
     }
 }
 
@@ -0,0 +1,859 @@
+//! The logical plan of a statement, a tree of the computation in shards and control.
+//!
+//! The leaves scan the shards by the sqls pushed down to them, and the other nodes
+//! compute over the rows of their inputs in control layer. A plan is built from the AST
+//! of a query by [`PlanNode::build`], simplified by the rewrite passes of
+//! [`PlanNode::optimize`], then executed by walking the tree in [`ControlService::exec_node`].
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use common::{Result, RuntimeError, ServerId};
+use futures::future::{try_join_all, BoxFuture};
+use itertools::Itertools;
+use sqlparser::ast::{Expr, OrderByExpr, Query, SelectItem, SetExpr, SetOperator, Value};
+
+use super::eval::{eval_expr, is_true, resolve_columns};
+use super::set_operation::combine_runs;
+use super::{
+    distinct_runs, do_join_plan, do_order_by_and_limit, parse_row, project_join, resolve_column,
+    resolve_sort_key, AggregatePlan, JoinPlan, QueryContext, TopN,
+};
+use crate::session::Transaction;
+use crate::ControlService;
+
+/// The rows computed by a plan node, in runs which are sorted separately.
+pub type Runs = Vec<Vec<Vec<mysql::Value>>>;
+
+/// The columns of the rows of a plan node, by which the expressions are resolved.
+#[derive(Debug, Clone)]
+pub enum Columns {
+    /// the columns of the result, by their names
+    Named(Vec<String>),
+    /// all the columns of the joined tables, in the order of FROM clause
+    Joined(Arc<JoinPlan>),
+}
+
+impl Columns {
+    /// Get the position of column `expr` in the rows, `None` if it is not a column of them.
+    fn resolve(&self, expr: &Expr) -> Result<Option<usize>> {
+        match self {
+            Columns::Named(header) => {
+                let name = expr.to_string();
+                Ok(header.iter().position(|h| h.eq_ignore_ascii_case(&name)))
+            }
+            Columns::Joined(join_plan) => {
+                resolve_column(expr, &join_plan.tables, &join_plan.schema)
+            }
+        }
+    }
+
+    /// The header and the join plan by which the ORDER BY keys are resolved.
+    fn sort_context(&self) -> (&[String], Option<&JoinPlan>) {
+        match self {
+            Columns::Named(header) => (header, None),
+            Columns::Joined(join_plan) => (&[], Some(join_plan)),
+        }
+    }
+}
+
+/// A node of the plan.
+#[derive(Debug, Clone)]
+pub enum PlanNode {
+    /// execute the sql in the server, `None` if the shard is pruned
+    Scan {
+        server_id: ServerId,
+        sql: Option<String>,
+    },
+    /// keep the rows on which the predicate is true
+    Filter {
+        input: Box<PlanNode>,
+        predicate: Expr,
+        columns: Columns,
+    },
+    /// compute the projection of the joined rows
+    Project {
+        input: Box<PlanNode>,
+        join_plan: Arc<JoinPlan>,
+    },
+    /// join the rows of the fragments in control, `inputs[i]` gathers the scans of fragment `i`
+    ///
+    /// The inputs are only gathers, since the sqls of their scans are reduced by semi-joins
+    /// before they are executed, see [`PlanNode::join`].
+    Join {
+        inputs: Vec<PlanNode>,
+        join_plan: Arc<JoinPlan>,
+    },
+    /// merge the partial aggregates of shards, or aggregate the rows of `joined` which are
+    /// joined in control layer
+    Aggregate {
+        input: Box<PlanNode>,
+        plan: AggregatePlan,
+        joined: Option<Arc<JoinPlan>>,
+    },
+    /// remove the duplicate rows, keeping the first one of each
+    Distinct { input: Box<PlanNode> },
+    /// sort the rows, the runs of the input are merged if they are `presorted`
+    Sort {
+        input: Box<PlanNode>,
+        order_by: Vec<OrderByExpr>,
+        columns: Columns,
+        presorted: bool,
+    },
+    /// skip `offset` rows, then keep `limit` rows
+    Limit {
+        input: Box<PlanNode>,
+        limit: Option<usize>,
+        offset: usize,
+    },
+    /// keep the first `width` columns, dropping the hidden ones fetched for ORDER BY
+    Truncate { input: Box<PlanNode>, width: usize },
+    /// the set operation of the inputs from left to right,
+    /// the rows of shards are gathered by UNION ALL of their scans
+    Union {
+        inputs: Vec<PlanNode>,
+        op: SetOperator,
+        all: bool,
+    },
+}
+
+impl PlanNode {
+    /// Gather the rows of the shards executing the sqls.
+    pub fn gather(shard_sql: HashMap<ServerId, Option<String>>) -> Self {
+        let scans = shard_sql
+            .into_iter()
+            .sorted_by_key(|(server_id, _)| *server_id)
+            .map(|(server_id, sql)| PlanNode::Scan { server_id, sql })
+            .collect();
+        PlanNode::Union {
+            inputs: scans,
+            op: SetOperator::Union,
+            all: true,
+        }
+    }
+
+    /// Join the rows of the fragments scanned by `inputs`.
+    ///
+    /// Return error if any input is not a gather, whose rows can not be reduced by semi-joins.
+    pub fn join(inputs: Vec<PlanNode>, join_plan: Arc<JoinPlan>) -> Result<Self> {
+        if !inputs.iter().all(PlanNode::is_gather) {
+            return Err(RuntimeError::UnsupportSql(
+                "join of the fragments not scanned from shards".to_owned(),
+            ));
+        }
+        Ok(PlanNode::Join { inputs, join_plan })
+    }
+
+    /// Whether the node only scans shards, whose rows are kept apart in runs.
+    pub fn is_gather(&self) -> bool {
+        match self {
+            PlanNode::Scan { .. } => true,
+            PlanNode::Union {
+                inputs,
+                op: SetOperator::Union,
+                all: true,
+            } => inputs
+                .iter()
+                .all(|input| matches!(input, PlanNode::Scan { .. })),
+            _ => false,
+        }
+    }
+
+    /// Get the sqls of the shards scanned by the node, except the pruned ones.
+    pub fn shard_sql(&self) -> Vec<(ServerId, String)> {
+        let mut shard_sql = vec![];
+        self.visit(&mut |node| {
+            if let PlanNode::Scan {
+                server_id,
+                sql: Some(sql),
+            } = node
+            {
+                shard_sql.push((*server_id, sql.clone()));
+            }
+        });
+        shard_sql
+    }
+
+    /// The inputs of the node.
+    pub fn inputs(&self) -> Vec<&PlanNode> {
+        match self {
+            PlanNode::Scan { .. } => vec![],
+            PlanNode::Join { inputs, .. } | PlanNode::Union { inputs, .. } => {
+                inputs.iter().collect()
+            }
+            PlanNode::Filter { input, .. }
+            | PlanNode::Project { input, .. }
+            | PlanNode::Aggregate { input, .. }
+            | PlanNode::Distinct { input }
+            | PlanNode::Sort { input, .. }
+            | PlanNode::Limit { input, .. }
+            | PlanNode::Truncate { input, .. } => vec![input],
+        }
+    }
+
+    /// Visit the nodes of the tree in pre-order.
+    pub fn visit<F: FnMut(&PlanNode)>(&self, f: &mut F) {
+        f(self);
+        for input in self.inputs() {
+            input.visit(f);
+        }
+    }
+
+    /// Rewrite the nodes of the tree bottom-up by `f`.
+    fn transform_up<F: Fn(PlanNode) -> PlanNode>(self, f: &F) -> PlanNode {
+        let node = match self {
+            PlanNode::Scan { .. } => self,
+            PlanNode::Join { inputs, join_plan } => PlanNode::Join {
+                inputs: inputs.into_iter().map(|i| i.transform_up(f)).collect(),
+                join_plan,
+            },
+            PlanNode::Union { inputs, op, all } => PlanNode::Union {
+                inputs: inputs.into_iter().map(|i| i.transform_up(f)).collect(),
+                op,
+                all,
+            },
+            PlanNode::Filter {
+                input,
+                predicate,
+                columns,
+            } => PlanNode::Filter {
+                input: Box::new(input.transform_up(f)),
+                predicate,
+                columns,
+            },
+            PlanNode::Project { input, join_plan } => PlanNode::Project {
+                input: Box::new(input.transform_up(f)),
+                join_plan,
+            },
+            PlanNode::Aggregate {
+                input,
+                plan,
+                joined,
+            } => PlanNode::Aggregate {
+                input: Box::new(input.transform_up(f)),
+                plan,
+                joined,
+            },
+            PlanNode::Distinct { input } => PlanNode::Distinct {
+                input: Box::new(input.transform_up(f)),
+            },
+            PlanNode::Sort {
+                input,
+                order_by,
+                columns,
+                presorted,
+            } => PlanNode::Sort {
+                input: Box::new(input.transform_up(f)),
+                order_by,
+                columns,
+                presorted,
+            },
+            PlanNode::Limit {
+                input,
+                limit,
+                offset,
+            } => PlanNode::Limit {
+                input: Box::new(input.transform_up(f)),
+                limit,
+                offset,
+            },
+            PlanNode::Truncate { input, width } => PlanNode::Truncate {
+                input: Box::new(input.transform_up(f)),
+                width,
+            },
+        };
+        f(node)
+    }
+
+    /// Build the plan of a query, whose result has the columns of `header`.
+    ///
+    /// The filters, projections and partial aggregates are pushed down to the scans of
+    /// shards, ORDER BY and LIMIT are pushed down if the rows of shards are not joined or
+    /// aggregated in control. The plan is not optimized yet.
+    pub fn build(ctx: &QueryContext, mut query: Query, mut header: Vec<String>) -> Result<Self> {
+        let top_n = TopN::from_query(&query)?;
+        // 1. rewrite the aggregate functions into partial aggregates
+        let mut aggregate = None;
+        let mut distinct = false;
+        let mut hidden = vec![];
+        if let SetExpr::Select(select) = query.body.as_mut() {
+            aggregate = AggregatePlan::build(select)?;
+            distinct = select.distinct;
+            if aggregate.is_none() && !distinct {
+                hidden = hidden_sort_keys(&top_n.order_by, &header);
+            }
+        }
+        // 2. split the joins into fragments pushed down to shards
+        let (mut fragments, join_plan) = ctx.extract_join(*query.body.clone(), &query.order_by)?;
+        // the joined rows are sorted by all the columns of the tables
+        if join_plan.is_some() {
+            hidden.clear();
+        }
+        let width = header.len();
+        header.extend(hidden.iter().map(Expr::to_string));
+        for body in fragments.iter_mut().flat_map(|f| f.values_mut()).flatten() {
+            if let SetExpr::Select(select) = body {
+                let hidden = hidden.iter().cloned().map(SelectItem::UnnamedExpr);
+                select.projection.extend(hidden);
+            }
+        }
+        // ORDER BY and LIMIT apply to the joined rows or the merged aggregates,
+        // so the results from shards must not be sorted or truncated.
+        if join_plan.is_some() || aggregate.is_some() {
+            query.order_by = vec![];
+            query.limit = None;
+        } else {
+            // each shard returns its top LIMIT + OFFSET rows, OFFSET is skipped in control
+            query.limit = top_n
+                .shard_limit()
+                .map(|n| Expr::Value(Value::Number(n.to_string(), false)));
+        }
+        query.offset = None;
+        let mut inputs = fragments
+            .into_iter()
+            .map(|shard_select| {
+                let shard_sql = shard_select
+                    .into_iter()
+                    .map(|(server_id, body)| {
+                        let sql = body.map(|body| {
+                            let mut shard_query = query.clone();
+                            *shard_query.body = body;
+                            shard_query.to_string()
+                        });
+                        (server_id, sql)
+                    })
+                    .collect();
+                PlanNode::gather(shard_sql)
+            })
+            .collect::<Vec<_>>();
+
+        // 3. compute the rest in control
+        let mut columns = Columns::Named(header);
+        let mut projection = None;
+        let mut joined = None;
+        let mut node = match join_plan {
+            Some(mut join_plan) => {
+                let filter = join_plan.filter.take();
+                let join_plan = Arc::new(join_plan);
+                let mut node = PlanNode::join(inputs, join_plan.clone())?;
+                if let Some(predicate) = filter {
+                    node = PlanNode::Filter {
+                        input: Box::new(node),
+                        predicate,
+                        columns: Columns::Joined(join_plan.clone()),
+                    };
+                }
+                if aggregate.is_some() {
+                    // the aggregates are computed over the joined rows
+                    joined = Some(join_plan);
+                    node
+                } else if distinct {
+                    // DISTINCT applies to the projection of the joined rows
+                    PlanNode::Project {
+                        input: Box::new(node),
+                        join_plan,
+                    }
+                } else {
+                    // the columns not in the projection are fetched for sorting
+                    columns = Columns::Joined(join_plan.clone());
+                    projection = Some(join_plan);
+                    node
+                }
+            }
+            None => inputs.pop().unwrap(),
+        };
+        if let Some(plan) = aggregate {
+            node = PlanNode::Aggregate {
+                input: Box::new(node),
+                plan,
+                joined,
+            };
+        }
+        if distinct {
+            node = PlanNode::Distinct {
+                input: Box::new(node),
+            };
+        }
+        node = PlanNode::Sort {
+            input: Box::new(node),
+            order_by: top_n.order_by,
+            columns,
+            presorted: false,
+        };
+        node = PlanNode::Limit {
+            input: Box::new(node),
+            limit: top_n.limit,
+            offset: top_n.offset,
+        };
+        if !hidden.is_empty() {
+            node = PlanNode::Truncate {
+                input: Box::new(node),
+                width,
+            };
+        }
+        if let Some(join_plan) = projection {
+            node = PlanNode::Project {
+                input: Box::new(node),
+                join_plan,
+            };
+        }
+        Ok(node)
+    }
+
+    /// Apply the rewrite passes to the plan.
+    pub fn optimize(self) -> Self {
+        self.transform_up(&remove_noop)
+            .transform_up(&merge_sorted_runs)
+    }
+}
+
+/// The columns of ORDER BY not in the select list, which are fetched from shards as the
+/// hidden columns after `header` for sorting.
+fn hidden_sort_keys(order_by: &[OrderByExpr], header: &[String]) -> Vec<Expr> {
+    order_by
+        .iter()
+        .map(|order_by| &order_by.expr)
+        .filter(|expr| {
+            matches!(expr, Expr::Identifier(_) | Expr::CompoundIdentifier(_))
+                && resolve_sort_key(expr, header, None).is_err()
+        })
+        .cloned()
+        .collect()
+}
+
+/// Remove the sorts without ORDER BY and the limits without LIMIT or OFFSET.
+fn remove_noop(node: PlanNode) -> PlanNode {
+    match node {
+        PlanNode::Sort {
+            input, order_by, ..
+        } if order_by.is_empty() => *input,
+        PlanNode::Limit {
+            input,
+            limit: None,
+            offset: 0,
+        } => *input,
+        node => node,
+    }
+}
+
+/// Merge the sorted runs of shards instead of sorting all the rows again.
+///
+/// The shards sort their rows by ORDER BY if they are not joined or aggregated in
+/// control, and the deduplication keeps the order of each run.
+fn merge_sorted_runs(node: PlanNode) -> PlanNode {
+    match node {
+        PlanNode::Sort {
+            input,
+            order_by,
+            columns,
+            presorted: false,
+        } => {
+            let runs = match input.as_ref() {
+                PlanNode::Distinct { input } => input.as_ref(),
+                input => input,
+            };
+            let presorted = runs.is_gather();
+            PlanNode::Sort {
+                input,
+                order_by,
+                columns,
+                presorted,
+            }
+        }
+        node => node,
+    }
+}
+
+/// Keep the rows of the runs on which `predicate` is true.
+fn filter_runs(runs: Runs, predicate: &Expr, columns: &Columns) -> Result<Runs> {
+    let positions = resolve_columns(predicate, |expr| columns.resolve(expr))?;
+    let mut filtered = vec![];
+    for run in runs {
+        let mut rows = vec![];
+        for row in run {
+            let lookup = |expr: &Expr| row.get(*positions.get(expr)?).cloned();
+            if is_true(&eval_expr(predicate, &lookup)?) {
+                rows.push(row);
+            }
+        }
+        filtered.push(rows);
+    }
+    Ok(filtered)
+}
+
+impl ControlService {
+    /// Execute the plan in the transaction if any, return the rows of the root.
+    ///
+    /// The inputs of a node are executed at the same time, except in a transaction,
+    /// whose statements are serial.
+    pub(super) fn exec_node<'a>(
+        &'a self,
+        txn: Option<&'a mut Transaction>,
+        node: &'a PlanNode,
+    ) -> BoxFuture<'a, Result<Runs>> {
+        Box::pin(async move {
+            let runs = match node {
+                // keep the rows of each shard apart, they are sorted if the query has ORDER BY
+                node if node.is_gather() => self
+                    .fetch_rows_in(txn, node.shard_sql())
+                    .await?
+                    .iter()
+                    .map(|rows| rows.iter().map(|row| parse_row(row, &[])).collect())
+                    .collect(),
+                PlanNode::Join { inputs, join_plan } => {
+                    debug_assert!(inputs.iter().all(PlanNode::is_gather));
+                    let rewrite_sqls = inputs.iter().map(PlanNode::shard_sql).collect();
+                    let branches = self.fetch_fragments(txn, rewrite_sqls, join_plan).await?;
+                    let rows = do_join_plan(branches, join_plan)?;
+                    vec![rows.iter().map(|row| parse_row(row, &[])).collect()]
+                }
+                PlanNode::Union { inputs, op, all } => {
+                    let inputs = match txn {
+                        Some(txn) => {
+                            let mut results = vec![];
+                            for input in inputs {
+                                results.push(self.exec_node(Some(&mut *txn), input).await?);
+                            }
+                            results
+                        }
+                        None => {
+                            try_join_all(inputs.iter().map(|i| self.exec_node(None, i))).await?
+                        }
+                    };
+                    combine_runs(op, *all, inputs)?
+                }
+                PlanNode::Filter {
+                    input,
+                    predicate,
+                    columns,
+                } => filter_runs(self.exec_node(txn, input).await?, predicate, columns)?,
+                PlanNode::Project { input, join_plan } => {
+                    let rows = self.exec_node(txn, input).await?.concat();
+                    vec![project_join(rows, join_plan)?]
+                }
+                PlanNode::Aggregate {
+                    input,
+                    plan,
+                    joined,
+                } => {
+                    let rows = self.exec_node(txn, input).await?.concat();
+                    let rows = match joined {
+                        Some(join_plan) => plan.partial_rows(rows, |expr| {
+                            resolve_column(expr, &join_plan.tables, &join_plan.schema)
+                        })?,
+                        None => rows,
+                    };
+                    // merge the partial aggregates from all shards
+                    vec![plan.merge(rows)?]
+                }
+                PlanNode::Distinct { input } => distinct_runs(self.exec_node(txn, input).await?),
+                PlanNode::Limit {
+                    input,
+                    limit,
+                    offset,
+                } => {
+                    // the top LIMIT + OFFSET rows are kept while sorting
+                    let (input, order_by, columns, presorted) = match input.as_ref() {
+                        PlanNode::Sort {
+                            input,
+                            order_by,
+                            columns,
+                            presorted,
+                        } => (input.as_ref(), order_by.clone(), Some(columns), *presorted),
+                        input => (input, vec![], None, false),
+                    };
+                    let top_n = TopN {
+                        order_by,
+                        limit: *limit,
+                        offset: *offset,
+                    };
+                    let (header, join_plan) =
+                        columns.map_or((&[][..], None), Columns::sort_context);
+                    let runs = self.exec_node(txn, input).await?;
+                    vec![do_order_by_and_limit(
+                        runs,
+                        presorted,
+                        Some(top_n),
+                        header,
+                        join_plan,
+                    )?]
+                }
+                PlanNode::Sort {
+                    input,
+                    order_by,
+                    columns,
+                    presorted,
+                } => {
+                    let top_n = TopN {
+                        order_by: order_by.clone(),
+                        ..Default::default()
+                    };
+                    let (header, join_plan) = columns.sort_context();
+                    let runs = self.exec_node(txn, input).await?;
+                    vec![do_order_by_and_limit(
+                        runs,
+                        *presorted,
+                        Some(top_n),
+                        header,
+                        join_plan,
+                    )?]
+                }
+                PlanNode::Truncate { input, width } => self
+                    .exec_node(txn, input)
+                    .await?
+                    .into_iter()
+                    .map(|run| {
+                        run.into_iter()
+                            .map(|mut row| {
+                                row.truncate(*width);
+                                row
+                            })
+                            .collect()
+                    })
+                    .collect(),
+                PlanNode::Scan { .. } => unreachable!("a scan is a gather of one shard"),
+            };
+            Ok(runs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use protos::DbShard;
+
+    use super::{Columns, PlanNode};
+    use crate::query::optimizer::Optimizer;
+    use crate::Catalog;
+
+    fn plan(sql: &str) -> PlanNode {
+        let shards = [(0, DbShard::One), (1, DbShard::Two)];
+        let mut optimizer = Optimizer::new(
+            sql.to_owned(),
+            shards.into_iter(),
+            Arc::new(Catalog::mock()),
+        );
+        optimizer.parse().unwrap();
+        optimizer.rewrite().unwrap()
+    }
+
+    /// Describe the nodes of the tree in pre-order.
+    fn shape(node: &PlanNode) -> Vec<&'static str> {
+        let mut names = vec![];
+        node.visit(&mut |node| {
+            names.push(match node {
+                PlanNode::Scan { .. } => "scan",
+                PlanNode::Filter { .. } => "filter",
+                PlanNode::Project { .. } => "project",
+                PlanNode::Join { .. } => "join",
+                PlanNode::Aggregate { .. } => "aggregate",
+                PlanNode::Distinct { .. } => "distinct",
+                PlanNode::Sort { .. } => "sort",
+                PlanNode::Limit { .. } => "limit",
+                PlanNode::Truncate { .. } => "truncate",
+                PlanNode::Union { .. } => "union",
+            })
+        });
+        names
+    }
+
+    #[test]
+    fn test_build_plan() {
+        // the rows of shards are sorted, whose runs are merged
+        let node = plan("SELECT DISTINCT name FROM user ORDER BY name LIMIT 5");
+        assert_eq!(
+            shape(&node),
+            ["limit", "sort", "distinct", "union", "scan", "scan"]
+        );
+        let PlanNode::Limit { input, .. } = &node else {
+            unreachable!()
+        };
+        assert!(matches!(
+            input.as_ref(),
+            PlanNode::Sort {
+                presorted: true,
+                ..
+            }
+        ));
+        assert_eq!(
+            node.shard_sql(),
+            [
+                (
+                    0,
+                    "SELECT DISTINCT name FROM user ORDER BY name LIMIT 5".to_owned()
+                ),
+                (
+                    1,
+                    "SELECT DISTINCT name FROM user ORDER BY name LIMIT 5".to_owned()
+                )
+            ]
+        );
+
+        // the shards return the top LIMIT + OFFSET rows, OFFSET is skipped in control
+        let node = plan("SELECT name FROM user ORDER BY name LIMIT 2 OFFSET 3");
+        assert!(matches!(
+            node,
+            PlanNode::Limit {
+                limit: Some(2),
+                offset: 3,
+                ..
+            }
+        ));
+        assert_eq!(
+            node.shard_sql()[1].1,
+            "SELECT name FROM user ORDER BY name LIMIT 5"
+        );
+        // the rows joined or aggregated in control are not truncated in shards
+        let node =
+            plan("SELECT region, count(*) FROM user GROUP BY region ORDER BY region LIMIT 1");
+        assert!(!node.shard_sql()[0].1.contains("LIMIT"));
+
+        // the sorts and limits without ORDER BY or LIMIT are removed
+        let node = plan("SELECT region, count(*) FROM user GROUP BY region");
+        assert_eq!(shape(&node), ["aggregate", "union", "scan", "scan"]);
+        assert!(plan("SELECT name FROM user").is_gather());
+
+        // the joined rows are filtered and sorted before the projection
+        let node = plan(
+            "SELECT u.uid FROM user AS u LEFT JOIN article AS a ON u.uid = a.aid \
+            WHERE a.aid IS NULL ORDER BY a.timestamp",
+        );
+        assert_eq!(
+            shape(&node),
+            [
+                "project", "sort", "filter", "join", "union", "scan", "scan", "union", "scan",
+                "scan"
+            ]
+        );
+        let PlanNode::Project { input, .. } = &node else {
+            unreachable!()
+        };
+        assert!(matches!(
+            input.as_ref(),
+            PlanNode::Sort {
+                presorted: false,
+                ..
+            }
+        ));
+
+        // only the scans of shards are joined
+        let mut join = None;
+        node.visit(&mut |node| {
+            if let PlanNode::Join { inputs, join_plan } = node {
+                join = Some((inputs.clone(), join_plan.clone()));
+            }
+        });
+        let (inputs, join_plan) = join.unwrap();
+        assert!(PlanNode::join(inputs.clone(), join_plan.clone()).is_ok());
+        let distinct = PlanNode::Distinct {
+            input: Box::new(inputs[0].clone()),
+        };
+        assert!(PlanNode::join(vec![distinct, inputs[1].clone()], join_plan.clone()).is_err());
+    }
+
+    #[test]
+    fn test_hidden_sort_keys() {
+        // the ORDER BY keys not in the select list are fetched after it, then dropped
+        let node = plan("SELECT name FROM user ORDER BY timestamp LIMIT 3");
+        assert_eq!(
+            shape(&node),
+            ["truncate", "limit", "sort", "union", "scan", "scan"]
+        );
+        let PlanNode::Truncate { input, width: 1 } = &node else {
+            unreachable!()
+        };
+        let PlanNode::Limit { input, .. } = input.as_ref() else {
+            unreachable!()
+        };
+        let PlanNode::Sort {
+            columns: Columns::Named(header),
+            presorted: true,
+            ..
+        } = input.as_ref()
+        else {
+            unreachable!()
+        };
+        assert_eq!(header, &["name", "timestamp"]);
+        assert_eq!(
+            node.shard_sql()[0].1,
+            "SELECT name, timestamp FROM user ORDER BY timestamp LIMIT 3"
+        );
+
+        // the shards return the top LIMIT + OFFSET rows with the hidden keys
+        let node =
+            plan("SELECT u.name FROM user AS u ORDER BY u.region, u.uid DESC LIMIT 2 OFFSET 1");
+        assert_eq!(
+            node.shard_sql()[0].1,
+            "SELECT u.name, u.region, u.uid FROM user AS u ORDER BY u.region, u.uid DESC LIMIT 3"
+        );
+
+        // the keys in the select list are not fetched again
+        let node = plan("SELECT name, timestamp FROM user ORDER BY timestamp LIMIT 3");
+        assert_eq!(shape(&node), ["limit", "sort", "union", "scan", "scan"]);
+        assert!(plan("SELECT * FROM user ORDER BY timestamp").shard_sql()[0]
+            .1
+            .starts_with("SELECT * FROM user"));
+    }
+
+    #[test]
+    fn test_replicated_table() {
+        let servers = |sql: &str| {
+            plan(sql)
+                .shard_sql()
+                .into_iter()
+                .map(|(server_id, _)| server_id)
+                .collect::<Vec<_>>()
+        };
+        // the replicated tables are only scanned in the first replica
+        assert_eq!(servers("SELECT * FROM article"), [1]);
+        assert_eq!(servers("SELECT count(*) FROM article"), [1]);
+        assert_eq!(
+            servers("SELECT * FROM article WHERE category = 'technology'"),
+            [1]
+        );
+        assert_eq!(servers("SELECT * FROM be_read"), [1]);
+        assert_eq!(servers("SELECT sum(readNum) FROM be_read"), [1]);
+        assert_eq!(servers("SELECT count(*) FROM user"), [0, 1]);
+    }
+
+    #[test]
+    fn test_aggregate_join() {
+        // the co-located join is aggregated in shards
+        let node = plan(
+            "SELECT u.region, count(*) FROM user AS u JOIN user_read AS r ON u.uid = r.uid \
+            GROUP BY u.region",
+        );
+        assert_eq!(shape(&node), ["aggregate", "union", "scan", "scan"]);
+
+        // the rows joined in control are aggregated from scratch
+        let node = plan(
+            "SELECT a.category, count(*) FROM user_read AS r JOIN article AS a ON r.aid = a.aid \
+            GROUP BY a.category HAVING count(*) > 1",
+        );
+        assert_eq!(
+            shape(&node),
+            [
+                "aggregate",
+                "join",
+                "union",
+                "scan",
+                "scan",
+                "union",
+                "scan",
+                "scan"
+            ]
+        );
+        let PlanNode::Aggregate { joined, .. } = &node else {
+            unreachable!()
+        };
+        assert!(joined.is_some());
+        for (_, sql) in node.shard_sql() {
+            assert!(
+                !sql.contains("GROUP BY") && !sql.contains("HAVING"),
+                "{sql}"
+            );
+        }
+    }
+}
@@ -0,0 +1,377 @@
+//! Shard pruning by the partition column in WHERE.
+//!
+//! All rows of a shard have the partition column in a known range,
+//! e.g. `region = 'Beijing'` in shard1. Substituting the range into the predicate
+//! decides whether it can be TRUE for any row of the shard, and simplifies the predicate
+//! which is sent to the shard.
+
+use std::collections::HashMap;
+
+use common::{sharding_rules, ShardId, ShardRange};
+use sqlparser::ast::{BinaryOperator, Expr, UnaryOperator, Value};
+
+/// The possible results of a predicate, a set of TRUE, FALSE and NULL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Truth(u8);
+
+impl Truth {
+    pub const TRUE: Self = Self(1);
+    pub const FALSE: Self = Self(2);
+    pub const NULL: Self = Self(4);
+    pub const UNKNOWN: Self = Self(7);
+
+    fn values(self) -> impl Iterator<Item = Self> {
+        [Self::TRUE, Self::FALSE, Self::NULL]
+            .into_iter()
+            .filter(move |t| self.0 & t.0 != 0)
+    }
+
+    fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Apply `f` on each pair of the possible results.
+    fn combine(self, other: Self, f: fn(Self, Self) -> Self) -> Self {
+        let mut result = Self(0);
+        for a in self.values() {
+            for b in other.values() {
+                result = result.union(f(a, b));
+            }
+        }
+        result
+    }
+
+    pub fn and(self, other: Self) -> Self {
+        self.combine(other, |a, b| match (a, b) {
+            (Self::FALSE, _) | (_, Self::FALSE) => Self::FALSE,
+            (Self::NULL, _) | (_, Self::NULL) => Self::NULL,
+            _ => Self::TRUE,
+        })
+    }
+
+    pub fn or(self, other: Self) -> Self {
+        self.combine(other, |a, b| match (a, b) {
+            (Self::TRUE, _) | (_, Self::TRUE) => Self::TRUE,
+            (Self::NULL, _) | (_, Self::NULL) => Self::NULL,
+            _ => Self::FALSE,
+        })
+    }
+
+    pub fn not(self) -> Self {
+        self.values()
+            .map(|t| match t {
+                Self::TRUE => Self::FALSE,
+                Self::FALSE => Self::TRUE,
+                t => t,
+            })
+            .fold(Self(0), Self::union)
+    }
+
+    /// Whether the rows can be selected.
+    pub fn may_be_true(self) -> bool {
+        self.0 & Self::TRUE.0 != 0
+    }
+
+    /// Whether the result is always TRUE or always FALSE.
+    fn is_constant(self) -> bool {
+        self == Self::TRUE || self == Self::FALSE
+    }
+}
+
+/// The rows of a shard have `column` in `range`.
+#[derive(Debug, Clone)]
+pub struct ShardPartition {
+    pub column: String,
+    pub range: ShardRange,
+}
+
+/// Get the partition of each shard of `table` from the sharding rules.
+pub fn get_shard_partitions(table: &str) -> HashMap<ShardId, ShardPartition> {
+    let Some(partition) = sharding_rules().get_partition(table) else {
+        return HashMap::new();
+    };
+    partition
+        .shards
+        .iter()
+        .map(|(shard_id, range)| {
+            let partition = ShardPartition {
+                column: partition.column.clone(),
+                range: range.clone(),
+            };
+            (*shard_id, partition)
+        })
+        .collect()
+}
+
+/// Get the string literal, `Some(None)` for NULL.
+///
+/// Double quoted strings are parsed as identifiers by the dialect.
+pub fn literal(expr: &Expr) -> Option<Option<String>> {
+    match expr {
+        Expr::Value(
+            Value::SingleQuotedString(s) | Value::DoubleQuotedString(s) | Value::Number(s, _),
+        ) => Some(Some(s.clone())),
+        Expr::Value(Value::Null) => Some(None),
+        Expr::Identifier(ident) if ident.quote_style == Some('"') => {
+            Some(Some(ident.value.clone()))
+        }
+        Expr::Nested(expr) => literal(expr),
+        _ => None,
+    }
+}
+
+impl ShardPartition {
+    fn is_partition_column(&self, expr: &Expr) -> bool {
+        let column = match expr {
+            Expr::Identifier(ident) if ident.quote_style != Some('"') => ident,
+            Expr::CompoundIdentifier(idents) => idents.last().unwrap(),
+            Expr::Nested(expr) => return self.is_partition_column(expr),
+            _ => return false,
+        };
+        column.value.eq_ignore_ascii_case(&self.column)
+    }
+
+    /// Get the results of `column = value` in the shard.
+    fn equals(&self, value: &str) -> Truth {
+        match self.range.values() {
+            // same as the default collation of mysql
+            Some(values) => values
+                .iter()
+                .map(|v| {
+                    if v.eq_ignore_ascii_case(value) {
+                        Truth::TRUE
+                    } else {
+                        Truth::FALSE
+                    }
+                })
+                .fold(Truth(0), Truth::union),
+            // other values may be in the shard too
+            None if self.range.contains(value) => Truth::TRUE.union(Truth::FALSE),
+            None => Truth::FALSE,
+        }
+    }
+
+    /// The results of a predicate only on the partition column, `None` if it is not.
+    fn analyze_atom(&self, expr: &Expr) -> Option<Truth> {
+        match expr {
+            Expr::BinaryOp {
+                left,
+                op: op @ (BinaryOperator::Eq | BinaryOperator::NotEq),
+                right,
+            } => {
+                let value = if self.is_partition_column(left) {
+                    literal(right)?
+                } else if self.is_partition_column(right) {
+                    literal(left)?
+                } else {
+                    return None;
+                };
+                let Some(value) = value else {
+                    return Some(Truth::NULL);
+                };
+                let truth = self.equals(&value);
+                Some(if *op == BinaryOperator::Eq {
+                    truth
+                } else {
+                    truth.not()
+                })
+            }
+            Expr::InList {
+                expr,
+                list,
+                negated,
+            } if self.is_partition_column(expr) => {
+                let list = list.iter().map(literal).collect::<Option<Vec<_>>>()?;
+                let truth = match self.range.values() {
+                    Some(values) => values
+                        .iter()
+                        .map(|v| {
+                            if list
+                                .iter()
+                                .flatten()
+                                .any(|value| v.eq_ignore_ascii_case(value))
+                            {
+                                Truth::TRUE
+                            } else if list.iter().any(Option::is_none) {
+                                Truth::NULL
+                            } else {
+                                Truth::FALSE
+                            }
+                        })
+                        .fold(Truth(0), Truth::union),
+                    // `x IN (a, b)` is `x = a OR x = b`
+                    None => list
+                        .iter()
+                        .map(|value| match value {
+                            Some(value) => self.equals(value),
+                            None => Truth::NULL,
+                        })
+                        .reduce(Truth::or)
+                        .unwrap_or(Truth::FALSE),
+                };
+                Some(if *negated { truth.not() } else { truth })
+            }
+            Expr::IsNull(expr) if self.is_partition_column(expr) => Some(Truth::FALSE),
+            Expr::IsNotNull(expr) if self.is_partition_column(expr) => Some(Truth::TRUE),
+            _ => None,
+        }
+    }
+
+    /// Analyze `expr` for the rows of this shard.
+    ///
+    /// Return the possible results, and the simplified predicate
+    /// which is `None` if the result is always TRUE or always FALSE.
+    pub fn analyze(&self, expr: &Expr) -> (Truth, Option<Expr>) {
+        let (truth, simplified) = match expr {
+            Expr::Nested(inner) => {
+                let (truth, simplified) = self.analyze(inner);
+                (truth, simplified.map(|e| Expr::Nested(Box::new(e))))
+            }
+            Expr::BinaryOp {
+                left,
+                op: op @ (BinaryOperator::And | BinaryOperator::Or),
+                right,
+            } => {
+                let (l, left) = self.analyze(left);
+                let (r, right) = self.analyze(right);
+                let truth = if *op == BinaryOperator::And {
+                    l.and(r)
+                } else {
+                    l.or(r)
+                };
+                // drop the side which is always TRUE in AND, or always FALSE in OR
+                let simplified = match (left, right) {
+                    (Some(left), Some(right)) => Some(Expr::BinaryOp {
+                        left: Box::new(left),
+                        op: op.clone(),
+                        right: Box::new(right),
+                    }),
+                    (left, right) => left.or(right),
+                };
+                (truth, simplified)
+            }
+            Expr::UnaryOp {
+                op: UnaryOperator::Not,
+                expr: inner,
+            } => {
+                let (truth, simplified) = self.analyze(inner);
+                let simplified = simplified.map(|e| Expr::UnaryOp {
+                    op: UnaryOperator::Not,
+                    expr: Box::new(e),
+                });
+                (truth.not(), simplified)
+            }
+            expr => match self.analyze_atom(expr) {
+                Some(truth) => (truth, Some(expr.clone())),
+                None => (Truth::UNKNOWN, Some(expr.clone())),
+            },
+        };
+        if truth.is_constant() {
+            (truth, None)
+        } else {
+            (truth, simplified)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ShardPartition, Truth};
+    use common::ShardRange;
+    use sqlparser::ast::{Expr, SetExpr, Statement};
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn mock_selection(sql: &str) -> Expr {
+        let sql = format!("SELECT * FROM user AS u WHERE {sql}");
+        let ast = Parser::parse_sql(&GenericDialect {}, &sql).unwrap();
+        if let Statement::Query(query) = &ast[0] {
+            if let SetExpr::Select(select) = query.body.as_ref() {
+                return select.selection.clone().unwrap();
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn test_truth() {
+        assert_eq!(Truth::UNKNOWN.and(Truth::FALSE), Truth::FALSE);
+        assert_eq!(Truth::UNKNOWN.or(Truth::TRUE), Truth::TRUE);
+        assert_eq!(Truth::NULL.not(), Truth::NULL);
+        assert!(!Truth::NULL.or(Truth::FALSE).may_be_true());
+    }
+
+    #[test]
+    fn test_shard_pruning() {
+        let beijing = ShardPartition {
+            column: "region".to_owned(),
+            range: ShardRange::Values(vec!["Beijing".to_owned()]),
+        };
+        let hong_kong = ShardPartition {
+            column: "region".to_owned(),
+            range: ShardRange::Values(vec!["Hong Kong".to_owned()]),
+        };
+        // (selected by Beijing, selected by Hong Kong, predicate sent to Beijing)
+        let cases = [
+            ("region = \"Beijing\"", true, false, None),
+            ("u.region = 'Beijing'", true, false, None),
+            ("'Hong Kong' = region", false, true, None),
+            ("region <> 'Beijing' AND uid < 10", false, true, None),
+            (
+                "region IN ('Beijing', 'Hong Kong') AND uid < 10",
+                true,
+                true,
+                Some("uid < 10"),
+            ),
+            ("region NOT IN ('Beijing', NULL)", false, false, None),
+            ("NOT (region = 'Beijing' OR uid = 1)", false, true, None),
+            (
+                "(region = 'Beijing' AND uid = 1) OR (region = 'Hong Kong' AND uid = 2)",
+                true,
+                true,
+                Some("(uid = 1)"),
+            ),
+            (
+                "region = 'Beijing' AND region = 'Hong Kong'",
+                false,
+                false,
+                None,
+            ),
+            ("uid = 1 OR region IS NULL", true, true, Some("uid = 1")),
+            ("region LIKE 'Bei%'", true, true, Some("region LIKE 'Bei%'")),
+        ];
+        for (sql, in_beijing, in_hong_kong, predicate) in cases {
+            let selection = mock_selection(sql);
+            let (truth, simplified) = beijing.analyze(&selection);
+            assert_eq!(truth.may_be_true(), in_beijing, "{sql}");
+            if in_beijing {
+                assert_eq!(
+                    simplified.map(|e| e.to_string()).as_deref(),
+                    predicate,
+                    "{sql}"
+                );
+            }
+            let (truth, _) = hong_kong.analyze(&selection);
+            assert_eq!(truth.may_be_true(), in_hong_kong, "{sql}");
+        }
+    }
+
+    #[test]
+    fn test_range_pruning() {
+        let lower = ShardPartition {
+            column: "id".to_owned(),
+            range: ShardRange::Range {
+                lower: None,
+                upper: Some("100".to_owned()),
+            },
+        };
+        let (truth, simplified) = lower.analyze(&mock_selection("id = '10' AND name = 'a'"));
+        assert!(truth.may_be_true());
+        // other ids are in the shard too, the predicate is kept
+        assert_eq!(simplified.unwrap().to_string(), "id = '10' AND name = 'a'");
+        let (truth, _) = lower.analyze(&mock_selection("id IN ('100', '200')"));
+        assert!(!truth.may_be_true());
+        let (truth, _) = lower.analyze(&mock_selection("id <> '100'"));
+        assert!(truth.may_be_true());
+    }
+}
@@ -1,24 +1,32 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::vec;
 
-use common::{DataShard, ServerId, SymbolTable};
+use common::{sharding_rules, DataShard, Result, ServerId, ShardId};
 
 use sqlparser::ast::{
-    Expr, Ident, Join, JoinOperator, ObjectName, OrderByExpr, Query, Select, SelectItem, SetExpr,
-    Statement, TableFactor, TableWithJoins, Value,
+    Expr, JoinOperator, OrderByExpr, Query, Select, SelectItem, SetExpr, Statement, TableFactor,
+    TableWithJoins,
 };
 use sqlparser::dialect::{Dialect, GenericDialect};
 
 use super::{
-    extract_selection, get_expr_shard, get_table_factor, get_table_info, get_wild_projection,
-    reslove_from, return_expr_op, rewrite_placeholder,
+    get_shard_partitions, plan_join, plan_semi_join, push_down_projection, reslove_from, DmlPlan,
+    InsertPlan, JoinFragment, JoinPlan,
 };
+use crate::cluster::ShardMap;
+use crate::{Catalog, Column};
+
+/// The query body executed in each server, `None` if the shard is pruned.
+pub type ShardSelects = HashMap<ServerId, Option<SetExpr>>;
 
 #[derive(Debug)]
 pub struct QueryContext {
     dialect: Box<dyn Dialect>,
     ast: Vec<Statement>,
-    server_list: Vec<ServerId>,
+    /// the servers of the shards which are alive
+    shards: ShardMap,
+    catalog: Arc<Catalog>,
 }
 
 impl Default for QueryContext {
@@ -26,7 +34,8 @@ impl Default for QueryContext {
         Self {
             dialect: Box::new(GenericDialect::default()),
             ast: vec![],
-            server_list: vec![],
+            shards: ShardMap::default(),
+            catalog: Default::default(),
         }
     }
 }
@@ -44,8 +53,25 @@ impl QueryContext {
         self.ast = ast.collect();
     }
 
-    pub fn set_server_list(&mut self, server_id: impl Iterator<Item = ServerId>) {
-        self.server_list = server_id.collect();
+    pub fn set_shards(&mut self, shards: ShardMap) {
+        self.shards = shards;
+    }
+
+    pub fn set_catalog(&mut self, catalog: Arc<Catalog>) {
+        self.catalog = catalog;
+    }
+
+    /// Whether the statement changes the schema of tables.
+    pub fn is_ddl(&self) -> bool {
+        self.ast.iter().any(|statement| {
+            matches!(
+                statement,
+                Statement::CreateTable { .. }
+                    | Statement::AlterTable { .. }
+                    | Statement::Drop { .. }
+                    | Statement::CreateView { .. }
+            )
+        })
     }
 
     pub fn is_query(&self) -> Option<Query> {
@@ -59,96 +85,91 @@ impl QueryContext {
         }
     }
 
-    pub fn is_insert(&self) -> Option<ServerId> {
+    /// Get the plan to split the rows of `INSERT ... VALUES` into shards.
+    pub fn is_insert(&self) -> Result<Option<InsertPlan>> {
         if self.ast.len() != 1 {
-            return None;
+            return Ok(None);
         }
-        let statement = self.ast.get(0).unwrap().clone();
-        match statement {
-            Statement::Insert {
-                table_name, source, ..
-            } => {
-                let Query { body, .. } = *source;
-                let body = *body;
-                if let SetExpr::Values(values) = body {
-                    if table_name
-                        == ObjectName(vec![Ident {
-                            value: "user".to_string(),
-                            quote_style: None,
-                        }])
-                    {
-                        if let Some(x) = values.0.get(0).unwrap().get(10) {
-                            if x.eq(&Expr::Value(Value::SingleQuotedString(
-                                "Beijing".to_string(),
-                            ))) {
-                                return Some(0);
-                            } else {
-                                return Some(1);
-                            }
-                        } else {
-                            return None;
-                        }
-                    }
-                }
-                None
-            }
-            _ => None,
-        }
-    }
-
-    pub fn extract_order_by(&self, query: &Query) -> Vec<OrderByExpr> {
-        query.order_by.clone()
+        InsertPlan::build(&self.ast[0], &self.catalog)
     }
 
-    pub fn extract_limit(&self, query: &Query) -> Option<Expr> {
-        query.limit.clone()
+    /// Get the plan of UPDATE or DELETE of a single table.
+    pub fn is_dml(&self) -> Result<Option<DmlPlan>> {
+        if self.ast.len() != 1 {
+            return Ok(None);
+        }
+        DmlPlan::build(&self.ast[0])
     }
 
-    pub fn get_header(&self, query_body: SetExpr) -> Vec<String> {
-        fn reslove_table_name(tables: TableWithJoins) -> Vec<String> {
+    /// Get the columns of the result of the query,
+    /// the type is empty if the column is not a column of the tables.
+    pub fn get_schema(&self, query_body: SetExpr) -> Result<Vec<Column>> {
+        /// Get the names and aliases of the tables.
+        fn reslove_table_name(tables: TableWithJoins) -> Vec<(String, Option<String>)> {
             let TableWithJoins { relation, joins } = tables;
             let mut table_names = vec![];
-            if let TableFactor::Table { name, .. } = relation {
-                let table_name = name.0[0].value.clone();
-                table_names.push(table_name);
-            }
-            if joins.len() == 1 {
-                let Join { relation, .. } = joins.get(0).unwrap().clone();
-                if let TableFactor::Table { name, .. } = relation {
+            for relation in std::iter::once(relation).chain(joins.into_iter().map(|j| j.relation)) {
+                if let TableFactor::Table { name, alias, .. } = relation {
                     let table_name = name.0[0].value.clone();
-                    table_names.push(table_name);
+                    table_names.push((table_name, alias.map(|alias| alias.name.value)));
                 }
             }
             table_names
         }
 
+        /// Get the type of the column referenced by `expr` in `columns` of the tables.
+        fn reslove_type(columns: &[(String, Column)], expr: &Expr) -> String {
+            let column = match expr {
+                Expr::Identifier(ident) => columns.iter().find(|(_, c)| c.name == ident.value),
+                Expr::CompoundIdentifier(idents) if idents.len() == 2 => columns
+                    .iter()
+                    .find(|(t, c)| *t == idents[0].value && c.name == idents[1].value),
+                _ => None,
+            };
+            column
+                .map(|(_, column)| column.data_type.clone())
+                .unwrap_or_default()
+        }
+
         let mut symbol_table = vec![];
         if let SetExpr::Select(select) = query_body {
             let Select {
                 projection, from, ..
             } = *select;
-            let mut table_names = vec![];
+            // the columns of the tables, with the alias or name of their tables
+            let mut table_columns = vec![];
             for tables in from {
                 let table_name = reslove_table_name(tables);
-                let table_info = get_table_info();
-                for name in table_name {
-                    table_names.append(&mut table_info.get(&name).unwrap().clone());
+                for (name, alias) in table_name {
+                    let qualifier = alias.unwrap_or_else(|| name.clone());
+                    for column in self.catalog.get_columns(&name)? {
+                        table_columns.push((qualifier.clone(), column.clone()));
+                    }
                 }
             }
 
             for projection_item in projection {
                 match projection_item {
                     SelectItem::Wildcard => {
-                        symbol_table.append(&mut table_names.clone());
+                        symbol_table.extend(table_columns.iter().map(|(_, c)| c.clone()));
                     }
                     SelectItem::UnnamedExpr(iden) => {
-                        symbol_table.push(iden.to_string());
+                        symbol_table.push(Column {
+                            name: iden.to_string(),
+                            data_type: reslove_type(&table_columns, &iden),
+                        });
+                    }
+                    SelectItem::ExprWithAlias { expr, alias } => {
+                        symbol_table.push(Column {
+                            name: alias.value,
+                            data_type: reslove_type(&table_columns, &expr),
+                        });
                     }
                     _ => {}
                 }
             }
         }
-        symbol_table
+        Ok(symbol_table)
     }
 }
 
@@ -162,134 +183,119 @@ impl QueryContext {
     ///
     /// Return : Vec<HashMap<ServerId, Option<SetExpr>>>
     ///
-    /// Vec: multiply query_body
+    /// Vec: multiply query_body, one for each fragment of the join
     /// HashMap: <ServerId, new_query_body>
+    /// JoinPlan: the joins of the fragments need to compute in control layer
     pub fn extract_join(
         &self,
         query_body: SetExpr,
-    ) -> (
-        Vec<HashMap<ServerId, Option<SetExpr>>>,
-        Option<JoinOperator>,
-    ) {
-        fn get_shard_set_expr(
-            symbol_table: SymbolTable,
-            selection: Option<Expr>,
-            select: Select,
-        ) -> (SetExpr, SetExpr) {
-            let table_name1 = symbol_table.get_index(0).unwrap();
-            let alias_name1 = symbol_table.get(&table_name1);
-            let from1 = get_table_factor(table_name1.clone(), alias_name1.clone());
+        order_by: &[OrderByExpr],
+    ) -> Result<(Vec<ShardSelects>, Option<JoinPlan>)> {
+        // 从from中能够推断出当前查询要访问的目标表，以及其是否是join连接
+        let SetExpr::Select(select) = &query_body else {
+            return Ok((vec![self.rewrite_selection(query_body)], None));
+        };
+        let is_join =
+            select.from.len() > 1 || select.from.iter().any(|from| !from.joins.is_empty());
+        let Some((tables, operators)) = reslove_from(select.from.clone()).filter(|_| is_join)
+        else {
+            return Ok((vec![self.rewrite_table(query_body)], None));
+        };
 
-            let table_name2 = symbol_table.get_index(1).unwrap();
-            let alias_name2 = symbol_table.get(&table_name2);
-            let from2 = get_table_factor(table_name2.clone(), alias_name2.clone());
+        let (mut fragments, join_plan) =
+            plan_join(&tables, &operators, select.selection.clone(), &self.catalog)?;
+        let Some(mut join_plan) = join_plan else {
+            // all tables are co-located, no need to rewrite
+            let final_query = match fragments[0].shard {
+                DataShard::Whole(shard) => self.rewrite_whole(shard, query_body),
+                DataShard::Shard => self.rewrite_selection(query_body),
+            };
+            return Ok((vec![final_query], None));
+        };
 
-            let (selection1, selection2) = extract_selection(
-                table_name1,
-                alias_name1,
-                table_name2,
-                alias_name2,
-                selection,
-            );
-
-            let mut new_select1 = select.clone();
-            let new_projection1 = get_wild_projection();
-            new_select1.projection = new_projection1;
-            new_select1.selection = selection1;
-            new_select1.from = from1;
-            let new_query_body1 = SetExpr::Select(Box::new(new_select1));
-
-            let mut new_select2 = select;
-            let new_projection2 = get_wild_projection();
-            new_select2.projection = new_projection2;
-            new_select2.selection = selection2;
-            new_select2.from = from2;
-            let new_query_body2 = SetExpr::Select(Box::new(new_select2));
-            (new_query_body1, new_query_body2)
+        // query the needed columns of each fragment, and join them in control layer
+        push_down_projection(
+            &mut fragments,
+            &mut join_plan,
+            &select.projection,
+            order_by,
+            &self.catalog,
+        )?;
+        plan_semi_join(&fragments, &mut join_plan, &self.catalog)?;
+        let mut final_queries = vec![];
+        for JoinFragment {
+            shard,
+            from,
+            selection,
+            projection,
+            ..
+        } in fragments
+        {
+            let mut new_select = *select.clone();
+            new_select.projection = projection;
+            new_select.from = vec![from];
+            new_select.selection = selection;
+            // the groups are aggregated after the join
+            new_select.group_by = vec![];
+            new_select.having = None;
+            let new_query_body = SetExpr::Select(Box::new(new_select));
+            final_queries.push(match shard {
+                DataShard::Whole(shard) => self.rewrite_whole(shard, new_query_body),
+                DataShard::Shard => self.rewrite_selection(new_query_body),
+            });
         }
+        Ok((final_queries, Some(join_plan)))
+    }
 
-        let mut final_query = HashMap::new();
-
-        // 1. need rewrite
-        match query_body.clone() {
-            SetExpr::Select(select) => {
-                // 从from中能够推断出当前查询要访问的目标表，以及其是否是join连接
-                // projection 提取要返回的信息表
-                // selection 代表的是表的筛选条件的信息
-                let Select {
-                    from, selection, ..
-                } = *select.clone();
-
-                if from.len() == 1 {
-                    // 1. 提取出要访问的表的名字以及其别名，如果不需要rewrite则返回false
-                    if let Some(iter) = from.into_iter().next() {
-                        let (symbol_table, join_info) = reslove_from(iter);
-                        // println!("debug: {join_info:#?}");
-                        match join_info {
-                            // no need to shard, continue to execute
-                            Some((DataShard::NotShard, _)) => {
-                                final_query = self.rewrite_selection(query_body);
-                                return (vec![final_query], None);
-                            }
-                            // rewrite sql and compute in control layer
-                            Some((DataShard::Shard, join_info)) => {
-                                // user join article
-                                let mut final_query1 = HashMap::new();
-                                let mut final_query2 = HashMap::new();
-
-                                let (new_query_body1, new_query_body2) =
-                                    get_shard_set_expr(symbol_table, selection, *select);
-
-                                for server_id in self.server_list.clone() {
-                                    // println!("debug server id: {server_id:#?}");
-                                    final_query1.insert(server_id, Some(new_query_body1.clone()));
-                                    final_query2.insert(server_id, Some(new_query_body2.clone()));
-                                }
-                                return (vec![final_query1, final_query2], Some(join_info));
-                            }
-                            // only to need query in shard2, no need rewrite
-                            Some((DataShard::OnlyTwo, _)) => {
-                                final_query.insert(0, None);
-                                final_query.insert(1, Some(query_body));
-                                return (vec![final_query], None);
-                            }
-                            _ => final_query = self.rewrite_selection(query_body),
-                        }
+    /// Rewrite the query of a single table, the replicated table is only queried in its
+    /// first replica, so its rows are not duplicated by the other shards.
+    fn rewrite_table(&self, query_body: SetExpr) -> ShardSelects {
+        if let SetExpr::Select(select) = &query_body {
+            if let Some((tables, _)) = reslove_from(select.from.clone()) {
+                if let [(table, _)] = tables.as_slice() {
+                    if let DataShard::Whole(shard) = sharding_rules().table_location(table) {
+                        return self.rewrite_whole(shard, query_body);
                     }
-                } else {
-                    final_query = self.rewrite_selection(query_body);
                 }
             }
-            _ => final_query = self.rewrite_selection(query_body),
         }
-        (vec![final_query], None)
+        self.rewrite_selection(query_body)
+    }
+
+    /// Only need to query in the shard holding all the rows, no need rewrite
+    fn rewrite_whole(&self, shard: ShardId, query_body: SetExpr) -> ShardSelects {
+        self.shards
+            .iter()
+            .map(|(shard_id, server_id)| {
+                let query_body = (shard_id == shard).then(|| query_body.clone());
+                (server_id, query_body)
+            })
+            .collect()
     }
 
     // this function is used to rewrite sql like "SELECT name, gender FROM User WHERE name = \"user10\""
     /// Step 2. rewrite the seletion
-    pub fn rewrite_selection(&self, query_body: SetExpr) -> HashMap<ServerId, Option<SetExpr>> {
+    pub fn rewrite_selection(&self, query_body: SetExpr) -> ShardSelects {
         let mut final_query = HashMap::new();
         match query_body.clone() {
             SetExpr::Select(select) => {
                 // from       当前查询要访问的目标表，以及其是否是join连接
                 // projection 提取要返回的信息表
                 // selection  代表的是表的筛选条件的信息
-                let Select { selection, .. } = *select.clone();
+                let Select {
+                    selection, from, ..
+                } = *select.clone();
 
                 // 1. 提取出要访问的表的名字以及其别名，如果不需要rewrite则返回false
                 // 2. 根据上面表的别名判断selection里是否涉及到跟分片有关的属性，如果有的话直接进行分割
                 if let Some(selection) = selection {
-                    let res = self.reslove_selection(selection);
-                    for server_id in self.server_list.clone() {
-                        let selection = res.get(&(server_id as i32));
-                        // need shard, for no shard, no need query
-                        if let Some(selection) = selection {
-                            // rewrite placeholder
-                            let rewrite_selection = rewrite_placeholder(selection.clone());
+                    let res = self.reslove_selection(&from, selection);
+                    for (shard_id, server_id) in self.shards.iter() {
+                        // the shard is pruned if the selection is never true in it
+                        if let Some(selection) = res.get(&shard_id) {
                             // replace selection expr
                             let mut new_select = *select.clone();
-                            new_select.selection = rewrite_selection;
-                            // println!("new_selection: {:#?}", new_select);
+                            new_select.selection = selection.clone();
                             let new_query_body = SetExpr::Select(Box::new(new_select));
                             final_query.insert(server_id, Some(new_query_body));
                         } else {
@@ -298,14 +304,14 @@ impl QueryContext {
                     }
                 } else {
                     // no shard partitioning
-                    for server_id in self.server_list.clone() {
+                    for (_, server_id) in self.shards.iter() {
                         final_query.insert(server_id, Some(query_body.clone()));
                     }
                 };
                 final_query
             }
             _ => {
-                for server_id in self.server_list.clone() {
+                for (_, server_id) in self.shards.iter() {
                     final_query.insert(server_id, Some(query_body.clone()));
                 }
                 final_query
@@ -313,64 +319,51 @@ impl QueryContext {
         }
     }
 
-    // 1. 分片，另一个分片直接取消
-    pub fn reslove_selection(&self, selection: Expr) -> HashMap<i32, Expr> {
+    /// Get the selection of each shard which needs to be scanned.
+    ///
+    /// The predicates on the partition column are decided by the partition of each shard,
+    /// the shard is absent if the selection is never true in it, and the selection is `None`
+    /// if it is always true.
+    pub fn reslove_selection(
+        &self,
+        from: &[TableWithJoins],
+        selection: Expr,
+    ) -> HashMap<ShardId, Option<Expr>> {
         let mut res = HashMap::new();
-        let shards_id = self.server_list.clone();
-        // 1. 对于每一层selection判断其是否有很多层: 只对于有region划分功能的expr才进行划分
-        if return_expr_op(&selection) {
-            // 此时是Eq或者NotEq
-            if let Some(shard_id) = get_expr_shard(&selection) {
-                // allocate to target shard, and this selection can be none.
-                res.insert(
-                    shard_id,
-                    Expr::Value(Value::HexStringLiteral("placeholder".to_string())),
-                );
-            } else {
-                for shard_id in shards_id {
-                    res.insert(shard_id as _, selection.clone());
-                }
-            }
-            res
-        } else {
-            // 此时有两层Expr，需要进行迭代判断
-            match selection {
-                Expr::BinaryOp { left, op, right } => {
-                    let left_result = self.reslove_selection(*left);
-                    let right_result = self.reslove_selection(*right);
-                    // all shard id
-                    for shard_id in shards_id {
-                        let left_expr = left_result.get(&(shard_id as _));
-                        let right_expr = right_result.get(&(shard_id as _));
-                        if let (Some(left_expr), Some(right_expr)) = (left_expr, right_expr) {
-                            let placeholder =
-                                Expr::Value(Value::HexStringLiteral("placeholder".to_string()));
-                            let new_expr =
-                                match (left_expr.eq(&placeholder), right_expr.eq(&placeholder)) {
-                                    (true, true) => Expr::Value(Value::HexStringLiteral(
-                                        "placeholder".to_string(),
-                                    )),
-                                    (true, false) => right_expr.clone(),
-                                    (false, true) => left_expr.clone(),
-                                    (false, false) => Expr::BinaryOp {
-                                        left: Box::new(left_expr.clone()),
-                                        op: op.clone(),
-                                        right: Box::new(right_expr.clone()),
-                                    },
-                                };
-                            res.insert(shard_id as _, new_expr);
-                        }
+        // the first table split over shards places the rows, the others are co-located with it
+        let rules = sharding_rules();
+        // the partition column may be NULL padded by an outer join, whose shard is unknown
+        let outer_join = from.iter().flat_map(|from| from.joins.iter()).any(|join| {
+            matches!(
+                join.join_operator,
+                JoinOperator::LeftOuter(_)
+                    | JoinOperator::RightOuter(_)
+                    | JoinOperator::FullOuter(_)
+            )
+        });
+        let partitions = reslove_from(from.to_vec())
+            .filter(|_| !outer_join)
+            .and_then(|(tables, _)| {
+                tables.into_iter().find(|(name, _)| {
+                    rules.table_location(name) == DataShard::Shard
+                        && rules.get_partition(name).is_some()
+                })
+            })
+            .map(|(name, _)| get_shard_partitions(&name))
+            .unwrap_or_default();
+        for (shard_id, _) in self.shards.iter() {
+            match partitions.get(&shard_id) {
+                Some(partition) => {
+                    let (truth, selection) = partition.analyze(&selection);
+                    if truth.may_be_true() {
+                        res.insert(shard_id, selection);
                     }
-                    res
                 }
-                _ => {
-                    for shard_id in shards_id {
-                        let new_expr = selection.clone();
-                        res.insert(shard_id as _, new_expr);
-                    }
-                    res
+                None => {
+                    res.insert(shard_id, Some(selection.clone()));
                 }
             }
         }
+        res
     }
 }
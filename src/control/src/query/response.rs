@@ -0,0 +1,178 @@
+//! Convert the result of execution into the typed responses.
+
+use common::{ExecuteResult, ResultSet};
+use protos::{value::Kind, Column, Date, ExecResponse, Profile, Row, Time, Value};
+
+/// Convert the result into the typed response.
+pub(super) fn to_exec_response(result: ExecuteResult) -> ExecResponse {
+    let ExecuteResult {
+        result_set,
+        affected_rows,
+        profile,
+    } = result;
+    let (schema, rows) = match result_set {
+        Some(ResultSet {
+            header,
+            types,
+            table,
+        }) => (
+            to_proto_schema(header, types, &table),
+            table.into_iter().map(to_proto_row).collect(),
+        ),
+        None => (vec![], vec![]),
+    };
+    ExecResponse {
+        result: String::new(),
+        schema,
+        rows,
+        affected_rows,
+        profile: Some(to_proto_profile(profile)),
+    }
+}
+
+/// Get the columns of the result set, the unknown type of a column, e.g. of an expression,
+/// is inferred from its values in `table`.
+pub(super) fn to_proto_schema(
+    header: Vec<String>,
+    types: Vec<String>,
+    table: &[Vec<mysql::Value>],
+) -> Vec<Column> {
+    let mut types = types.into_iter();
+    header
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let mut sql_type = types.next().unwrap_or_default();
+            if sql_type.is_empty() {
+                sql_type = table
+                    .iter()
+                    .filter_map(|row| row.get(i))
+                    .find_map(infer_type)
+                    .unwrap_or_default()
+                    .to_owned();
+            }
+            Column { name, sql_type }
+        })
+        .collect()
+}
+
+/// Get the type name in mysql of a value, `None` if it is NULL.
+fn infer_type(value: &mysql::Value) -> Option<&'static str> {
+    use mysql::Value as MyValue;
+
+    match value {
+        MyValue::NULL => None,
+        MyValue::Bytes(_) => Some("varchar"),
+        MyValue::Int(_) => Some("bigint"),
+        MyValue::UInt(_) => Some("bigint unsigned"),
+        MyValue::Float(_) => Some("float"),
+        MyValue::Double(_) => Some("double"),
+        MyValue::Date(..) => Some("datetime"),
+        MyValue::Time(..) => Some("time"),
+    }
+}
+
+pub(super) fn to_proto_row(values: Vec<mysql::Value>) -> Row {
+    Row {
+        values: values.into_iter().map(to_proto_value).collect(),
+    }
+}
+
+pub(super) fn to_proto_value(value: mysql::Value) -> Value {
+    use mysql::Value as MyValue;
+
+    let kind = match value {
+        MyValue::NULL => None,
+        MyValue::Bytes(bytes) => Some(Kind::Bytes(bytes)),
+        MyValue::Int(v) => Some(Kind::Int(v)),
+        MyValue::UInt(v) => Some(Kind::Uint(v)),
+        MyValue::Float(v) => Some(Kind::Double(v.into())),
+        MyValue::Double(v) => Some(Kind::Double(v)),
+        MyValue::Date(year, month, day, hour, minute, second, micro_second) => {
+            Some(Kind::Date(Date {
+                year: year.into(),
+                month: month.into(),
+                day: day.into(),
+                hour: hour.into(),
+                minute: minute.into(),
+                second: second.into(),
+                micro_second,
+            }))
+        }
+        MyValue::Time(negative, days, hours, minutes, seconds, micro_seconds) => {
+            Some(Kind::Time(Time {
+                negative,
+                days,
+                hours: hours.into(),
+                minutes: minutes.into(),
+                seconds: seconds.into(),
+                micro_seconds,
+            }))
+        }
+    };
+    Value { kind }
+}
+
+pub(super) fn to_proto_profile(profile: common::Profile) -> Profile {
+    Profile {
+        total_time: profile.total_time,
+        parser_time: profile.parser_time,
+        rewrite_time: profile.rewrite_time,
+        exec_time: profile.exec_time,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{to_exec_response, to_proto_value};
+    use common::{ExecuteResult, ResultSet};
+    use mysql::Value as MyValue;
+    use protos::{value::Kind, Date};
+
+    #[test]
+    fn test_to_proto_value() {
+        assert_eq!(to_proto_value(MyValue::NULL).kind, None);
+        assert_eq!(to_proto_value(MyValue::Int(-1)).kind, Some(Kind::Int(-1)));
+        assert_eq!(
+            to_proto_value(MyValue::Bytes(b"u1".to_vec())).kind,
+            Some(Kind::Bytes(b"u1".to_vec()))
+        );
+        assert_eq!(
+            to_proto_value(MyValue::Date(2017, 9, 25, 12, 0, 1, 0)).kind,
+            Some(Kind::Date(Date {
+                year: 2017,
+                month: 9,
+                day: 25,
+                hour: 12,
+                minute: 0,
+                second: 1,
+                micro_second: 0,
+            }))
+        );
+    }
+
+    #[test]
+    fn test_to_exec_response() {
+        let result_set = ResultSet {
+            header: vec!["uid".to_owned(), "COUNT(*)".to_owned()],
+            types: vec!["char".to_owned(), String::new()],
+            table: vec![
+                vec![MyValue::NULL, MyValue::NULL],
+                vec![MyValue::Bytes(b"u1".to_vec()), MyValue::Int(2)],
+            ],
+        };
+        let response = to_exec_response(ExecuteResult {
+            result_set: Some(result_set),
+            ..Default::default()
+        });
+        let schema = response
+            .schema
+            .iter()
+            .map(|c| (c.name.as_str(), c.sql_type.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(schema, [("uid", "char"), ("COUNT(*)", "bigint")]);
+        assert_eq!(response.rows.len(), 2);
+        assert_eq!(response.rows[1].values[1].kind, Some(Kind::Int(2)));
+        assert!(response.affected_rows.is_none());
+    }
+}
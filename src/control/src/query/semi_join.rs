@@ -0,0 +1,516 @@
+//! Semi-join reduction of the joins computed in control layer.
+//!
+//! The more selective fragment of the first join is fetched first, and its distinct join keys
+//! are pushed down to the other fragment as `IN (...)`, or as a temporary table when there are
+//! many keys, so the other fragment only returns the rows which can be joined.
+
+use std::collections::HashSet;
+
+use common::{HashValue, MyRow, Result, RuntimeError, ServerId};
+use itertools::Itertools;
+use protos::ExecSqlWithSetupRequest;
+use sqlparser::ast::{BinaryOperator, Expr, Ident, JoinOperator, SetExpr, Statement, Value};
+use sqlparser::dialect::GenericDialect;
+use tracing::debug;
+
+use super::{
+    deserialize_rows, extract_join_keys, hash_key, parse_sql, split_conjuncts, JoinFragment,
+    JoinPlan, RewriteSqls,
+};
+use crate::session::Transaction;
+use crate::{Catalog, ControlService};
+
+/// The selectivity of `column = value`.
+const EQ_SELECTIVITY: f64 = 0.1;
+/// The selectivity of the other predicates.
+const DEFAULT_SELECTIVITY: f64 = 1.0 / 3.0;
+/// The max number of keys pushed down in `IN (...)`, more keys are put in a temporary table.
+const IN_LIST_LIMIT: usize = 1000;
+/// The number of keys inserted into the temporary table by one statement.
+const INSERT_BATCH_SIZE: usize = 1000;
+/// The temporary table of the keys, which is only visible in its connection.
+const KEYS_TABLE: &str = "semi_join_keys";
+
+/// Fetch the `build` fragment first, and filter the `probe` fragment by its join keys.
+#[derive(Debug, Clone)]
+pub struct SemiJoin {
+    pub build: usize,
+    pub probe: usize,
+    /// the positions of the keys in the rows of `build`
+    pub build_keys: Vec<usize>,
+    /// the `((table name, alias), column)` of the keys in `probe`
+    pub probe_keys: Vec<((String, String), String)>,
+    /// the estimated rows of `build` and `probe`
+    pub estimated_rows: (f64, f64),
+}
+
+/// Estimate the rows of the fragment by its largest table and the predicates in its selection.
+fn estimate_rows(
+    fragment: &JoinFragment,
+    tables: &[(String, String)],
+    catalog: &Catalog,
+) -> Option<f64> {
+    let rows = fragment
+        .tables
+        .iter()
+        .map(|table| catalog.table_rows(&tables[*table].0))
+        .collect::<Option<Vec<_>>>()?
+        .into_iter()
+        .max()?;
+    let mut conjuncts = vec![];
+    if let Some(selection) = &fragment.selection {
+        split_conjuncts(selection.clone(), &mut conjuncts);
+    }
+    let selectivity = conjuncts
+        .iter()
+        .map(|conjunct| match conjunct {
+            Expr::BinaryOp {
+                op: BinaryOperator::Eq,
+                ..
+            } => EQ_SELECTIVITY,
+            _ => DEFAULT_SELECTIVITY,
+        })
+        .product::<f64>();
+    Some(rows as f64 * selectivity)
+}
+
+/// Get the `((table name, alias), column)` at `position` of the rows of `tables`.
+fn column_at(
+    position: usize,
+    tables: &[(String, String)],
+    schema: &Catalog,
+) -> Result<((String, String), String)> {
+    let mut offset = 0;
+    for table in tables {
+        let columns = schema.get_columns(&table.0)?;
+        if position < offset + columns.len() {
+            return Ok((table.clone(), columns[position - offset].name.clone()));
+        }
+        offset += columns.len();
+    }
+    Err(RuntimeError::UnsupportSql(format!(
+        "cannot find the join key at {position}"
+    )))
+}
+
+/// Plan the semi-join of the first join of `join_plan` by the estimated rows of the fragments.
+///
+/// The semi-join is used if the rows of the build fragment, its keys and the reduced rows of
+/// the probe fragment are fewer than the rows of the probe fragment, assuming that the keys
+/// match the rows of the probe fragment uniformly.
+pub fn plan_semi_join(
+    fragments: &[JoinFragment],
+    join_plan: &mut JoinPlan,
+    catalog: &Catalog,
+) -> Result<()> {
+    let (Some(step), [left, right, ..]) = (join_plan.steps.first(), fragments) else {
+        return Ok(());
+    };
+    if !matches!(step.operator, JoinOperator::Inner(_)) {
+        return Ok(());
+    }
+    let (left_keys, right_keys) = extract_join_keys(step, &join_plan.schema)?;
+    if left_keys.is_empty() {
+        return Ok(());
+    }
+    let tables = &join_plan.tables;
+    let (Some(left_rows), Some(right_rows)) = (
+        estimate_rows(left, tables, catalog),
+        estimate_rows(right, tables, catalog),
+    ) else {
+        return Ok(());
+    };
+    let (build, probe, build_keys, probe_keys, probe_tables) = if left_rows <= right_rows {
+        (0, 1, left_keys, right_keys, &step.right)
+    } else {
+        (1, 0, right_keys, left_keys, &step.left)
+    };
+    let (build_rows, probe_rows) = if build == 0 {
+        (left_rows, right_rows)
+    } else {
+        (right_rows, left_rows)
+    };
+    let probe_table_rows = probe_tables
+        .iter()
+        .filter_map(|(table_name, _)| catalog.table_rows(table_name))
+        .max()
+        .unwrap_or_default() as f64;
+    let reduced_rows = probe_rows * (build_rows / probe_table_rows).min(1.0);
+    debug!("semi-join: build {build_rows}, probe {probe_rows}, reduced {reduced_rows}");
+    if build_rows + reduced_rows >= probe_rows {
+        return Ok(());
+    }
+    let probe_keys = probe_keys
+        .into_iter()
+        .map(|position| column_at(position, probe_tables, &join_plan.schema))
+        .collect::<Result<Vec<_>>>()?;
+    join_plan.semi_join = Some(SemiJoin {
+        build,
+        probe,
+        build_keys,
+        probe_keys,
+        estimated_rows: (build_rows, probe_rows),
+    });
+    Ok(())
+}
+
+/// Convert the value into a literal of sql.
+pub(super) fn to_literal(value: &mysql::Value) -> Expr {
+    use mysql::Value::*;
+    let value = match value {
+        NULL => Value::Null,
+        Int(i) => Value::Number(i.to_string(), false),
+        UInt(u) => Value::Number(u.to_string(), false),
+        Float(f) => Value::Number(f.to_string(), false),
+        Double(d) => Value::Number(d.to_string(), false),
+        Bytes(bytes) => match std::str::from_utf8(bytes) {
+            // the backslash is an escape character in mysql
+            Ok(s) if !s.contains('\\') => Value::SingleQuotedString(s.to_owned()),
+            _ => Value::HexStringLiteral(bytes.iter().map(|b| format!("{b:02X}")).collect()),
+        },
+        Date(..) | Time(..) => Value::SingleQuotedString(value.as_sql(false).replace('\'', "")),
+    };
+    Expr::Value(value)
+}
+
+/// Build a tuple if there are several exprs.
+pub(super) fn to_tuple(mut exprs: Vec<Expr>) -> Expr {
+    if exprs.len() == 1 {
+        exprs.remove(0)
+    } else {
+        Expr::Tuple(exprs)
+    }
+}
+
+/// Add the `filter` to the selection of the query `sql`.
+fn add_filter(sql: &str, filter: &Expr) -> Result<String> {
+    let mut statements = parse_sql(&GenericDialect {}, sql)?;
+    if let Some(Statement::Query(query)) = statements.first_mut() {
+        if let SetExpr::Select(select) = query.body.as_mut() {
+            select.selection = Some(match select.selection.take() {
+                Some(selection) => Expr::BinaryOp {
+                    left: Box::new(Expr::Nested(Box::new(selection))),
+                    op: BinaryOperator::And,
+                    right: Box::new(filter.clone()),
+                },
+                None => filter.clone(),
+            });
+            return Ok(query.to_string());
+        }
+    }
+    Err(RuntimeError::UnsupportSql(format!(
+        "cannot filter {sql} by the semi-join"
+    )))
+}
+
+impl SemiJoin {
+    /// Get the distinct non-NULL join keys of the rows of `build`.
+    pub fn distinct_keys(&self, rows: &[MyRow]) -> Result<Vec<Vec<mysql::Value>>> {
+        let mut seen = HashSet::new();
+        let mut keys = vec![];
+        for row in rows {
+            if let Some(key) = hash_key(row, &self.build_keys)? {
+                if seen.insert(key.clone()) {
+                    keys.push(key.into_iter().map(|HashValue(value)| value).collect());
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// The keys of `probe`, e.g. `a.aid` or `(a.aid, a.id)`.
+    fn probe_expr(&self) -> Expr {
+        let columns = self
+            .probe_keys
+            .iter()
+            .map(|((_, alias_name), column)| {
+                Expr::CompoundIdentifier(vec![Ident::new(alias_name), Ident::new(column)])
+            })
+            .collect();
+        to_tuple(columns)
+    }
+
+    /// Filter the sqls of `probe` by `IN (keys)`.
+    pub fn filter_in_list(
+        &self,
+        shard_sql: &[(ServerId, String)],
+        keys: &[Vec<mysql::Value>],
+    ) -> Result<Vec<(ServerId, String)>> {
+        let filter = Expr::InList {
+            expr: Box::new(self.probe_expr()),
+            list: keys
+                .iter()
+                .map(|key| to_tuple(key.iter().map(to_literal).collect()))
+                .collect(),
+            negated: false,
+        };
+        shard_sql
+            .iter()
+            .map(|(server_id, sql)| Ok((*server_id, add_filter(sql, &filter)?)))
+            .collect()
+    }
+
+    /// Filter the sqls of `probe` by the keys in a temporary table, which is created with
+    /// the types of the keys of `probe`, filled and dropped in the same connection.
+    pub fn filter_temp_table(
+        &self,
+        shard_sql: &[(ServerId, String)],
+        keys: &[Vec<mysql::Value>],
+    ) -> Result<Vec<(ServerId, ExecSqlWithSetupRequest)>> {
+        let key_names = (0..self.probe_keys.len())
+            .map(|idx| format!("k{idx}"))
+            .collect::<Vec<_>>();
+        let columns = self
+            .probe_keys
+            .iter()
+            .zip(key_names.iter())
+            .map(|(((_, alias_name), column), key_name)| {
+                format!("{alias_name}.{column} AS {key_name}")
+            })
+            .join(", ");
+        let from = self
+            .probe_keys
+            .iter()
+            .map(|((table_name, alias_name), _)| format!("{table_name} AS {alias_name}"))
+            .unique()
+            .join(", ");
+        let drop = format!("DROP TEMPORARY TABLE IF EXISTS {KEYS_TABLE}");
+        let mut setup = vec![
+            drop.clone(),
+            format!("CREATE TEMPORARY TABLE {KEYS_TABLE} SELECT {columns} FROM {from} LIMIT 0"),
+        ];
+        for batch in keys.chunks(INSERT_BATCH_SIZE) {
+            let values = batch
+                .iter()
+                .map(|key| format!("({})", key.iter().map(|v| v.as_sql(false)).join(", ")))
+                .join(", ");
+            setup.push(format!("INSERT INTO {KEYS_TABLE} VALUES {values}"));
+        }
+
+        let subquery = format!("SELECT {} FROM {KEYS_TABLE}", key_names.join(", "));
+        let Some(Statement::Query(subquery)) = parse_sql(&GenericDialect {}, &subquery)?.pop()
+        else {
+            unreachable!()
+        };
+        let filter = Expr::InSubquery {
+            expr: Box::new(self.probe_expr()),
+            subquery,
+            negated: false,
+        };
+        shard_sql
+            .iter()
+            .map(|(server_id, sql)| {
+                let req = ExecSqlWithSetupRequest {
+                    setup: setup.clone(),
+                    sql: add_filter(sql, &filter)?,
+                    cleanup: vec![drop.clone()],
+                };
+                Ok((*server_id, req))
+            })
+            .collect()
+    }
+
+    /// Describe the semi-join.
+    pub fn describe(&self) -> String {
+        let (build_rows, probe_rows) = self.estimated_rows;
+        format!(
+            "fetch fragment {} first (~{build_rows:.0} rows), \
+            filter fragment {} (~{probe_rows:.0} rows) by {} IN its keys",
+            self.build,
+            self.probe,
+            self.probe_expr()
+        )
+    }
+}
+
+impl ControlService {
+    /// Fetch the rows of each fragment of the join.
+    ///
+    /// The fragments are fetched at the same time, except that the probe fragment of the
+    /// semi-join is fetched after the build fragment. In a transaction they are fetched one
+    /// by one, since the statements of a session are serial.
+    pub(super) async fn fetch_fragments(
+        &self,
+        txn: Option<&mut Transaction>,
+        rewrite_sqls: RewriteSqls,
+        join_plan: &JoinPlan,
+    ) -> Result<Vec<Vec<MyRow>>> {
+        let semi_join = join_plan.semi_join.as_ref();
+        let (reduced, others): (Vec<_>, Vec<_>) = rewrite_sqls
+            .iter()
+            .cloned()
+            .enumerate()
+            .partition(|(idx, _)| semi_join.is_some_and(|s| *idx == s.build || *idx == s.probe));
+        let (others_idx, others_sql): (Vec<_>, Vec<_>) = others.into_iter().unzip();
+        debug!("fetch fragments {others_idx:?}, reduce fragments {reduced:?}");
+
+        let (reduced_rows, others_rows) = match txn {
+            Some(txn) => {
+                let reduced_rows = match semi_join {
+                    Some(semi_join) => Some(
+                        self.exec_semi_join(Some(&mut *txn), semi_join, &rewrite_sqls)
+                            .await?,
+                    ),
+                    None => None,
+                };
+                let mut others_rows = vec![];
+                for shard_sql in others_sql {
+                    let rows = self.fetch_rows_in(Some(&mut *txn), shard_sql).await?;
+                    others_rows.push(rows.into_iter().flatten().collect());
+                }
+                (reduced_rows, others_rows)
+            }
+            None => {
+                let reduced = async {
+                    match semi_join {
+                        Some(semi_join) => self
+                            .exec_semi_join(None, semi_join, &rewrite_sqls)
+                            .await
+                            .map(Some),
+                        None => Ok(None),
+                    }
+                };
+                futures::try_join!(reduced, self.fetch_branches(others_sql))?
+            }
+        };
+
+        let mut branches = vec![vec![]; rewrite_sqls.len()];
+        for (idx, rows) in others_idx.into_iter().zip(others_rows) {
+            branches[idx] = rows;
+        }
+        if let (Some(semi_join), Some((build_rows, probe_rows))) = (semi_join, reduced_rows) {
+            branches[semi_join.build] = build_rows;
+            branches[semi_join.probe] = probe_rows;
+        }
+        Ok(branches)
+    }
+
+    /// Fetch the build fragment, then the probe fragment filtered by the keys of it.
+    ///
+    /// The keys are always pushed down in `IN (...)` in a transaction, whose connection
+    /// is pinned by the session.
+    async fn exec_semi_join(
+        &self,
+        mut txn: Option<&mut Transaction>,
+        semi_join: &SemiJoin,
+        rewrite_sqls: &RewriteSqls,
+    ) -> Result<(Vec<MyRow>, Vec<MyRow>)> {
+        let build_sql = rewrite_sqls[semi_join.build].clone();
+        let build_rows = self
+            .fetch_rows_in(txn.as_deref_mut(), build_sql)
+            .await?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        let keys = semi_join.distinct_keys(&build_rows)?;
+        debug!("semi-join: {} keys", keys.len());
+        let probe_sql = &rewrite_sqls[semi_join.probe];
+        let probe_rows = if keys.is_empty() {
+            // nothing can be joined
+            vec![]
+        } else if keys.len() <= IN_LIST_LIMIT || txn.is_some() {
+            let shard_sql = semi_join.filter_in_list(probe_sql, &keys)?;
+            self.fetch_rows_in(txn, shard_sql).await?
+        } else {
+            let reqs = semi_join.filter_temp_table(probe_sql, &keys)?;
+            self.fetch_rows_with_setup(reqs).await?
+        };
+        Ok((build_rows, probe_rows.into_iter().flatten().collect()))
+    }
+
+    /// Execute the sqls with their setup statements in shards, return the rows of each shard.
+    async fn fetch_rows_with_setup(
+        &self,
+        reqs: Vec<(ServerId, ExecSqlWithSetupRequest)>,
+    ) -> Result<Vec<Vec<MyRow>>> {
+        let futs = reqs.into_iter().map(|(server_id, req)| {
+            let client = self.get_client(server_id);
+            async move {
+                let buffer = client?.exec_sql_with_setup(req).await?.into_inner();
+                deserialize_rows(&buffer)
+            }
+        });
+        futures::future::join_all(futs).await.into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use common::MyRow;
+    use mysql::Value;
+    use protos::DbShard;
+    use sqlparser::ast::{SetExpr, Statement};
+    use sqlparser::dialect::GenericDialect;
+
+    use super::super::{parse_sql, QueryContext};
+    use crate::cluster::ShardMap;
+    use crate::Catalog;
+
+    fn plan(sql: &str) -> Option<super::SemiJoin> {
+        let statements = parse_sql(&GenericDialect {}, sql).unwrap();
+        let Statement::Query(query) = &statements[0] else {
+            unreachable!()
+        };
+        let mut ctx = QueryContext::new();
+        ctx.set_shards(ShardMap::new([(0, DbShard::One), (1, DbShard::Two)]));
+        ctx.set_catalog(Arc::new(Catalog::mock()));
+        assert!(matches!(query.body.as_ref(), SetExpr::Select(_)));
+        let (_, join_plan) = ctx
+            .extract_join(*query.body.clone(), &query.order_by)
+            .unwrap();
+        join_plan.unwrap().semi_join
+    }
+
+    #[test]
+    fn test_semi_join() {
+        // the selective users are fetched first
+        let semi_join = plan(
+            "SELECT u.name, a.title FROM user AS u JOIN article AS a ON u.uid = a.aid \
+            WHERE u.region = 'Beijing'",
+        )
+        .unwrap();
+        assert_eq!((semi_join.build, semi_join.probe), (0, 1));
+        assert_eq!(semi_join.build_keys, [0]);
+
+        let shard_sql = [(1, "SELECT a.aid, a.title FROM article AS a".to_owned())];
+        let keys = semi_join
+            .distinct_keys(&[
+                MyRow::from(vec![Value::from("1"), Value::from("u1")]),
+                MyRow::from(vec![Value::from("1"), Value::from("u2")]),
+                MyRow::from(vec![Value::NULL, Value::from("u3")]),
+                MyRow::from(vec![Value::from("it's"), Value::from("u4")]),
+            ])
+            .unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(
+            semi_join.filter_in_list(&shard_sql, &keys).unwrap()[0].1,
+            "SELECT a.aid, a.title FROM article AS a WHERE a.aid IN ('1', 'it''s')"
+        );
+        let reqs = semi_join.filter_temp_table(&shard_sql, &keys).unwrap();
+        let req = &reqs[0].1;
+        assert_eq!(
+            req.setup[1..],
+            [
+                "CREATE TEMPORARY TABLE semi_join_keys SELECT a.aid AS k0 FROM article AS a LIMIT 0",
+                "INSERT INTO semi_join_keys VALUES ('1'), ('it\\'s')",
+            ]
+        );
+        assert_eq!(
+            req.sql,
+            "SELECT a.aid, a.title FROM article AS a WHERE a.aid IN (SELECT k0 FROM semi_join_keys)"
+        );
+
+        // the selective articles are fetched first
+        let semi_join = plan(
+            "SELECT * FROM user AS u JOIN article AS a ON u.uid = a.aid \
+            WHERE a.category = 'science' AND a.language = 'en'",
+        )
+        .unwrap();
+        assert_eq!((semi_join.build, semi_join.probe), (1, 0));
+
+        // both sides are large
+        assert!(plan("SELECT * FROM user AS u JOIN article AS a ON u.uid = a.aid").is_none());
+    }
+}
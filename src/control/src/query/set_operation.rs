@@ -0,0 +1,262 @@
+//! UNION, INTERSECT and EXCEPT across shards.
+//!
+//! Each operand of the set operations is planned as a separate distributed plan,
+//! which are the inputs of the `Union` nodes doing the deduplication, intersection and
+//! difference over the merged rows in control layer, followed by ORDER BY and LIMIT
+//! of the whole query.
+
+use std::collections::{HashMap, HashSet};
+
+use common::{HashValue, Result, RuntimeError};
+use mysql::Value;
+use sqlparser::ast::{Query, SetExpr, SetOperator, SetQuantifier, Statement};
+use sqlparser::dialect::GenericDialect;
+
+use super::plan::{Columns, PlanNode, Runs};
+use super::{distinct_runs, parse_sql, TopN};
+
+/// The set operations of a query.
+#[derive(Debug)]
+pub struct SetOperationPlan {
+    /// the tree of the set operations, whose leaves are the operands in order
+    body: SetExpr,
+    /// the operands planned separately
+    pub operands: Vec<String>,
+    /// ORDER BY, LIMIT and OFFSET of the whole query
+    top_n: TopN,
+}
+
+/// Whether the statement is a query of set operations.
+pub fn is_set_operation(statement: &Statement) -> bool {
+    let Statement::Query(query) = statement else {
+        return false;
+    };
+    matches!(*query.body, SetExpr::SetOperation { .. })
+}
+
+/// Collect the operands of the set operations as queries.
+fn collect_operands(body: &SetExpr, query: &Query, operands: &mut Vec<String>) {
+    match body {
+        SetExpr::SetOperation { left, right, .. } => {
+            collect_operands(left, query, operands);
+            collect_operands(right, query, operands);
+        }
+        // a parenthesized query has its own ORDER BY and LIMIT
+        SetExpr::Query(operand) => operands.push(operand.to_string()),
+        operand => {
+            let operand = Query {
+                body: Box::new(operand.clone()),
+                order_by: vec![],
+                limit: None,
+                offset: None,
+                fetch: None,
+                ..query.clone()
+            };
+            operands.push(operand.to_string());
+        }
+    }
+}
+
+/// The key of a row in the set operations, where NULL equals to NULL.
+fn row_key(row: &[Value]) -> Vec<HashValue> {
+    row.iter().cloned().map(HashValue).collect()
+}
+
+/// Get the distinct rows in their first appearance order.
+fn distinct(rows: Vec<Vec<Value>>) -> Vec<Vec<Value>> {
+    distinct_runs(vec![rows]).concat()
+}
+
+/// Apply the set operation on the rows of two operands.
+fn set_operation(
+    op: &SetOperator,
+    all: bool,
+    left: Vec<Vec<Value>>,
+    right: Vec<Vec<Value>>,
+) -> Vec<Vec<Value>> {
+    if let SetOperator::Union = op {
+        let rows = [left, right].concat();
+        return if all { rows } else { distinct(rows) };
+    }
+    // the times each row of the right rows can be matched
+    let mut counts: HashMap<Vec<HashValue>, usize> = HashMap::new();
+    for row in right.iter() {
+        *counts.entry(row_key(row)).or_default() += 1;
+    }
+    let intersect = matches!(op, SetOperator::Intersect);
+    let left = if all { left } else { distinct(left) };
+    left.into_iter()
+        .filter(|row| {
+            let matched = match counts.get_mut(&row_key(row)) {
+                Some(count) if *count > 0 => {
+                    // each row of the right rows is matched once in ALL
+                    if all {
+                        *count -= 1;
+                    }
+                    true
+                }
+                _ => false,
+            };
+            matched == intersect
+        })
+        .collect()
+}
+
+/// Combine the rows of the inputs of a set operation from left to right.
+///
+/// The runs of UNION ALL are kept apart, other operations merge the runs of each input.
+pub fn combine_runs(op: &SetOperator, all: bool, inputs: Vec<Runs>) -> Result<Runs> {
+    let widths = inputs
+        .iter()
+        .flatten()
+        .flat_map(|rows| rows.iter().map(Vec::len))
+        .collect::<HashSet<_>>();
+    if widths.len() > 1 {
+        return Err(RuntimeError::UnsupportSql(
+            "the SELECT statements of the set operation have different numbers of columns"
+                .to_owned(),
+        ));
+    }
+    if matches!(op, SetOperator::Union) && all {
+        return Ok(inputs.concat());
+    }
+    let mut inputs = inputs.into_iter().map(|runs| runs.concat());
+    let first = inputs.next().unwrap_or_default();
+    Ok(vec![inputs.fold(first, |left, right| {
+        set_operation(op, all, left, right)
+    })])
+}
+
+impl SetOperationPlan {
+    /// Plan the query of set operations, `None` if `sql` is not such a query.
+    pub fn new(sql: &str) -> Result<Option<Self>> {
+        let statements = parse_sql(&GenericDialect {}, sql)?;
+        let [statement] = statements.as_slice() else {
+            return Ok(None);
+        };
+        let Statement::Query(query) = statement else {
+            return Ok(None);
+        };
+        if !is_set_operation(statement) {
+            return Ok(None);
+        }
+        let mut operands = vec![];
+        collect_operands(&query.body, query, &mut operands);
+        Ok(Some(Self {
+            body: *query.body.clone(),
+            operands,
+            top_n: TopN::from_query(query)?,
+        }))
+    }
+
+    /// Build the plan combining the plans of the operands, `operands[i]` is the plan of
+    /// `self.operands[i]`, whose columns are named by the first operand.
+    pub fn build(self, operands: Vec<PlanNode>, header: Vec<String>) -> PlanNode {
+        fn build(body: SetExpr, operands: &mut impl Iterator<Item = PlanNode>) -> PlanNode {
+            match body {
+                SetExpr::SetOperation {
+                    op,
+                    set_quantifier,
+                    left,
+                    right,
+                } => PlanNode::Union {
+                    inputs: vec![build(*left, operands), build(*right, operands)],
+                    op,
+                    all: matches!(set_quantifier, SetQuantifier::All),
+                },
+                _ => operands.next().unwrap(),
+            }
+        }
+
+        let node = PlanNode::Sort {
+            input: Box::new(build(self.body, &mut operands.into_iter())),
+            order_by: self.top_n.order_by,
+            columns: Columns::Named(header),
+            presorted: false,
+        };
+        PlanNode::Limit {
+            input: Box::new(node),
+            limit: self.top_n.limit,
+            offset: self.top_n.offset,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mysql::Value;
+    use sqlparser::ast::SetOperator;
+
+    use super::{combine_runs, PlanNode, Runs, SetOperationPlan};
+
+    fn rows(values: &[i64]) -> Vec<Vec<Value>> {
+        values.iter().map(|v| vec![Value::Int(*v)]).collect()
+    }
+
+    /// Compute the plan whose operands are the scans of servers `0..operands.len()`.
+    fn eval(node: &PlanNode, operands: &[&[i64]]) -> Runs {
+        match node {
+            PlanNode::Scan { server_id, .. } => vec![rows(operands[*server_id as usize])],
+            PlanNode::Union { inputs, op, all } => {
+                let inputs = inputs.iter().map(|i| eval(i, operands)).collect();
+                combine_runs(op, *all, inputs).unwrap()
+            }
+            node => eval(node.inputs()[0], operands),
+        }
+    }
+
+    fn combine(sql: &str, operands: &[&[i64]]) -> Vec<Vec<Value>> {
+        let plan = SetOperationPlan::new(sql).unwrap().unwrap();
+        assert_eq!(plan.operands.len(), operands.len());
+        let scans = (0..operands.len())
+            .map(|idx| PlanNode::Scan {
+                server_id: idx as _,
+                sql: None,
+            })
+            .collect();
+        eval(&plan.build(scans, vec!["uid".to_owned()]), operands).concat()
+    }
+
+    #[test]
+    fn test_set_operation() {
+        let plan = SetOperationPlan::new(
+            "SELECT uid FROM user UNION (SELECT uid FROM user_read ORDER BY uid LIMIT 5) \
+            ORDER BY uid DESC LIMIT 10",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            plan.operands,
+            [
+                "SELECT uid FROM user",
+                "SELECT uid FROM user_read ORDER BY uid LIMIT 5"
+            ]
+        );
+        assert_eq!(plan.top_n.limit, Some(10));
+        assert!(SetOperationPlan::new("SELECT uid FROM user")
+            .unwrap()
+            .is_none());
+
+        let (a, b, c): (&[i64], &[i64], &[i64]) = (&[1, 1, 2, 3], &[1, 3, 3, 4], &[3]);
+        let sql = |op: &str| format!("SELECT uid FROM user {op} SELECT uid FROM user_read");
+        assert_eq!(combine(&sql("UNION"), &[a, b]), rows(&[1, 2, 3, 4]));
+        assert_eq!(
+            combine(&sql("UNION ALL"), &[a, b]),
+            rows(&[1, 1, 2, 3, 1, 3, 3, 4])
+        );
+        assert_eq!(combine(&sql("INTERSECT"), &[a, b]), rows(&[1, 3]));
+        assert_eq!(combine(&sql("INTERSECT ALL"), &[a, b]), rows(&[1, 3]));
+        assert_eq!(combine(&sql("EXCEPT"), &[a, b]), rows(&[2]));
+        assert_eq!(combine(&sql("EXCEPT ALL"), &[a, b]), rows(&[1, 2]));
+
+        // UNION and EXCEPT are left associative
+        let sql = "SELECT uid FROM user UNION ALL SELECT uid FROM user_read EXCEPT SELECT uid FROM be_read";
+        assert_eq!(combine(sql, &[a, b, c]), rows(&[1, 2, 4]));
+
+        let inputs = vec![
+            vec![vec![vec![Value::Int(1), Value::NULL]]],
+            vec![rows(&[1])],
+        ];
+        assert!(combine_runs(&SetOperator::Union, false, inputs).is_err());
+    }
+}
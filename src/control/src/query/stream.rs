@@ -0,0 +1,211 @@
+//! Stream the result of a statement to the client in batches of typed rows.
+
+use std::pin::Pin;
+
+use common::{ExecuteResult, Profiler, Result, StatusResult};
+use futures::{future, stream, Stream, StreamExt};
+use protos::{ExecRequest, ExecSqlBatchRequest, Row, StreamExecResponse};
+use sqlparser::dialect::GenericDialect;
+
+use super::plan::PlanNode;
+use super::response::{to_proto_profile, to_proto_row, to_proto_schema};
+use super::{deserialize_rows, parse_row, parse_sql, RewriteResult};
+use crate::session::{is_transaction_control, Session};
+use crate::ControlService;
+
+/// The max number of rows in a response.
+pub(super) const STREAM_BATCH_SIZE: usize = 1024;
+
+pub type ExecStream = Pin<Box<dyn Stream<Item = StatusResult<StreamExecResponse>> + Send>>;
+
+impl RewriteResult {
+    /// Whether the rows of shards can be sent to the client as they are read,
+    /// without joining, aggregating or sorting them in control.
+    fn is_streamable(&self) -> bool {
+        let gather = match &self.plan {
+            PlanNode::Limit { input, .. } => input,
+            plan => plan,
+        };
+        gather.is_gather()
+            && self.insert.is_none()
+            && self.dml.is_none()
+            && !self.explain
+            && !self.ddl
+    }
+}
+
+impl ControlService {
+    /// Execute the statement and stream its result.
+    ///
+    /// The rows of a single query are streamed from db servers by `exec_sql_batch`, which
+    /// are only read as the client reads the responses. Other statements, and queries in
+    /// a transaction, are executed as [`ControlService::exec`] and sent in batches.
+    pub async fn stream_exec(&self, req: ExecRequest) -> Result<ExecStream> {
+        let ExecRequest {
+            statement,
+            session_id,
+            ..
+        } = req;
+        let mut session = self.lock_session(session_id).await?;
+        let statements = parse_sql(&GenericDialect {}, &statement)?;
+        let in_txn = session
+            .as_deref_mut()
+            .and_then(Session::transaction)
+            .is_some();
+        if let ([ast], false) = (statements.as_slice(), in_txn) {
+            if !is_transaction_control(ast) {
+                let mut profiler = Profiler::default();
+                let plan = self.plan_query(None, statement, &mut profiler).await?;
+                if plan.is_streamable() {
+                    return self.stream_plan(plan, profiler).await;
+                }
+                let result = self.exec_plan(None, plan, profiler).await?;
+                return Ok(stream_result(result));
+            }
+        }
+        let result = self
+            .exec_statements(session.as_deref_mut(), statement, statements)
+            .await?;
+        Ok(stream_result(result))
+    }
+
+    async fn stream_plan(&self, plan: RewriteResult, mut profiler: Profiler) -> Result<ExecStream> {
+        let (offset, limit) = match &plan.plan {
+            PlanNode::Limit { limit, offset, .. } => (*offset, limit.unwrap_or(usize::MAX)),
+            _ => (0, usize::MAX),
+        };
+        profiler.reset_last();
+        let shard_sql = plan.plan.shard_sql();
+        let futs = shard_sql.into_iter().map(|(server_id, sql)| {
+            let client = self.get_client(server_id);
+            async move {
+                let req = ExecSqlBatchRequest {
+                    sql,
+                    batch_size: STREAM_BATCH_SIZE as u64,
+                };
+                Result::Ok(client?.exec_sql_batch(req).await?.into_inner())
+            }
+        });
+        let streams = futures::future::join_all(futs)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+
+        let header = StreamExecResponse {
+            schema: to_proto_schema(plan.header, plan.types, &[]),
+            ..Default::default()
+        };
+        // the streams of shards are dropped after LIMIT rows, which cancels them
+        let rows = stream::select_all(streams)
+            .map(|batch| decode_rows(&batch?))
+            .scan((offset, limit), |(offset, remaining), rows| {
+                if *remaining == 0 {
+                    return future::ready(None);
+                }
+                let rows = rows.map(|mut rows| {
+                    // skip the OFFSET rows, the shard returns LIMIT + OFFSET rows
+                    let skipped = (*offset).min(rows.len());
+                    rows.drain(..skipped);
+                    *offset -= skipped;
+                    rows.truncate(*remaining);
+                    *remaining -= rows.len();
+                    StreamExecResponse {
+                        rows,
+                        ..Default::default()
+                    }
+                });
+                future::ready(Some(rows.map_err(Into::into)))
+            });
+        let last = stream::once(async move {
+            profiler.exec_finished();
+            Ok(StreamExecResponse {
+                profile: Some(to_proto_profile(profiler.finished())),
+                ..Default::default()
+            })
+        });
+        Ok(Box::pin(
+            stream::once(future::ready(Ok(header)))
+                .chain(rows)
+                .chain(last),
+        ))
+    }
+}
+
+/// Send the executed result in batches.
+fn stream_result(result: ExecuteResult) -> ExecStream {
+    let ExecuteResult {
+        result_set,
+        affected_rows,
+        profile,
+    } = result;
+    let mut responses = vec![];
+    if let Some(result_set) = result_set {
+        responses.push(StreamExecResponse {
+            schema: to_proto_schema(result_set.header, result_set.types, &result_set.table),
+            ..Default::default()
+        });
+        let mut rows = result_set.table.into_iter().map(to_proto_row).peekable();
+        while rows.peek().is_some() {
+            responses.push(StreamExecResponse {
+                rows: rows.by_ref().take(STREAM_BATCH_SIZE).collect(),
+                ..Default::default()
+            });
+        }
+    }
+    responses.push(StreamExecResponse {
+        affected_rows,
+        profile: Some(to_proto_profile(profile)),
+        ..Default::default()
+    });
+    Box::pin(stream::iter(responses.into_iter().map(Ok)))
+}
+
+/// Decode a batch of `exec_sql_batch` into rows.
+fn decode_rows(batch: &[u8]) -> Result<Vec<Row>> {
+    Ok(deserialize_rows(batch)?
+        .iter()
+        .map(|row| to_proto_row(parse_row(row, &[])))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{stream_result, STREAM_BATCH_SIZE};
+    use common::{ExecuteResult, ResultSet};
+    use futures::StreamExt;
+    use mysql::Value as MyValue;
+
+    #[tokio::test]
+    async fn test_stream_result() {
+        let result_set = ResultSet {
+            header: vec!["uid".to_owned()],
+            types: vec!["char".to_owned()],
+            table: vec![vec![MyValue::Int(1)]; STREAM_BATCH_SIZE + 1],
+        };
+        let result = ExecuteResult {
+            result_set: Some(result_set),
+            ..Default::default()
+        };
+        let responses = stream_result(result)
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(responses.len(), 4);
+        assert_eq!(responses[0].schema[0].name, "uid");
+        assert_eq!(responses[0].schema[0].sql_type, "char");
+        assert_eq!(responses[1].rows.len(), STREAM_BATCH_SIZE);
+        assert_eq!(responses[2].rows.len(), 1);
+        assert!(responses[3].profile.is_some());
+
+        let result = ExecuteResult {
+            affected_rows: Some(2),
+            ..Default::default()
+        };
+        let responses = stream_result(result)
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].affected_rows, Some(2));
+    }
+}
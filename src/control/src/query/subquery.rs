@@ -0,0 +1,547 @@
+//! Subqueries in the WHERE, HAVING and projection of SELECT.
+//!
+//! The tables of a subquery may be sharded differently from the outer query, so the
+//! uncorrelated subqueries are executed across shards first, and their results are inlined
+//! into the statement as literals. A correlated `[NOT] EXISTS` on equalities is decorrelated
+//! into a semi-join: the distinct keys of the subquery are inlined as `IN (...)`.
+
+use std::collections::HashSet;
+
+use common::{HashValue, Result, RuntimeError};
+use sqlparser::ast::{BinaryOperator, Expr, Ident, Query, SelectItem, SetExpr, Statement, Value};
+use sqlparser::dialect::GenericDialect;
+
+use super::eval::{visit_expr, visit_expr_mut};
+use super::semi_join::{to_literal, to_tuple};
+use super::{combine_conjuncts, is_column, parse_sql, reslove_from, split_conjuncts};
+use crate::session::Transaction;
+use crate::{Catalog, ControlService};
+
+/// How the result of a subquery is inlined.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubqueryKind {
+    /// `expr [NOT] IN (subquery)`
+    In { expr: Expr, negated: bool },
+    /// `[NOT] EXISTS (subquery)`
+    Exists { negated: bool },
+    /// `(subquery)` returning at most one value
+    Scalar,
+    /// `[NOT] EXISTS` decorrelated into `outer [NOT] IN` the distinct keys of the subquery
+    SemiJoin { outer: Vec<Expr>, negated: bool },
+}
+
+/// A subquery executed before the statement.
+#[derive(Debug, Clone)]
+pub struct Subquery {
+    /// the uncorrelated query executed across shards
+    pub query: Query,
+    pub kind: SubqueryKind,
+}
+
+/// Whether `expr` is a subquery which can be inlined.
+fn is_subquery(expr: &Expr) -> bool {
+    matches!(
+        expr,
+        Expr::InSubquery { .. } | Expr::Exists { .. } | Expr::Subquery(_)
+    )
+}
+
+/// Whether `expr` is a column of `tables`.
+fn is_inner_column(expr: &Expr, tables: &[(String, String)], catalog: &Catalog) -> bool {
+    let (qualifier, column) = match expr {
+        Expr::Identifier(column) => (None, &column.value),
+        Expr::CompoundIdentifier(idents) if idents.len() == 2 => {
+            (Some(&idents[0].value), &idents[1].value)
+        }
+        _ => return false,
+    };
+    tables.iter().any(|(table_name, alias_name)| {
+        qualifier.is_none_or(|q| q == alias_name || q == table_name)
+            && catalog.column_position(table_name, column).is_some()
+    })
+}
+
+/// Whether `expr` refers to the columns out of `tables`, i.e. of the outer query.
+fn is_correlated(expr: &Expr, tables: &[(String, String)], catalog: &Catalog) -> Result<bool> {
+    let mut correlated = false;
+    visit_expr(expr, &mut |expr| {
+        if is_column(expr) {
+            correlated |= !is_inner_column(expr, tables, catalog);
+            return Ok(false);
+        }
+        Ok(true)
+    })?;
+    Ok(correlated)
+}
+
+/// Get the tables of the subquery, `None` if they cannot be resolved by the catalog,
+/// whose columns are unknown.
+fn inner_tables(query: &Query, catalog: &Catalog) -> Option<Vec<(String, String)>> {
+    let SetExpr::Select(select) = query.body.as_ref() else {
+        return None;
+    };
+    let (tables, _) = reslove_from(select.from.clone())?;
+    tables
+        .iter()
+        .all(|(table_name, _)| catalog.get_columns(table_name).is_ok())
+        .then_some(tables)
+}
+
+/// Whether the subquery refers to the columns of the outer query.
+fn is_correlated_query(query: &Query, catalog: &Catalog) -> Result<bool> {
+    let (Some(tables), SetExpr::Select(select)) = (inner_tables(query, catalog), &*query.body)
+    else {
+        return Ok(false);
+    };
+    let mut exprs = vec![];
+    for item in select.projection.iter() {
+        if let SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } = item {
+            exprs.push(expr);
+        }
+    }
+    exprs.extend(select.selection.iter());
+    exprs.extend(select.having.iter());
+    exprs.extend(select.group_by.iter());
+    for expr in exprs {
+        if is_correlated(expr, &tables, catalog)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Decorrelate `EXISTS (SELECT ... WHERE inner = outer AND ...)` into the query of the
+/// distinct inner keys, return the query and the outer keys.
+fn decorrelate_exists(query: &Query, catalog: &Catalog) -> Result<(Query, Vec<Expr>)> {
+    let unsupported = || {
+        RuntimeError::UnsupportSql(format!(
+            "correlated subquery {query} is only supported in EXISTS on equalities"
+        ))
+    };
+    let (Some(tables), SetExpr::Select(select)) = (inner_tables(query, catalog), &*query.body)
+    else {
+        return Err(unsupported());
+    };
+    if !select.group_by.is_empty() || select.having.is_some() {
+        return Err(unsupported());
+    }
+    let mut conjuncts = vec![];
+    if let Some(selection) = select.selection.clone() {
+        split_conjuncts(selection, &mut conjuncts);
+    }
+    let mut kept = vec![];
+    let (mut inner, mut outer) = (vec![], vec![]);
+    for conjunct in conjuncts {
+        if !is_correlated(&conjunct, &tables, catalog)? {
+            kept.push(conjunct);
+            continue;
+        }
+        let Expr::BinaryOp {
+            left,
+            op: BinaryOperator::Eq,
+            right,
+        } = &conjunct
+        else {
+            return Err(unsupported());
+        };
+        let is_inner = |expr: &Expr| is_column(expr) && is_inner_column(expr, &tables, catalog);
+        let is_outer = |expr: &Expr| is_column(expr) && !is_inner_column(expr, &tables, catalog);
+        match (left.as_ref(), right.as_ref()) {
+            (l, r) if is_inner(l) && is_outer(r) => {
+                inner.push(l.clone());
+                outer.push(r.clone());
+            }
+            (l, r) if is_outer(l) && is_inner(r) => {
+                inner.push(r.clone());
+                outer.push(l.clone());
+            }
+            _ => return Err(unsupported()),
+        }
+    }
+
+    let mut select = select.clone();
+    select.distinct = true;
+    select.projection = inner.into_iter().map(SelectItem::UnnamedExpr).collect();
+    select.selection = combine_conjuncts(kept);
+    let query = Query {
+        body: Box::new(SetExpr::Select(select)),
+        order_by: vec![],
+        limit: None,
+        offset: None,
+        fetch: None,
+        ..query.clone()
+    };
+    Ok((query, outer))
+}
+
+impl Subquery {
+    /// Plan the subquery `expr`, which is one of [`is_subquery`].
+    pub fn new(expr: &Expr, catalog: &Catalog) -> Result<Self> {
+        let correlated = |query: &Query| -> Result<()> {
+            if is_correlated_query(query, catalog)? {
+                return Err(RuntimeError::UnsupportSql(format!(
+                    "correlated subquery {query} is only supported in EXISTS on equalities"
+                )));
+            }
+            Ok(())
+        };
+        let subquery = match expr {
+            Expr::InSubquery {
+                expr,
+                subquery,
+                negated,
+            } => {
+                correlated(subquery)?;
+                Self {
+                    query: *subquery.clone(),
+                    kind: SubqueryKind::In {
+                        expr: *expr.clone(),
+                        negated: *negated,
+                    },
+                }
+            }
+            Expr::Exists { subquery, negated } if is_correlated_query(subquery, catalog)? => {
+                let (query, outer) = decorrelate_exists(subquery, catalog)?;
+                Self {
+                    query,
+                    kind: SubqueryKind::SemiJoin {
+                        outer,
+                        negated: *negated,
+                    },
+                }
+            }
+            Expr::Exists { subquery, negated } => {
+                let mut query = *subquery.clone();
+                // one row decides the result
+                if query.limit.is_none() && query.offset.is_none() {
+                    query.limit = Some(Expr::Value(Value::Number("1".to_owned(), false)));
+                }
+                Self {
+                    query,
+                    kind: SubqueryKind::Exists { negated: *negated },
+                }
+            }
+            Expr::Subquery(subquery) => {
+                correlated(subquery)?;
+                Self {
+                    query: *subquery.clone(),
+                    kind: SubqueryKind::Scalar,
+                }
+            }
+            _ => {
+                return Err(RuntimeError::UnsupportSql(format!(
+                    "{expr} is not a subquery"
+                )))
+            }
+        };
+        Ok(subquery)
+    }
+
+    /// Inline the rows of the subquery as literals.
+    pub fn inline(&self, rows: Vec<Vec<mysql::Value>>) -> Result<Expr> {
+        let check_width = |width: usize| -> Result<()> {
+            match rows.iter().find(|row| row.len() != width) {
+                Some(_) => Err(RuntimeError::UnsupportSql(format!(
+                    "operand of subquery {} should contain {width} column(s)",
+                    self.query
+                ))),
+                None => Ok(()),
+            }
+        };
+        let boolean = |b: bool| Expr::Value(Value::Boolean(b));
+        let expr = match &self.kind {
+            SubqueryKind::In { expr, negated } => {
+                let width = match expr {
+                    Expr::Tuple(exprs) => exprs.len(),
+                    _ => 1,
+                };
+                check_width(width)?;
+                if rows.is_empty() {
+                    // nothing is IN the empty set, even NULL
+                    return Ok(boolean(*negated));
+                }
+                Expr::InList {
+                    expr: Box::new(expr.clone()),
+                    list: distinct(rows, false),
+                    negated: *negated,
+                }
+            }
+            SubqueryKind::Exists { negated } => boolean(rows.is_empty() == *negated),
+            SubqueryKind::Scalar => {
+                check_width(1)?;
+                match rows.as_slice() {
+                    [] => Expr::Value(Value::Null),
+                    [row] => to_literal(&row[0]),
+                    _ => {
+                        return Err(RuntimeError::UnsupportSql(format!(
+                            "subquery {} returns more than 1 row",
+                            self.query
+                        )))
+                    }
+                }
+            }
+            SubqueryKind::SemiJoin { outer, negated } => {
+                // NULL never equals to anything
+                let keys = distinct(rows, true);
+                if keys.is_empty() {
+                    return Ok(boolean(*negated));
+                }
+                let in_list = Expr::InList {
+                    expr: Box::new(to_tuple(outer.clone())),
+                    list: keys,
+                    negated: *negated,
+                };
+                if !*negated {
+                    return Ok(in_list);
+                }
+                // the row without the keys does not exist in the subquery
+                let null_keys = outer
+                    .iter()
+                    .map(|expr| Expr::IsNull(Box::new(expr.clone())));
+                let expr = null_keys
+                    .chain([in_list])
+                    .reduce(|left, right| Expr::BinaryOp {
+                        left: Box::new(left),
+                        op: BinaryOperator::Or,
+                        right: Box::new(right),
+                    });
+                Expr::Nested(Box::new(expr.unwrap()))
+            }
+        };
+        Ok(expr)
+    }
+}
+
+/// Get the distinct rows as literals, skip the rows with NULL if `skip_null`.
+fn distinct(rows: Vec<Vec<mysql::Value>>, skip_null: bool) -> Vec<Expr> {
+    let mut seen = HashSet::new();
+    let mut list = vec![];
+    for row in rows {
+        if skip_null && row.contains(&mysql::Value::NULL) {
+            continue;
+        }
+        if seen.insert(row.iter().cloned().map(HashValue).collect::<Vec<_>>()) {
+            list.push(to_tuple(row.iter().map(to_literal).collect()));
+        }
+    }
+    list
+}
+
+/// Visit the subqueries in the WHERE, HAVING and projection of the statement.
+fn visit_subqueries<F>(statement: &mut Statement, f: &mut F) -> Result<()>
+where
+    F: FnMut(&mut Expr) -> Result<()>,
+{
+    let Statement::Query(query) = statement else {
+        return Ok(());
+    };
+    let SetExpr::Select(select) = query.body.as_mut() else {
+        return Ok(());
+    };
+    let mut visit = |expr: &mut Expr| {
+        visit_expr_mut(expr, &mut |expr| {
+            if is_subquery(expr) {
+                f(expr)?;
+                return Ok(false);
+            }
+            Ok(true)
+        })
+    };
+    for item in select.projection.iter_mut() {
+        if let SelectItem::UnnamedExpr(expr) = item {
+            // keep the name of the column after inlining
+            let mut contains = false;
+            visit_expr(expr, &mut |expr| {
+                contains |= is_subquery(expr);
+                Ok(!contains)
+            })?;
+            if contains {
+                *item = SelectItem::ExprWithAlias {
+                    alias: Ident::with_quote('`', expr.to_string()),
+                    expr: expr.clone(),
+                };
+            }
+        }
+        if let SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } = item {
+            visit(expr)?;
+        }
+    }
+    if let Some(selection) = select.selection.as_mut() {
+        visit(selection)?;
+    }
+    if let Some(having) = select.having.as_mut() {
+        visit(having)?;
+    }
+    Ok(())
+}
+
+/// Plan the subqueries of the statement, in the order of [`visit_subqueries`].
+fn plan_subqueries(statement: &mut Statement, catalog: &Catalog) -> Result<Vec<Subquery>> {
+    let mut subqueries = vec![];
+    visit_subqueries(statement, &mut |expr| {
+        subqueries.push(Subquery::new(expr, catalog)?);
+        Ok(())
+    })?;
+    Ok(subqueries)
+}
+
+impl ControlService {
+    /// Execute the subqueries of a SELECT statement, return the statement whose subqueries
+    /// are replaced by their results.
+    pub(super) async fn inline_subqueries(
+        &self,
+        mut txn: Option<&mut Transaction>,
+        statement: String,
+    ) -> Result<String> {
+        let mut statements = parse_sql(&GenericDialect {}, &statement)?;
+        let [ast @ Statement::Query(_)] = statements.as_mut_slice() else {
+            return Ok(statement);
+        };
+        let subqueries = plan_subqueries(ast, &self.get_catalog())?;
+        if subqueries.is_empty() {
+            return Ok(statement);
+        }
+        let mut inlined = vec![];
+        for subquery in subqueries {
+            let rows = self
+                .exec_subquery(txn.as_deref_mut(), subquery.query.to_string())
+                .await?;
+            inlined.push(subquery.inline(rows)?);
+        }
+        let mut inlined = inlined.into_iter();
+        visit_subqueries(ast, &mut |expr| {
+            if let Some(value) = inlined.next() {
+                *expr = value;
+            }
+            Ok(())
+        })?;
+        Ok(ast.to_string())
+    }
+
+    /// Execute the subquery across shards, return its rows.
+    async fn exec_subquery(
+        &self,
+        txn: Option<&mut Transaction>,
+        sql: String,
+    ) -> Result<Vec<Vec<mysql::Value>>> {
+        let result = self.exec_query(txn, sql).await?;
+        Ok(result
+            .result_set
+            .map(|result_set| result_set.table)
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mysql::Value;
+    use sqlparser::dialect::GenericDialect;
+
+    use super::{plan_subqueries, visit_subqueries, SubqueryKind};
+    use crate::query::parse_sql;
+    use crate::Catalog;
+
+    fn bytes(s: &str) -> Value {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+
+    /// Plan the subqueries of `sql`, inline `rows` as their results and return the statement.
+    fn inline(sql: &str, rows: Vec<Vec<Vec<Value>>>) -> (Vec<String>, String) {
+        let mut statement = parse_sql(&GenericDialect {}, sql).unwrap().remove(0);
+        let subqueries = plan_subqueries(&mut statement, &Catalog::mock()).unwrap();
+        let queries = subqueries.iter().map(|s| s.query.to_string()).collect();
+        let mut inlined = subqueries
+            .iter()
+            .zip(rows)
+            .map(|(subquery, rows)| subquery.inline(rows).unwrap());
+        visit_subqueries(&mut statement, &mut |expr| {
+            *expr = inlined.next().unwrap();
+            Ok(())
+        })
+        .unwrap();
+        (queries, statement.to_string())
+    }
+
+    #[test]
+    fn test_uncorrelated_subquery() {
+        let (queries, sql) = inline(
+            "SELECT aid FROM user_read WHERE uid IN (SELECT uid FROM user WHERE region = 'Beijing')",
+            vec![vec![vec![bytes("1")], vec![bytes("2")], vec![bytes("1")]]],
+        );
+        assert_eq!(queries, ["SELECT uid FROM user WHERE region = 'Beijing'"]);
+        assert_eq!(sql, "SELECT aid FROM user_read WHERE uid IN ('1', '2')");
+
+        // nothing is IN the empty set
+        let (_, sql) = inline(
+            "SELECT aid FROM user_read WHERE uid NOT IN (SELECT uid FROM user)",
+            vec![vec![]],
+        );
+        assert_eq!(sql, "SELECT aid FROM user_read WHERE true");
+
+        let (queries, sql) = inline(
+            "SELECT uid, (SELECT max(timestamp) FROM article) FROM user \
+            WHERE EXISTS (SELECT * FROM be_read)",
+            vec![vec![vec![Value::Int(10)]], vec![]],
+        );
+        assert_eq!(
+            queries,
+            [
+                "SELECT max(timestamp) FROM article",
+                "SELECT * FROM be_read LIMIT 1"
+            ]
+        );
+        assert_eq!(
+            sql,
+            "SELECT uid, 10 AS `(SELECT max(timestamp) FROM article)` FROM user WHERE false"
+        );
+
+        let mut statement = parse_sql(
+            &GenericDialect {},
+            "SELECT * FROM user WHERE uid = (SELECT uid FROM user_read)",
+        )
+        .unwrap()
+        .remove(0);
+        let subqueries = plan_subqueries(&mut statement, &Catalog::mock()).unwrap();
+        assert_eq!(subqueries[0].kind, SubqueryKind::Scalar);
+        let rows = vec![vec![bytes("1")], vec![bytes("2")]];
+        assert!(subqueries[0].inline(rows).is_err());
+    }
+
+    #[test]
+    fn test_correlated_exists() {
+        let (queries, sql) = inline(
+            "SELECT a.aid FROM article AS a WHERE NOT EXISTS \
+            (SELECT * FROM be_read AS b WHERE b.aid = a.aid AND b.readNum > 10)",
+            vec![vec![vec![bytes("1")], vec![Value::NULL]]],
+        );
+        assert_eq!(
+            queries,
+            ["SELECT DISTINCT b.aid FROM be_read AS b WHERE b.readNum > 10"]
+        );
+        assert_eq!(
+            sql,
+            "SELECT a.aid FROM article AS a WHERE (a.aid IS NULL OR a.aid NOT IN ('1'))"
+        );
+
+        let (_, sql) = inline(
+            "SELECT uid FROM user AS u WHERE EXISTS \
+            (SELECT 1 FROM user_read AS r WHERE u.uid = r.uid AND u.timestamp = r.timestamp)",
+            vec![vec![vec![bytes("1"), bytes("10")]]],
+        );
+        assert_eq!(
+            sql,
+            "SELECT uid FROM user AS u WHERE (u.uid, u.timestamp) IN (('1', '10'))"
+        );
+
+        // only EXISTS on equalities is decorrelated
+        for sql in [
+            "SELECT * FROM user AS u WHERE u.uid IN (SELECT r.uid FROM user_read AS r \
+            WHERE r.timestamp = u.timestamp)",
+            "SELECT * FROM article AS a WHERE EXISTS (SELECT * FROM be_read AS b \
+            WHERE b.aid > a.aid)",
+        ] {
+            let mut statement = parse_sql(&GenericDialect {}, sql).unwrap().remove(0);
+            assert!(plan_subqueries(&mut statement, &Catalog::mock()).is_err());
+        }
+    }
+}
@@ -1,14 +1,95 @@
 // use sqlparser::ast::{Expr, JoinOperator, OrderByExpr};
 
-use std::collections::HashMap;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::ops::Range;
 
-use common::{
-    get_join_condition, get_shards_info, join_shard_info, DataShard, MyRow, Result, SymbolTable,
-};
+use super::eval::{eval_expr, is_true, resolve_columns, visit_expr};
+use super::semi_join::SemiJoin;
+
+use crate::Catalog;
+use common::{collate, sharding_rules, DataShard, HashValue, MyRow, Result, RuntimeError};
 use sqlparser::ast::{
-    BinaryOperator, Expr, Ident, Join, JoinOperator, ObjectName, OrderByExpr, SelectItem,
-    TableAlias, TableFactor, TableWithJoins, Value,
+    BinaryOperator, Expr, Ident, Join, JoinConstraint, JoinOperator, ObjectName, OrderByExpr,
+    Query, SelectItem, Statement, TableAlias, TableFactor, TableWithJoins, Value,
 };
+use sqlparser::dialect::Dialect;
+use sqlparser::parser::{Parser, ParserError};
+use sqlparser::tokenizer::{Token, Tokenizer};
+
+/// Parse `sql` into statements, the syntax error is reported with its line and column.
+pub fn parse_sql(dialect: &dyn Dialect, sql: &str) -> Result<Vec<Statement>> {
+    let tokens = Tokenizer::new(dialect, sql)
+        .tokenize()
+        .map_err(ParserError::from)?;
+    let mut parser = Parser::new(tokens.clone(), dialect);
+    let mut statements = vec![];
+    let mut expecting_delimiter = false;
+    loop {
+        while parser.consume_token(&Token::SemiColon) {
+            expecting_delimiter = false;
+        }
+        if parser.peek_token() == Token::EOF {
+            break;
+        }
+        let statement = if expecting_delimiter {
+            parser.expected("end of statement", parser.peek_token())
+        } else {
+            parser.parse_statement()
+        };
+        match statement {
+            Ok(statement) => {
+                statements.push(statement);
+                expecting_delimiter = true;
+            }
+            Err(ParserError::ParserError(message)) => {
+                let mut remaining = 0;
+                while parser.next_token_no_skip().is_some() {
+                    remaining += 1;
+                }
+                let (line, column) = locate_token(&tokens, tokens.len() - remaining, &message);
+                return Err(ParserError::ParserError(format!(
+                    "{message} at Line: {line}, Column {column}"
+                ))
+                .into());
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(statements)
+}
+
+/// Get the position of the token where the parser fails,
+/// which is the last consumed token or the next one.
+fn locate_token(tokens: &[Token], index: usize, message: &str) -> (u64, u64) {
+    let mut positions = vec![];
+    let (mut line, mut column) = (1, 1);
+    for token in tokens {
+        positions.push((line, column));
+        for c in token.to_string().chars() {
+            if c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+    }
+    // the end of sql
+    positions.push((line, column));
+
+    let is_whitespace = |token: &Token| matches!(token, Token::Whitespace(_));
+    let last = tokens[..index].iter().rposition(|t| !is_whitespace(t));
+    if let Some(last) = last.filter(|last| message.ends_with(&format!("found: {}", tokens[*last])))
+    {
+        return positions[last];
+    }
+    let next = tokens[index..]
+        .iter()
+        .position(|t| !is_whitespace(t))
+        .map_or(tokens.len(), |offset| index + offset);
+    positions[next]
+}
 
 pub fn reslove_table_factor(relation: TableFactor) -> Option<(String, Option<String>)> {
     match relation {
@@ -21,209 +102,37 @@ pub fn reslove_table_factor(relation: TableFactor) -> Option<(String, Option<Str
     }
 }
 
-pub fn get_table_info() -> HashMap<String, Vec<String>> {
-    let mut table_info = HashMap::new();
-    let user_prop = vec![
-        "timestamp".to_string(),
-        "id".to_string(),
-        "uid".to_string(),
-        "name".to_string(),
-        "gender".to_string(),
-        "email".to_string(),
-        "phone".to_string(),
-        "dept".to_string(),
-        "grade".to_string(),
-        "language".to_string(),
-        "region".to_string(),
-        "role".to_string(),
-        "preferTags".to_string(),
-        "obtainedCredits".to_string(),
-    ];
-    table_info.insert("user".to_string(), user_prop);
-
-    let article_prop = vec![
-        "timestamp".to_string(),
-        "id".to_string(),
-        "aid".to_string(),
-        "title".to_string(),
-        "category".to_string(),
-        "abstract".to_string(),
-        "articleTags".to_string(),
-        "authors".to_string(),
-        "language".to_string(),
-        "text".to_string(),
-        "image".to_string(),
-        "video".to_string(),
-    ];
-    table_info.insert("article".to_string(), article_prop);
-
-    let read_prop = vec![
-        "timestamp".to_string(),
-        "id".to_string(),
-        "uid".to_string(),
-        "aid".to_string(),
-        "readTimeLength".to_string(),
-        "aggreeOrNot".to_string(),
-        "commentOrNot".to_string(),
-        "shareOrNot".to_string(),
-        "commentDetail".to_string(),
-    ];
-    table_info.insert("user_read".to_string(), read_prop);
-
-    let be_read_prop = vec![
-        "id".to_string(),
-        "aid".to_string(),
-        "readNum".to_string(),
-        "readUidList".to_string(),
-        "commentNum".to_string(),
-        "commentUidList".to_string(),
-        "agreeNum".to_string(),
-        "agreeUidList".to_string(),
-        "shareNum".to_string(),
-        "shareUidList".to_string(),
-    ];
-    table_info.insert("be_read".to_string(), be_read_prop);
-
-    let popular_rank_prop = vec![
-        "id".to_string(),
-        "popularDate".to_string(),
-        "temporalGranularity".to_string(),
-        "articleAidList".to_string(),
-    ];
-    table_info.insert("popular_rank".to_string(), popular_rank_prop);
-
-    table_info
-}
-
-/// Return value: SymbolTable, Option<DataShard, Joinoperator>
-///
-/// None - not join, don't need devide
-///
-/// Some(DataShard::Shard, JoinOperator) - rewrite sql and compute in control layer
+/// The `(table name, alias)` of each table in FROM clause, and the operators joining them.
+pub type FromTables = (Vec<(String, String)>, Vec<JoinOperator>);
+
+/// Get the tables in FROM clause and the operators joining them.
 ///
-/// Some(DataShard::NotShard, JoinOperator) - no need to shard, continue to execute
+/// Return value: the `(table name, alias)` of each table, and the operator joining each table
+/// after the first one, the tables separated by comma are cross joined.
 ///
-/// Some(DataShard::Two, JoinOperator) - only to need query in shard2, no need rewrite
-pub fn reslove_from(from: TableWithJoins) -> (SymbolTable, Option<(DataShard, JoinOperator)>) {
-    // let mut table_alias = HashMap::new();
-    let mut symbol_table = SymbolTable::default();
-
-    let TableWithJoins { relation, joins } = from;
-
-    // join from factor
-    if let Some((table_name, Some(alias_name))) = reslove_table_factor(relation) {
-        symbol_table.insert(table_name, alias_name);
-    }
-
-    // 1. not join
-    if joins.is_empty() {
-        (symbol_table, None)
-    } else {
-        let join_shard_info = join_shard_info();
-        // 2. a join b: currently we only support a join b
-        let Join {
-            relation,
-            join_operator,
-        } = joins.get(0).unwrap().clone();
-
-        if let Some((table_name, Some(alias_name))) = reslove_table_factor(relation) {
-            symbol_table.insert(table_name, alias_name);
-        }
-        assert_eq!(symbol_table.len(), 2);
-        let join_key = (
-            symbol_table.get_index(0).unwrap(),
-            symbol_table.get_index(1).unwrap(),
-        );
-        // println!("symbol table: {:?}", symbol_table);
-        let ret = if let Some(shard_info) = join_shard_info.get(&join_key) {
-            (symbol_table, Some((shard_info.clone(), join_operator)))
-        } else {
-            (symbol_table, None)
-        };
-        // println!("ret: {:?}",ret);
-        ret
-    }
-}
-
-pub fn extract_selection(
-    left_name: String,
-    left_alias_name: Option<String>,
-    right_name: String,
-    right_alias_name: Option<String>,
-    selection: Option<Expr>,
-) -> (Option<Expr>, Option<Expr>) {
-    let join_condition = get_join_condition();
-    let left_join = join_condition.get(&left_name).unwrap();
-    let right_join = join_condition.get(&right_name).unwrap();
-
-    let left_join = left_alias_name.map_or_else(
-        || Expr::Identifier(left_join.clone()),
-        |alias_name| {
-            Expr::CompoundIdentifier(vec![
-                Ident {
-                    value: alias_name,
-                    quote_style: None,
-                },
-                left_join.clone(),
-            ])
-        },
-    );
-    let right_join = right_alias_name.map_or_else(
-        || Expr::Identifier(right_join.clone()),
-        |alias_name| {
-            Expr::CompoundIdentifier(vec![
-                Ident {
-                    value: alias_name,
-                    quote_style: None,
-                },
-                right_join.clone(),
-            ])
-        },
-    );
-
-    if let Some(selection) = selection {
-        match selection.clone() {
-            Expr::BinaryOp { left, op, right } => {
-                let left_expr = *left;
-                let right_expr = *right;
-
-                if left_expr.eq(&left_join) || left_expr.eq(&right_join) {
-                    // the slection is relationed to join, we need to rewrite
-                    let left_selection = Expr::BinaryOp {
-                        left: Box::new(left_join),
-                        op: op.clone(),
-                        right: Box::new(right_expr.clone()),
-                    };
-                    let right_selection = Expr::BinaryOp {
-                        left: Box::new(right_join),
-                        op,
-                        right: Box::new(right_expr),
-                    };
-                    (Some(left_selection), Some(right_selection))
-                } else if right_expr.eq(&left_join) || right_expr.eq(&right_join) {
-                    let left_selection = Expr::BinaryOp {
-                        left: Box::new(left_join),
-                        op: op.clone(),
-                        right: Box::new(left_expr.clone()),
-                    };
-                    let right_selection = Expr::BinaryOp {
-                        left: Box::new(right_join),
-                        op,
-                        right: Box::new(left_expr),
-                    };
-                    (Some(left_selection), Some(right_selection))
-                } else {
-                    (Some(selection.clone()), Some(selection))
-                }
-            }
-            _ => (Some(selection.clone()), Some(selection)),
+/// Return `None` if FROM contains anything other than tables, e.g. a subquery.
+pub fn reslove_from(from: Vec<TableWithJoins>) -> Option<FromTables> {
+    let mut tables = vec![];
+    let mut operators = vec![];
+    for (idx, TableWithJoins { relation, joins }) in from.into_iter().enumerate() {
+        if idx > 0 {
+            operators.push(JoinOperator::CrossJoin);
+        }
+        let relations =
+            std::iter::once(relation).chain(joins.iter().map(|join| join.relation.clone()));
+        for relation in relations {
+            // the table without alias is referred by its name
+            let (table_name, alias_name) = reslove_table_factor(relation)?;
+            let alias_name = alias_name.unwrap_or_else(|| table_name.clone());
+            tables.push((table_name, alias_name));
         }
-    } else {
-        (None, None)
+        operators.extend(joins.into_iter().map(|join| join.join_operator));
     }
+    Some((tables, operators))
 }
 
 pub fn get_table_factor(table_name: String, alias_name: Option<String>) -> Vec<TableWithJoins> {
+    let alias_name = alias_name.filter(|alias| *alias != table_name);
     let table_factor = TableFactor::Table {
         name: ObjectName(vec![Ident {
             value: table_name,
@@ -245,79 +154,1846 @@ pub fn get_table_factor(table_name: String, alias_name: Option<String>) -> Vec<T
     }]
 }
 
-pub fn return_expr_op(expr: &Expr) -> bool {
-    match expr {
-        Expr::BinaryOp { op, .. } => op.eq(&BinaryOperator::Eq) || op.eq(&BinaryOperator::NotEq),
-        _ => false,
+pub fn get_wild_projection() -> Vec<SelectItem> {
+    vec![SelectItem::Wildcard]
+}
+
+/// The join computed in control layer.
+///
+/// `left` and `right` are the `(table name, alias)` of the tables on each side,
+/// in the same order as their columns appear in the fetched rows.
+#[derive(Debug, Clone)]
+pub struct JoinInfo {
+    pub left: Vec<(String, String)>,
+    pub right: Vec<(String, String)>,
+    pub operator: JoinOperator,
+}
+
+/// Get the position of column `expr` in the rows which are the concatenation
+/// of all columns of `tables`.
+///
+/// Return `None` if the column does not belong to these tables.
+pub fn resolve_column(
+    expr: &Expr,
+    tables: &[(String, String)],
+    catalog: &Catalog,
+) -> Result<Option<usize>> {
+    let (qualifier, column) = match expr {
+        Expr::Identifier(column) => (None, &column.value),
+        Expr::CompoundIdentifier(idents) if idents.len() == 2 => {
+            (Some(&idents[0].value), &idents[1].value)
+        }
+        Expr::Nested(expr) => return resolve_column(expr, tables, catalog),
+        _ => return Ok(None),
+    };
+    let mut offset = 0;
+    let mut position = None;
+    for (table_name, alias_name) in tables {
+        let columns = catalog.get_columns(table_name)?;
+        let matched = qualifier.is_none_or(|q| q == alias_name || q == table_name);
+        if let Some(idx) = columns
+            .iter()
+            .position(|c| c.name == *column)
+            .filter(|_| matched)
+        {
+            if position.is_some() {
+                return Err(RuntimeError::UnsupportSql(format!(
+                    "column {expr} in join condition is ambiguous"
+                )));
+            }
+            position = Some(offset + idx);
+        }
+        offset += columns.len();
     }
+    Ok(position)
 }
 
-pub fn get_expr_shard(my_expr: &Expr) -> Option<i32> {
-    let shards_info = get_shards_info();
-    for (shard_id, shard_expr) in shards_info {
-        for expr in shard_expr {
-            if my_expr.eq(&expr) {
-                // 可以分片，则
-                return Some(shard_id);
+/// Extract the equality keys of a join constraint,
+/// return the key positions of the `(left rows, right rows)`.
+pub fn extract_join_keys(
+    join_info: &JoinInfo,
+    catalog: &Catalog,
+) -> Result<(Vec<usize>, Vec<usize>)> {
+    fn collect_eq(expr: &Expr, pairs: &mut Vec<(Expr, Expr)>) -> Result<()> {
+        match expr {
+            Expr::Nested(expr) => collect_eq(expr, pairs),
+            Expr::BinaryOp {
+                left,
+                op: BinaryOperator::And,
+                right,
+            } => {
+                collect_eq(left, pairs)?;
+                collect_eq(right, pairs)
+            }
+            Expr::BinaryOp {
+                left,
+                op: BinaryOperator::Eq,
+                right,
+            } => {
+                pairs.push((*left.clone(), *right.clone()));
+                Ok(())
+            }
+            _ => Err(RuntimeError::UnsupportSql(format!(
+                "only equi-join is supported, get join condition {expr}"
+            ))),
+        }
+    }
+
+    let JoinInfo {
+        left,
+        right,
+        operator,
+    } = join_info;
+    let constraint = match operator {
+        JoinOperator::Inner(constraint)
+        | JoinOperator::LeftOuter(constraint)
+        | JoinOperator::RightOuter(constraint)
+        | JoinOperator::FullOuter(constraint) => constraint,
+        JoinOperator::CrossJoin => return Ok((vec![], vec![])),
+        _ => {
+            return Err(RuntimeError::UnsupportSql(format!(
+                "unsupported join operator {operator:?}"
+            )))
+        }
+    };
+    let mut pairs = vec![];
+    match constraint {
+        JoinConstraint::On(expr) => collect_eq(expr, &mut pairs)?,
+        JoinConstraint::Using(columns) => {
+            for column in columns {
+                let column = Expr::Identifier(column.clone());
+                pairs.push((column.clone(), column));
+            }
+        }
+        JoinConstraint::Natural => {
+            let columns_of = |tables: &[(String, String)]| -> Result<Vec<String>> {
+                let mut columns = vec![];
+                for (name, _) in tables {
+                    columns.extend(catalog.get_column_names(name)?);
+                }
+                Ok(columns)
+            };
+            let right_columns = columns_of(right)?;
+            for column in columns_of(left)? {
+                if right_columns.contains(&column) {
+                    let column = Expr::Identifier(Ident::new(column));
+                    pairs.push((column.clone(), column));
+                }
             }
         }
+        JoinConstraint::None => {}
     }
-    None
+
+    let mut left_keys = vec![];
+    let mut right_keys = vec![];
+    for (a, b) in pairs {
+        let keys = match (
+            resolve_column(&a, left, catalog)?,
+            resolve_column(&b, right, catalog)?,
+        ) {
+            (Some(l), Some(r)) => (l, r),
+            _ => match (
+                resolve_column(&b, left, catalog)?,
+                resolve_column(&a, right, catalog)?,
+            ) {
+                (Some(l), Some(r)) => (l, r),
+                _ => {
+                    return Err(RuntimeError::UnsupportSql(format!(
+                        "cannot resolve join condition {a} = {b}"
+                    )))
+                }
+            },
+        };
+        left_keys.push(keys.0);
+        right_keys.push(keys.1);
+    }
+    Ok((left_keys, right_keys))
 }
 
-pub fn rewrite_placeholder(expr: Expr) -> Option<Expr> {
-    let placeholder = Expr::Value(Value::HexStringLiteral("placeholder".to_string()));
-    if expr.eq(&placeholder) {
-        None
-    } else {
-        Some(expr)
+/// Get the hash key of `row` on columns `keys`.
+///
+/// Return `None` if any of the key is NULL, since NULL never equals to anything.
+pub fn hash_key(row: &MyRow, keys: &[usize]) -> Result<Option<Vec<HashValue>>> {
+    let mut hash_key = Vec::with_capacity(keys.len());
+    for &idx in keys {
+        let value = row
+            .get(idx)
+            .and_then(|v| v.as_ref())
+            .ok_or_else(|| RuntimeError::DBTypeParseError(format!("cannot get join key {idx}")))?
+            .value();
+        if *value == mysql::Value::NULL {
+            return Ok(None);
+        }
+        hash_key.push(HashValue(value.clone()));
     }
+    Ok(Some(hash_key))
 }
 
-pub fn get_wild_projection() -> Vec<SelectItem> {
-    vec![SelectItem::Wildcard]
+/// Get a row of NULL in the width of the rows of `tables`.
+fn null_row(tables: &[(String, String)], catalog: &Catalog) -> Result<MyRow> {
+    let mut width = 0;
+    for (table_name, _) in tables {
+        width += catalog.get_columns(table_name)?.len();
+    }
+    Ok(MyRow::from(vec![mysql::Value::NULL; width]))
 }
 
+/// Join the rows fetched from shards.
+///
+/// Without `join_info` the rows are simply concatenated,
+/// otherwise a hash join is performed on the equality keys of the join constraint:
+/// the right rows are used to build the hash table, and the left rows probe it.
+/// The unmatched rows of the preserved side of an outer join are padded with NULL columns.
 pub fn do_join(
     mut left_rows: Vec<MyRow>,
     mut right_rows: Vec<MyRow>,
-    join_operator: Option<JoinOperator>,
+    join_info: Option<JoinInfo>,
+    catalog: &Catalog,
 ) -> Result<Vec<MyRow>> {
+    let join_info = match join_info {
+        Some(join_info) => join_info,
+        None => {
+            left_rows.append(&mut right_rows);
+            return Ok(left_rows);
+        }
+    };
+    let (left_keys, right_keys) = extract_join_keys(&join_info, catalog)?;
+    let (keep_left, keep_right) = match join_info.operator {
+        JoinOperator::LeftOuter(_) => (true, false),
+        JoinOperator::RightOuter(_) => (false, true),
+        JoinOperator::FullOuter(_) => (true, true),
+        _ => (false, false),
+    };
+
+    // build
+    let mut hash_table: HashMap<Vec<HashValue>, Vec<usize>> = HashMap::new();
+    for (idx, row) in right_rows.iter().enumerate() {
+        if let Some(key) = hash_key(row, &right_keys)? {
+            hash_table.entry(key).or_default().push(idx);
+        }
+    }
+
+    // probe
     let mut final_ans = vec![];
-    if let Some(_join_condition) = join_operator {
-        if !left_rows.is_empty() && !right_rows.is_empty() {
-            for mut left_row in left_rows {
-                for right_row in right_rows.clone() {
-                    left_row.append(&mut right_row.clone());
+    let mut right_matched = vec![false; right_rows.len()];
+    let right_nulls = null_row(&join_info.right, catalog)?;
+    for left_row in left_rows {
+        let matched = match hash_key(&left_row, &left_keys)? {
+            Some(key) => hash_table.get(&key),
+            None => None,
+        };
+        match matched {
+            Some(matched) => {
+                for &idx in matched {
+                    right_matched[idx] = true;
+                    let mut row = left_row.clone();
+                    row.extend(right_rows[idx].iter().cloned());
+                    final_ans.push(row);
                 }
-                final_ans.push(left_row);
             }
+            None if keep_left => {
+                let mut row = left_row;
+                row.extend(right_nulls.iter().cloned());
+                final_ans.push(row);
+            }
+            None => {}
+        }
+    }
+    if keep_right {
+        let left_nulls = null_row(&join_info.left, catalog)?;
+        for (right_row, matched) in right_rows.into_iter().zip(right_matched) {
+            if !matched {
+                let mut row = left_nulls.clone();
+                row.extend(right_row.iter().cloned());
+                final_ans.push(row);
+            }
+        }
+    }
+    Ok(final_ans)
+}
+
+/// Split `expr` into the conjuncts of AND.
+pub fn split_conjuncts(expr: Expr, conjuncts: &mut Vec<Expr>) {
+    match expr {
+        Expr::BinaryOp {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            split_conjuncts(*left, conjuncts);
+            split_conjuncts(*right, conjuncts);
+        }
+        Expr::Nested(expr)
+            if matches!(
+                expr.as_ref(),
+                Expr::BinaryOp {
+                    op: BinaryOperator::And,
+                    ..
+                }
+            ) =>
+        {
+            split_conjuncts(*expr, conjuncts)
+        }
+        expr => conjuncts.push(expr),
+    }
+}
+
+/// Combine the conjuncts with AND.
+pub fn combine_conjuncts(conjuncts: Vec<Expr>) -> Option<Expr> {
+    conjuncts.into_iter().reduce(|left, right| Expr::BinaryOp {
+        left: Box::new(left),
+        op: BinaryOperator::And,
+        right: Box::new(right),
+    })
+}
+
+/// Whether `expr` is a column, double quoted strings are parsed as identifiers.
+pub fn is_column(expr: &Expr) -> bool {
+    match expr {
+        Expr::Identifier(ident) => ident.quote_style != Some('"'),
+        Expr::CompoundIdentifier(idents) => idents.len() == 2,
+        _ => false,
+    }
+}
+
+/// Get the positions of the tables whose columns are referred by `expr`.
+fn referenced_tables(
+    expr: &Expr,
+    tables: &[(String, String)],
+    catalog: &Catalog,
+) -> Result<Vec<usize>> {
+    let mut referenced = vec![];
+    visit_expr(expr, &mut |expr| {
+        if !is_column(expr) {
+            return Ok(true);
+        }
+        let matched = tables
+            .iter()
+            .enumerate()
+            .filter(|(_, (table_name, alias_name))| match expr {
+                Expr::Identifier(column) => {
+                    catalog.column_position(table_name, &column.value).is_some()
+                }
+                Expr::CompoundIdentifier(idents) => {
+                    idents[0].value == *alias_name || idents[0].value == *table_name
+                }
+                _ => unreachable!(),
+            })
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+        if matched.len() > 1 {
+            return Err(RuntimeError::UnsupportSql(format!(
+                "column {expr} is ambiguous"
+            )));
+        }
+        referenced.extend(matched);
+        Ok(false)
+    })?;
+    referenced.sort_unstable();
+    referenced.dedup();
+    Ok(referenced)
+}
+
+/// A condition in ON or WHERE, with the tables it refers to.
+struct Conjunct {
+    expr: Expr,
+    tables: Vec<usize>,
+    used: bool,
+}
+
+impl Conjunct {
+    fn new(expr: Expr, tables: &[(String, String)], catalog: &Catalog) -> Result<Self> {
+        Ok(Self {
+            tables: referenced_tables(&expr, tables, catalog)?,
+            expr,
+            used: false,
+        })
+    }
+
+    /// Whether it is `column = column` of table `a` and table `b`.
+    fn is_equi_join(
+        &self,
+        a: &[usize],
+        b: &[usize],
+        tables: &[(String, String)],
+        catalog: &Catalog,
+    ) -> bool {
+        let Expr::BinaryOp {
+            left,
+            op: BinaryOperator::Eq,
+            right,
+        } = &self.expr
+        else {
+            return false;
+        };
+        if !is_column(left) || !is_column(right) {
+            return false;
+        }
+        let (Ok(l), Ok(r)) = (
+            referenced_tables(left, tables, catalog),
+            referenced_tables(right, tables, catalog),
+        ) else {
+            return false;
+        };
+        match (l.as_slice(), r.as_slice()) {
+            ([l], [r]) => (a.contains(l) && b.contains(r)) || (a.contains(r) && b.contains(l)),
+            _ => false,
+        }
+    }
+}
+
+/// Take the conjuncts which are not used and satisfy `pred`.
+fn take_conjuncts<F>(conjuncts: &mut [Conjunct], mut pred: F) -> Vec<Expr>
+where
+    F: FnMut(&Conjunct) -> bool,
+{
+    conjuncts
+        .iter_mut()
+        .filter(|conjunct| !conjunct.used && pred(conjunct))
+        .map(|conjunct| {
+            conjunct.used = true;
+            conjunct.expr.clone()
+        })
+        .collect()
+}
+
+/// The co-located tables whose join is pushed down to shards as one query.
+#[derive(Debug, Clone)]
+pub struct JoinFragment {
+    /// positions of the tables in FROM clause
+    pub tables: Vec<usize>,
+    /// DataShard::Shard - the rows are split over all shards
+    ///
+    /// DataShard::Whole - all rows are in one shard
+    pub shard: DataShard,
+    pub from: TableWithJoins,
+    pub selection: Option<Expr>,
+    /// the columns fetched from shards
+    pub projection: Vec<SelectItem>,
+}
+
+/// The joins of fragments computed in control layer.
+#[derive(Debug, Clone)]
+pub struct JoinPlan {
+    /// each step joins the rows accumulated so far with the rows of the next fragment
+    pub steps: Vec<JoinInfo>,
+    /// the tables in FROM clause, the joined rows are reordered into their column order
+    pub tables: Vec<(String, String)>,
+    /// the conditions checked after all the joins
+    pub filter: Option<Expr>,
+    /// the columns fetched for each table, the joined rows are the concatenation of them
+    pub schema: Catalog,
+    /// the projection computed after the joins
+    pub projection: Vec<SelectItem>,
+    /// the semi-join reduction of the first join
+    pub semi_join: Option<SemiJoin>,
+}
+
+/// Plan the join of `tables` in FROM clause, `operators[i]` joins `tables[i + 1]`.
+///
+/// The co-located tables (by the sharding rules) are grouped into fragments, whose joins
+/// are pushed down to shards, then the fragments are joined in control layer from left to right.
+/// Inner joins may be reordered to group the co-located tables which are not adjacent,
+/// otherwise a fragment only contains adjacent tables.
+///
+/// The `JoinPlan` is `None` if the whole join is one fragment.
+pub fn plan_join(
+    tables: &[(String, String)],
+    operators: &[JoinOperator],
+    selection: Option<Expr>,
+    catalog: &Catalog,
+) -> Result<(Vec<JoinFragment>, Option<JoinPlan>)> {
+    let rules = sharding_rules();
+    let location = |table: usize| rules.table_location(&tables[table].0);
+    // the columns on which the rows of `a` and `b` are in the same shard
+    let join_key = |a: usize, b: usize| rules.join_key(&tables[a].0, &tables[b].0);
+    // whether `conjunct` joins `a` and `b` on the shard key, e.g. `user.uid = user_read.uid`
+    let join_on_key = |expr: &Expr, a: usize, b: usize| {
+        let Expr::BinaryOp {
+            left,
+            op: BinaryOperator::Eq,
+            right,
+        } = expr
+        else {
+            return false;
+        };
+        let Some((key_a, key_b)) = join_key(a, b) else {
+            return false;
+        };
+        let is_key = |expr: &Expr, table: usize, key: &str| {
+            let column = match expr {
+                Expr::Identifier(ident) => ident,
+                Expr::CompoundIdentifier(idents) if idents.len() == 2 => &idents[1],
+                _ => return false,
+            };
+            column.value == key
+                && referenced_tables(expr, tables, catalog).is_ok_and(|t| t == [table])
+        };
+        (is_key(left, a, &key_a) && is_key(right, b, &key_b))
+            || (is_key(left, b, &key_b) && is_key(right, a, &key_a))
+    };
+
+    let reorder = operators.iter().all(|operator| {
+        matches!(
+            operator,
+            JoinOperator::Inner(JoinConstraint::On(_) | JoinConstraint::None)
+                | JoinOperator::CrossJoin
+        )
+    });
+
+    let mut conjuncts = vec![];
+    if reorder {
+        // all conditions of inner joins are equivalent to WHERE
+        for operator in operators {
+            if let JoinOperator::Inner(JoinConstraint::On(expr)) = operator {
+                split_conjuncts(expr.clone(), &mut conjuncts);
+            }
+        }
+    }
+    if let Some(selection) = selection {
+        split_conjuncts(selection, &mut conjuncts);
+    }
+    let mut conjuncts = conjuncts
+        .into_iter()
+        .map(|expr| Conjunct::new(expr, tables, catalog))
+        .collect::<Result<Vec<_>>>()?;
+
+    // whether `table` can be joined with `group` in shards
+    let co_locate =
+        |group: &[usize], shard: &DataShard, table: usize| -> Result<Option<DataShard>> {
+            let linked = |other: usize| -> Result<bool> {
+                if reorder {
+                    return Ok(conjuncts
+                        .iter()
+                        .any(|conjunct| join_on_key(&conjunct.expr, other, table)));
+                }
+                // USING and NATURAL join on the columns of the same name
+                let same_key = join_key(other, table).filter(|(a, b)| a == b);
+                Ok(match &operators[table - 1] {
+                    JoinOperator::Inner(constraint)
+                    | JoinOperator::LeftOuter(constraint)
+                    | JoinOperator::RightOuter(constraint)
+                    | JoinOperator::FullOuter(constraint) => match constraint {
+                        JoinConstraint::On(expr) => {
+                            let mut on = vec![];
+                            split_conjuncts(expr.clone(), &mut on);
+                            // the condition must not refer to the tables out of the group
+                            let mut inside = true;
+                            for expr in on.iter() {
+                                inside &= referenced_tables(expr, tables, catalog)?
+                                    .iter()
+                                    .all(|t| *t == table || group.contains(t));
+                            }
+                            inside && on.iter().any(|expr| join_on_key(expr, other, table))
+                        }
+                        JoinConstraint::Using(columns) => {
+                            same_key.is_some_and(|(key, _)| columns.iter().any(|c| c.value == key))
+                        }
+                        JoinConstraint::Natural => same_key.is_some(),
+                        JoinConstraint::None => false,
+                    },
+                    _ => false,
+                })
+            };
+            Ok(match (shard, location(table)) {
+                (DataShard::Shard, DataShard::Shard) => {
+                    let mut co_located = false;
+                    for &other in group {
+                        co_located |= join_key(other, table).is_some() && linked(other)?;
+                    }
+                    co_located.then_some(DataShard::Shard)
+                }
+                // all the rows are in the same shard
+                (DataShard::Whole(a), DataShard::Whole(b)) if *a == b => Some(DataShard::Whole(b)),
+                _ => None,
+            })
+        };
+
+    // 1. group the co-located tables
+    let mut groups: Vec<(Vec<usize>, DataShard)> = vec![];
+    for table in 0..tables.len() {
+        let candidates = if reorder {
+            0..groups.len()
         } else {
-            final_ans = vec![];
+            groups.len().saturating_sub(1)..groups.len()
+        };
+        let mut found = None;
+        for idx in candidates {
+            if let Some(shard) = co_locate(&groups[idx].0, &groups[idx].1, table)? {
+                found = Some((idx, shard));
+                break;
+            }
         }
-    } else {
-        if !left_rows.is_empty() {
-            final_ans.append(&mut left_rows);
+        match found {
+            Some((idx, shard)) => {
+                groups[idx].0.push(table);
+                groups[idx].1 = shard;
+            }
+            None => groups.push((vec![table], location(table))),
         }
-        if !right_rows.is_empty() {
-            final_ans.append(&mut right_rows);
+    }
+
+    // 2. build the query of each fragment
+    let factor = |table: usize| {
+        let (table_name, alias_name) = tables[table].clone();
+        get_table_factor(table_name, Some(alias_name))
+            .remove(0)
+            .relation
+    };
+    // WHERE can be pushed down unless a fragment is outer joined in control layer
+    let push_selection = groups.iter().skip(1).all(|(group, _)| {
+        matches!(
+            operators[group[0] - 1],
+            JoinOperator::Inner(_) | JoinOperator::CrossJoin
+        )
+    });
+    let mut fragments = vec![];
+    for (idx, (group, shard)) in groups.into_iter().enumerate() {
+        let mut placed = vec![group[0]];
+        let mut joins = vec![];
+        for &table in group[1..].iter() {
+            let join_operator = if reorder {
+                let on = take_conjuncts(&mut conjuncts, |conjunct| {
+                    conjunct.tables.len() > 1
+                        && conjunct.tables.contains(&table)
+                        && conjunct
+                            .tables
+                            .iter()
+                            .all(|t| *t == table || placed.contains(t))
+                });
+                combine_conjuncts(on).map_or(JoinOperator::CrossJoin, |on| {
+                    JoinOperator::Inner(JoinConstraint::On(on))
+                })
+            } else {
+                operators[table - 1].clone()
+            };
+            placed.push(table);
+            joins.push(Join {
+                relation: factor(table),
+                join_operator,
+            });
         }
+        let selection = if push_selection {
+            combine_conjuncts(take_conjuncts(&mut conjuncts, |conjunct| {
+                // the conditions without any column are checked in the first fragment
+                (!conjunct.tables.is_empty() || idx == 0)
+                    && conjunct.tables.iter().all(|t| group.contains(t))
+            }))
+        } else {
+            None
+        };
+        fragments.push(JoinFragment {
+            from: TableWithJoins {
+                relation: factor(group[0]),
+                joins,
+            },
+            tables: group,
+            shard,
+            selection,
+            projection: get_wild_projection(),
+        });
     }
-    Ok(final_ans)
+    if fragments.len() == 1 {
+        return Ok((fragments, None));
+    }
+
+    // 3. join the fragments in control layer
+    let mut steps = vec![];
+    let mut joined = fragments[0].tables.clone();
+    for fragment in fragments[1..].iter() {
+        let operator = if reorder {
+            let on = take_conjuncts(&mut conjuncts, |conjunct| {
+                conjunct.is_equi_join(&joined, &fragment.tables, tables, catalog)
+            });
+            combine_conjuncts(on).map_or(JoinOperator::CrossJoin, |on| {
+                JoinOperator::Inner(JoinConstraint::On(on))
+            })
+        } else {
+            operators[fragment.tables[0] - 1].clone()
+        };
+        steps.push(JoinInfo {
+            left: joined.iter().map(|t| tables[*t].clone()).collect(),
+            right: fragment.tables.iter().map(|t| tables[*t].clone()).collect(),
+            operator,
+        });
+        joined.extend(fragment.tables.iter().cloned());
+    }
+    let filter = combine_conjuncts(take_conjuncts(&mut conjuncts, |_| true));
+    let join_plan = JoinPlan {
+        steps,
+        tables: tables.to_vec(),
+        filter,
+        schema: catalog.clone(),
+        projection: get_wild_projection(),
+        semi_join: None,
+    };
+    Ok((fragments, Some(join_plan)))
 }
 
-pub fn do_order_by_and_limit(
-    results: Vec<Vec<mysql::Value>>,
-    order_by_and_limit: Option<(Vec<OrderByExpr>, Option<Expr>)>,
+/// Get the `(table name, column)` of the columns of `tables` referred by `expr`.
+fn referenced_columns(
+    expr: &Expr,
+    tables: &[(String, String)],
+    catalog: &Catalog,
+) -> Result<Vec<(String, String)>> {
+    let mut columns = vec![];
+    visit_expr(expr, &mut |expr| {
+        let (qualifier, column) = match expr {
+            Expr::Identifier(column) if is_column(expr) => (None, &column.value),
+            Expr::CompoundIdentifier(idents) if idents.len() == 2 => {
+                (Some(&idents[0].value), &idents[1].value)
+            }
+            _ => return Ok(true),
+        };
+        for (table_name, alias_name) in tables {
+            let matched = qualifier.is_none_or(|q| q == alias_name || q == table_name);
+            if matched && catalog.column_position(table_name, column).is_some() {
+                columns.push((table_name.clone(), column.clone()));
+            }
+        }
+        Ok(false)
+    })?;
+    Ok(columns)
+}
+
+/// Fetch only the columns needed by `projection`, `order_by` and the joins and filter of
+/// `join_plan` from the fragments, `projection` is computed after the joins.
+pub fn push_down_projection(
+    fragments: &mut [JoinFragment],
+    join_plan: &mut JoinPlan,
+    projection: &[SelectItem],
+    order_by: &[OrderByExpr],
+    catalog: &Catalog,
+) -> Result<()> {
+    let tables = &join_plan.tables;
+    let mut needed: HashMap<String, HashSet<String>> = tables
+        .iter()
+        .map(|(table_name, _)| (table_name.clone(), HashSet::new()))
+        .collect();
+    let mut exprs = vec![];
+    for item in projection {
+        match item {
+            SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                exprs.push(expr.clone())
+            }
+            SelectItem::QualifiedWildcard(name) => {
+                let name = name.to_string();
+                for (table_name, alias_name) in tables {
+                    if *alias_name == name || *table_name == name {
+                        needed
+                            .entry(table_name.clone())
+                            .or_default()
+                            .extend(catalog.get_column_names(table_name)?);
+                    }
+                }
+            }
+            SelectItem::Wildcard => {
+                for (table_name, _) in tables {
+                    needed
+                        .entry(table_name.clone())
+                        .or_default()
+                        .extend(catalog.get_column_names(table_name)?);
+                }
+            }
+        }
+    }
+    exprs.extend(order_by.iter().map(|order_by| order_by.expr.clone()));
+    exprs.extend(join_plan.filter.clone());
+    for JoinInfo {
+        left,
+        right,
+        operator,
+    } in join_plan.steps.iter()
+    {
+        let constraint = match operator {
+            JoinOperator::Inner(constraint)
+            | JoinOperator::LeftOuter(constraint)
+            | JoinOperator::RightOuter(constraint)
+            | JoinOperator::FullOuter(constraint) => constraint,
+            _ => continue,
+        };
+        // USING and NATURAL join on the columns of the same name on both sides
+        let shared = match constraint {
+            JoinConstraint::On(expr) => {
+                exprs.push(expr.clone());
+                continue;
+            }
+            JoinConstraint::Using(columns) => columns
+                .iter()
+                .map(|column| column.value.clone())
+                .collect::<HashSet<_>>(),
+            JoinConstraint::Natural => {
+                let mut right_columns = HashSet::new();
+                for (table_name, _) in right {
+                    right_columns.extend(catalog.get_column_names(table_name)?);
+                }
+                let mut shared = HashSet::new();
+                for (table_name, _) in left {
+                    for column in catalog.get_column_names(table_name)? {
+                        if right_columns.contains(&column) {
+                            shared.insert(column);
+                        }
+                    }
+                }
+                shared
+            }
+            JoinConstraint::None => continue,
+        };
+        for (table_name, _) in left.iter().chain(right.iter()) {
+            for column in shared.iter() {
+                if catalog.column_position(table_name, column).is_some() {
+                    needed
+                        .entry(table_name.clone())
+                        .or_default()
+                        .insert(column.clone());
+                }
+            }
+        }
+    }
+    for expr in exprs.iter() {
+        for (table_name, column) in referenced_columns(expr, tables, catalog)? {
+            needed.entry(table_name).or_default().insert(column);
+        }
+    }
+    // a fragment fetches at least one column, otherwise its rows are lost
+    for fragment in fragments.iter() {
+        let empty = fragment
+            .tables
+            .iter()
+            .all(|table| needed[&tables[*table].0].is_empty());
+        if empty {
+            let table_name = &tables[fragment.tables[0]].0;
+            if let Some(column) = catalog.get_columns(table_name)?.first() {
+                needed
+                    .entry(table_name.clone())
+                    .or_default()
+                    .insert(column.name.clone());
+            }
+        }
+    }
+
+    let schema = catalog.project(&needed);
+    for fragment in fragments.iter_mut() {
+        let mut columns = vec![];
+        for table in fragment.tables.iter() {
+            let (table_name, alias_name) = &tables[*table];
+            for column in schema.get_columns(table_name)? {
+                columns.push(SelectItem::UnnamedExpr(Expr::CompoundIdentifier(vec![
+                    Ident::new(alias_name),
+                    Ident::new(&column.name),
+                ])));
+            }
+        }
+        fragment.projection = columns;
+    }
+    join_plan.schema = schema;
+    join_plan.projection = projection.to_vec();
+    Ok(())
+}
+
+/// Join the rows of the fragments by `join_plan`, `branches[i]` are the rows of fragment `i`.
+pub fn do_join_plan(branches: Vec<Vec<MyRow>>, join_plan: &JoinPlan) -> Result<Vec<MyRow>> {
+    let catalog = &join_plan.schema;
+    let mut branches = branches.into_iter();
+    let mut rows = branches.next().unwrap_or_default();
+    for (step, right_rows) in join_plan.steps.iter().zip(branches) {
+        rows = do_join(rows, right_rows, Some(step.clone()), catalog)?;
+    }
+
+    // the columns are in the order of fragments, reorder them as FROM clause
+    if let Some(JoinInfo { left, right, .. }) = join_plan.steps.last() {
+        let joined = [left.as_slice(), right.as_slice()].concat();
+        if joined != join_plan.tables {
+            let mut ranges = HashMap::new();
+            let mut offset = 0;
+            for table in joined.iter() {
+                let len = catalog.get_columns(&table.0)?.len();
+                ranges.insert(table, offset..offset + len);
+                offset += len;
+            }
+            let ranges = join_plan
+                .tables
+                .iter()
+                .map(|table| ranges[table].clone())
+                .collect::<Vec<_>>();
+            rows = rows
+                .into_iter()
+                .map(|row| {
+                    let mut new_row = MyRow::from(vec![]);
+                    for range in ranges.iter() {
+                        new_row.extend(row[range.clone()].iter().cloned());
+                    }
+                    new_row
+                })
+                .collect();
+        }
+    }
+
+    if let Some(filter) = &join_plan.filter {
+        let positions = resolve_columns(filter, |expr| {
+            resolve_column(expr, &join_plan.tables, catalog)
+        })?;
+        let mut filtered = vec![];
+        for row in rows {
+            let lookup = |expr: &Expr| {
+                row.get(*positions.get(expr)?).map(|value| {
+                    value
+                        .as_ref()
+                        .map_or(mysql::Value::NULL, |value| value.value().clone())
+                })
+            };
+            if is_true(&eval_expr(filter, &lookup)?) {
+                filtered.push(row);
+            }
+        }
+        rows = filtered;
+    }
+    Ok(rows)
+}
+
+/// Compute the projection of the joined rows.
+pub fn project_join(
+    rows: Vec<Vec<mysql::Value>>,
+    join_plan: &JoinPlan,
+) -> Result<Vec<Vec<mysql::Value>>> {
+    /// The columns of a table, or an expression, in the projection.
+    enum Output<'a> {
+        Columns(Range<usize>),
+        Expr(&'a Expr),
+    }
+
+    let JoinPlan {
+        tables,
+        schema,
+        projection,
+        ..
+    } = join_plan;
+    let mut ranges = vec![];
+    let mut offset = 0;
+    for (table_name, _) in tables {
+        let len = schema.get_columns(table_name)?.len();
+        ranges.push(offset..offset + len);
+        offset += len;
+    }
+    let mut outputs = vec![];
+    for item in projection {
+        match item {
+            SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                outputs.push(Output::Expr(expr))
+            }
+            SelectItem::QualifiedWildcard(name) => {
+                let name = name.to_string();
+                let matched = tables
+                    .iter()
+                    .zip(ranges.iter())
+                    .filter(|((table_name, alias_name), _)| {
+                        *alias_name == name || *table_name == name
+                    })
+                    .map(|(_, range)| Output::Columns(range.clone()))
+                    .collect::<Vec<_>>();
+                if matched.is_empty() {
+                    return Err(RuntimeError::UnsupportSql(format!("unknown table {name}")));
+                }
+                outputs.extend(matched);
+            }
+            SelectItem::Wildcard => {
+                outputs.extend(ranges.iter().map(|range| Output::Columns(range.clone())))
+            }
+        }
+    }
+
+    let mut positions = HashMap::new();
+    for output in outputs.iter() {
+        if let Output::Expr(expr) = output {
+            positions.extend(resolve_columns(expr, |expr| {
+                resolve_column(expr, tables, schema)
+            })?);
+        }
+    }
+    let mut results = Vec::with_capacity(rows.len());
+    for row in rows {
+        let lookup = |expr: &Expr| row.get(*positions.get(expr)?).cloned();
+        let mut new_row = vec![];
+        for output in outputs.iter() {
+            match output {
+                Output::Columns(range) => new_row.extend(row[range.clone()].iter().cloned()),
+                Output::Expr(expr) => new_row.push(eval_expr(expr, &lookup)?),
+            }
+        }
+        results.push(new_row);
+    }
+    Ok(results)
+}
+
+/// A resolved `ORDER BY` key.
+#[derive(Debug, Clone, Copy)]
+pub struct SortKey {
+    /// position of the key in the result rows
+    pub index: usize,
+    pub asc: bool,
+    pub nulls_first: bool,
+}
+
+/// Resolve the position of an `ORDER BY` expression in the result rows.
+///
+/// Rows of a join computed in control layer contain all the columns of the joined tables,
+/// so the key is resolved by `join_plan`, otherwise it is looked up in `header`.
+pub fn resolve_sort_key(
+    expr: &Expr,
+    header: &[String],
+    join_plan: Option<&JoinPlan>,
+) -> Result<usize> {
+    if let Some(JoinPlan { tables, schema, .. }) = join_plan {
+        return resolve_column(expr, tables, schema)?.ok_or_else(|| {
+            RuntimeError::UnsupportSql(format!("cannot resolve ORDER BY {expr} in join"))
+        });
+    }
+
+    // ORDER BY 1
+    if let Expr::Value(Value::Number(number, _)) = expr {
+        return number
+            .parse::<usize>()
+            .ok()
+            .filter(|n| (1..=header.len()).contains(n))
+            .map(|n| n - 1)
+            .ok_or_else(|| RuntimeError::UnsupportSql(format!("ORDER BY {number} out of range")));
+    }
+
+    let expr_str = expr.to_string();
+    if let Some(index) = header
+        .iter()
+        .position(|h| h.eq_ignore_ascii_case(&expr_str))
+    {
+        return Ok(index);
+    }
+    // the header may be qualified (`a.title`) or not (`title`, from `SELECT *`)
+    let column = match expr {
+        Expr::Identifier(ident) => &ident.value,
+        Expr::CompoundIdentifier(idents) => &idents.last().unwrap().value,
+        _ => {
+            return Err(RuntimeError::UnsupportSql(format!(
+                "ORDER BY {expr} must be in the select list"
+            )))
+        }
+    };
+    let matched = header
+        .iter()
+        .enumerate()
+        .filter(|(_, h)| {
+            let name = h.rsplit('.').next().unwrap_or(h);
+            name.eq_ignore_ascii_case(column)
+        })
+        .map(|(index, _)| index)
+        .collect::<Vec<_>>();
+    match matched.as_slice() {
+        [index] => Ok(*index),
+        [] => Err(RuntimeError::UnsupportSql(format!(
+            "ORDER BY {expr} must be in the select list"
+        ))),
+        _ => Err(RuntimeError::UnsupportSql(format!(
+            "ORDER BY {expr} is ambiguous"
+        ))),
+    }
+}
+
+pub fn resolve_sort_keys(
+    order_by: &[OrderByExpr],
+    header: &[String],
+    join_plan: Option<&JoinPlan>,
+) -> Result<Vec<SortKey>> {
+    order_by
+        .iter()
+        .map(
+            |OrderByExpr {
+                 expr,
+                 asc,
+                 nulls_first,
+             }| {
+                let asc = asc.unwrap_or(true);
+                Ok(SortKey {
+                    index: resolve_sort_key(expr, header, join_plan)?,
+                    asc,
+                    // same as mysql, NULL is smaller than any other values
+                    nulls_first: nulls_first.unwrap_or(asc),
+                })
+            },
+        )
+        .collect()
+}
+
+/// Compare two non-NULL values.
+///
+/// Numbers are compared by value, strings are compared case-insensitively,
+/// the same order as the shards sort their rows.
+pub fn compare_value(a: &mysql::Value, b: &mysql::Value) -> Ordering {
+    use mysql::Value::*;
+    fn as_f64(v: &mysql::Value) -> Option<f64> {
+        match v {
+            Int(v) => Some(*v as f64),
+            UInt(v) => Some(*v as f64),
+            Float(v) => Some(*v as f64),
+            Double(v) => Some(*v),
+            Bytes(bytes) => std::str::from_utf8(bytes).ok()?.trim().parse().ok(),
+            _ => None,
+        }
+    }
+    match (a, b) {
+        (Int(a), Int(b)) => a.cmp(b),
+        (UInt(a), UInt(b)) => a.cmp(b),
+        (Bytes(a), Bytes(b)) => collate(a).cmp(&collate(b)),
+        (Date(..), Date(..)) => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+        (Time(neg_a, ..), Time(neg_b, ..)) => match (neg_a, neg_b) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+            (true, true) => b.partial_cmp(a).unwrap_or(Ordering::Equal),
+        },
+        _ => match (as_f64(a), as_f64(b)) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            _ => a.partial_cmp(b).unwrap_or(Ordering::Equal),
+        },
+    }
+}
+
+/// Compare two rows by the `ORDER BY` keys.
+pub fn compare_rows(a: &[mysql::Value], b: &[mysql::Value], keys: &[SortKey]) -> Ordering {
+    for key in keys {
+        let (x, y) = (&a[key.index], &b[key.index]);
+        let ord = match (*x == mysql::Value::NULL, *y == mysql::Value::NULL) {
+            (true, true) => Ordering::Equal,
+            (true, false) if key.nulls_first => Ordering::Less,
+            (true, false) => Ordering::Greater,
+            (false, true) if key.nulls_first => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            (false, false) if key.asc => compare_value(x, y),
+            (false, false) => compare_value(y, x),
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    Ordering::Equal
+}
+
+/// A row ordered by the sort keys, the rows of equal keys are ordered by `seq`.
+struct SortedRow<'a> {
+    row: Vec<mysql::Value>,
+    seq: usize,
+    keys: &'a [SortKey],
+}
+
+impl PartialEq for SortedRow<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SortedRow<'_> {}
+
+impl PartialOrd for SortedRow<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SortedRow<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_rows(&self.row, &other.row, self.keys).then(self.seq.cmp(&other.seq))
+    }
+}
+
+/// Merge the runs which have already been sorted by `keys`, stop after `limit` rows.
+fn merge_sorted_runs(
+    runs: Vec<Vec<Vec<mysql::Value>>>,
+    keys: &[SortKey],
+    limit: usize,
 ) -> Vec<Vec<mysql::Value>> {
-    if let Some((_order_by, Some(limit))) = order_by_and_limit {
-        let return_number = match limit {
-            Expr::Value(Value::Number(number, _)) => number.parse::<usize>().unwrap(),
-            _ => results.len(),
+    let mut runs = runs.into_iter().map(Vec::into_iter).collect::<Vec<_>>();
+    // the min-heap of the first rows of runs, `seq` is the index of the run
+    let mut heap = BinaryHeap::with_capacity(runs.len());
+    for (seq, run) in runs.iter_mut().enumerate() {
+        if let Some(row) = run.next() {
+            heap.push(Reverse(SortedRow { row, seq, keys }));
+        }
+    }
+    let mut results = vec![];
+    while results.len() < limit {
+        let Some(Reverse(SortedRow { row, seq, .. })) = heap.pop() else {
+            break;
+        };
+        if let Some(next) = runs[seq].next() {
+            heap.push(Reverse(SortedRow {
+                row: next,
+                seq,
+                keys,
+            }));
+        }
+        results.push(row);
+    }
+    results
+}
+
+/// Get the first `limit` rows sorted by `keys`, at most `limit` rows are kept in a max-heap.
+fn top_rows(
+    rows: impl Iterator<Item = Vec<mysql::Value>>,
+    keys: &[SortKey],
+    limit: usize,
+) -> Vec<Vec<mysql::Value>> {
+    let mut heap = BinaryHeap::new();
+    for (seq, row) in rows.enumerate() {
+        let row = SortedRow { row, seq, keys };
+        if heap.len() < limit {
+            heap.push(row);
+        } else if let Some(mut last) = heap.peek_mut() {
+            if row < *last {
+                *last = row;
+            }
+        }
+    }
+    heap.into_sorted_vec()
+        .into_iter()
+        .map(|sorted| sorted.row)
+        .collect()
+}
+
+/// Get the number of LIMIT or OFFSET.
+fn get_count(clause: &str, expr: &Expr) -> Result<usize> {
+    match expr {
+        Expr::Value(Value::Number(number, _)) => number
+            .parse::<usize>()
+            .map_err(|_| RuntimeError::UnsupportSql(format!("invalid {clause} {number}"))),
+        expr => Err(RuntimeError::UnsupportSql(format!(
+            "invalid {clause} {expr}"
+        ))),
+    }
+}
+
+/// ORDER BY, LIMIT and OFFSET of a query, applied in control to the merged rows.
+#[derive(Debug, Clone, Default)]
+pub struct TopN {
+    pub order_by: Vec<OrderByExpr>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+impl TopN {
+    pub fn from_query(query: &Query) -> Result<Self> {
+        let limit = match &query.limit {
+            Some(limit) => Some(get_count("LIMIT", limit)?),
+            None => None,
+        };
+        let offset = match &query.offset {
+            Some(offset) => get_count("OFFSET", &offset.value)?,
+            None => 0,
+        };
+        Ok(Self {
+            order_by: query.order_by.clone(),
+            limit,
+            offset,
+        })
+    }
+
+    /// The number of rows each shard returns, which contain the rows of the result.
+    pub fn shard_limit(&self) -> Option<usize> {
+        self.limit.map(|limit| limit.saturating_add(self.offset))
+    }
+}
+
+/// Remove the duplicate rows across the runs, keeping the first one of each.
+///
+/// The order of each run is kept, so the sorted runs are still sorted.
+pub fn distinct_runs(runs: Vec<Vec<Vec<mysql::Value>>>) -> Vec<Vec<Vec<mysql::Value>>> {
+    let mut seen = HashSet::new();
+    runs.into_iter()
+        .map(|run| {
+            run.into_iter()
+                .filter(|row| seen.insert(row.iter().cloned().map(HashValue).collect::<Vec<_>>()))
+                .collect()
+        })
+        .collect()
+}
+
+/// Sort and truncate the rows from shards.
+///
+/// - `runs`: the rows returned by each shard
+/// - `presorted`: whether the shards have already sorted their rows by `ORDER BY`,
+///   if so the runs are merged instead of re-sorted.
+pub fn do_order_by_and_limit(
+    runs: Vec<Vec<Vec<mysql::Value>>>,
+    presorted: bool,
+    top_n: Option<TopN>,
+    header: &[String],
+    join_plan: Option<&JoinPlan>,
+) -> Result<Vec<Vec<mysql::Value>>> {
+    let Some(top_n) = top_n else {
+        return Ok(runs.into_iter().flatten().collect());
+    };
+    let limit = top_n.limit.unwrap_or(usize::MAX);
+    if top_n.order_by.is_empty() {
+        return Ok(runs
+            .into_iter()
+            .flatten()
+            .skip(top_n.offset)
+            .take(limit)
+            .collect());
+    }
+
+    let keys = resolve_sort_keys(&top_n.order_by, header, join_plan)?;
+    let results = match top_n.shard_limit() {
+        Some(limit) if presorted => merge_sorted_runs(runs, &keys, limit),
+        None if presorted => merge_sorted_runs(runs, &keys, usize::MAX),
+        Some(limit) => top_rows(runs.into_iter().flatten(), &keys, limit),
+        None => {
+            let mut results = runs.into_iter().flatten().collect::<Vec<_>>();
+            results.sort_by(|a, b| compare_rows(a, b, &keys));
+            results
+        }
+    };
+    Ok(results.into_iter().skip(top_n.offset).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        do_join, do_join_plan, do_order_by_and_limit, merge_sorted_runs, parse_sql, plan_join,
+        project_join, push_down_projection, reslove_from, top_rows, JoinFragment, JoinInfo,
+        SortKey, TopN,
+    };
+    use crate::Catalog;
+    use common::{DataShard, MyRow};
+    use itertools::Itertools;
+    use mysql::Value;
+    use sqlparser::ast::{
+        BinaryOperator, Expr, Ident, JoinConstraint, JoinOperator, SelectItem, SetExpr, Statement,
+    };
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    /// Build a row of `table` whose column `key` is `value`, other columns are NULL.
+    fn mock_row(table: &str, key: &str, value: &str) -> MyRow {
+        let columns = Catalog::mock().get_column_names(table).unwrap();
+        let row = columns
+            .iter()
+            .map(|c| {
+                if c == key {
+                    Value::Bytes(value.as_bytes().to_vec())
+                } else {
+                    Value::NULL
+                }
+            })
+            .collect::<Vec<_>>();
+        row.into()
+    }
+
+    fn mock_join_operator(sql: &str) -> JoinOperator {
+        let ast = Parser::parse_sql(&GenericDialect {}, sql).unwrap();
+        if let Statement::Query(query) = &ast[0] {
+            if let SetExpr::Select(select) = query.body.as_ref() {
+                return select.from[0].joins[0].join_operator.clone();
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn test_hash_join() {
+        let join_info = JoinInfo {
+            left: vec![("user".to_owned(), "a".to_owned())],
+            right: vec![("article".to_owned(), "b".to_owned())],
+            operator: mock_join_operator(
+                "SELECT * FROM user AS a INNER JOIN article AS b ON a.uid = b.aid",
+            ),
+        };
+        let left = vec![
+            mock_row("user", "uid", "1"),
+            mock_row("user", "uid", "2"),
+            mock_row("user", "uid", "3"),
+        ];
+        let right = vec![
+            mock_row("article", "aid", "2"),
+            mock_row("article", "aid", "3"),
+            mock_row("article", "aid", "3"),
+            mock_row("article", "aid", "4"),
+        ];
+        let catalog = Catalog::mock();
+        let user_len = catalog.get_columns("user").unwrap().len();
+        let article_len = catalog.get_columns("article").unwrap().len();
+        let uid_idx = catalog.column_position("user", "uid").unwrap();
+        let aid_idx = catalog.column_position("article", "aid").unwrap();
+
+        let rows = do_join(left, right, Some(join_info), &catalog).unwrap();
+        assert_eq!(rows.len(), 3);
+        for row in rows {
+            assert_eq!(row.len(), user_len + article_len);
+            assert_eq!(
+                row.get_row_str(uid_idx).unwrap(),
+                row.get_row_str(user_len + aid_idx).unwrap()
+            );
+        }
+
+        // the keys are compared case-insensitively as the collation of shards
+        let join_info = JoinInfo {
+            left: vec![("user".to_owned(), "a".to_owned())],
+            right: vec![("article".to_owned(), "b".to_owned())],
+            operator: mock_join_operator(
+                "SELECT * FROM user AS a INNER JOIN article AS b ON a.uid = b.aid",
+            ),
+        };
+        let left = vec![mock_row("user", "uid", "Beijing")];
+        let right = vec![
+            mock_row("article", "aid", "beijing"),
+            mock_row("article", "aid", "BEIJING"),
+            mock_row("article", "aid", "Hong Kong"),
+        ];
+        let rows = do_join(left, right, Some(join_info), &catalog).unwrap();
+        assert_eq!(rows.len(), 2);
+    }
+
+    #[test]
+    fn test_outer_join() {
+        let join_info = |sql: &str| JoinInfo {
+            left: vec![("user".to_owned(), "a".to_owned())],
+            right: vec![("article".to_owned(), "b".to_owned())],
+            operator: mock_join_operator(sql),
+        };
+        let left = vec![mock_row("user", "uid", "1"), mock_row("user", "uid", "2")];
+        let right = vec![
+            mock_row("article", "aid", "2"),
+            mock_row("article", "aid", "3"),
+        ];
+        let catalog = Catalog::mock();
+        let user_len = catalog.get_columns("user").unwrap().len();
+        let uid_idx = catalog.column_position("user", "uid").unwrap();
+        let aid_idx = catalog.column_position("article", "aid").unwrap();
+        // the (uid, aid) of the joined rows, NULL columns are padded for the unmatched rows
+        let keys = |rows: Vec<MyRow>| {
+            rows.iter()
+                .map(|row| {
+                    let row = row.get_raw_value().unwrap();
+                    (row[uid_idx].clone(), row[user_len + aid_idx].clone())
+                })
+                .collect::<Vec<_>>()
+        };
+        let key = |uid: Option<&str>, aid: Option<&str>| {
+            let value = |v: Option<&str>| v.map_or(Value::NULL, |v| Value::Bytes(v.into()));
+            (value(uid), value(aid))
+        };
+
+        let rows = do_join(
+            left.clone(),
+            right.clone(),
+            Some(join_info(
+                "SELECT * FROM user AS a LEFT JOIN article AS b ON a.uid = b.aid",
+            )),
+            &catalog,
+        )
+        .unwrap();
+        assert_eq!(
+            keys(rows),
+            [key(Some("1"), None), key(Some("2"), Some("2"))]
+        );
+
+        let rows = do_join(
+            left.clone(),
+            right.clone(),
+            Some(join_info(
+                "SELECT * FROM user AS a RIGHT JOIN article AS b ON a.uid = b.aid",
+            )),
+            &catalog,
+        )
+        .unwrap();
+        assert_eq!(
+            keys(rows),
+            [key(Some("2"), Some("2")), key(None, Some("3"))]
+        );
+
+        let rows = do_join(
+            left,
+            right,
+            Some(join_info(
+                "SELECT * FROM user AS a FULL JOIN article AS b ON a.uid = b.aid",
+            )),
+            &catalog,
+        )
+        .unwrap();
+        assert_eq!(
+            keys(rows),
+            [
+                key(Some("1"), None),
+                key(Some("2"), Some("2")),
+                key(None, Some("3"))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_join_without_condition() {
+        let catalog = Catalog::mock();
+        let left = vec![mock_row("user", "uid", "1")];
+        let right = vec![mock_row("user", "uid", "2")];
+        let rows = do_join(left, right, None, &catalog).unwrap();
+        assert_eq!(rows.len(), 2);
+
+        let join_info = JoinInfo {
+            left: vec![("user".to_owned(), "a".to_owned())],
+            right: vec![("article".to_owned(), "b".to_owned())],
+            operator: mock_join_operator(
+                "SELECT * FROM user AS a JOIN article AS b ON a.uid > b.aid",
+            ),
+        };
+        let left = vec![mock_row("user", "uid", "1")];
+        let right = vec![mock_row("article", "aid", "2")];
+        assert!(do_join(left, right, Some(join_info), &catalog).is_err());
+    }
+
+    type MockFrom = (Vec<(String, String)>, Vec<JoinOperator>, Option<Expr>);
+
+    fn mock_from(sql: &str) -> MockFrom {
+        let ast = Parser::parse_sql(&GenericDialect {}, sql).unwrap();
+        if let Statement::Query(query) = &ast[0] {
+            if let SetExpr::Select(select) = query.body.as_ref() {
+                let (tables, operators) = reslove_from(select.from.clone()).unwrap();
+                return (tables, operators, select.selection.clone());
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn test_plan_join() {
+        let fragment_sql = |sql: &str| {
+            let (tables, operators, selection) = mock_from(sql);
+            let (fragments, join_plan) =
+                plan_join(&tables, &operators, selection, &Catalog::mock()).unwrap();
+            let fragments = fragments
+                .into_iter()
+                .map(|fragment| {
+                    let selection = fragment
+                        .selection
+                        .map(|selection| format!(" WHERE {selection}"))
+                        .unwrap_or_default();
+                    (format!("{}{selection}", fragment.from), fragment.shard)
+                })
+                .collect::<Vec<_>>();
+            (fragments, join_plan)
+        };
+
+        // user and user_read are co-located, article is joined in control layer
+        let (fragments, join_plan) = fragment_sql(
+            "SELECT * FROM user AS u JOIN user_read AS r ON u.uid = r.uid \
+            JOIN article AS a ON r.aid = a.aid \
+            WHERE u.region = \"Beijing\" AND a.category = \"science\" AND u.uid < a.aid",
+        );
+        assert_eq!(fragments.len(), 2);
+        assert_eq!(
+            fragments[0].0,
+            "user AS u JOIN user_read AS r ON u.uid = r.uid WHERE u.region = \"Beijing\""
+        );
+        assert!(matches!(fragments[0].1, DataShard::Shard));
+        assert_eq!(
+            fragments[1].0,
+            "article AS a WHERE a.category = \"science\""
+        );
+        assert!(matches!(fragments[1].1, DataShard::Whole(1)));
+        let join_plan = join_plan.unwrap();
+        assert_eq!(join_plan.steps.len(), 1);
+        assert_eq!(join_plan.steps[0].left.len(), 2);
+        assert!(matches!(
+            &join_plan.steps[0].operator,
+            JoinOperator::Inner(JoinConstraint::On(on)) if on.to_string() == "r.aid = a.aid"
+        ));
+        assert_eq!(join_plan.filter.unwrap().to_string(), "u.uid < a.aid");
+
+        // the co-located tables are grouped even if they are not adjacent
+        let (fragments, join_plan) = fragment_sql(
+            "SELECT * FROM user AS u JOIN article AS a JOIN user_read AS r \
+            ON u.uid = r.uid AND r.aid = a.aid",
+        );
+        assert_eq!(fragments.len(), 2);
+        assert_eq!(
+            fragments[0].0,
+            "user AS u JOIN user_read AS r ON u.uid = r.uid"
+        );
+        assert_eq!(fragments[1].0, "article AS a");
+        assert_eq!(join_plan.unwrap().tables[1].1, "a");
+
+        // the outer join is not reordered
+        let (fragments, _) = fragment_sql(
+            "SELECT * FROM user AS u LEFT JOIN article AS a ON u.uid = a.aid \
+            JOIN user_read AS r ON u.uid = r.uid",
+        );
+        assert_eq!(fragments.len(), 3);
+
+        // all the rows are in shard2
+        let (fragments, join_plan) = fragment_sql(
+            "SELECT * FROM article AS a JOIN be_read AS b ON a.aid = b.aid WHERE b.readNum > 10",
+        );
+        assert_eq!(fragments.len(), 1);
+        assert!(matches!(fragments[0].1, DataShard::Whole(1)));
+        assert!(join_plan.is_none());
+    }
+
+    #[test]
+    fn test_do_join_plan() {
+        let (tables, operators, selection) = mock_from(
+            "SELECT * FROM user AS u JOIN article AS a JOIN user_read AS r \
+            ON u.uid = r.uid AND r.aid = a.aid WHERE u.uid <> a.aid",
+        );
+        let catalog = Catalog::mock();
+        let (_, join_plan) = plan_join(&tables, &operators, selection, &catalog).unwrap();
+        let join_plan = join_plan.unwrap();
+
+        let position = |table: &str, column: &str| catalog.column_position(table, column).unwrap();
+        let user_len = catalog.get_columns("user").unwrap().len();
+        let article_len = catalog.get_columns("article").unwrap().len();
+        // rows of `user JOIN user_read` in shards
+        let mut fragment = vec![];
+        for (uid, aid) in [("1", "1"), ("1", "2"), ("2", "3")] {
+            let mut row = mock_row("user", "uid", uid);
+            let mut read = mock_row("user_read", "aid", aid);
+            read[position("user_read", "uid")] = row[position("user", "uid")].clone();
+            row.extend(read.iter().cloned());
+            fragment.push(row);
+        }
+        let articles = vec![
+            mock_row("article", "aid", "1"),
+            mock_row("article", "aid", "2"),
+        ];
+
+        let rows = do_join_plan(vec![fragment, articles], &join_plan).unwrap();
+        // `u.uid <> a.aid` filters out (1, 1)
+        assert_eq!(rows.len(), 1);
+        // the columns are in the order of FROM clause
+        let row = &rows[0];
+        assert_eq!(row.get_row_str(position("user", "uid")).unwrap(), "1");
+        assert_eq!(
+            row.get_row_str(user_len + position("article", "aid"))
+                .unwrap(),
+            "2"
+        );
+        assert_eq!(
+            row.get_row_str(user_len + article_len + position("user_read", "aid"))
+                .unwrap(),
+            "2"
+        );
+    }
+
+    fn mock_top_n(sql: &str) -> TopN {
+        let ast = Parser::parse_sql(&GenericDialect {}, sql).unwrap();
+        match &ast[0] {
+            Statement::Query(query) => TopN::from_query(query).unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_project_join() {
+        let sql = "SELECT a.title, u.uid + 1 AS next FROM user AS u JOIN article AS a \
+            ON u.uid = a.aid ORDER BY a.timestamp";
+        let (tables, operators, selection) = mock_from(sql);
+        let catalog = Catalog::mock();
+        let (mut fragments, join_plan) =
+            plan_join(&tables, &operators, selection, &catalog).unwrap();
+        let mut join_plan = join_plan.unwrap();
+        let Statement::Query(query) = &parse_sql(&GenericDialect {}, sql).unwrap()[0] else {
+            unreachable!()
+        };
+        let SetExpr::Select(select) = query.body.as_ref() else {
+            unreachable!()
         };
-        let return_number = std::cmp::min(return_number, results.len());
-        results[0..return_number].to_vec()
-    } else {
-        results
+        push_down_projection(
+            &mut fragments,
+            &mut join_plan,
+            &select.projection,
+            &query.order_by,
+            &catalog,
+        )
+        .unwrap();
+        let projection = |fragment: &JoinFragment| fragment.projection.iter().join(", ");
+        assert_eq!(projection(&fragments[0]), "u.uid");
+        assert_eq!(projection(&fragments[1]), "a.timestamp, a.aid, a.title");
+
+        let users = vec![MyRow::from(vec![Value::Int(1)]), vec![Value::Int(3)].into()];
+        let articles = vec![
+            MyRow::from(vec![
+                Value::Int(10),
+                Value::Int(1),
+                Value::Bytes(b"t1".to_vec()),
+            ]),
+            vec![Value::Int(20), Value::Int(2), Value::Bytes(b"t2".to_vec())].into(),
+        ];
+        let rows = do_join_plan(vec![users, articles], &join_plan)
+            .unwrap()
+            .iter()
+            .map(|row| row.get_raw_value().unwrap())
+            .collect();
+        let rows = project_join(rows, &join_plan).unwrap();
+        assert_eq!(rows, [vec![Value::Bytes(b"t1".to_vec()), Value::Int(2)]]);
+
+        // the column in both tables is ambiguous, instead of NULL
+        join_plan.projection = vec![SelectItem::UnnamedExpr(Expr::BinaryOp {
+            left: Box::new(Expr::Identifier(Ident::new("uid"))),
+            op: BinaryOperator::Plus,
+            right: Box::new(Expr::Identifier(Ident::new("aid"))),
+        })];
+        let row = vec![Value::Int(1), Value::Int(10), Value::Int(1), Value::NULL];
+        assert!(project_join(vec![row.clone()], &join_plan).is_ok());
+        join_plan
+            .tables
+            .push(("article".to_owned(), "b".to_owned()));
+        let err = project_join(vec![row], &join_plan).unwrap_err();
+        assert!(err.to_string().contains("ambiguous"), "{err}");
+    }
+
+    #[test]
+    fn test_order_by() {
+        let header = vec!["a.name".to_owned(), "timestamp".to_owned()];
+        let row = |name: &str, timestamp: Option<i64>| {
+            vec![
+                Value::Bytes(name.as_bytes().to_vec()),
+                timestamp.map_or(Value::NULL, Value::Int),
+            ]
+        };
+        let names = |rows: Vec<Vec<Value>>| {
+            rows.into_iter()
+                .map(|row| match &row[0] {
+                    Value::Bytes(bytes) => String::from_utf8(bytes.clone()).unwrap(),
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // merge the sorted runs of two shards
+        let runs = vec![
+            vec![row("u3", Some(30)), row("u1", Some(10)), row("u0", None)],
+            vec![row("u4", Some(40)), row("u2", Some(20))],
+        ];
+        let top_n = mock_top_n("SELECT * FROM user ORDER BY timestamp DESC LIMIT 4");
+        let rows = do_order_by_and_limit(runs.clone(), true, Some(top_n), &header, None).unwrap();
+        assert_eq!(names(rows), ["u4", "u3", "u2", "u1"]);
+
+        // each shard returns LIMIT + OFFSET rows, OFFSET is skipped after merging
+        let top_n = mock_top_n("SELECT * FROM user ORDER BY timestamp DESC LIMIT 2 OFFSET 1");
+        assert_eq!(top_n.shard_limit(), Some(3));
+        let rows = do_order_by_and_limit(runs.clone(), true, Some(top_n), &header, None).unwrap();
+        assert_eq!(names(rows), ["u3", "u2"]);
+
+        // keep the top rows of the unsorted rows, NULL is the first one in ascending order
+        let top_n = mock_top_n("SELECT * FROM user ORDER BY timestamp LIMIT 2 OFFSET 1");
+        let rows = do_order_by_and_limit(runs.clone(), false, Some(top_n), &header, None).unwrap();
+        assert_eq!(names(rows), ["u1", "u2"]);
+
+        let top_n = mock_top_n("SELECT * FROM user LIMIT 2 OFFSET 2");
+        let rows = do_order_by_and_limit(runs, false, Some(top_n), &header, None).unwrap();
+        assert_eq!(names(rows), ["u0", "u4"]);
+
+        // sort the unsorted rows, NULL is the last one unless NULLS FIRST
+        let runs = vec![vec![
+            row("u2", Some(20)),
+            row("u0", None),
+            row("u1", Some(10)),
+            row("u3", Some(20)),
+        ]];
+        let top_n = mock_top_n("SELECT * FROM user AS a ORDER BY timestamp DESC, a.name");
+        let rows = do_order_by_and_limit(runs.clone(), false, Some(top_n), &header, None).unwrap();
+        assert_eq!(names(rows), ["u2", "u3", "u1", "u0"]);
+
+        let top_n = mock_top_n("SELECT * FROM user ORDER BY 2 NULLS FIRST, 1 DESC");
+        let rows = do_order_by_and_limit(runs, false, Some(top_n), &header, None).unwrap();
+        assert_eq!(names(rows), ["u0", "u1", "u3", "u2"]);
+
+        // the runs are sorted by the case-insensitive collation in shards
+        let runs = vec![
+            vec![row("a", None), row("B", None)],
+            vec![row("A", None), row("b", None), row("C", None)],
+        ];
+        let top_n = mock_top_n("SELECT * FROM user AS a ORDER BY a.name");
+        let rows = do_order_by_and_limit(runs, true, Some(top_n), &header, None).unwrap();
+        let names = names(rows)
+            .into_iter()
+            .map(|name| name.to_lowercase())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a", "a", "b", "b", "c"]);
+    }
+
+    #[test]
+    fn test_top_n() {
+        let keys = [SortKey {
+            index: 0,
+            asc: true,
+            nulls_first: true,
+        }];
+        let row = |key: i64, name: &str| vec![Value::Int(key), Value::Bytes(name.into())];
+        let runs = vec![
+            vec![row(1, "a0"), row(2, "a1"), row(2, "a2")],
+            vec![row(2, "b0"), row(3, "b1")],
+            vec![],
+        ];
+
+        // the ties are kept in the order of runs, then in the order of each run
+        let rows = merge_sorted_runs(runs.clone(), &keys, usize::MAX);
+        assert_eq!(
+            rows,
+            [
+                row(1, "a0"),
+                row(2, "a1"),
+                row(2, "a2"),
+                row(2, "b0"),
+                row(3, "b1")
+            ]
+        );
+        assert_eq!(merge_sorted_runs(runs.clone(), &keys, 3).len(), 3);
+        assert!(merge_sorted_runs(vec![], &keys, 3).is_empty());
+
+        // the ties of unsorted rows are kept in their order
+        let rows = top_rows(runs.clone().into_iter().flatten(), &keys, 3);
+        assert_eq!(rows, [row(1, "a0"), row(2, "a1"), row(2, "a2")]);
+
+        // LIMIT larger than the rows keeps all of them, OFFSET past them keeps none
+        let header = vec!["uid".to_owned(), "name".to_owned()];
+        let top_n = mock_top_n("SELECT * FROM user ORDER BY uid LIMIT 100 OFFSET 1");
+        assert_eq!(top_n.shard_limit(), Some(101));
+        let rows = do_order_by_and_limit(runs.clone(), true, Some(top_n), &header, None).unwrap();
+        assert_eq!(rows.len(), 4);
+        let top_n = mock_top_n("SELECT * FROM user ORDER BY uid LIMIT 2 OFFSET 5");
+        let rows = do_order_by_and_limit(runs.clone(), false, Some(top_n), &header, None).unwrap();
+        assert!(rows.is_empty());
+
+        // OFFSET is skipped from the tied rows of different runs
+        let top_n = mock_top_n("SELECT * FROM user ORDER BY uid LIMIT 2 OFFSET 2");
+        let rows = do_order_by_and_limit(runs, true, Some(top_n), &header, None).unwrap();
+        assert_eq!(rows, [row(2, "a2"), row(2, "b0")]);
+
+        // without LIMIT the shards return all the rows
+        let top_n = mock_top_n("SELECT * FROM user ORDER BY uid OFFSET 2");
+        assert_eq!(top_n.shard_limit(), None);
+    }
+
+    #[test]
+    fn test_parse_sql() {
+        let dialect = GenericDialect {};
+        assert_eq!(parse_sql(&dialect, "SELECT 1; SELECT 2").unwrap().len(), 2);
+        let cases = [
+            (
+                "SELECT * FORM user",
+                "Expected end of statement, found: FORM at Line: 1, Column 10",
+            ),
+            (
+                "SELECT name FROM user\nWHERE uid = = 1",
+                "Expected an expression:, found: = at Line: 2, Column 13",
+            ),
+            (
+                "SELECT name FROM",
+                "Expected identifier, found: EOF at Line: 1, Column 17",
+            ),
+            (
+                "SELECT 'Beijing",
+                "Unterminated string literal at Line: 1, Column 8",
+            ),
+        ];
+        for (sql, message) in cases {
+            let error = parse_sql(&dialect, sql).unwrap_err().to_string();
+            assert!(error.ends_with(message), "{sql}: {error}");
+        }
     }
 }
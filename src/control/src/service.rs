@@ -1,12 +1,16 @@
-use crate::DbClient;
+use crate::query::ExecStream;
+use crate::session::SessionRef;
+use crate::transaction::Coordinator;
+use crate::{Catalog, DbClient, DEFAULT_DECISION_LOG};
 use common::{ServerId, StatusResult, TemporalGranularity};
 use protos::{
     control_server_server::ControlServer, ExecRequest, ExecResponse, GetArticleTextRequest,
-    ServerRegisterRequest, ServerRegisterResponse,
+    OpenSessionResponse, ServerRegisterRequest, ServerRegisterResponse,
 };
 use protos::{DbServerMeta, ListServerStatusResponse};
 use std::collections::HashMap;
-use std::sync::{atomic::AtomicU64, RwLock};
+use std::path::PathBuf;
+use std::sync::{atomic::AtomicU64, Arc, RwLock};
 use tokio::fs::read_to_string;
 use tonic::{Request, Response};
 use tracing::info;
@@ -19,6 +23,13 @@ pub struct Inner {
     pub db_server_meta: RwLock<HashMap<ServerId, DbServerMeta>>,
     pub clients: RwLock<HashMap<ServerId, DbClient>>,
     pub next_server_id: AtomicU64,
+    /// the schema of tables in shards, loaded after cluster init
+    pub catalog: RwLock<Arc<Catalog>>,
+    /// the coordinator of distributed transactions
+    pub coordinator: Coordinator,
+    /// the sessions of clients, each may be in a transaction
+    pub sessions: RwLock<HashMap<u64, SessionRef>>,
+    pub next_session_id: AtomicU64,
 }
 
 impl Default for ControlService {
@@ -29,11 +40,20 @@ impl Default for ControlService {
 
 impl ControlService {
     pub fn new() -> Self {
+        Self::with_decision_log(DEFAULT_DECISION_LOG)
+    }
+
+    /// Create the service logging the decisions of distributed transactions in `path`.
+    pub fn with_decision_log(path: impl Into<PathBuf>) -> Self {
         Self {
             inner: Inner {
                 db_server_meta: RwLock::new(Default::default()),
                 clients: RwLock::new(Default::default()),
                 next_server_id: AtomicU64::new(0),
+                catalog: RwLock::new(Default::default()),
+                coordinator: Coordinator::new(path),
+                sessions: RwLock::new(Default::default()),
+                next_session_id: AtomicU64::new(0),
             },
         }
     }
@@ -41,6 +61,8 @@ impl ControlService {
 
 #[tonic::async_trait]
 impl ControlServer for ControlService {
+    type StreamExecStream = ExecStream;
+
     async fn ping(&self, _: Request<()>) -> StatusResult<Response<()>> {
         info!("recv ping");
         Ok(Response::new(()))
@@ -77,9 +99,26 @@ impl ControlServer for ControlService {
 
     // Query Related
     async fn exec(&self, req: Request<ExecRequest>) -> StatusResult<Response<ExecResponse>> {
-        // let ExecRequest { statement } = req.into_inner();
-        let result = self.exec(req.into_inner()).await?;
-        Ok(Response::new(ExecResponse { result }))
+        let response = self.exec(req.into_inner()).await?;
+        Ok(Response::new(response))
+    }
+
+    async fn stream_exec(
+        &self,
+        req: Request<ExecRequest>,
+    ) -> StatusResult<Response<Self::StreamExecStream>> {
+        let stream = self.stream_exec(req.into_inner()).await?;
+        Ok(Response::new(stream))
+    }
+
+    async fn open_session(&self, _: Request<()>) -> StatusResult<Response<OpenSessionResponse>> {
+        let session_id = self.open_session();
+        Ok(Response::new(OpenSessionResponse { session_id }))
+    }
+
+    async fn close_session(&self, req: Request<u64>) -> StatusResult<Response<()>> {
+        self.close_session(req.into_inner()).await?;
+        Ok(Response::new(()))
     }
 
     async fn generate_popular_table(&self, req: Request<i32>) -> StatusResult<Response<()>> {
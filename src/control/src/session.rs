@@ -0,0 +1,241 @@
+//! Sessions of clients, the statements between BEGIN and COMMIT of a session are in
+//! a distributed transaction over the shards they touch.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use common::{MyRow, Result, RuntimeError, ServerId};
+use flexbuffers::Reader;
+use protos::{EndSessionRequest, SessionEnd, SessionExecRequest};
+use serde::Deserialize;
+use sqlparser::ast::Statement;
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+use tracing::info;
+
+use crate::{ControlService, DbClient};
+
+/// The transaction of a session.
+#[derive(Debug)]
+pub struct Transaction {
+    xid: String,
+    /// the servers touched by the transaction, which have begun the session
+    participants: BTreeMap<ServerId, DbClient>,
+}
+
+#[derive(Debug, Default)]
+pub struct Session {
+    txn: Option<Transaction>,
+}
+
+impl Session {
+    pub fn transaction(&mut self) -> Option<&mut Transaction> {
+        self.txn.as_mut()
+    }
+}
+
+pub type SessionRef = Arc<AsyncMutex<Session>>;
+
+impl ControlService {
+    pub fn open_session(&self) -> u64 {
+        let session_id = self.inner.next_session_id.fetch_add(1, Ordering::Relaxed);
+        self.inner
+            .sessions
+            .write()
+            .unwrap()
+            .insert(session_id, Default::default());
+        info!("open session {session_id}");
+        session_id
+    }
+
+    /// Close the session, its transaction is rolled back.
+    pub async fn close_session(&self, session_id: u64) -> Result<()> {
+        let session = self.inner.sessions.write().unwrap().remove(&session_id);
+        if let Some(session) = session {
+            info!("close session {session_id}");
+            self.rollback(&mut *session.lock().await).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn get_session(&self, session_id: u64) -> Result<SessionRef> {
+        self.inner
+            .sessions
+            .read()
+            .unwrap()
+            .get(&session_id)
+            .cloned()
+            .ok_or_else(|| RuntimeError::InvalidArg(format!("unknown session {session_id}")))
+    }
+
+    /// Lock the session if any, its statements are executed one by one.
+    pub(crate) async fn lock_session(
+        &self,
+        session_id: Option<u64>,
+    ) -> Result<Option<OwnedMutexGuard<Session>>> {
+        match session_id {
+            Some(session_id) => Ok(Some(self.get_session(session_id)?.lock_owned().await)),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn begin(&self, session: &mut Session) -> Result<()> {
+        if session.txn.is_some() {
+            return Err(RuntimeError::InvalidArg(
+                "the session is already in a transaction".to_owned(),
+            ));
+        }
+        session.txn = Some(Transaction {
+            xid: self.inner.coordinator.next_xid(),
+            participants: BTreeMap::new(),
+        });
+        Ok(())
+    }
+
+    /// Commit the transaction of the session, in two phases if it touches several servers.
+    pub(crate) async fn commit(&self, session: &mut Session) -> Result<()> {
+        let Some(Transaction { xid, participants }) = session.txn.take() else {
+            return Ok(());
+        };
+        let participants = participants.into_values().collect::<Vec<_>>();
+        if let [client] = participants.as_slice() {
+            return end_session(client, &xid, SessionEnd::CommitOnePhase).await;
+        }
+        let futs = participants
+            .iter()
+            .map(|client| end_session(client, &xid, SessionEnd::Prepare));
+        let prepared = futures::future::join_all(futs)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>();
+        if let Err(e) = prepared {
+            self.rollback_prepared(&xid, &participants).await;
+            return Err(e);
+        }
+        self.commit_prepared(&xid, &participants).await
+    }
+
+    pub(crate) async fn rollback(&self, session: &mut Session) -> Result<()> {
+        let Some(Transaction { xid, participants }) = session.txn.take() else {
+            return Ok(());
+        };
+        let futs = participants
+            .values()
+            .map(|client| end_session(client, &xid, SessionEnd::Rollback));
+        futures::future::join_all(futs)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        Ok(())
+    }
+
+    /// Get the client of the server in the transaction, begin the session in it at first.
+    async fn join(&self, txn: &mut Transaction, server_id: ServerId) -> Result<DbClient> {
+        if let Some(client) = txn.participants.get(&server_id) {
+            return Ok(client.clone());
+        }
+        let mut client = self.get_client(server_id)?;
+        client.begin_session(txn.xid.clone()).await?;
+        txn.participants.insert(server_id, client.clone());
+        Ok(client)
+    }
+
+    /// Execute the sqls in shards, in the transaction if any, return the rows of each shard.
+    pub(crate) async fn fetch_rows_in(
+        &self,
+        txn: Option<&mut Transaction>,
+        shard_sql: Vec<(ServerId, String)>,
+    ) -> Result<Vec<Vec<MyRow>>> {
+        let Some(txn) = txn else {
+            return self.fetch_rows(shard_sql).await;
+        };
+        let mut futs = vec![];
+        for (server_id, sql) in shard_sql {
+            let mut client = self.join(txn, server_id).await?;
+            let xid = txn.xid.clone();
+            futs.push(async move {
+                let resp = client
+                    .session_exec_sql(SessionExecRequest { xid, sql })
+                    .await?;
+                let buffer = resp.into_inner();
+                let s = Reader::get_root(buffer.as_slice())
+                    .map_err(|e| RuntimeError::DBTypeParseError(e.to_string()))?;
+                Ok(Vec::<MyRow>::deserialize(s)?)
+            });
+        }
+        futures::future::join_all(futs).await.into_iter().collect()
+    }
+
+    /// Execute the statements in each server in the transaction if any, otherwise
+    /// atomically by [`ControlService::exec_write`], return their affected rows.
+    pub(crate) async fn exec_write_in(
+        &self,
+        txn: Option<&mut Transaction>,
+        server_statements: Vec<(ServerId, Vec<String>)>,
+    ) -> Result<Vec<Vec<u64>>> {
+        let Some(txn) = txn else {
+            return self.exec_write(server_statements).await;
+        };
+        let mut futs = vec![];
+        for (server_id, statements) in server_statements {
+            let mut client = self.join(txn, server_id).await?;
+            let xid = txn.xid.clone();
+            futs.push(async move {
+                let mut affected = vec![];
+                for sql in statements {
+                    let req = SessionExecRequest {
+                        xid: xid.clone(),
+                        sql,
+                    };
+                    affected.push(client.session_exec_sql_drop(req).await?.into_inner());
+                }
+                Result::Ok(affected)
+            });
+        }
+        futures::future::join_all(futs).await.into_iter().collect()
+    }
+}
+
+/// Whether the statement is BEGIN, COMMIT or ROLLBACK.
+pub(crate) fn is_transaction_control(statement: &Statement) -> bool {
+    matches!(
+        statement,
+        Statement::StartTransaction { .. } | Statement::Commit { .. } | Statement::Rollback { .. }
+    )
+}
+
+async fn end_session(client: &DbClient, xid: &str, end: SessionEnd) -> Result<()> {
+    let req = EndSessionRequest {
+        xid: xid.to_owned(),
+        end: end as _,
+    };
+    client.clone().end_session(req).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ControlService;
+
+    #[tokio::test]
+    async fn test_session() {
+        let path = std::env::temp_dir().join(format!("ddbs_session_{}.log", std::process::id()));
+        let service = ControlService::with_decision_log(&path);
+        let session_id = service.open_session();
+        let session = service.get_session(session_id).unwrap();
+        {
+            let mut session = session.lock().await;
+            assert!(session.transaction().is_none());
+            service.begin(&mut session).unwrap();
+            assert!(service.begin(&mut session).is_err());
+            assert!(session.transaction().is_some());
+            // nothing touched, nothing to commit
+            service.commit(&mut session).await.unwrap();
+            assert!(session.transaction().is_none());
+            service.begin(&mut session).unwrap();
+        }
+        service.close_session(session_id).await.unwrap();
+        assert!(session.lock().await.transaction().is_none());
+        assert!(service.get_session(session_id).is_err());
+    }
+}
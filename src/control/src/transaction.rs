@@ -0,0 +1,301 @@
+//! Distributed transactions over shards by the two-phase commit of MySQL XA.
+//!
+//! The commit decision is durable in the decision log before any participant commits.
+//! A prepared transaction without a decision is rolled back in recovery (presumed abort).
+
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use common::{Result, RuntimeError, ServerId};
+use protos::PrepareRequest;
+use tracing::{info, warn};
+
+use crate::{ControlService, DbClient};
+
+pub const DEFAULT_DECISION_LOG: &str = "ddbs_decisions.log";
+const XID_PREFIX: &str = "ddbs";
+
+/// The append-only log of `COMMIT <xid>` and `DONE <xid>` records.
+#[derive(Debug)]
+pub struct DecisionLog {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+}
+
+impl DecisionLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            file: Mutex::new(None),
+        }
+    }
+
+    fn append(&self, record: &str, xid: &str) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+        let file = file.as_mut().unwrap();
+        writeln!(file, "{record} {xid}")?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// The committed transactions which may be not committed in all participants.
+    fn pending(&self) -> Result<HashSet<String>> {
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashSet::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut pending = HashSet::new();
+        for line in content.lines() {
+            match line.split_once(' ') {
+                Some(("COMMIT", xid)) => pending.insert(xid.to_owned()),
+                Some(("DONE", xid)) => pending.remove(xid),
+                _ => {
+                    return Err(RuntimeError::ConfigError(format!(
+                        "invalid record in {}: {line}",
+                        self.path.display()
+                    )))
+                }
+            };
+        }
+        Ok(pending)
+    }
+}
+
+/// The coordinator of two-phase commit.
+#[derive(Debug)]
+pub struct Coordinator {
+    log: DecisionLog,
+    /// the start time of control, distinguishing the transactions before a restart
+    epoch: u128,
+    next_txn: AtomicU64,
+}
+
+impl Coordinator {
+    pub fn new(log_path: impl Into<PathBuf>) -> Self {
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis();
+        Self {
+            log: DecisionLog::new(log_path),
+            epoch,
+            next_txn: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn next_xid(&self) -> String {
+        let txn = self.next_txn.fetch_add(1, Ordering::Relaxed);
+        format!("{XID_PREFIX}-{}-{txn}", self.epoch)
+    }
+
+    /// Whether the transaction was started before the restart of control.
+    fn is_in_doubt(&self, xid: &str) -> bool {
+        xid.strip_prefix(XID_PREFIX)
+            .and_then(|xid| xid.strip_prefix('-'))
+            .and_then(|xid| xid.split('-').next())
+            .is_some_and(|epoch| epoch != self.epoch.to_string())
+    }
+}
+
+impl ControlService {
+    pub(crate) fn get_client(&self, server_id: ServerId) -> Result<DbClient> {
+        let clients = self.inner.clients.read().unwrap();
+        clients
+            .get(&server_id)
+            .cloned()
+            .ok_or(RuntimeError::ServerNotAlive)
+    }
+
+    /// Execute the statements in each server atomically, return their affected rows.
+    ///
+    /// A single statement is executed directly, otherwise in a two-phase commit.
+    pub(crate) async fn exec_write(
+        &self,
+        server_statements: Vec<(ServerId, Vec<String>)>,
+    ) -> Result<Vec<Vec<u64>>> {
+        if let [(server_id, statements)] = server_statements.as_slice() {
+            if let [statement] = statements.as_slice() {
+                let affected = self
+                    .exec_drop(vec![(*server_id, statement.clone())])
+                    .await?;
+                return Ok(vec![affected]);
+            }
+        }
+        let participants = server_statements
+            .iter()
+            .map(|(server_id, _)| self.get_client(*server_id))
+            .collect::<Result<Vec<_>>>()?;
+        let coordinator = &self.inner.coordinator;
+        let xid = coordinator.next_xid();
+
+        // 1. prepare all participants
+        let futs = participants
+            .iter()
+            .zip(server_statements)
+            .map(|(client, (_, statements))| {
+                let mut client = client.clone();
+                let req = PrepareRequest {
+                    xid: xid.clone(),
+                    statements,
+                };
+                async move { client.prepare(req).await }
+            });
+        let prepared = futures::future::join_all(futs)
+            .await
+            .into_iter()
+            .collect::<std::result::Result<Vec<_>, _>>();
+        let prepared = match prepared {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                self.rollback_prepared(&xid, &participants).await;
+                return Err(e.into());
+            }
+        };
+
+        // 2. commit all participants
+        self.commit_prepared(&xid, &participants).await?;
+        Ok(prepared
+            .into_iter()
+            .map(|resp| resp.into_inner().affected_rows)
+            .collect())
+    }
+
+    /// Log the decision and commit the prepared participants.
+    ///
+    /// The participants are rolled back if the decision fails to log.
+    pub(crate) async fn commit_prepared(&self, xid: &str, participants: &[DbClient]) -> Result<()> {
+        let coordinator = &self.inner.coordinator;
+        if let Err(e) = coordinator.log.append("COMMIT", xid) {
+            self.rollback_prepared(xid, participants).await;
+            return Err(e);
+        }
+        let futs = participants.iter().map(|client| {
+            let mut client = client.clone();
+            let xid = xid.to_owned();
+            async move { client.commit(xid).await }
+        });
+        let mut done = true;
+        for result in futures::future::join_all(futs).await {
+            if let Err(e) = result {
+                warn!("commit {xid} failed, left to recovery: {e}");
+                done = false;
+            }
+        }
+        // the transaction is committed, the DONE entry is written again by recovery
+        if done {
+            if let Err(e) = coordinator.log.append("DONE", xid) {
+                warn!("log the end of {xid} failed, left to recovery: {e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Abort the transaction, the participants failed to prepare have rolled back.
+    pub(crate) async fn rollback_prepared(&self, xid: &str, participants: &[DbClient]) {
+        let futs = participants.iter().map(|client| {
+            let mut client = client.clone();
+            let xid = xid.to_owned();
+            async move { client.rollback(xid).await }
+        });
+        for result in futures::future::join_all(futs).await {
+            if let Err(e) = result {
+                warn!("roll back {xid} failed, left to recovery: {e}");
+            }
+        }
+    }
+
+    /// Resolve the prepared transactions left by the last run of control,
+    /// commit them if the decision is logged, otherwise roll back.
+    ///
+    /// The transactions failed to resolve are logged, their decisions are kept pending
+    /// to be resolved in the next run.
+    pub async fn resolve_in_doubt(&self) -> Result<()> {
+        let coordinator = &self.inner.coordinator;
+        let pending = coordinator.log.pending()?;
+        let clients = self
+            .inner
+            .clients
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(server_id, client)| (*server_id, client.clone()))
+            .collect::<Vec<_>>();
+        // the decisions are kept if the in-doubt transactions of any server are unknown
+        let mut recovered = true;
+        let mut failed = HashSet::new();
+        for (server_id, mut client) in clients {
+            let xids = match client.recover(()).await {
+                Ok(resp) => resp.into_inner().xids,
+                Err(e) => {
+                    warn!("recover server {server_id} failed: {e}");
+                    recovered = false;
+                    continue;
+                }
+            };
+            for xid in xids.into_iter().filter(|xid| coordinator.is_in_doubt(xid)) {
+                let result = if pending.contains(&xid) {
+                    info!("commit in-doubt transaction {xid}");
+                    client.commit(xid.clone()).await
+                } else {
+                    info!("roll back in-doubt transaction {xid}");
+                    client.rollback(xid.clone()).await
+                };
+                if let Err(e) = result {
+                    warn!("resolve {xid} in server {server_id} failed: {e}");
+                    failed.insert(xid);
+                }
+            }
+        }
+        if !recovered {
+            return Ok(());
+        }
+        for xid in pending
+            .iter()
+            .filter(|xid| coordinator.is_in_doubt(xid) && !failed.contains(*xid))
+        {
+            if let Err(e) = coordinator.log.append("DONE", xid) {
+                warn!("log the end of {xid} failed: {e}");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Coordinator, DecisionLog};
+
+    #[test]
+    fn test_decision_log() {
+        let path = std::env::temp_dir().join(format!("ddbs_decisions_{}.log", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let log = DecisionLog::new(&path);
+        assert!(log.pending().unwrap().is_empty());
+        log.append("COMMIT", "ddbs-1-0").unwrap();
+        log.append("COMMIT", "ddbs-1-1").unwrap();
+        log.append("DONE", "ddbs-1-0").unwrap();
+        let pending = DecisionLog::new(&path).pending().unwrap();
+        assert_eq!(pending.into_iter().collect::<Vec<_>>(), ["ddbs-1-1"]);
+        std::fs::remove_file(&path).unwrap();
+
+        let coordinator = Coordinator::new(&path);
+        let xid = coordinator.next_xid();
+        assert!(!coordinator.is_in_doubt(&xid));
+        assert!(coordinator.is_in_doubt("ddbs-1-1"));
+        assert!(!coordinator.is_in_doubt("other-1-1"));
+    }
+}
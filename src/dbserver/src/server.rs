@@ -7,10 +7,16 @@ use mysql::prelude::*;
 use mysql::*;
 use protos::{control_server_client::ControlServerClient, db_server_server::DbServer as Server};
 use protos::{
-    AppTables, DbShard, ExecSqlBatchRequest, ExecSqlFirstResponse, ServerRegisterRequest,
+    AppTables, DbShard, EndSessionRequest, ExecSqlBatchRequest, ExecSqlFirstResponse,
+    ExecSqlWithSetupRequest, PrepareRequest, PrepareResponse, RecoverResponse,
+    ServerRegisterRequest, SessionEnd, SessionExecRequest,
 };
 use serde::Serialize;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{fs, io::Write};
 use tokio::sync::{
     mpsc::{self, Receiver},
@@ -19,12 +25,28 @@ use tokio::sync::{
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tonic::transport::{Channel, Uri};
 use tonic::{Request, Response, Status};
-use tracing::{info, trace};
+use tracing::{info, trace, warn};
+
+/// The error code of an unknown XA transaction, e.g. it is already rolled back.
+const ER_XAER_NOTA: u16 = 1397;
+
+/// The session idle for this long is rolled back, to release its connection and locks.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// The connections pinned by the sessions in transaction, keyed by the xid.
+type Sessions = Arc<Mutex<HashMap<String, Session>>>;
+
+struct Session {
+    /// `None` while the session is beginning or executing a statement
+    conn: Option<PooledConn>,
+    last_active: Instant,
+}
 
 pub struct DbServer {
     control_client: AsyncMutex<ControlServerClient<Channel>>,
     /// state that need init
     inner: OnceCell<Inner>,
+    sessions: Sessions,
 }
 
 struct Inner {
@@ -48,9 +70,12 @@ impl DbServer {
                 uri: uri.to_string(),
             })
             .await?;
+        let sessions = Sessions::default();
+        tokio::spawn(expire_sessions(sessions.clone()));
         Ok(DbServer {
             control_client: AsyncMutex::new(control_client),
             inner: OnceCell::new(),
+            sessions,
         })
     }
 
@@ -178,12 +203,69 @@ impl DbServer {
     }
 
     #[aux_macro::elapsed]
-    fn execute_sql_drop(&self, sql: String) -> Result<()> {
+    fn execute_sql_drop(&self, sql: String) -> Result<u64> {
         trace!("exec sql drop: {sql}");
         let inner = self.get_inner()?;
         let mut conn = inner.connection_pool.get_conn()?;
         conn.query_drop(sql)?;
-        Ok(())
+        Ok(conn.affected_rows())
+    }
+
+    #[aux_macro::elapsed]
+    fn prepare(&self, xid: &str, statements: Vec<String>) -> Result<Vec<u64>> {
+        trace!("prepare {xid}: {statements:?}");
+        check_xid(xid)?;
+        let inner = self.get_inner()?;
+        // the connection is closed after preparing, then the prepared transaction
+        // is detached from it and can be committed in any connection
+        let mut conn = inner.connection_pool.get_conn()?.unwrap();
+        conn.query_drop(format!("XA START '{xid}'"))?;
+        let exec = || {
+            let mut affected_rows = vec![];
+            for statement in statements {
+                conn.query_drop(statement)?;
+                affected_rows.push(conn.affected_rows());
+            }
+            conn.query_drop(format!("XA END '{xid}'"))?;
+            conn.query_drop(format!("XA PREPARE '{xid}'"))?;
+            Ok(affected_rows)
+        };
+        let result = exec();
+        if result.is_err() {
+            conn.query_drop(format!("XA END '{xid}'")).ok();
+            conn.query_drop(format!("XA ROLLBACK '{xid}'")).ok();
+        }
+        result
+    }
+
+    /// Commit or roll back the prepared transaction.
+    ///
+    /// Rolling back an unknown transaction is done, e.g. it failed to prepare, but committing
+    /// it is an error, since it may have been rolled back.
+    fn finish(&self, xid: &str, commit: bool) -> Result<()> {
+        trace!("finish {xid}, commit: {commit}");
+        check_xid(xid)?;
+        let inner = self.get_inner()?;
+        let mut conn = inner.connection_pool.get_conn()?;
+        let action = if commit { "COMMIT" } else { "ROLLBACK" };
+        match conn.query_drop(format!("XA {action} '{xid}'")) {
+            Err(Error::MySqlError(e)) if !commit && e.code == ER_XAER_NOTA => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    fn recover(&self) -> Result<Vec<String>> {
+        let inner = self.get_inner()?;
+        let mut conn = inner.connection_pool.get_conn()?;
+        // the columns are formatID, gtrid_length, bqual_length and data
+        let xids = conn
+            .query::<(i64, usize, usize, Vec<u8>), _>("XA RECOVER")?
+            .into_iter()
+            .map(|(_, gtrid_length, _, data)| {
+                String::from_utf8_lossy(&data[..gtrid_length]).into_owned()
+            })
+            .collect();
+        Ok(xids)
     }
 
     #[aux_macro::elapsed]
@@ -205,17 +287,176 @@ impl DbServer {
         trace!("exec sql: {sql}");
         let inner = self.get_inner()?;
         let mut conn = inner.connection_pool.get_conn()?;
-        let my_row_vec: Vec<MyRow> = conn
-            .exec(sql, ())?
+        query_rows(&mut conn, sql)
+    }
+
+    fn exec_sql_with_setup(&self, req: ExecSqlWithSetupRequest) -> Result<Vec<u8>> {
+        let ExecSqlWithSetupRequest {
+            setup,
+            sql,
+            cleanup,
+        } = req;
+        trace!("exec sql with {} setup statements: {sql}", setup.len());
+        let inner = self.get_inner()?;
+        let mut conn = inner.connection_pool.get_conn()?;
+        let result = setup
             .into_iter()
-            .map(|row: Row| row.into())
-            .collect();
-        let mut s = FlexbufferSerializer::new();
-        my_row_vec.serialize(&mut s).expect("serialize error");
-        Ok(s.take_buffer())
+            .try_for_each(|statement| conn.query_drop(statement))
+            .map_err(Into::into)
+            .and_then(|_| query_rows(&mut conn, sql));
+        // the connection returns to the pool, so the temporary tables must be dropped
+        for statement in cleanup {
+            conn.query_drop(statement)?;
+        }
+        result
+    }
+
+    fn begin_session(&self, xid: String) -> Result<()> {
+        trace!("begin session {xid}");
+        check_xid(&xid)?;
+        // reserve the xid before starting the transaction
+        match self.sessions.lock().unwrap().entry(xid.clone()) {
+            Entry::Occupied(_) => {
+                return Err(RuntimeError::RpcInvalidArg(format!(
+                    "session {xid} has begun"
+                )))
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Session {
+                    conn: None,
+                    last_active: Instant::now(),
+                });
+            }
+        }
+        let start = || {
+            let mut conn = self.get_inner()?.connection_pool.get_conn()?;
+            conn.query_drop(format!("XA START '{xid}'"))?;
+            Ok(conn)
+        };
+        let result = start();
+        let mut sessions = self.sessions.lock().unwrap();
+        match result {
+            Ok(conn) => {
+                sessions.get_mut(&xid).unwrap().conn = Some(conn);
+                Ok(())
+            }
+            Err(e) => {
+                sessions.remove(&xid);
+                Err(e)
+            }
+        }
+    }
+
+    /// Take the connection out of the session, which is busy until it is put back.
+    fn take_session(&self, xid: &str) -> Result<PooledConn> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .get_mut(xid)
+            .ok_or_else(|| RuntimeError::RpcInvalidArg(format!("unknown session {xid}")))?;
+        session
+            .conn
+            .take()
+            .ok_or_else(|| RuntimeError::RpcInvalidArg(format!("session {xid} is busy")))
+    }
+
+    /// Run `f` in the connection of the session.
+    ///
+    /// The connection is taken out during `f`, so the statements of a session are serial.
+    fn with_session<T>(
+        &self,
+        xid: &str,
+        f: impl FnOnce(&mut PooledConn) -> Result<T>,
+    ) -> Result<T> {
+        let mut conn = self.take_session(xid)?;
+        let result = f(&mut conn);
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(xid).unwrap();
+        session.conn = Some(conn);
+        session.last_active = Instant::now();
+        result
+    }
+
+    fn end_session(&self, xid: String, end: SessionEnd) -> Result<()> {
+        trace!("end session {xid}: {end:?}");
+        let mut conn = self.take_session(&xid)?;
+        self.sessions.lock().unwrap().remove(&xid);
+        let result = match end {
+            SessionEnd::Prepare => conn
+                .query_drop(format!("XA END '{xid}'"))
+                .and_then(|_| conn.query_drop(format!("XA PREPARE '{xid}'"))),
+            SessionEnd::Rollback => {
+                conn.query_drop(format!("XA END '{xid}'")).ok();
+                conn.query_drop(format!("XA ROLLBACK '{xid}'"))
+            }
+            SessionEnd::CommitOnePhase => conn
+                .query_drop(format!("XA END '{xid}'"))
+                .and_then(|_| conn.query_drop(format!("XA COMMIT '{xid}' ONE PHASE"))),
+        };
+        match (end, result) {
+            // detach the prepared transaction from the connection as `prepare`
+            (SessionEnd::Prepare, Ok(())) => drop(conn.unwrap()),
+            (_, Ok(())) => {}
+            (_, Err(e)) => {
+                conn.query_drop(format!("XA ROLLBACK '{xid}'")).ok();
+                drop(conn.unwrap());
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Roll back the sessions idle longer than [`SESSION_IDLE_TIMEOUT`] periodically,
+/// e.g. their control server has crashed.
+async fn expire_sessions(sessions: Sessions) {
+    let mut interval = tokio::time::interval(SESSION_IDLE_TIMEOUT / 10);
+    loop {
+        interval.tick().await;
+        let expired = {
+            let mut sessions = sessions.lock().unwrap();
+            let xids = sessions
+                .iter()
+                .filter(|(_, session)| {
+                    session.conn.is_some() && session.last_active.elapsed() > SESSION_IDLE_TIMEOUT
+                })
+                .map(|(xid, _)| xid.clone())
+                .collect::<Vec<_>>();
+            xids.into_iter()
+                .map(|xid| {
+                    let conn = sessions.remove(&xid).unwrap().conn.unwrap();
+                    (xid, conn)
+                })
+                .collect::<Vec<_>>()
+        };
+        if expired.is_empty() {
+            continue;
+        }
+        tokio::task::spawn_blocking(move || {
+            for (xid, mut conn) in expired {
+                info!("roll back idle session {xid}");
+                conn.query_drop(format!("XA END '{xid}'")).ok();
+                if let Err(e) = conn.query_drop(format!("XA ROLLBACK '{xid}'")) {
+                    warn!("roll back idle session {xid} failed: {e}");
+                    // close the connection, then its transaction is rolled back
+                    drop(conn.unwrap());
+                }
+            }
+        });
     }
 }
 
+/// Execute the query and serialize the rows.
+fn query_rows(conn: &mut impl Queryable, sql: String) -> Result<Vec<u8>> {
+    let my_row_vec: Vec<MyRow> = conn
+        .exec(sql, ())?
+        .into_iter()
+        .map(|row: Row| row.into())
+        .collect();
+    let mut s = FlexbufferSerializer::new();
+    my_row_vec.serialize(&mut s).expect("serialize error");
+    Ok(s.take_buffer())
+}
+
 #[tonic::async_trait]
 impl Server for DbServer {
     type StreamExecSqlStream = Pin<Box<dyn Stream<Item = StatusResult<Vec<u8>>> + Send>>;
@@ -280,6 +521,17 @@ impl Server for DbServer {
         Ok(Response::new(response))
     }
 
+    /// `exec_sql_with_setup` executes the setup statements, the query and the cleanup
+    /// statements in one connection, so the query can refer to the temporary tables
+    /// created in the setup.
+    async fn exec_sql_with_setup(
+        &self,
+        req: Request<ExecSqlWithSetupRequest>,
+    ) -> StatusResult<Response<Vec<u8>>> {
+        let response = self.exec_sql_with_setup(req.into_inner())?;
+        Ok(Response::new(response))
+    }
+
     /// `exec_sql_batch` is used for *batch* purpose (e.g. read a large bunch of tuples).
     /// The request has a paramater called batch_size,
     /// which indicates the results stream size (in terms of rows).
@@ -320,11 +572,87 @@ impl Server for DbServer {
     }
 
     /// `exec_sql_drop` is used to execute a query,
-    /// do not result any tuples.
+    /// do not result any tuples but the number of affected rows.
     ///
     /// Typical usage is update, delete, create table or something that result is not needed.
-    async fn exec_sql_drop(&self, req: Request<String>) -> StatusResult<Response<()>> {
-        self.execute_sql_drop(req.into_inner())?;
+    async fn exec_sql_drop(&self, req: Request<String>) -> StatusResult<Response<u64>> {
+        let affected_rows = self.execute_sql_drop(req.into_inner())?;
+        Ok(Response::new(affected_rows))
+    }
+
+    /// `prepare` is the first phase of two-phase commit,
+    /// the statements are executed in a XA transaction which is prepared at last.
+    ///
+    /// The transaction is rolled back if any statement fails.
+    async fn prepare(
+        &self,
+        req: Request<PrepareRequest>,
+    ) -> StatusResult<Response<PrepareResponse>> {
+        let PrepareRequest { xid, statements } = req.into_inner();
+        let affected_rows = self.prepare(&xid, statements)?;
+        Ok(Response::new(PrepareResponse { affected_rows }))
+    }
+
+    async fn commit(&self, req: Request<String>) -> StatusResult<Response<()>> {
+        self.finish(&req.into_inner(), true)?;
+        Ok(Response::new(()))
+    }
+
+    async fn rollback(&self, req: Request<String>) -> StatusResult<Response<()>> {
+        self.finish(&req.into_inner(), false)?;
         Ok(Response::new(()))
     }
+
+    /// `recover` lists the prepared transactions, which are in doubt if the coordinator crashes.
+    async fn recover(&self, _: Request<()>) -> StatusResult<Response<RecoverResponse>> {
+        let xids = self.recover()?;
+        Ok(Response::new(RecoverResponse { xids }))
+    }
+
+    /// `begin_session` pins a connection to the session of a client,
+    /// the statements of the session are executed in the XA transaction of the xid.
+    async fn begin_session(&self, req: Request<String>) -> StatusResult<Response<()>> {
+        self.begin_session(req.into_inner())?;
+        Ok(Response::new(()))
+    }
+
+    async fn session_exec_sql(
+        &self,
+        req: Request<SessionExecRequest>,
+    ) -> StatusResult<Response<Vec<u8>>> {
+        let SessionExecRequest { xid, sql } = req.into_inner();
+        trace!("session {xid} exec sql: {sql}");
+        let rows = self.with_session(&xid, |conn| query_rows(conn, sql))?;
+        Ok(Response::new(rows))
+    }
+
+    async fn session_exec_sql_drop(
+        &self,
+        req: Request<SessionExecRequest>,
+    ) -> StatusResult<Response<u64>> {
+        let SessionExecRequest { xid, sql } = req.into_inner();
+        trace!("session {xid} exec sql drop: {sql}");
+        let affected_rows = self.with_session(&xid, |conn| {
+            conn.query_drop(sql)?;
+            Ok(conn.affected_rows())
+        })?;
+        Ok(Response::new(affected_rows))
+    }
+
+    /// `end_session` ends the XA transaction of the session and releases the connection.
+    async fn end_session(&self, req: Request<EndSessionRequest>) -> StatusResult<Response<()>> {
+        let EndSessionRequest { xid, end } = req.into_inner();
+        let end = SessionEnd::from_i32(end)
+            .ok_or_else(|| RuntimeError::RpcInvalidArg("session end is invalid".to_owned()))?;
+        self.end_session(xid, end)?;
+        Ok(Response::new(()))
+    }
+}
+
+/// The xid is quoted in the XA statements.
+fn check_xid(xid: &str) -> Result<()> {
+    if xid.is_empty() || !xid.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(RuntimeError::RpcInvalidArg(format!("invalid xid {xid}")));
+    }
+    Ok(())
 }
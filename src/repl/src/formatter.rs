@@ -1,77 +1,106 @@
-pub use common::{
-    Result, RuntimeError,
-    RuntimeError::{JsonContentError, JsonParseError},
-};
-
 pub use std::{fmt::Write, str::FromStr};
 
 pub use prettytable::{cell, color, row, Attr, Cell, Row, Table};
-pub use serde_json::Value;
-
-pub fn format_output(data: &str) -> Result<String> {
-    let v: Value = serde_json::from_str(data).map_err(|_| JsonParseError)?;
-
-    let profile = &v["profile"];
-    let total_time = profile["totalTime"].as_f64().unwrap_or(f64::NAN);
-    let parser_time = profile["parserTime"].as_f64().unwrap_or(f64::NAN);
-    let rewrite_time = profile["rewriteTime"].as_f64().unwrap_or(f64::NAN);
-    let exec_time = profile["execTime"].as_f64().unwrap_or(f64::NAN);
+use protos::{value::Kind, StreamExecResponse};
 
-    let mut ret = String::new();
+/// Format the responses of `StreamExec` as they arrive.
+#[derive(Debug, Default)]
+pub struct StreamFormatter {
+    header: Vec<String>,
+    /// whether the statement returns a result set
+    has_result_set: bool,
+    lines: usize,
+}
 
-    if let Value::Object(result_set) = &v["resultSet"] {
-        let header = result_set
-            .get("header")
-            .and_then(|h| h.as_array())
-            .ok_or(JsonContentError)?
-            .iter()
-            .map(|v| v.as_str().map(|x| x.to_string()))
-            .collect::<Option<Vec<_>>>()
-            .ok_or(JsonContentError)?;
+impl StreamFormatter {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        let result_set_results = result_set
-            .get("table")
-            .ok_or(JsonContentError)?
-            .as_array()
-            .ok_or(JsonContentError)?
-            .iter()
-            .map(|row| {
-                Ok::<_, RuntimeError>(
-                    row.as_array()
-                        .ok_or(JsonContentError)?
-                        .iter()
-                        .map(format_value)
-                        .collect(),
-                )
-            });
+    /// Format a response, the rows of a batch are in a table,
+    /// the first table has the header.
+    pub fn format(&mut self, resp: StreamExecResponse) -> String {
+        let mut ret = String::new();
+        let Some(profile) = resp.profile else {
+            self.has_result_set = true;
+            if !resp.schema.is_empty() {
+                self.header = resp.schema.into_iter().map(|column| column.name).collect();
+            }
+            if !resp.rows.is_empty() {
+                let rows = resp
+                    .rows
+                    .iter()
+                    .map(|row| row.values.iter().map(format_value).collect())
+                    .collect::<Vec<_>>();
+                if self.lines == 0 {
+                    ret += &table_string(&self.header, &rows);
+                } else {
+                    ret += &table_string(&[], &rows);
+                }
+                self.lines += rows.len();
+            }
+            return ret;
+        };
 
-        let mut result_set = vec![];
-        for x in result_set_results {
-            result_set.push(x?);
-        }
-        if !header.is_empty() {
-            ret += &table_string(&header, &result_set);
+        // the last response
+        if let Some(affected_rows) = resp.affected_rows {
+            ret += &format!("{affected_rows} rows affected.\n");
+        } else if self.has_result_set {
+            if self.lines == 0 && !self.header.is_empty() {
+                ret += &table_string(&self.header, &[]);
+            }
+            ret += &format!("{} lines returned.\n", self.lines);
+        } else {
+            ret += "No result produced.\n";
         }
-        ret += &format!("{} lines returned.\n", result_set.len());
-    } else {
-        ret += "No result produced.\n";
+        ret += &format!(
+            "Time: total {:.2} ms, parser {:.2} ms, rewrite {:.2} ms, execution {:.2} ms\n",
+            profile.total_time, profile.parser_time, profile.rewrite_time, profile.exec_time
+        );
+        ret
     }
-
-    ret += &format!(
-        "Time: total {total_time:.2} ms, parser {parser_time:.2} ms, rewrite {rewrite_time:.2} ms, execution {exec_time:.2} ms\n"
-    );
-    Ok(ret)
 }
 
 fn table_string(titles: &[String], contents: &[Vec<String>]) -> String {
     let mut table = Table::new();
-    table.add_row(Row::new(titles.iter().map(|t| Cell::new(t)).collect()));
+    if !titles.is_empty() {
+        table.add_row(Row::new(titles.iter().map(|t| Cell::new(t)).collect()));
+    }
     for row_content in contents.iter() {
         table.add_row(Row::new(row_content.iter().map(|r| Cell::new(r)).collect()));
     }
     table.to_string()
 }
 
-fn format_value(val: &Value) -> String {
-    val.to_string()
+fn format_value(val: &protos::Value) -> String {
+    match &val.kind {
+        None => "null".to_owned(),
+        Some(Kind::Int(v)) => v.to_string(),
+        Some(Kind::Uint(v)) => v.to_string(),
+        Some(Kind::Double(v)) => v.to_string(),
+        Some(Kind::Bytes(v)) => format!("{:?}", String::from_utf8_lossy(v)),
+        Some(Kind::Date(d)) => {
+            let mut s = format!(
+                "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                d.year, d.month, d.day, d.hour, d.minute, d.second
+            );
+            if d.micro_second > 0 {
+                s += &format!(".{:06}", d.micro_second);
+            }
+            s
+        }
+        Some(Kind::Time(t)) => {
+            let mut s = format!(
+                "{}{:02}:{:02}:{:02}",
+                if t.negative { "-" } else { "" },
+                t.days * 24 + t.hours,
+                t.minutes,
+                t.seconds
+            );
+            if t.micro_seconds > 0 {
+                s += &format!(".{:06}", t.micro_seconds);
+            }
+            s
+        }
+    }
 }
@@ -25,6 +25,8 @@ pub struct Repl {
     #[allow(unused)]
     store_client: Vec<DbServerClient<Channel>>,
     history_file: PathBuf,
+    /// the session of the connection, in which BEGIN starts a transaction
+    session_id: u64,
 }
 
 impl Repl {
@@ -40,7 +42,12 @@ impl Repl {
 
         let mut editor = Editor::<()>::new();
         let prompt = String::new();
-        let control_client = ControlServerClient::connect(channel).await?;
+        let mut control_client = ControlServerClient::connect(channel).await?;
+        let session_id = control_client
+            .open_session(())
+            .await?
+            .into_inner()
+            .session_id;
 
         let mut store_client = vec![];
         for store_server in opts.store_server {
@@ -64,6 +71,7 @@ impl Repl {
             control_client,
             store_client,
             history_file,
+            session_id,
         })
     }
 
@@ -94,27 +102,32 @@ impl Repl {
                     let timer = std::time::Instant::now();
                     let result = self
                         .control_client
-                        .exec(ExecRequest {
+                        .stream_exec(ExecRequest {
                             statement: statement.to_string(),
+                            session_id: Some(self.session_id),
+                            ..Default::default()
                         })
                         .await;
-                    let total_time = timer.elapsed();
 
+                    // print the rows as they arrive
                     match result {
-                        Ok(data) => {
-                            let formatted_output =
-                                formatter::format_output(data.into_inner().result.as_str());
-                            if formatted_output.is_err() {
-                                println!(
-                                    "format output error {:?}",
-                                    formatted_output.err().unwrap()
-                                );
-                            } else {
-                                print!("{}", formatted_output.unwrap());
+                        Ok(stream) => {
+                            let mut stream = stream.into_inner();
+                            let mut formatter = formatter::StreamFormatter::new();
+                            loop {
+                                match stream.message().await {
+                                    Ok(Some(resp)) => print!("{}", formatter.format(resp)),
+                                    Ok(None) => break,
+                                    Err(status) => {
+                                        println!("{status}");
+                                        break;
+                                    }
+                                }
                             }
                         }
                         Err(status) => println!("{status}"),
                     };
+                    let total_time = timer.elapsed();
 
                     println!("Total time: {:.2} ms", total_time.as_secs_f64() * 1000.0);
                 }
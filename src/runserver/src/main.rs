@@ -37,6 +37,12 @@ pub enum ServerType {
         /// Controler address
         #[clap(short, long, default_value = "0.0.0.0:27022", value_name = "HOST:PORT")]
         addr: String,
+        /// The sharding rules, the rules in `src/common/sharding_rules.toml` are used by default
+        #[clap(short = 'r', long, value_name = "PATH")]
+        sharding_rules: Option<String>,
+        /// The log of the commit decisions of distributed transactions
+        #[clap(short, long, default_value = control::DEFAULT_DECISION_LOG, value_name = "PATH")]
+        decision_log: String,
     },
     #[clap(about = "Run as a DBMS Server daemon")]
     DbServer {
@@ -72,10 +78,17 @@ const DEFAULT_ALLOW_HEADERS: [&str; 4] =
 ///listener.
 async fn run_server(args: CliArgs) -> Result<()> {
     match args.server_type {
-        ServerType::Control { addr } => {
+        ServerType::Control {
+            addr,
+            sharding_rules,
+            decision_log,
+        } => {
+            if let Some(path) = sharding_rules {
+                common::init_sharding_rules(&path)?;
+            }
             let addr = addr.to_socket_addrs()?.next().unwrap();
             let incoming_listener = TcpListenerStream::new(TcpListener::bind(addr).await?);
-            let control_service = ControlService::new();
+            let control_service = ControlService::with_decision_log(decision_log);
             let service = protos::control_server_server::ControlServerServer::new(control_service);
             let cors_layer = CorsLayer::new()
                 .allow_methods([Method::GET, Method::POST])